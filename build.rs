@@ -5,7 +5,27 @@ use std::{
     path::{Path, PathBuf},
 };
 
-fn build_slang(file: &str) {
+// Threads per workgroup of the compute shaders, the same value is compiled into the
+// dispatches. See ComputeSettings.
+const DEFAULT_WORKGROUP_SIZE: u32 = 64;
+
+fn workgroup_size() -> u32 {
+    println!("cargo:rerun-if-env-changed=RAYTRACER_WORKGROUP_SIZE");
+    let size = match std::env::var("RAYTRACER_WORKGROUP_SIZE") {
+        Ok(s) => s
+            .parse()
+            .expect("RAYTRACER_WORKGROUP_SIZE is not a whole number"),
+        Err(_) => DEFAULT_WORKGROUP_SIZE,
+    };
+    // WebGPU's default limit on invocations per workgroup is 256:
+    assert!(
+        (1..=256).contains(&size),
+        "RAYTRACER_WORKGROUP_SIZE must be between 1 and 256, not {size}"
+    );
+    size
+}
+
+fn build_slang(file: &str, workgroup_size: u32) {
     let manifest_dir = std::env::var("CARGO_MANIFEST_DIR").unwrap();
     let out_dir = PathBuf::from(std::env::var("OUT_DIR").unwrap());
 
@@ -23,6 +43,7 @@ fn build_slang(file: &str) {
 
     let output = std::process::Command::new("slangc")
         .args(["-profile", "glsl_460"])
+        .arg(format!("-DWORKGROUP_SIZE={workgroup_size}"))
         .arg(&shader)
         .arg("-o")
         .arg(&output_spv)
@@ -43,10 +64,14 @@ fn build_slang(file: &str) {
 fn main() {
     println!("cargo:rerun-if-changed=shaders");
 
-    build_slang("render");
-    build_slang("sample");
-    build_slang("ray_extend");
-    build_slang("shade");
+    let workgroup_size = workgroup_size();
+    println!("cargo:rustc-env=RAYTRACER_WORKGROUP_SIZE={workgroup_size}");
+
+    build_slang("render", workgroup_size);
+    build_slang("sample", workgroup_size);
+    build_slang("ray_extend", workgroup_size);
+    build_slang("shade", workgroup_size);
+    build_slang("bloom", workgroup_size);
     // build_slang("logic");
     // build_slang("new_ray");
     // build_slang("extension");
@@ -17,13 +17,16 @@ impl BevyApp {
         }
     }
 
-    pub fn run(&mut self) {
+    pub fn startup(&mut self) {
         if !self.startup_has_run {
             self.world.run_schedule(schedule::PreStartup);
             self.world.run_schedule(schedule::Startup);
             self.startup_has_run = true;
         }
+    }
 
+    pub fn run(&mut self) {
+        self.startup();
         self.world.run_schedule(schedule::Update);
     }
 }
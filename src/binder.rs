@@ -1,19 +1,30 @@
 use std::{collections::HashMap, io::Read, num::NonZero};
 
-use bevy_ecs::prelude::*;
-use glam::Vec4;
+use bevy_ecs::{prelude::*, system::SystemParam};
+use glam::{Vec3, Vec4};
 use itertools::Itertools;
+use tracing::debug;
 use wgpu::util::DeviceExt;
 
 use crate::{
     app::BevyApp,
-    bvh::{AABB, BVHNodeGPU},
-    instance::Instance,
-    material::{Material, MaterialId, MaterialServer},
-    mesh::{MeshId, MeshServer},
+    bvh::{AABB, BVHNodeGPU, BvhSettings},
+    camera::Camera,
+    environment::Environment,
+    instance::{Instance, RayVisibility, Velocity},
+    light::{
+        AreaLights, DirectionalLight, DirectionalLightGPU, LightKind, LightPower, LightSourceGPU,
+        PointLight, PointLightGPU, SpotLight, SpotLightGPU, log_lights,
+    },
+    material::{Material, MaterialGroups, MaterialId, MaterialServer},
+    mesh::{self, MeshId, MeshServer},
     pathtracer::{Pathtracer, PathtracerOutput},
+    picking::PickScene,
+    plane::{PLANE_GEOMETRY, Plane},
     render_resources::{RenderDevice, RenderQueue},
     schedule,
+    sphere::{SPHERE_GEOMETRY, Sphere},
+    texture::{MAX_TEXTURES, TextureServer},
     tlas::TLAS,
     transform::Transform,
 };
@@ -22,151 +33,326 @@ pub fn initialize(app: &mut BevyApp) {
     app.world.insert_resource(SceneBindings::default());
     app.world
         .get_resource_or_init::<Schedules>()
-        .add_systems(schedule::Update, binder_system);
+        // Binds meshes the frame they finish loading:
+        .add_systems(
+            schedule::Update,
+            binder_system.after(mesh::mesh_loading_system),
+        );
 }
 
 #[derive(Resource, Default)]
 pub struct SceneBindings {
     pub bind_group: Option<wgpu::BindGroup>,
     pub bind_group_layout: Option<wgpu::BindGroupLayout>,
+    pub picking: PickScene,
+    // Bytes in the bound TLAS buffers, and in the rest of the buffers made here:
+    pub tlas_bytes: u64,
+    pub scene_bytes: u64,
+    // What the bound lights emit, emissive meshes then analytic lights. See scene_lights:
+    area_lights: Vec<LightPower>,
+    analytic_lights: Vec<LightPower>,
+    // Times each has been made, for profiling. The layout is made exactly once, keeping
+    // the pathtracer pipelines built against it compatible:
+    pub layouts_created: u32,
+    pub bind_groups_created: u32,
+}
+
+impl SceneBindings {
+    // Every light next event estimation samples, with what it emits. Logged whenever that
+    // changes.
+    pub fn scene_lights(&self) -> impl Iterator<Item = &LightPower> {
+        self.area_lights.iter().chain(&self.analytic_lights)
+    }
 }
 
 #[derive(Resource)]
 pub struct BinderLocal {
-    tlas_cache: Option<wgpu::Buffer>,
-    tlas_iids: Option<wgpu::Buffer>,
+    buffers: SceneBuffers,
+    dirty: BinderDirty,
     tlas_regenerate: bool,
+    tlas_refit: bool,
+    tlas: TLAS,
+    // Geometry index of each instance the cached TLAS was built over:
+    tlas_geometry: Vec<u32>,
+    // The shutter its moving instances are bounded over:
+    shutter_time: f32,
+    // Whether the lights have been logged since they last changed:
+    lights_logged: bool,
 }
 
 impl Default for BinderLocal {
     fn default() -> Self {
         Self {
-            tlas_cache: Default::default(),
-            tlas_iids: None,
+            buffers: SceneBuffers::default(),
+            dirty: BinderDirty::default(),
             tlas_regenerate: true,
+            tlas_refit: false,
+            tlas: TLAS::default(),
+            tlas_geometry: Vec::new(),
+            shutter_time: 0.0,
+            lights_logged: false,
+        }
+    }
+}
+
+// A buffer kept from run to run, rewritten in place for as long as its size holds.
+struct CachedBuffer {
+    label: &'static str,
+    usage: wgpu::BufferUsages,
+    buffer: Option<wgpu::Buffer>,
+}
+
+impl CachedBuffer {
+    fn new(label: &'static str, usage: wgpu::BufferUsages) -> Self {
+        Self {
+            label,
+            usage: usage | wgpu::BufferUsages::COPY_DST,
+            buffer: None,
+        }
+    }
+
+    // True if the buffer was replaced, leaving bind groups that hold the old one stale.
+    fn upload(&mut self, device: &wgpu::Device, queue: &wgpu::Queue, contents: &[u8]) -> bool {
+        if let Some(buffer) = &self.buffer
+            && buffer.size() == contents.len() as u64
+        {
+            queue.write_buffer(buffer, 0, contents);
+            return false;
+        }
+        self.buffer = Some(
+            device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+                label: Some(self.label),
+                contents,
+                usage: self.usage,
+            }),
+        );
+        true
+    }
+
+    // Writes over `contents.len()` bytes from `offset`, inside what was last uploaded.
+    fn write_at(&self, queue: &wgpu::Queue, offset: u64, contents: &[u8]) {
+        if let Some(buffer) = &self.buffer {
+            queue.write_buffer(buffer, offset, contents);
+        }
+    }
+
+    // Every buffer is uploaded on the first run that gets as far as binding, see BinderDirty.
+    fn binding(&self) -> wgpu::BindingResource<'_> {
+        self.buffer
+            .as_ref()
+            .expect("Scene buffers are uploaded before they are bound")
+            .as_entire_binding()
+    }
+
+    fn size(&self) -> u64 {
+        self.buffer.as_ref().map_or(0, wgpu::Buffer::size)
+    }
+}
+
+struct SceneBuffers {
+    tlas_nodes: CachedBuffer,
+    tlas_iids: CachedBuffer,
+    materials: CachedBuffer,
+    instances: CachedBuffer,
+    transforms: CachedBuffer,
+    plane_instances: CachedBuffer,
+    light_sources: CachedBuffer,
+    light_triangles: CachedBuffer,
+    instance_lights: CachedBuffer,
+    directional_lights: CachedBuffer,
+    point_lights: CachedBuffer,
+    spot_lights: CachedBuffer,
+    environment: CachedBuffer,
+}
+
+impl Default for SceneBuffers {
+    fn default() -> Self {
+        let storage = |label| CachedBuffer::new(label, wgpu::BufferUsages::STORAGE);
+        Self {
+            tlas_nodes: storage("TLAS BVHNode Buffer"),
+            tlas_iids: storage("TLAS IID Buffer"),
+            materials: storage("Material Buffer"),
+            instances: storage("Instance Buffer"),
+            transforms: storage("Transform Buffer"),
+            plane_instances: storage("Plane Instance Buffer"),
+            light_sources: storage("Light Source Buffer"),
+            light_triangles: storage("Light Triangle Buffer"),
+            instance_lights: storage("Instance Light Buffer"),
+            directional_lights: storage("Directional Light Buffer"),
+            point_lights: storage("Point Light Buffer"),
+            spot_lights: storage("Spot Light Buffer"),
+            environment: CachedBuffer::new("Environment Buffer", wgpu::BufferUsages::UNIFORM),
+        }
+    }
+}
+
+impl SceneBuffers {
+    // Everything but the TLAS:
+    fn scene_bytes(&self) -> u64 {
+        [
+            &self.materials,
+            &self.instances,
+            &self.transforms,
+            &self.plane_instances,
+            &self.light_sources,
+            &self.light_triangles,
+            &self.instance_lights,
+            &self.directional_lights,
+            &self.point_lights,
+            &self.spot_lights,
+            &self.environment,
+        ]
+        .map(CachedBuffer::size)
+        .iter()
+        .sum()
+    }
+}
+
+// Which cached buffers are out of date. Changes are only seen on the run they happen in,
+// so they're held here through runs that return before uploading. All set at first.
+struct BinderDirty {
+    // Instances, materials and plane instances:
+    instances: bool,
+    // Transforms and the area lights placed by them:
+    transforms: bool,
+    lights: bool,
+    environment: bool,
+    bind_group: bool,
+}
+
+impl Default for BinderDirty {
+    fn default() -> Self {
+        Self {
+            instances: true,
+            transforms: true,
+            lights: true,
+            environment: true,
+            bind_group: true,
         }
     }
 }
 
+// Scene objects that went away since the last run:
+#[derive(SystemParam)]
+pub struct RemovedObjects<'w, 's> {
+    transforms: RemovedComponents<'w, 's, Transform>,
+    meshids: RemovedComponents<'w, 's, MeshId>,
+    spheres: RemovedComponents<'w, 's, Sphere>,
+    planes: RemovedComponents<'w, 's, Plane>,
+    materials: RemovedComponents<'w, 's, MaterialId>,
+}
+
+impl RemovedObjects<'_, '_> {
+    fn any(&self) -> bool {
+        !self.transforms.is_empty()
+            || !self.meshids.is_empty()
+            || !self.spheres.is_empty()
+            || !self.planes.is_empty()
+            || !self.materials.is_empty()
+    }
+}
+
+// The analytic lights, none of which are in the TLAS:
+#[derive(SystemParam)]
+pub struct SceneLights<'w, 's> {
+    directional: Query<'w, 's, Ref<'static, DirectionalLight>>,
+    point: Query<'w, 's, Ref<'static, PointLight>>,
+    spot: Query<'w, 's, Ref<'static, SpotLight>>,
+    removed_directional: RemovedComponents<'w, 's, DirectionalLight>,
+    removed_point: RemovedComponents<'w, 's, PointLight>,
+    removed_spot: RemovedComponents<'w, 's, SpotLight>,
+}
+
+impl SceneLights<'_, '_> {
+    // Any added, changed or removed since the last run.
+    fn changed(&self) -> bool {
+        self.directional.iter().any(|l| l.is_changed())
+            || self.point.iter().any(|l| l.is_changed())
+            || self.spot.iter().any(|l| l.is_changed())
+            || !self.removed_directional.is_empty()
+            || !self.removed_point.is_empty()
+            || !self.removed_spot.is_empty()
+    }
+}
+
+// What becomes an instance, each seen by every ray unless it has a RayVisibility and
+// still unless it has a Velocity. Meshes may also have MaterialGroups:
+type MeshObject = (
+    Ref<'static, Transform>,
+    Ref<'static, MeshId>,
+    Ref<'static, MaterialId>,
+    Option<Ref<'static, MaterialGroups>>,
+    Option<&'static RayVisibility>,
+    Option<&'static Velocity>,
+);
+type SphereObject = (
+    Ref<'static, Transform>,
+    Ref<'static, Sphere>,
+    Ref<'static, MaterialId>,
+    Option<&'static RayVisibility>,
+    Option<&'static Velocity>,
+);
+
+#[allow(clippy::too_many_arguments)]
 pub fn binder_system(
-    objects: Query<(Ref<Transform>, Ref<MeshId>, &MaterialId)>,
-    removed_transforms: RemovedComponents<Transform>,
-    removed_meshids: RemovedComponents<MeshId>,
+    objects: Query<MeshObject>,
+    spheres: Query<SphereObject>,
+    planes: Query<(Ref<Plane>, Ref<MaterialId>, Option<&RayVisibility>)>,
+    lights: SceneLights,
+    cameras: Query<&Camera>,
+    mut pathtracers: Query<&mut Pathtracer>,
+    removed: RemovedObjects,
     mesh_server: Res<MeshServer>,
+    bvh_settings: Res<BvhSettings>,
     material_server: Res<MaterialServer>,
+    texture_server: Res<TextureServer>,
+    environment: Res<Environment>,
     device: Res<RenderDevice>,
+    queue: Res<RenderQueue>,
     mut binder_local: Local<BinderLocal>,
     mut path_tracer_bindings: ResMut<SceneBindings>,
 ) {
-    let bind_group_layout = device
-        .0
-        .create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
-            label: Some("Pathtracer Bindgroup Layout"),
-            entries: &[
-                wgpu::BindGroupLayoutEntry {
-                    binding: 0,
-                    visibility: wgpu::ShaderStages::COMPUTE,
-                    ty: wgpu::BindingType::Buffer {
-                        ty: wgpu::BufferBindingType::Storage { read_only: true },
-                        has_dynamic_offset: false,
-                        min_binding_size: None,
-                    },
-                    count: None,
-                },
-                wgpu::BindGroupLayoutEntry {
-                    binding: 1,
-                    visibility: wgpu::ShaderStages::COMPUTE,
-                    ty: wgpu::BindingType::Buffer {
-                        ty: wgpu::BufferBindingType::Storage { read_only: true },
-                        has_dynamic_offset: false,
-                        min_binding_size: None,
-                    },
-                    count: None,
-                },
-                wgpu::BindGroupLayoutEntry {
-                    binding: 2,
-                    visibility: wgpu::ShaderStages::COMPUTE,
-                    ty: wgpu::BindingType::Buffer {
-                        ty: wgpu::BufferBindingType::Storage { read_only: true },
-                        has_dynamic_offset: false,
-                        min_binding_size: None,
-                    },
-                    count: None,
-                },
-                wgpu::BindGroupLayoutEntry {
-                    binding: 3,
-                    visibility: wgpu::ShaderStages::COMPUTE,
-                    ty: wgpu::BindingType::Buffer {
-                        ty: wgpu::BufferBindingType::Storage { read_only: true },
-                        has_dynamic_offset: false,
-                        min_binding_size: None,
-                    },
-                    count: None,
-                },
-                wgpu::BindGroupLayoutEntry {
-                    binding: 4,
-                    visibility: wgpu::ShaderStages::COMPUTE,
-                    ty: wgpu::BindingType::Buffer {
-                        ty: wgpu::BufferBindingType::Storage { read_only: true },
-                        has_dynamic_offset: false,
-                        min_binding_size: None,
-                    },
-                    count: None,
-                },
-                wgpu::BindGroupLayoutEntry {
-                    binding: 5,
-                    visibility: wgpu::ShaderStages::COMPUTE,
-                    ty: wgpu::BindingType::Buffer {
-                        ty: wgpu::BufferBindingType::Storage { read_only: true },
-                        has_dynamic_offset: false,
-                        min_binding_size: None,
-                    },
-                    count: None,
-                },
-                wgpu::BindGroupLayoutEntry {
-                    binding: 6,
-                    visibility: wgpu::ShaderStages::COMPUTE,
-                    ty: wgpu::BindingType::Buffer {
-                        ty: wgpu::BufferBindingType::Storage { read_only: true },
-                        has_dynamic_offset: false,
-                        min_binding_size: None,
-                    },
-                    count: None,
-                },
-                wgpu::BindGroupLayoutEntry {
-                    binding: 7,
-                    visibility: wgpu::ShaderStages::COMPUTE,
-                    ty: wgpu::BindingType::Buffer {
-                        ty: wgpu::BufferBindingType::Storage { read_only: true },
-                        has_dynamic_offset: false,
-                        min_binding_size: None,
-                    },
-                    count: None,
-                },
-                wgpu::BindGroupLayoutEntry {
-                    binding: 8,
-                    visibility: wgpu::ShaderStages::COMPUTE,
-                    ty: wgpu::BindingType::Buffer {
-                        ty: wgpu::BufferBindingType::Storage { read_only: true },
-                        has_dynamic_offset: false,
-                        min_binding_size: None,
-                    },
-                    count: None,
-                },
-                wgpu::BindGroupLayoutEntry {
-                    binding: 9,
-                    visibility: wgpu::ShaderStages::COMPUTE,
-                    ty: wgpu::BindingType::Buffer {
-                        ty: wgpu::BufferBindingType::Storage { read_only: true },
-                        has_dynamic_offset: false,
-                        min_binding_size: None,
-                    },
-                    count: None,
-                },
-            ],
-        });
+    // Made once, the pipelines are built against it. The device only exists from startup,
+    // so it can't be made in initialize:
+    let bind_group_layout = match &path_tracer_bindings.bind_group_layout {
+        Some(layout) => layout.clone(),
+        None => {
+            let layout = create_bind_group_layout(&device.0);
+            path_tracer_bindings.bind_group_layout = Some(layout.clone());
+            path_tracer_bindings.layouts_created += 1;
+            debug!("Created the scene bind group layout");
+            layout
+        }
+    };
 
-    path_tracer_bindings.bind_group_layout = Some(bind_group_layout.clone());
+    let structure_changed = removed.any()
+        || mesh_server.is_changed()
+        || material_server.is_changed()
+        || objects.iter().any(|(t, m, mat, groups, ..)| {
+            t.is_added()
+                || m.is_changed()
+                || mat.is_changed()
+                || groups.is_some_and(|g| g.is_changed())
+        })
+        || spheres
+            .iter()
+            .any(|(t, s, mat, ..)| t.is_added() || s.is_added() || mat.is_changed())
+        || planes
+            .iter()
+            .any(|(p, mat, _)| p.is_added() || mat.is_changed());
+    let moved = objects.iter().any(|(t, ..)| t.is_changed())
+        || spheres
+            .iter()
+            .any(|(t, s, ..)| t.is_changed() || s.is_changed())
+        || planes.iter().any(|(p, _, _)| p.is_changed());
+    let dirty = &mut binder_local.dirty;
+    dirty.instances |= structure_changed;
+    dirty.transforms |= structure_changed || moved;
+    dirty.lights |= lights.changed();
+    dirty.environment |= environment.is_changed();
+    // The mesh, texture and environment buffers and views bound along with ours:
+    dirty.bind_group |=
+        mesh_server.is_changed() || texture_server.is_changed() || environment.is_changed();
 
     let Some(vertex_buffer) = mesh_server.vertex_buffer().as_ref() else {
         return;
@@ -180,14 +366,24 @@ pub fn binder_system(
     let Some(geometry_buffer) = mesh_server.offset_buffer().as_ref() else {
         return;
     };
+    let (Some(texture_views), Some(texture_samplers)) =
+        (texture_server.views(), texture_server.samplers())
+    else {
+        return;
+    };
 
     let mut materials = Vec::<Material>::new();
     let mut transforms = Vec::<Transform>::new();
     let mut instances = Vec::<Instance>::new();
+    let mut material_ids = Vec::<MaterialId>::new();
     let mut materials_id_map = HashMap::<MaterialId, u32>::new();
-    let mut light_sources = Vec::<u32>::new();
+    // The first of each run of materials pushed for a mesh with several face groups:
+    let mut material_groups_map = HashMap::<Vec<MaterialId>, u32>::new();
 
-    if !removed_transforms.is_empty() && !removed_meshids.is_empty() {
+    if (!removed.transforms.is_empty() && !removed.meshids.is_empty())
+        || !removed.spheres.is_empty()
+        || bvh_settings.is_changed()
+    {
         binder_local.tlas_regenerate = true;
     }
 
@@ -195,140 +391,454 @@ pub fn binder_system(
     // let mut textures = vec![];
     // let mut samplers = vec![];
 
-    for (transform, mesh_id, mat_id) in objects {
-        if transform.is_changed()
-            || transform.is_added()
-            || mesh_id.is_changed()
-            || mesh_id.is_added()
-            || mesh_server.is_changed()
-        {
+    // Planes aren't in the TLAS, so changing them only restarts accumulation:
+    let planes_changed =
+        !removed.planes.is_empty() || planes.iter().any(|(p, _, _)| p.is_changed());
+
+    let flags =
+        |visibility: Option<&RayVisibility>| visibility.copied().unwrap_or_default().flags();
+    let velocity = |velocity: Option<&Velocity>| velocity.map_or(Vec3::ZERO, |v| v.0).extend(0.0);
+    // All become instances: (transform, moved, regenerate, geometry, materials, flags,
+    // velocity), with a material for each face group:
+    let meshes = objects
+        .iter()
+        .map(|(transform, mesh_id, mat_id, groups, visibility, vel)| {
+            let regenerate = transform.is_added()
+                || mesh_id.is_changed()
+                || mesh_id.is_added()
+                || mesh_server.is_changed();
+            // Get the geometry index from the mesh server
+            let geometry_idx = mesh_server.geom_id(*mesh_id);
+            let group_count = mesh_server
+                .mesh_data(*mesh_id)
+                .map_or(1, |d| d.material_groups);
+            let groups = groups.map(|g| g.into_inner().0.as_slice());
+            let materials = (0..group_count as usize)
+                .map(|g| *groups.and_then(|gs| gs.get(g)).unwrap_or(&mat_id))
+                .collect_vec();
+            (
+                *transform,
+                transform.is_changed(),
+                regenerate,
+                geometry_idx,
+                materials,
+                flags(visibility),
+                velocity(vel),
+            )
+        });
+    // Spheres trace a unit sphere, with the radius folded into the transform:
+    let spheres = spheres
+        .iter()
+        .map(|(transform, sphere, mat_id, visibility, vel)| {
+            let moved = transform.is_changed() || sphere.is_changed();
+            let regenerate = transform.is_added() || sphere.is_added();
+            let transform = sphere.instance_transform(&transform);
+            (
+                transform,
+                moved,
+                regenerate,
+                Some(SPHERE_GEOMETRY),
+                vec![*mat_id],
+                flags(visibility),
+                velocity(vel),
+            )
+        });
+    // Last, so the instances the TLAS is built over are a prefix of the list:
+    let planes = planes.iter().map(|(plane, mat_id, visibility)| {
+        let transform = plane.instance_transform();
+        (
+            transform,
+            false,
+            false,
+            Some(PLANE_GEOMETRY),
+            vec![*mat_id],
+            flags(visibility),
+            Vec4::ZERO,
+        )
+    });
+
+    for (transform, moved, regenerate, geometry_idx, mat_ids, flags, velocity) in
+        meshes.chain(spheres).chain(planes)
+    {
+        if regenerate {
             binder_local.tlas_regenerate = true;
+        } else if moved {
+            // Moving an instance doesn't change the tree's topology:
+            binder_local.tlas_refit = true;
         }
 
-        // Get the geometry index from the mesh server
-        let Some(geometry_idx) = mesh_server.geom_id(*mesh_id) else {
+        let Some(geometry_idx) = geometry_idx else {
             continue;
         };
 
-        let mut emissive = false;
-        let material_idx = if let Some(&idx) = materials_id_map.get(mat_id) {
+        let material_idx = if let [mat_id] = mat_ids.as_slice() {
+            if let Some(&idx) = materials_id_map.get(mat_id) {
+                idx
+            } else {
+                let Some(material) = material_server.get(*mat_id) else {
+                    continue;
+                };
+
+                materials.push(*material);
+                material_ids.push(*mat_id);
+
+                let idx = (materials.len() - 1) as u32;
+                materials_id_map.insert(*mat_id, idx);
+                idx
+            }
+        } else if let Some(&idx) = material_groups_map.get(&mat_ids) {
             idx
         } else {
-            let Some(material) = material_server.get(*mat_id) else {
+            // The shader finds a face's material at the instance's plus its group, so
+            // each group's is pushed again in order:
+            let Some(group_materials) = mat_ids
+                .iter()
+                .map(|id| material_server.get(*id).copied())
+                .collect::<Option<Vec<_>>>()
+            else {
                 continue;
             };
 
-            if material.emissive != Vec4::ZERO || material.emissive_texture > 0 {
-                emissive = true;
-            }
-            materials.push(*material);
-
-            let idx = (materials.len() - 1) as u32;
-            materials_id_map.insert(*mat_id, idx);
+            let idx = materials.len() as u32;
+            materials.extend(group_materials);
+            material_ids.extend(&mat_ids);
+            material_groups_map.insert(mat_ids, idx);
             idx
         };
 
-        transforms.push(*transform);
+        transforms.push(transform);
         let transform_idx = (transforms.len() - 1) as u32;
 
         let instance = Instance {
             transform_idx,
             geometry_idx,
             material_idx,
+            flags,
+            velocity,
         };
         instances.push(instance);
-
-        if emissive {
-            light_sources.push((instances.len() - 1) as u32);
-        }
     }
 
-    if instances.is_empty() {
-        // Gonna have a hard time binding this :)
-        return;
+    let bounded = instances
+        .iter()
+        .take_while(|i| i.geometry_idx != PLANE_GEOMETRY)
+        .count();
+    // With nothing bounded the TLAS is a single empty leaf, so only the planes and the
+    // environment are seen:
+    let (tlas_instances, plane_instances) = instances.split_at(bounded);
+
+    let mut plane_instances = (bounded..bounded + plane_instances.len())
+        .map(|i| i as u32)
+        .collect_vec();
+    if plane_instances.is_empty() {
+        // Padding, skipped by the shader
+        plane_instances.push(u32::MAX);
     }
 
-    if light_sources.is_empty() {
-        // what to do here? Could insist that all indexes are >0 i guess
-        // TODO properly support having no light sources lol
-        light_sources.push(u32::MAX);
+    // Moving an entity to another archetype reorders the queries without marking anything
+    // changed, so the arrays are checked against the last upload too:
+    let picking = &path_tracer_bindings.picking;
+    let reordered =
+        bytes_differ(&instances, &picking.instances) || materials.len() != picking.materials.len();
+    // The same instances with different values in some of their materials, as when one is
+    // edited live. Only those entries are written over, see below:
+    let edited_materials = if reordered {
+        Vec::new()
+    } else {
+        (0..materials.len())
+            .filter(|&i| bytes_differ(&materials[i..=i], &picking.materials[i..=i]))
+            .collect_vec()
+    };
+    // Emission places the area lights, so changing it rebuilds them along with the transforms:
+    let emission_edited = edited_materials
+        .iter()
+        .any(|&i| !materials[i].emits_like(&picking.materials[i]));
+    let moved = bytes_differ(&transforms, &picking.transforms);
+    // Nothing moves when an instance is hidden, but the image changes all the same:
+    let visibility_changed = instances.len() != picking.instances.len()
+        || instances
+            .iter()
+            .zip(&picking.instances)
+            .any(|(a, b)| a.flags != b.flags);
+    binder_local.dirty.instances |= reordered;
+    binder_local.dirty.transforms |= reordered || moved || emission_edited;
+    binder_local.tlas_refit |= moved;
+
+    // The TLAS bounds everything moving over the longest shutter of any camera, so
+    // changing either only needs a refit:
+    let shutter_time = cameras
+        .iter()
+        .map(|c| c.data.shutter_time)
+        .fold(0.0, f32::max);
+    let motion_changed = shutter_time != binder_local.shutter_time
+        || instances.len() != picking.instances.len()
+        || instances
+            .iter()
+            .zip(&picking.instances)
+            .any(|(a, b)| a.velocity != b.velocity);
+    binder_local.shutter_time = shutter_time;
+    binder_local.tlas_refit |= motion_changed;
+
+    // A refit is only valid over the exact instances the tree was built with,
+    // anything added, removed or pointed at other geometry needs a rebuild:
+    if !binder_local.tlas_regenerate
+        && (tlas_instances.len() != binder_local.tlas_geometry.len()
+            || tlas_instances
+                .iter()
+                .zip(&binder_local.tlas_geometry)
+                .any(|(i, &g)| i.geometry_idx != g))
+    {
+        binder_local.tlas_regenerate = true;
     }
 
     if binder_local.tlas_regenerate {
-        // Regenerate the TLAS only when transforms or meshes have changed
+        // Regenerate the TLAS only when instances or meshes have changed
         binder_local.tlas_regenerate = false;
-        let tlas = TLAS::new(mesh_server.aabbs(), &transforms, &instances);
-        let iids = tlas.instance_ids.iter().map(|i| *i as u32).collect_vec();
-        let nodes = tlas
-            .nodes
-            .clone()
-            .into_iter()
-            .map(|node| BVHNodeGPU::from(node))
-            .collect_vec();
-        binder_local.tlas_cache = Some(device.0.create_buffer_init(
-            &wgpu::util::BufferInitDescriptor {
-                label: Some("TLAS BVHNode Buffer"),
-                contents: bytemuck::cast_slice(nodes.as_slice()),
-                usage: wgpu::BufferUsages::STORAGE,
-            },
-        ));
-        binder_local.tlas_iids = Some(device.0.create_buffer_init(
-            &wgpu::util::BufferInitDescriptor {
-                label: Some("TLAS IID Buffer"),
-                contents: bytemuck::cast_slice(iids.as_slice()),
-                usage: wgpu::BufferUsages::STORAGE,
-            },
-        ));
+        binder_local.tlas_refit = false;
+        let tlas = TLAS::new(
+            mesh_server.aabbs(),
+            &transforms,
+            tlas_instances,
+            bvh_settings.tlas_leaf_size,
+            shutter_time,
+        );
+        let mut iids = tlas.instance_ids.iter().map(|i| *i as u32).collect_vec();
+        if iids.is_empty() {
+            // Padding, no leaf reaches it
+            iids.push(u32::MAX);
+        }
+        let nodes = tlas_gpu_nodes(&tlas);
+        let BinderLocal { buffers, dirty, .. } = &mut *binder_local;
+        dirty.bind_group |=
+            buffers
+                .tlas_nodes
+                .upload(&device.0, &queue.0, bytemuck::cast_slice(&nodes));
+        dirty.bind_group |=
+            buffers
+                .tlas_iids
+                .upload(&device.0, &queue.0, bytemuck::cast_slice(&iids));
+        path_tracer_bindings.picking.tlas_nodes = nodes;
+        path_tracer_bindings.picking.tlas_instance_ids = tlas.instance_ids.clone();
+        binder_local.tlas = tlas;
+        binder_local.tlas_geometry = tlas_instances.iter().map(|i| i.geometry_idx).collect_vec();
+        pathtracers
+            .iter_mut()
+            .for_each(|mut pt| pt.reset_accumulation());
+    } else if binder_local.tlas_refit {
+        // Only transforms changed, so keep the partitioning and the instance ids
+        binder_local.tlas_refit = false;
+        binder_local.tlas.refit(
+            mesh_server.aabbs(),
+            &transforms,
+            tlas_instances,
+            shutter_time,
+        );
+        // Same partitioning, same node count, so this writes over the nodes in place:
+        let nodes = tlas_gpu_nodes(&binder_local.tlas);
+        let BinderLocal { buffers, dirty, .. } = &mut *binder_local;
+        dirty.bind_group |=
+            buffers
+                .tlas_nodes
+                .upload(&device.0, &queue.0, bytemuck::cast_slice(&nodes));
+        path_tracer_bindings.picking.tlas_nodes = nodes;
+        pathtracers
+            .iter_mut()
+            .for_each(|mut pt| pt.reset_accumulation());
+    } else if planes_changed
+        || visibility_changed
+        || environment.is_changed()
+        || !edited_materials.is_empty()
+    {
+        pathtracers
+            .iter_mut()
+            .for_each(|mut pt| pt.reset_accumulation());
     }
 
-    let Some(tlas_node_buffer) = &binder_local.tlas_cache else {
-        return;
-    };
+    let (device, queue) = (&device.0, &queue.0);
+    let BinderLocal {
+        buffers,
+        dirty,
+        lights_logged,
+        ..
+    } = &mut *binder_local;
+    if dirty.instances {
+        dirty.instances = false;
+        dirty.bind_group |=
+            buffers
+                .instances
+                .upload(device, queue, bytemuck::cast_slice(&instances));
+        dirty.bind_group |=
+            buffers
+                .materials
+                .upload(device, queue, bytemuck::cast_slice(&materials));
+        dirty.bind_group |=
+            buffers
+                .plane_instances
+                .upload(device, queue, bytemuck::cast_slice(&plane_instances));
+    } else {
+        let size = std::mem::size_of::<Material>() as u64;
+        for &i in &edited_materials {
+            buffers
+                .materials
+                .write_at(queue, i as u64 * size, bytemuck::bytes_of(&materials[i]));
+        }
+    }
 
-    let Some(tlas_iids_buffer) = &binder_local.tlas_iids else {
-        return;
-    };
+    // Replaced below when rebuilt, to log only what's changed:
+    let mut new_area_lights = None;
+    let mut new_analytic_lights = None;
+    if dirty.transforms {
+        dirty.transforms = false;
+        dirty.bind_group |=
+            buffers
+                .transforms
+                .upload(device, queue, bytemuck::cast_slice(&transforms));
 
-    // TODO: cache all of these!
-    let material_buffer = device
-        .0
-        .create_buffer_init(&wgpu::util::BufferInitDescriptor {
-            label: Some("Material Buffer"),
-            contents: bytemuck::cast_slice(materials.as_slice()),
-            usage: wgpu::BufferUsages::STORAGE,
-        });
+        // Emissive is a property of the material, so every mesh instance reusing one is a light:
+        let mut area_lights = AreaLights::new(&instances, &transforms, &materials, &mesh_server);
+        new_area_lights = Some(
+            area_lights
+                .sources
+                .iter()
+                .zip(&area_lights.powers)
+                .map(|(source, &power)| LightPower {
+                    kind: LightKind::Area {
+                        instance: source.instance,
+                    },
+                    power,
+                })
+                .collect_vec(),
+        );
+        if area_lights.sources.is_empty() {
+            // Padding, a zero pdf is never sampled
+            area_lights.sources.push(LightSourceGPU::default());
+            area_lights.triangle_cdf.push(1.0);
+        }
+        dirty.bind_group |=
+            buffers
+                .light_sources
+                .upload(device, queue, bytemuck::cast_slice(&area_lights.sources));
+        dirty.bind_group |= buffers.light_triangles.upload(
+            device,
+            queue,
+            bytemuck::cast_slice(&area_lights.triangle_cdf),
+        );
+        dirty.bind_group |= buffers.instance_lights.upload(
+            device,
+            queue,
+            bytemuck::cast_slice(&area_lights.instance_lights),
+        );
+    }
 
-    let instance_buffer = device
-        .0
-        .create_buffer_init(&wgpu::util::BufferInitDescriptor {
-            label: Some("Instance Buffer"),
-            contents: bytemuck::cast_slice(instances.as_slice()),
-            usage: wgpu::BufferUsages::STORAGE,
-        });
+    if dirty.lights {
+        dirty.lights = false;
+        new_analytic_lights = Some(
+            (lights.directional.iter().map(|l| LightPower {
+                kind: LightKind::Directional,
+                power: l.power(),
+            }))
+            .chain(lights.point.iter().map(|l| LightPower {
+                kind: LightKind::Point,
+                power: l.power(),
+            }))
+            .chain(lights.spot.iter().map(|l| LightPower {
+                kind: LightKind::Spot,
+                power: l.power(),
+            }))
+            .collect_vec(),
+        );
 
-    let transform_buffer = device
-        .0
-        .create_buffer_init(&wgpu::util::BufferInitDescriptor {
-            label: Some("Transform Buffer"),
-            contents: bytemuck::cast_slice(transforms.as_slice()),
-            usage: wgpu::BufferUsages::STORAGE,
-        });
+        let mut directional_lights = lights
+            .directional
+            .iter()
+            .map(|light| DirectionalLightGPU::from(*light))
+            .collect_vec();
+        if directional_lights.is_empty() {
+            // Can't bind an empty buffer, a zero radiance light is skipped by the shader
+            directional_lights.push(DirectionalLightGPU::default());
+        }
 
-    let light_sources_buffer = device
-        .0
-        .create_buffer_init(&wgpu::util::BufferInitDescriptor {
-            label: Some("Light Source Buffer"),
-            contents: bytemuck::cast_slice(light_sources.as_slice()),
-            usage: wgpu::BufferUsages::STORAGE,
-        });
+        let mut point_lights = lights
+            .point
+            .iter()
+            .map(|light| PointLightGPU::from(*light))
+            .collect_vec();
+        if point_lights.is_empty() {
+            point_lights.push(PointLightGPU::default());
+        }
+
+        let mut spot_lights = lights
+            .spot
+            .iter()
+            .map(|light| SpotLightGPU::from(*light))
+            .collect_vec();
+        if spot_lights.is_empty() {
+            spot_lights.push(SpotLightGPU::default());
+        }
+
+        dirty.bind_group |= buffers.directional_lights.upload(
+            device,
+            queue,
+            bytemuck::cast_slice(&directional_lights),
+        );
+        dirty.bind_group |=
+            buffers
+                .point_lights
+                .upload(device, queue, bytemuck::cast_slice(&point_lights));
+        dirty.bind_group |=
+            buffers
+                .spot_lights
+                .upload(device, queue, bytemuck::cast_slice(&spot_lights));
+    }
+
+    let mut lights_changed = false;
+    if let Some(area_lights) = new_area_lights {
+        lights_changed |= area_lights != path_tracer_bindings.area_lights;
+        path_tracer_bindings.area_lights = area_lights;
+    }
+    if let Some(analytic_lights) = new_analytic_lights {
+        lights_changed |= analytic_lights != path_tracer_bindings.analytic_lights;
+        path_tracer_bindings.analytic_lights = analytic_lights;
+    }
+    // Half loaded scenes would log at every step, and warn of no lights before they're in:
+    *lights_logged &= !lights_changed;
+    if !*lights_logged && !mesh_server.is_loading() {
+        *lights_logged = true;
+        log_lights(&path_tracer_bindings.scene_lights().copied().collect_vec());
+    }
+
+    if dirty.environment {
+        dirty.environment = false;
+        dirty.bind_group |=
+            buffers
+                .environment
+                .upload(device, queue, bytemuck::bytes_of(&environment.data()));
+    }
+
+    path_tracer_bindings.tlas_bytes = buffers.tlas_nodes.size() + buffers.tlas_iids.size();
+    path_tracer_bindings.scene_bytes = buffers.scene_bytes();
+
+    // Exactly what was last uploaded, for picking:
+    let picking = &mut path_tracer_bindings.picking;
+    picking.instances = instances;
+    picking.transforms = transforms;
+    picking.plane_instances = plane_instances;
+    picking.materials = materials;
+    picking.material_ids = material_ids;
+
+    // Rebound only once something it holds has been replaced:
+    if !dirty.bind_group && path_tracer_bindings.bind_group.is_some() {
+        return;
+    }
+    dirty.bind_group = false;
 
-    let bind_group = device.0.create_bind_group(&wgpu::BindGroupDescriptor {
+    let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
         label: Some("Pathtracer Bindgroup Descriptor"),
         layout: &bind_group_layout,
         entries: &[
             wgpu::BindGroupEntry {
                 binding: 0,
-                resource: instance_buffer.as_entire_binding(),
+                resource: buffers.instances.binding(),
             },
             wgpu::BindGroupEntry {
                 binding: 1,
@@ -348,26 +858,286 @@ pub fn binder_system(
             },
             wgpu::BindGroupEntry {
                 binding: 5,
-                resource: material_buffer.as_entire_binding(),
+                resource: buffers.materials.binding(),
             },
             wgpu::BindGroupEntry {
                 binding: 6,
-                resource: transform_buffer.as_entire_binding(),
+                resource: buffers.transforms.binding(),
             },
             wgpu::BindGroupEntry {
                 binding: 7,
-                resource: tlas_node_buffer.as_entire_binding(),
+                resource: buffers.tlas_nodes.binding(),
             },
             wgpu::BindGroupEntry {
                 binding: 8,
-                resource: tlas_iids_buffer.as_entire_binding(),
+                resource: buffers.tlas_iids.binding(),
             },
             wgpu::BindGroupEntry {
                 binding: 9,
-                resource: light_sources_buffer.as_entire_binding(),
+                resource: buffers.light_sources.binding(),
+            },
+            wgpu::BindGroupEntry {
+                binding: 10,
+                resource: buffers.directional_lights.binding(),
+            },
+            wgpu::BindGroupEntry {
+                binding: 11,
+                resource: wgpu::BindingResource::TextureView(&environment.view),
+            },
+            wgpu::BindGroupEntry {
+                binding: 12,
+                resource: buffers.environment.binding(),
+            },
+            wgpu::BindGroupEntry {
+                binding: 13,
+                resource: buffers.point_lights.binding(),
+            },
+            wgpu::BindGroupEntry {
+                binding: 14,
+                resource: buffers.spot_lights.binding(),
+            },
+            wgpu::BindGroupEntry {
+                binding: 15,
+                resource: wgpu::BindingResource::TextureViewArray(&texture_views),
+            },
+            wgpu::BindGroupEntry {
+                binding: 16,
+                resource: wgpu::BindingResource::SamplerArray(&texture_samplers),
+            },
+            wgpu::BindGroupEntry {
+                binding: 17,
+                resource: buffers.plane_instances.binding(),
+            },
+            wgpu::BindGroupEntry {
+                binding: 18,
+                resource: buffers.light_triangles.binding(),
+            },
+            wgpu::BindGroupEntry {
+                binding: 19,
+                resource: buffers.instance_lights.binding(),
             },
         ],
     });
 
     path_tracer_bindings.bind_group = Some(bind_group);
+    path_tracer_bindings.bind_groups_created += 1;
+    debug!(
+        "Created scene bind group {}",
+        path_tracer_bindings.bind_groups_created
+    );
+}
+
+fn create_bind_group_layout(device: &wgpu::Device) -> wgpu::BindGroupLayout {
+    device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+        label: Some("Pathtracer Bindgroup Layout"),
+        entries: &[
+            wgpu::BindGroupLayoutEntry {
+                binding: 0,
+                visibility: wgpu::ShaderStages::COMPUTE,
+                ty: wgpu::BindingType::Buffer {
+                    ty: wgpu::BufferBindingType::Storage { read_only: true },
+                    has_dynamic_offset: false,
+                    min_binding_size: None,
+                },
+                count: None,
+            },
+            wgpu::BindGroupLayoutEntry {
+                binding: 1,
+                visibility: wgpu::ShaderStages::COMPUTE,
+                ty: wgpu::BindingType::Buffer {
+                    ty: wgpu::BufferBindingType::Storage { read_only: true },
+                    has_dynamic_offset: false,
+                    min_binding_size: None,
+                },
+                count: None,
+            },
+            wgpu::BindGroupLayoutEntry {
+                binding: 2,
+                visibility: wgpu::ShaderStages::COMPUTE,
+                ty: wgpu::BindingType::Buffer {
+                    ty: wgpu::BufferBindingType::Storage { read_only: true },
+                    has_dynamic_offset: false,
+                    min_binding_size: None,
+                },
+                count: None,
+            },
+            wgpu::BindGroupLayoutEntry {
+                binding: 3,
+                visibility: wgpu::ShaderStages::COMPUTE,
+                ty: wgpu::BindingType::Buffer {
+                    ty: wgpu::BufferBindingType::Storage { read_only: true },
+                    has_dynamic_offset: false,
+                    min_binding_size: None,
+                },
+                count: None,
+            },
+            wgpu::BindGroupLayoutEntry {
+                binding: 4,
+                visibility: wgpu::ShaderStages::COMPUTE,
+                ty: wgpu::BindingType::Buffer {
+                    ty: wgpu::BufferBindingType::Storage { read_only: true },
+                    has_dynamic_offset: false,
+                    min_binding_size: None,
+                },
+                count: None,
+            },
+            wgpu::BindGroupLayoutEntry {
+                binding: 5,
+                visibility: wgpu::ShaderStages::COMPUTE,
+                ty: wgpu::BindingType::Buffer {
+                    ty: wgpu::BufferBindingType::Storage { read_only: true },
+                    has_dynamic_offset: false,
+                    min_binding_size: None,
+                },
+                count: None,
+            },
+            wgpu::BindGroupLayoutEntry {
+                binding: 6,
+                visibility: wgpu::ShaderStages::COMPUTE,
+                ty: wgpu::BindingType::Buffer {
+                    ty: wgpu::BufferBindingType::Storage { read_only: true },
+                    has_dynamic_offset: false,
+                    min_binding_size: None,
+                },
+                count: None,
+            },
+            wgpu::BindGroupLayoutEntry {
+                binding: 7,
+                visibility: wgpu::ShaderStages::COMPUTE,
+                ty: wgpu::BindingType::Buffer {
+                    ty: wgpu::BufferBindingType::Storage { read_only: true },
+                    has_dynamic_offset: false,
+                    min_binding_size: None,
+                },
+                count: None,
+            },
+            wgpu::BindGroupLayoutEntry {
+                binding: 8,
+                visibility: wgpu::ShaderStages::COMPUTE,
+                ty: wgpu::BindingType::Buffer {
+                    ty: wgpu::BufferBindingType::Storage { read_only: true },
+                    has_dynamic_offset: false,
+                    min_binding_size: None,
+                },
+                count: None,
+            },
+            wgpu::BindGroupLayoutEntry {
+                binding: 9,
+                visibility: wgpu::ShaderStages::COMPUTE,
+                ty: wgpu::BindingType::Buffer {
+                    ty: wgpu::BufferBindingType::Storage { read_only: true },
+                    has_dynamic_offset: false,
+                    min_binding_size: None,
+                },
+                count: None,
+            },
+            wgpu::BindGroupLayoutEntry {
+                binding: 10,
+                visibility: wgpu::ShaderStages::COMPUTE,
+                ty: wgpu::BindingType::Buffer {
+                    ty: wgpu::BufferBindingType::Storage { read_only: true },
+                    has_dynamic_offset: false,
+                    min_binding_size: None,
+                },
+                count: None,
+            },
+            wgpu::BindGroupLayoutEntry {
+                binding: 11,
+                visibility: wgpu::ShaderStages::COMPUTE,
+                ty: wgpu::BindingType::Texture {
+                    sample_type: wgpu::TextureSampleType::Float { filterable: false },
+                    view_dimension: wgpu::TextureViewDimension::D2,
+                    multisampled: false,
+                },
+                count: None,
+            },
+            wgpu::BindGroupLayoutEntry {
+                binding: 12,
+                visibility: wgpu::ShaderStages::COMPUTE,
+                ty: wgpu::BindingType::Buffer {
+                    ty: wgpu::BufferBindingType::Uniform,
+                    has_dynamic_offset: false,
+                    min_binding_size: None,
+                },
+                count: None,
+            },
+            wgpu::BindGroupLayoutEntry {
+                binding: 13,
+                visibility: wgpu::ShaderStages::COMPUTE,
+                ty: wgpu::BindingType::Buffer {
+                    ty: wgpu::BufferBindingType::Storage { read_only: true },
+                    has_dynamic_offset: false,
+                    min_binding_size: None,
+                },
+                count: None,
+            },
+            wgpu::BindGroupLayoutEntry {
+                binding: 14,
+                visibility: wgpu::ShaderStages::COMPUTE,
+                ty: wgpu::BindingType::Buffer {
+                    ty: wgpu::BufferBindingType::Storage { read_only: true },
+                    has_dynamic_offset: false,
+                    min_binding_size: None,
+                },
+                count: None,
+            },
+            wgpu::BindGroupLayoutEntry {
+                binding: 15,
+                visibility: wgpu::ShaderStages::COMPUTE,
+                ty: wgpu::BindingType::Texture {
+                    sample_type: wgpu::TextureSampleType::Float { filterable: true },
+                    view_dimension: wgpu::TextureViewDimension::D2,
+                    multisampled: false,
+                },
+                count: NonZero::new(MAX_TEXTURES as u32),
+            },
+            wgpu::BindGroupLayoutEntry {
+                binding: 16,
+                visibility: wgpu::ShaderStages::COMPUTE,
+                ty: wgpu::BindingType::Sampler(wgpu::SamplerBindingType::Filtering),
+                count: NonZero::new(MAX_TEXTURES as u32),
+            },
+            wgpu::BindGroupLayoutEntry {
+                binding: 17,
+                visibility: wgpu::ShaderStages::COMPUTE,
+                ty: wgpu::BindingType::Buffer {
+                    ty: wgpu::BufferBindingType::Storage { read_only: true },
+                    has_dynamic_offset: false,
+                    min_binding_size: None,
+                },
+                count: None,
+            },
+            wgpu::BindGroupLayoutEntry {
+                binding: 18,
+                visibility: wgpu::ShaderStages::COMPUTE,
+                ty: wgpu::BindingType::Buffer {
+                    ty: wgpu::BufferBindingType::Storage { read_only: true },
+                    has_dynamic_offset: false,
+                    min_binding_size: None,
+                },
+                count: None,
+            },
+            wgpu::BindGroupLayoutEntry {
+                binding: 19,
+                visibility: wgpu::ShaderStages::COMPUTE,
+                ty: wgpu::BindingType::Buffer {
+                    ty: wgpu::BufferBindingType::Storage { read_only: true },
+                    has_dynamic_offset: false,
+                    min_binding_size: None,
+                },
+                count: None,
+            },
+        ],
+    })
+}
+
+fn bytes_differ<T: bytemuck::Pod>(a: &[T], b: &[T]) -> bool {
+    bytemuck::cast_slice::<T, u8>(a) != bytemuck::cast_slice::<T, u8>(b)
+}
+
+fn tlas_gpu_nodes(tlas: &TLAS) -> Vec<BVHNodeGPU> {
+    tlas.nodes
+        .iter()
+        .map(|node| BVHNodeGPU::from(*node))
+        .collect_vec()
 }
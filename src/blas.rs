@@ -1,5 +1,8 @@
 use glam::UVec3;
+use glam::UVec4;
 use glam::Vec3;
+use glam::Vec4;
+use glam::Vec4Swizzles;
 use itertools::Itertools;
 use wgpu::util::DeviceExt;
 
@@ -7,33 +10,44 @@ use crate::bvh::AABB;
 use crate::bvh::BVH;
 use crate::bvh::BVHNode;
 use crate::bvh::BVHNodeGPU;
+use crate::bvh::SplitStrategy;
 use crate::mesh::Mesh;
 
+// Subtrees over fewer faces than this are built serially.
+const PARALLEL_CUTOFF: usize = 4096;
+
 #[derive(Debug)]
 pub struct BLAS {
     pub nodes: Vec<BVHNode>,
     pub mesh: Mesh,
 }
 
+fn face_bounds(positions: &[Vec4], face: UVec4) -> AABB {
+    let positions = face.xyz().to_array().map(|i| &positions[i as usize]);
+    let lb = positions[0]
+        .min(*positions[1])
+        .min(*positions[2])
+        .truncate();
+    let ub = positions[0]
+        .max(*positions[1])
+        .max(*positions[2])
+        .truncate();
+    AABB { lb, ub }
+}
+
+fn face_centroid(positions: &[Vec4], face: UVec4) -> Vec3 {
+    // w holds face flags rather than an index:
+    let positions = face.xyz().to_array().map(|i| positions[i as usize]);
+    (positions.into_iter().reduce(|acc, v| acc + v).unwrap() / 3.0).truncate()
+}
+
 impl BVH for BLAS {
     fn elem_bounds(&self, face: usize) -> AABB {
-        let face = self.mesh.faces[face];
-        let positions = face.to_array().map(|i| &self.mesh.positions[i as usize]);
-        let lb = positions[0]
-            .min(*positions[1])
-            .min(*positions[2])
-            .truncate();
-        let ub = positions[0]
-            .max(*positions[1])
-            .max(*positions[2])
-            .truncate();
-        AABB { lb, ub }
+        face_bounds(&self.mesh.positions, self.mesh.faces[face])
     }
 
     fn elem_centroid(&self, face: usize) -> Vec3 {
-        let face = self.mesh.faces[face];
-        let positions = face.to_array().map(|i| self.mesh.positions[i as usize]);
-        (positions.into_iter().reduce(|acc, v| acc + v).unwrap() / 3.0).truncate()
+        face_centroid(&self.mesh.positions, self.mesh.faces[face])
     }
 
     fn elem_swap(&mut self, elem: usize, elem2: usize) {
@@ -59,20 +73,138 @@ impl BVH for BLAS {
     }
 }
 
+// A subtree over the faces starting at `offset`, so that disjoint ranges
+// of the same mesh can be built on separate threads. Node start/end stay
+// in mesh face indices.
+struct FaceRange<'a> {
+    positions: &'a [Vec4],
+    faces: &'a mut [UVec4],
+    offset: usize,
+    nodes: Vec<BVHNode>,
+}
+
+impl BVH for FaceRange<'_> {
+    fn elem_bounds(&self, face: usize) -> AABB {
+        face_bounds(self.positions, self.faces[face - self.offset])
+    }
+
+    fn elem_centroid(&self, face: usize) -> Vec3 {
+        face_centroid(self.positions, self.faces[face - self.offset])
+    }
+
+    fn elem_swap(&mut self, elem: usize, elem2: usize) {
+        self.faces.swap(elem - self.offset, elem2 - self.offset);
+    }
+
+    fn node(&self, idx: usize) -> &BVHNode {
+        &self.nodes[idx]
+    }
+
+    fn push_node(&mut self, node: BVHNode) -> usize {
+        let i = self.nodes.len();
+        self.nodes.push(node);
+        i
+    }
+
+    fn node_mut(&mut self, idx: usize) -> &mut BVHNode {
+        &mut self.nodes[idx]
+    }
+
+    fn node_bounds(&self, idx: usize) -> AABB {
+        self.nodes[idx].bounds
+    }
+}
+
+// Builds the subtree over `faces`, splitting the two halves across rayon
+// while they're large enough to be worth it. The returned nodes are laid out
+// exactly as the serial `subdivide` would lay them out, rooted at 0.
+// Skips are not generated.
+fn build_range(
+    positions: &[Vec4],
+    faces: &mut [UVec4],
+    offset: usize,
+    threshold: usize,
+    strategy: SplitStrategy,
+) -> Vec<BVHNode> {
+    let len = faces.len();
+    let mut range = FaceRange {
+        positions,
+        faces,
+        offset,
+        nodes: vec![BVHNode {
+            is_leaf: true,
+            bounds: AABB::default(),
+            start: offset,
+            end: offset + len,
+            ..Default::default()
+        }],
+    };
+    range.compute_node_bounds(0);
+
+    if len < PARALLEL_CUTOFF {
+        range.subdivide(0, threshold, strategy);
+        return range.nodes;
+    }
+
+    let root = range.nodes[0];
+    if len <= threshold {
+        return range.nodes;
+    }
+    let Some(split) = range.partition(&root, strategy) else {
+        return range.nodes;
+    };
+
+    let FaceRange {
+        faces, mut nodes, ..
+    } = range;
+    let (left_faces, right_faces) = faces.split_at_mut(split - offset);
+    let (left, right) = rayon::join(
+        || build_range(positions, left_faces, offset, threshold, strategy),
+        || build_range(positions, right_faces, split, threshold, strategy),
+    );
+
+    // The serial build pushes both children, then the left descendants,
+    // then the right descendants:
+    let right_base = left.len() + 1;
+    let rebase_left = |i: usize| if i == 0 { 1 } else { i + 2 };
+    let rebase_right = |i: usize| if i == 0 { 2 } else { i + right_base };
+    let rebase = |mut node: BVHNode, map: &dyn Fn(usize) -> usize| {
+        if !node.is_leaf {
+            node.left = map(node.left);
+            node.right = map(node.right);
+        }
+        node
+    };
+
+    nodes[0] = BVHNode {
+        is_leaf: false,
+        bounds: left[0].bounds.union(&right[0].bounds),
+        left: 1,
+        right: 2,
+        start: root.start,
+        end: root.end,
+        ..Default::default()
+    };
+    nodes.reserve(left.len() + right.len());
+    nodes.push(rebase(left[0], &rebase_left));
+    nodes.push(rebase(right[0], &rebase_right));
+    nodes.extend(left[1..].iter().map(|n| rebase(*n, &rebase_left)));
+    nodes.extend(right[1..].iter().map(|n| rebase(*n, &rebase_right)));
+    nodes
+}
+
 impl BLAS {
-    pub fn new(mesh: Mesh) -> BLAS {
-        let mut bvh = BLAS {
-            nodes: vec![BVHNode {
-                is_leaf: true,
-                bounds: AABB::default(),
-                start: 0,
-                end: mesh.faces.len(),
-                ..Default::default()
-            }],
-            mesh: mesh,
-        };
+    pub fn new(mut mesh: Mesh, leaf_size: usize) -> BLAS {
+        let nodes = build_range(
+            &mesh.positions,
+            &mut mesh.faces,
+            0,
+            leaf_size.max(1),
+            SplitStrategy::Sah,
+        );
 
-        bvh.initialize(4);
+        let mut bvh = BLAS { nodes, mesh };
+        bvh.generate_skips(0, 0);
 
         bvh
     }
@@ -291,3 +423,69 @@ impl BLAS {
 //         }
 //     }
 // }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{mesh::NormalizeMode, traverse::Ray};
+
+    const TEAPOT: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/assets/teapot.obj");
+
+    // The whole tree through the trait's serial subdivide, as BLAS::new built it before
+    // build_range split the top of it across threads:
+    fn serial(mesh: Mesh, leaf_size: usize) -> BLAS {
+        let mut blas = BLAS {
+            nodes: vec![BVHNode {
+                is_leaf: true,
+                end: mesh.faces.len(),
+                ..Default::default()
+            }],
+            mesh,
+        };
+        blas.initialize(leaf_size, SplitStrategy::Sah);
+        blas
+    }
+
+    #[test]
+    fn parallel_build_matches_serial() {
+        let mesh = Mesh::from_obj(TEAPOT, &NormalizeMode::UnitCube).unwrap();
+        assert!(
+            mesh.faces.len() > PARALLEL_CUTOFF,
+            "The teapot has to be split across threads"
+        );
+        let serial = serial(mesh.clone(), 4);
+        let parallel = BLAS::new(mesh, 4);
+
+        assert_eq!(serial.mesh.faces, parallel.mesh.faces);
+        assert_eq!(serial.nodes.len(), parallel.nodes.len());
+        for (i, (s, p)) in serial.nodes.iter().zip(&parallel.nodes).enumerate() {
+            assert_eq!(
+                (s.left, s.right, s.skip, s.is_leaf, s.start, s.end),
+                (p.left, p.right, p.skip, p.is_leaf, p.start, p.end),
+                "Node {i}"
+            );
+            assert_eq!(
+                (s.bounds.lb, s.bounds.ub),
+                (p.bounds.lb, p.bounds.ub),
+                "Node {i}"
+            );
+        }
+
+        // A grid of rays down -Z across the teapot:
+        let mut hits = 0;
+        for (x, y) in (-8..=8).flat_map(|x| (-8..=8).map(move |y| (x, y))) {
+            let ray = Ray {
+                pos: Vec3::new(x as f32 * 0.06, y as f32 * 0.06, 2.0),
+                dir: Vec3::NEG_Z,
+            };
+            let (s, p) = (serial.intersect(ray), parallel.intersect(ray));
+            assert_eq!(
+                s.map(|h| (h.triangle_id, h.t)),
+                p.map(|h| (h.triangle_id, h.t)),
+                "{ray:?}"
+            );
+            hits += s.is_some() as usize;
+        }
+        assert!(hits > 100, "Only {hits} rays hit the teapot");
+    }
+}
@@ -0,0 +1,258 @@
+use wgpu::{include_spirv, util::DeviceExt};
+
+use crate::{pathtracer::PathtracerOutput, pathtracer_manager::WORKGROUP_SIZE};
+
+// Levels in the chain, the first at half the output's size and each after half the last.
+// Fewer once they're down to a single texel:
+const BLOOM_LEVELS: usize = 5;
+
+// Luminance glow starts at, in exposed radiance, so 1.0 is what clips to white.
+pub const DEFAULT_BLOOM_THRESHOLD: f32 = 1.0;
+
+#[repr(C)]
+#[derive(Copy, Clone, Debug, bytemuck::Pod, bytemuck::Zeroable)]
+struct BloomParams {
+    src_dims: [u32; 2],
+    dst_dims: [u32; 2],
+    step: [i32; 2],
+    threshold: f32,
+    _pad: u32,
+}
+
+// Indexes Bloom::pipelines:
+#[derive(Copy, Clone, Debug)]
+enum Stage {
+    Extract,
+    Downsample,
+    Blur,
+    Upsample,
+}
+
+// The entry point of bloom.slang for each stage:
+const ENTRY_POINTS: [&str; 4] = ["extract", "downsample", "blur", "upsample"];
+
+struct BloomPass {
+    stage: Stage,
+    bind_group: wgpu::BindGroup,
+    workgroups: u32,
+}
+
+// The compute passes that build the glow for one output, see bloom.slang. Only run while
+// the render phase's bloom intensity is above zero.
+pub struct Bloom {
+    pipelines: [wgpu::ComputePipeline; 4],
+    passes: Vec<BloomPass>,
+    // The extract pass's params, rewritten with the threshold every frame:
+    extract_params: BloomParams,
+    extract_buffer: wgpu::Buffer,
+    // The finished glow at the first level's size, read by the render shader:
+    pub output: wgpu::Buffer,
+}
+
+// Size of each level of the chain for an output of `dims`.
+fn level_dims(dims: (u32, u32)) -> Vec<(u32, u32)> {
+    let mut levels = vec![((dims.0 / 2).max(1), (dims.1 / 2).max(1))];
+    while levels.len() < BLOOM_LEVELS {
+        let last = levels[levels.len() - 1];
+        if last == (1, 1) {
+            break;
+        }
+        levels.push(((last.0 / 2).max(1), (last.1 / 2).max(1)));
+    }
+    levels
+}
+
+impl Bloom {
+    pub fn new(device: &wgpu::Device, pto: &PathtracerOutput) -> Self {
+        let storage = |binding, read_only| wgpu::BindGroupLayoutEntry {
+            binding,
+            visibility: wgpu::ShaderStages::COMPUTE,
+            ty: wgpu::BindingType::Buffer {
+                ty: wgpu::BufferBindingType::Storage { read_only },
+                has_dynamic_offset: false,
+                min_binding_size: None,
+            },
+            count: None,
+        };
+        let bind_group_layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            label: Some("Bloom Bind Group Layout"),
+            entries: &[
+                storage(0, true),
+                storage(1, false),
+                wgpu::BindGroupLayoutEntry {
+                    binding: 2,
+                    visibility: wgpu::ShaderStages::COMPUTE,
+                    ty: wgpu::BindingType::Buffer {
+                        ty: wgpu::BufferBindingType::Uniform,
+                        has_dynamic_offset: false,
+                        min_binding_size: None,
+                    },
+                    count: None,
+                },
+            ],
+        });
+
+        let shader =
+            device.create_shader_module(include_spirv!(concat!(env!("OUT_DIR"), "/bloom.spv")));
+        let pipeline_layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+            label: Some("Bloom Pipeline Layout"),
+            bind_group_layouts: &[&bind_group_layout],
+            push_constant_ranges: &[],
+        });
+        let pipelines = ENTRY_POINTS.map(|entry_point| {
+            device.create_compute_pipeline(&wgpu::ComputePipelineDescriptor {
+                label: Some("Bloom Pipeline"),
+                layout: Some(&pipeline_layout),
+                module: &shader,
+                entry_point: Some(entry_point),
+                compilation_options: wgpu::PipelineCompilationOptions {
+                    constants: &[],
+                    zero_initialize_workgroup_memory: false,
+                },
+                cache: None,
+            })
+        });
+
+        let levels = level_dims(pto.dims);
+        let level_buffer = |label, dims: (u32, u32)| {
+            device.create_buffer(&wgpu::BufferDescriptor {
+                label: Some(label),
+                size: (dims.0 * dims.1) as u64 * std::mem::size_of::<[f32; 4]>() as u64,
+                usage: wgpu::BufferUsages::STORAGE,
+                mapped_at_creation: false,
+            })
+        };
+        let chain = levels
+            .iter()
+            .map(|&dims| level_buffer("Bloom Level", dims))
+            .collect::<Vec<_>>();
+        // Holds the first direction of each level's blur:
+        let scratch = levels
+            .iter()
+            .map(|&dims| level_buffer("Bloom Scratch", dims))
+            .collect::<Vec<_>>();
+
+        let mut passes = Vec::new();
+        let mut pass = |stage,
+                        src: &wgpu::Buffer,
+                        src_dims: (u32, u32),
+                        dst: &wgpu::Buffer,
+                        dst_dims: (u32, u32),
+                        step: [i32; 2]| {
+            let params = BloomParams {
+                src_dims: [src_dims.0, src_dims.1],
+                dst_dims: [dst_dims.0, dst_dims.1],
+                step,
+                threshold: DEFAULT_BLOOM_THRESHOLD,
+                _pad: 0,
+            };
+            let params_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+                label: Some("Bloom Params Buffer"),
+                contents: bytemuck::bytes_of(&params),
+                usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+            });
+            let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+                label: Some("Bloom Bind Group"),
+                layout: &bind_group_layout,
+                entries: &[
+                    wgpu::BindGroupEntry {
+                        binding: 0,
+                        resource: src.as_entire_binding(),
+                    },
+                    wgpu::BindGroupEntry {
+                        binding: 1,
+                        resource: dst.as_entire_binding(),
+                    },
+                    wgpu::BindGroupEntry {
+                        binding: 2,
+                        resource: params_buffer.as_entire_binding(),
+                    },
+                ],
+            });
+            passes.push(BloomPass {
+                stage,
+                bind_group,
+                workgroups: (dst_dims.0 * dst_dims.1).div_ceil(WORKGROUP_SIZE),
+            });
+            (params, params_buffer)
+        };
+
+        let (extract_params, extract_buffer) = pass(
+            Stage::Extract,
+            &pto.source_buffer,
+            pto.dims,
+            &chain[0],
+            levels[0],
+            [0, 0],
+        );
+        for i in 1..levels.len() {
+            pass(
+                Stage::Downsample,
+                &chain[i - 1],
+                levels[i - 1],
+                &chain[i],
+                levels[i],
+                [0, 0],
+            );
+        }
+        for i in 0..levels.len() {
+            pass(
+                Stage::Blur,
+                &chain[i],
+                levels[i],
+                &scratch[i],
+                levels[i],
+                [1, 0],
+            );
+            pass(
+                Stage::Blur,
+                &scratch[i],
+                levels[i],
+                &chain[i],
+                levels[i],
+                [0, 1],
+            );
+        }
+        for i in (0..levels.len() - 1).rev() {
+            pass(
+                Stage::Upsample,
+                &chain[i + 1],
+                levels[i + 1],
+                &chain[i],
+                levels[i],
+                [0, 0],
+            );
+        }
+
+        Self {
+            pipelines,
+            passes,
+            extract_params,
+            extract_buffer,
+            output: chain[0].clone(),
+        }
+    }
+
+    // Builds the glow from the output's radiance, `threshold` being in radiance before
+    // exposure.
+    pub fn encode(&self, queue: &wgpu::Queue, encoder: &mut wgpu::CommandEncoder, threshold: f32) {
+        queue.write_buffer(
+            &self.extract_buffer,
+            0,
+            bytemuck::bytes_of(&BloomParams {
+                threshold,
+                ..self.extract_params
+            }),
+        );
+
+        let mut compute_pass = encoder.begin_compute_pass(&wgpu::ComputePassDescriptor {
+            label: Some("Bloom Pass"),
+            timestamp_writes: None,
+        });
+        for pass in &self.passes {
+            compute_pass.set_pipeline(&self.pipelines[pass.stage as usize]);
+            compute_pass.set_bind_group(0, &pass.bind_group, &[]);
+            compute_pass.dispatch_workgroups(pass.workgroups, 1, 1);
+        }
+    }
+}
@@ -0,0 +1,218 @@
+use std::sync::OnceLock;
+
+use rand::{Rng, SeedableRng, rngs::StdRng};
+use tracing::info;
+
+// Side of the square mask, tiled over the image. Must match BLUE_NOISE_SIZE in
+// shaders/pathtracer.slang.
+pub const BLUE_NOISE_SIZE: usize = 128;
+
+// Width of the gaussian each point pushes its neighbours away with, and how far out on the
+// torus it's cut off:
+const SIGMA: f32 = 1.5;
+const RADIUS: i32 = 6;
+// Points in the initial pattern, as a fraction of the mask:
+const INITIAL_DENSITY: f32 = 0.1;
+// The mask is the same on every run:
+const SEED: u64 = 0x6e6f697365;
+
+// A 128x128 tileable blue noise mask, each texel in 0.0..1.0 and every value taken once.
+// Built on first use with void and cluster, see:
+// https://cv.ulichney.com/papers/1993-void-cluster.pdf
+pub fn blue_noise_mask() -> &'static [f32] {
+    static MASK: OnceLock<Vec<f32>> = OnceLock::new();
+    MASK.get_or_init(|| {
+        let start = std::time::Instant::now();
+        let mask = void_and_cluster();
+        info!(
+            "Built {BLUE_NOISE_SIZE}x{BLUE_NOISE_SIZE} blue noise mask in {:?}",
+            start.elapsed()
+        );
+        mask
+    })
+}
+
+// Points on the torus with the energy their gaussians sum to at every texel.
+#[derive(Clone)]
+struct Pattern {
+    points: Vec<bool>,
+    energy: Vec<f32>,
+    count: usize,
+    // Each row's tightest cluster and largest void, only redone for the rows a change
+    // reaches so finding either doesn't scan the whole mask:
+    row_clusters: Vec<Option<usize>>,
+    row_voids: Vec<Option<usize>>,
+}
+
+impl Pattern {
+    fn new() -> Self {
+        let n = BLUE_NOISE_SIZE * BLUE_NOISE_SIZE;
+        Self {
+            points: vec![false; n],
+            energy: vec![0.0; n],
+            count: 0,
+            row_clusters: vec![None; BLUE_NOISE_SIZE],
+            row_voids: (0..BLUE_NOISE_SIZE)
+                .map(|y| Some(y * BLUE_NOISE_SIZE))
+                .collect(),
+        }
+    }
+
+    fn set(&mut self, kernel: &[f32], i: usize, point: bool) {
+        debug_assert_ne!(self.points[i], point);
+        self.points[i] = point;
+        if point {
+            self.count += 1;
+        } else {
+            self.count -= 1;
+        }
+
+        let sign = if point { 1.0 } else { -1.0 };
+        let size = BLUE_NOISE_SIZE as i32;
+        let (x, y) = ((i % BLUE_NOISE_SIZE) as i32, (i / BLUE_NOISE_SIZE) as i32);
+        let side = 2 * RADIUS + 1;
+        for dy in -RADIUS..=RADIUS {
+            for dx in -RADIUS..=RADIUS {
+                let j = (x + dx).rem_euclid(size) + (y + dy).rem_euclid(size) * size;
+                let k = (dx + RADIUS) + (dy + RADIUS) * side;
+                self.energy[j as usize] += sign * kernel[k as usize];
+            }
+        }
+        for dy in -RADIUS..=RADIUS {
+            self.update_row((y + dy).rem_euclid(size) as usize);
+        }
+    }
+
+    fn update_row(&mut self, y: usize) {
+        let row = y * BLUE_NOISE_SIZE..(y + 1) * BLUE_NOISE_SIZE;
+        let (mut cluster, mut void) = (None::<usize>, None::<usize>);
+        for i in row {
+            if self.points[i] {
+                if cluster.is_none_or(|c| self.energy[i] > self.energy[c]) {
+                    cluster = Some(i);
+                }
+            } else if void.is_none_or(|v| self.energy[i] < self.energy[v]) {
+                void = Some(i);
+            }
+        }
+        self.row_clusters[y] = cluster;
+        self.row_voids[y] = void;
+    }
+
+    // The point with the most energy, the middle of the densest cluster:
+    fn tightest_cluster(&self) -> usize {
+        self.row_clusters
+            .iter()
+            .flatten()
+            .copied()
+            .max_by(|&a, &b| self.energy[a].total_cmp(&self.energy[b]))
+            .unwrap()
+    }
+
+    // The empty texel with the least energy, the middle of the largest gap:
+    fn largest_void(&self) -> usize {
+        self.row_voids
+            .iter()
+            .flatten()
+            .copied()
+            .min_by(|&a, &b| self.energy[a].total_cmp(&self.energy[b]))
+            .unwrap()
+    }
+}
+
+// Ranks every texel by the order points are added to a pattern kept as evenly spread as
+// possible, so any threshold of the mask is blue noise too.
+fn void_and_cluster() -> Vec<f32> {
+    let n = BLUE_NOISE_SIZE * BLUE_NOISE_SIZE;
+    let side = 2 * RADIUS + 1;
+    let kernel = (0..side * side)
+        .map(|k| {
+            let (dx, dy) = ((k % side - RADIUS) as f32, (k / side - RADIUS) as f32);
+            (-(dx * dx + dy * dy) / (2.0 * SIGMA * SIGMA)).exp()
+        })
+        .collect::<Vec<_>>();
+
+    // Start from random points, then move the tightest cluster into the largest void until
+    // it'd move straight back:
+    let mut rng = StdRng::seed_from_u64(SEED);
+    let mut initial = Pattern::new();
+    while initial.count < (n as f32 * INITIAL_DENSITY) as usize {
+        let i = rng.random_range(0..n);
+        if !initial.points[i] {
+            initial.set(&kernel, i, true);
+        }
+    }
+    loop {
+        let cluster = initial.tightest_cluster();
+        initial.set(&kernel, cluster, false);
+        let void = initial.largest_void();
+        initial.set(&kernel, void, true);
+        if void == cluster {
+            break;
+        }
+    }
+
+    let mut rank = vec![0; n];
+    // The initial points rank below, tightest clusters first out and so lowest:
+    let mut pattern = initial.clone();
+    while pattern.count > 0 {
+        let cluster = pattern.tightest_cluster();
+        pattern.set(&kernel, cluster, false);
+        rank[cluster] = pattern.count;
+    }
+    // Then the rest above, filling the largest voids in turn:
+    let mut pattern = initial;
+    while pattern.count < n {
+        let void = pattern.largest_void();
+        rank[void] = pattern.count;
+        pattern.set(&kernel, void, true);
+    }
+
+    rank.into_iter()
+        .map(|r| (r as f32 + 0.5) / n as f32)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn mask_ranks_every_texel_once() {
+        let mask = blue_noise_mask();
+        let n = BLUE_NOISE_SIZE * BLUE_NOISE_SIZE;
+        assert_eq!(mask.len(), n);
+        let mut ranks = mask
+            .iter()
+            .map(|&v| (v * n as f32) as usize)
+            .collect::<Vec<_>>();
+        ranks.sort_unstable();
+        assert!(ranks.iter().copied().eq(0..n));
+    }
+
+    // Blue noise has little low frequency energy, so the means of small blocks of it vary
+    // far less than those of white noise:
+    #[test]
+    fn block_means_vary_less_than_white_noise() {
+        const BLOCK: usize = 4;
+        let mask = blue_noise_mask();
+        let blocks = BLUE_NOISE_SIZE / BLOCK;
+        let means = (0..blocks * blocks)
+            .map(|b| {
+                let (bx, by) = (b % blocks * BLOCK, b / blocks * BLOCK);
+                let sum: f32 = (0..BLOCK * BLOCK)
+                    .map(|t| mask[bx + t % BLOCK + (by + t / BLOCK) * BLUE_NOISE_SIZE])
+                    .sum();
+                sum / (BLOCK * BLOCK) as f32
+            })
+            .collect::<Vec<_>>();
+        let variance = means.iter().map(|m| (m - 0.5).powi(2)).sum::<f32>() / means.len() as f32;
+
+        // A uniform variable's variance is 1/12, and a mean of 16 of them a 16th of that:
+        let white = 1.0 / 12.0 / (BLOCK * BLOCK) as f32;
+        assert!(
+            variance < 0.5 * white,
+            "Block mean variance {variance}, white noise {white}"
+        );
+    }
+}
@@ -1,3 +1,4 @@
+use bevy_ecs::resource::Resource;
 use glam::{UVec3, Vec3};
 use itertools::Itertools;
 use wgpu::util::DeviceExt;
@@ -9,14 +10,77 @@ pub struct AABB {
 }
 
 impl AABB {
+    // An inverted box, the identity for union.
+    pub const EMPTY: AABB = AABB {
+        lb: Vec3::INFINITY,
+        ub: Vec3::NEG_INFINITY,
+    };
+
     pub fn union(&self, other: &AABB) -> AABB {
         AABB {
             lb: self.lb.min(other.lb),
             ub: self.ub.max(other.ub),
         }
     }
+
+    pub fn surface_area(&self) -> f32 {
+        let d = (self.ub - self.lb).max(Vec3::ZERO);
+        2.0 * (d.x * d.y + d.y * d.z + d.z * d.x)
+    }
+}
+
+// How a node is partitioned along its longest axis.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum SplitStrategy {
+    // Split at the middle of the centroid bounds.
+    Midpoint,
+    // Split at the cheapest of the binned surface area heuristic planes.
+    #[default]
+    Sah,
+}
+
+// Build trade-offs for the acceleration structures.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum BvhPreset {
+    // Bigger leaves, quicker to build, slower to trace.
+    Fast,
+    #[default]
+    Balanced,
+    // Smaller leaves, for fewer triangle tests per ray.
+    Quality,
 }
 
+// The most elements a node may hold before it's split. A leaf size of at least the element
+// count leaves a single leaf. BLAS sizes apply to meshes loaded after they change.
+#[derive(Resource, Clone, Copy, Debug, PartialEq, Eq)]
+pub struct BvhSettings {
+    pub blas_leaf_size: usize,
+    pub tlas_leaf_size: usize,
+}
+
+impl Default for BvhSettings {
+    fn default() -> Self {
+        Self::preset(BvhPreset::default())
+    }
+}
+
+impl BvhSettings {
+    pub fn preset(preset: BvhPreset) -> Self {
+        let (blas_leaf_size, tlas_leaf_size) = match preset {
+            BvhPreset::Fast => (8, 2),
+            BvhPreset::Balanced => (4, 1),
+            BvhPreset::Quality => (2, 1),
+        };
+        Self {
+            blas_leaf_size,
+            tlas_leaf_size,
+        }
+    }
+}
+
+// Number of centroid bins evaluated per SAH split.
+const SAH_BINS: usize = 12;
+
 #[derive(Clone, Copy, Debug, Default)]
 pub struct BVHNode {
     pub bounds: AABB,
@@ -56,8 +120,9 @@ pub trait BVH {
             let r = *self.node(node.right);
             node.bounds = l.bounds().union(&r.bounds());
         } else {
-            let mut new_bounds = self.elem_bounds(node.start);
-            for i in node.start + 1..node.end {
+            // An empty leaf, the root of a tree over nothing, bounds nothing:
+            let mut new_bounds = AABB::EMPTY;
+            for i in node.start..node.end {
                 new_bounds = new_bounds.union(&self.elem_bounds(i))
             }
             node.bounds = new_bounds;
@@ -65,59 +130,116 @@ pub trait BVH {
         *self.node_mut(idx) = node;
     }
 
-    fn subdivide(&mut self, idx: usize, threshold: usize) {
-        let node = *self.node(idx);
-        let node = if !node.is_leaf {
-            self.subdivide(node.left, threshold);
-            self.subdivide(node.right, threshold);
-            return;
-        } else {
-            // Don't subdivide if the number of circles within threshold:
-            if node.end - node.start <= threshold {
-                return;
+    // Finds the split plane along axis with the lowest SAH cost, considering
+    // the boundaries between SAH_BINS equal width bins of the centroid bounds.
+    fn sah_split(&self, node: &BVHNode, axis: usize, cmin: f32, cmax: f32) -> f32 {
+        if cmax <= cmin {
+            return cmin;
+        }
+
+        let mut counts = [0usize; SAH_BINS];
+        let mut bounds = [AABB::EMPTY; SAH_BINS];
+        let scale = SAH_BINS as f32 / (cmax - cmin);
+        for i in node.start..node.end {
+            let c = self.elem_centroid(i)[axis];
+            let b = (((c - cmin) * scale) as usize).min(SAH_BINS - 1);
+            counts[b] += 1;
+            bounds[b] = bounds[b].union(&self.elem_bounds(i));
+        }
+
+        // Sweep from the right so each left sweep can read the right side cost:
+        let mut right_costs = [0.0; SAH_BINS];
+        let (mut count, mut aabb) = (0, AABB::EMPTY);
+        for b in (1..SAH_BINS).rev() {
+            count += counts[b];
+            aabb = aabb.union(&bounds[b]);
+            right_costs[b - 1] = count as f32 * aabb.surface_area();
+        }
+
+        let mut best = (f32::INFINITY, 0);
+        let (mut count, mut aabb) = (0, AABB::EMPTY);
+        for b in 0..SAH_BINS - 1 {
+            count += counts[b];
+            aabb = aabb.union(&bounds[b]);
+            if count == 0 || count == node.end - node.start {
+                continue;
             }
+            let cost = count as f32 * aabb.surface_area() + right_costs[b];
+            if cost < best.0 {
+                best = (cost, b);
+            }
+        }
 
-            // Compute the longest axis, on which we will split
-            let extent = node.bounds.ub - node.bounds.lb;
-            let mut axis = 0;
-            if extent.y > extent.x {
-                axis = 1
-            };
-            if extent.z > extent[axis] {
-                axis = 2
-            };
+        cmin + (best.1 + 1) as f32 / scale
+    }
+
+    // Partitions the node's elements about a split plane on its longest axis,
+    // returning the index of the first element on the right side. None if
+    // every element landed on the same side.
+    fn partition(&mut self, node: &BVHNode, strategy: SplitStrategy) -> Option<usize> {
+        // Compute the longest axis, on which we will split
+        let extent = node.bounds.ub - node.bounds.lb;
+        let mut axis = 0;
+        if extent.y > extent.x {
+            axis = 1
+        };
+        if extent.z > extent[axis] {
+            axis = 2
+        };
 
-            // Get the median circle
-            // let split = node.bounds.lb[axis] + extent[axis] / 2.0;
-            let (mut i, mut j) = (node.start, node.end - 1);
+        // Get the median circle
+        // let split = node.bounds.lb[axis] + extent[axis] / 2.0;
+        let (mut i, mut j) = (node.start, node.end - 1);
 
-            let mut cmin = self.elem_centroid(node.start);
-            let mut cmax = cmin;
+        let mut cmin = self.elem_centroid(node.start);
+        let mut cmax = cmin;
 
-            for i in node.start + 1..node.end {
-                let c = self.elem_centroid(i);
-                cmin = cmin.min(c);
-                cmax = cmax.max(c);
-            }
+        for i in node.start + 1..node.end {
+            let c = self.elem_centroid(i);
+            cmin = cmin.min(c);
+            cmax = cmax.max(c);
+        }
 
-            let split = (cmin[axis] + cmax[axis]) * 0.5;
+        let split = match strategy {
+            SplitStrategy::Midpoint => (cmin[axis] + cmax[axis]) * 0.5,
+            SplitStrategy::Sah => self.sah_split(node, axis, cmin[axis], cmax[axis]),
+        };
 
-            while i < j {
-                if self.elem_centroid(i)[axis] < split {
-                    i += 1;
-                } else {
-                    self.elem_swap(i, j);
-                    j -= 1;
-                }
+        while i < j {
+            if self.elem_centroid(i)[axis] < split {
+                i += 1;
+            } else {
+                self.elem_swap(i, j);
+                j -= 1;
             }
+        }
+
+        if i == node.end || i == node.start {
+            // Either empty or one sided, so make no changes.
+            // This is probably unreachable given i use the median
+            // and a threshold, but here to be safe.
+            return None;
+        }
+
+        Some(i)
+    }
 
-            if i == node.end || i == node.start {
-                // Either empty or one sided, so make no changes.
-                // This is probably unreachable given i use the median
-                // and a threshold, but here to be safe.
+    fn subdivide(&mut self, idx: usize, threshold: usize, strategy: SplitStrategy) {
+        let node = *self.node(idx);
+        let node = if !node.is_leaf {
+            self.subdivide(node.left, threshold, strategy);
+            self.subdivide(node.right, threshold, strategy);
+            return;
+        } else {
+            // Don't subdivide if the number of circles within threshold:
+            if node.end - node.start <= threshold {
                 return;
             }
 
+            let Some(i) = self.partition(&node, strategy) else {
+                return;
+            };
+
             let left = BVHNode {
                 is_leaf: true,
                 bounds: Default::default(),
@@ -138,8 +260,8 @@ pub trait BVH {
 
             self.compute_node_bounds(l);
             self.compute_node_bounds(r);
-            self.subdivide(l, threshold);
-            self.subdivide(r, threshold);
+            self.subdivide(l, threshold, strategy);
+            self.subdivide(r, threshold, strategy);
 
             BVHNode {
                 is_leaf: false,
@@ -171,9 +293,9 @@ pub trait BVH {
         }
     }
 
-    fn initialize(&mut self, threshold: usize) {
+    fn initialize(&mut self, threshold: usize, strategy: SplitStrategy) {
         self.compute_node_bounds(0);
-        self.subdivide(0, threshold);
+        self.subdivide(0, threshold, strategy);
         self.generate_skips(0, 0);
     }
 }
@@ -197,12 +319,16 @@ impl From<AABB> for AABBGPU {
     }
 }
 
+// Nodes are uploaded threaded for stackless traversal. A ray that hits an inner node's
+// box descends to `left`, anything else continues at `skip`: the next node in preorder
+// outside this subtree, or 0 (the root) once the walk is done. The right child isn't
+// stored, it is always the left child's skip.
 #[repr(C)]
 #[derive(Copy, Clone, Debug, bytemuck::Pod, bytemuck::Zeroable, Default)]
 pub struct BVHNodeGPU {
     pub aabb: AABBGPU,
     pub left: u32,        // Left child, (meaningless if 0 || is_leaf)
-    pub right: u32,       // Right child, (meaningless if 0 || is_leaf)
+    pub skip: u32,        // Where to go on a miss or after a leaf, 0 when done
     pub is_leaf: u32,     // Leaf node? start/end are meaningless if 0
     pub start: u32,       // Start face, inclusive
     pub end: u32,         // End face, not inclusive
@@ -214,7 +340,7 @@ impl From<BVHNode> for BVHNodeGPU {
         BVHNodeGPU {
             aabb: AABBGPU::from(value.bounds),
             left: value.left as u32,
-            right: value.skip as u32,
+            skip: value.skip as u32,
             is_leaf: value.is_leaf as u32,
             start: value.start as u32,
             end: value.end as u32,
@@ -222,3 +348,147 @@ impl From<BVHNode> for BVHNodeGPU {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        blas::BLAS,
+        mesh::{Mesh, NormalizeMode},
+    };
+
+    const TEAPOT: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/assets/teapot.obj");
+    const DRAGON: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/assets/dragon.obj");
+
+    fn teapot(strategy: SplitStrategy) -> BLAS {
+        build(TEAPOT, strategy)
+    }
+
+    fn build(path: &str, strategy: SplitStrategy) -> BLAS {
+        let mesh = Mesh::from_obj(path, &NormalizeMode::UnitCube).unwrap();
+        let mut blas = BLAS {
+            nodes: vec![BVHNode {
+                is_leaf: true,
+                end: mesh.faces.len(),
+                ..Default::default()
+            }],
+            mesh,
+        };
+        blas.initialize(4, strategy);
+        blas
+    }
+
+    // Levels from the node down to its deepest leaf, the node included:
+    fn depth(nodes: &[BVHNode], idx: usize) -> usize {
+        let node = nodes[idx];
+        if node.is_leaf {
+            1
+        } else {
+            1 + depth(nodes, node.left).max(depth(nodes, node.right))
+        }
+    }
+
+    // Expected node visits and triangle tests of a random ray through the root, each node
+    // being hit in proportion to its surface area:
+    fn sah_cost(nodes: &[BVHNode]) -> f32 {
+        let root = nodes[0].bounds.surface_area();
+        nodes
+            .iter()
+            .map(|n| {
+                let p = n.bounds.surface_area() / root;
+                if n.is_leaf {
+                    p * (n.end - n.start) as f32
+                } else {
+                    p
+                }
+            })
+            .sum()
+    }
+
+    fn assert_sah_beats_midpoint(path: &str) {
+        let midpoint = build(path, SplitStrategy::Midpoint);
+        let sah = build(path, SplitStrategy::Sah);
+        assert!(sah.nodes.len() < midpoint.nodes.len());
+        assert!(depth(&sah.nodes, 0) < depth(&midpoint.nodes, 0));
+        assert!(sah_cost(&sah.nodes) < sah_cost(&midpoint.nodes));
+    }
+
+    #[test]
+    fn sah_beats_midpoint_on_teapot() {
+        assert_sah_beats_midpoint(TEAPOT);
+    }
+
+    // The dragon's 250k triangles take a while to load and build twice without
+    // optimisations, run with --release --ignored:
+    #[test]
+    #[ignore]
+    fn sah_beats_midpoint_on_dragon() {
+        assert_sah_beats_midpoint(DRAGON);
+    }
+
+    #[test]
+    fn leaf_sizes_bound_the_tree() {
+        let mesh = Mesh::from_obj(TEAPOT, &NormalizeMode::UnitCube).unwrap();
+
+        // A leaf as big as the mesh stops at the root, and a leaf size of 0 is taken as 1:
+        assert_eq!(BLAS::new(mesh.clone(), 1 << 20).nodes.len(), 1);
+        assert_eq!(
+            BLAS::new(Mesh::cube(), 0).nodes.len(),
+            BLAS::new(Mesh::cube(), 1).nodes.len()
+        );
+
+        let nodes = |preset| {
+            let settings = BvhSettings::preset(preset);
+            BLAS::new(mesh.clone(), settings.blas_leaf_size).nodes.len()
+        };
+        assert!(nodes(BvhPreset::Fast) < nodes(BvhPreset::Balanced));
+        assert!(nodes(BvhPreset::Balanced) < nodes(BvhPreset::Quality));
+    }
+
+    // Node indices in preorder, left subtrees before right:
+    fn preorder(nodes: &[BVHNode], idx: usize, order: &mut Vec<usize>) {
+        order.push(idx);
+        if !nodes[idx].is_leaf {
+            preorder(nodes, nodes[idx].left, order);
+            preorder(nodes, nodes[idx].right, order);
+        }
+    }
+
+    #[test]
+    fn skips_thread_the_tree_in_preorder() {
+        for nodes in [
+            BLAS::new(Mesh::cube(), 1).nodes,
+            teapot(SplitStrategy::Sah).nodes,
+        ] {
+            assert!(nodes.len() > 1);
+            let mut order = Vec::new();
+            preorder(&nodes, 0, &mut order);
+            assert_eq!(order.len(), nodes.len());
+
+            // Each node skips to the next node in preorder outside its own subtree, and
+            // the last subtrees end the walk at the root:
+            for (pos, &idx) in order.iter().enumerate() {
+                let mut subtree = Vec::new();
+                preorder(&nodes, idx, &mut subtree);
+                let next = order.get(pos + subtree.len()).copied().unwrap_or(0);
+                assert_eq!(nodes[idx].skip, next, "Node {idx}");
+                if !nodes[idx].is_leaf {
+                    // The right child isn't stored on the GPU, it's where the left skips to:
+                    assert_eq!(nodes[nodes[idx].left].skip, nodes[idx].right);
+                }
+            }
+
+            // Descending everywhere, as a ray hitting every box would, visits each node once:
+            let mut walk = vec![0];
+            loop {
+                let node = nodes[*walk.last().unwrap()];
+                let next = if node.is_leaf { node.skip } else { node.left };
+                if next == 0 {
+                    break;
+                }
+                walk.push(next);
+            }
+            assert_eq!(walk, order);
+        }
+    }
+}
@@ -1,14 +1,21 @@
-use std::collections::{HashMap, HashSet};
+use std::{
+    collections::{HashMap, HashSet},
+    path::Path,
+};
 
 use bevy_ecs::prelude::*;
-use glam::Vec2;
+use glam::{Mat4, Vec2, Vec3};
+use serde::{Deserialize, Serialize};
+use tracing::{error, info};
 use wgpu::util::DeviceExt;
-use winit::{event::WindowEvent, keyboard::KeyCode};
+use winit::{event::MouseButton, keyboard::KeyCode};
 
 use crate::{
     app::{self, BevyApp},
-    delta_time::DeltaTime,
+    delta_time::Time,
+    pathtracer::Pathtracer,
     render_resources::RenderQueue,
+    traverse::Ray,
     winnit::{WinitDeviceEvent, WinitWindowEvent},
 };
 
@@ -16,30 +23,34 @@ pub fn initialize(app: &mut BevyApp) {
     app.world.get_resource_or_init::<Schedules>().add_systems(
         crate::schedule::Update,
         (
-            camera_system.after(camera_buffer_system),
-            camera_buffer_system,
+            camera_system,
+            // Upload the same frame the input moved the camera:
+            camera_buffer_system.after(camera_system),
         ),
     );
 }
 
-fn camera_buffer_system(cameras: Query<&mut Camera>, queue: Res<RenderQueue>) {
+pub fn camera_buffer_system(cameras: Query<&mut Camera>, queue: Res<RenderQueue>) {
     for mut camera in cameras {
         camera.update(&queue.0);
     }
 }
 
-fn camera_system(
+pub fn camera_system(
     mut de_reader: MessageReader<WinitDeviceEvent>,
     mut we_reader: MessageReader<WinitWindowEvent>,
-    mut camera: Query<&mut Camera>,
+    mut camera: Query<(&mut Camera, Option<&mut Pathtracer>)>,
     mut keys_pressed: Local<HashSet<KeyCode>>,
-    dt: Res<DeltaTime>,
+    mut drag: Local<CursorDrag>,
+    time: Res<Time>,
 ) {
-    // DANGER: This is super sketch and will break the moment i try to do anything else with
-    // multiple cameras, or read any other kind of input (such as for resizing) yay!
     // TODO: DO THIS PROPERLY, HAVE A WINIT EVENT -> ENGINE EVENT mapping system.
 
-    let Ok(mut camera) = camera.single_mut() else {
+    // Only the primary camera is driven, viewports keep the view they were opened with:
+    let Some((mut camera, pathtracer)) = camera
+        .iter_mut()
+        .find(|(_, pt)| pt.as_ref().is_none_or(|pt| pt.is_primary))
+    else {
         return;
     };
 
@@ -62,19 +73,94 @@ fn camera_system(
                 let winit::keyboard::PhysicalKey::Code(key) = event.physical_key else {
                     continue;
                 };
+                if event.state.is_pressed() && !event.repeat {
+                    match key {
+                        KeyCode::F5 => {
+                            if let Err(e) = camera.save_pose(Path::new(CAMERA_POSE_PATH)) {
+                                error!("Failed to save camera pose: {e:#}");
+                            }
+                        }
+                        KeyCode::F9 => {
+                            if let Err(e) = camera.load_pose(Path::new(CAMERA_POSE_PATH)) {
+                                error!("Failed to load camera pose: {e:#}");
+                            }
+                        }
+                        KeyCode::F6 => match camera.mode {
+                            CameraMode::Fly => camera.orbit(DEFAULT_ORBIT_DISTANCE),
+                            CameraMode::Orbit { .. } => camera.fly(),
+                        },
+                        KeyCode::Equal | KeyCode::NumpadAdd => {
+                            let speed = camera.move_speed * MOVE_SPEED_STEP;
+                            camera.set_move_speed(speed);
+                        }
+                        KeyCode::Minus | KeyCode::NumpadSubtract => {
+                            let speed = camera.move_speed / MOVE_SPEED_STEP;
+                            camera.set_move_speed(speed);
+                        }
+                        _ => {}
+                    }
+                }
+
                 if event.state.is_pressed() {
                     keys_pressed.insert(key);
                 } else {
                     keys_pressed.remove(&key);
                 }
             }
+            winit::event::WindowEvent::MouseWheel { delta, .. } => {
+                let notches = match delta {
+                    winit::event::MouseScrollDelta::LineDelta(_, y) => *y,
+                    // Roughly a line's worth of pixels per notch:
+                    winit::event::MouseScrollDelta::PixelDelta(p) => p.y as f32 / 20.0,
+                };
+                // Scrolling up speeds up the fly camera, or brings the orbit camera closer:
+                match camera.mode {
+                    CameraMode::Fly => {
+                        let speed = camera.move_speed * MOVE_SPEED_STEP.powf(notches);
+                        camera.set_move_speed(speed);
+                    }
+                    CameraMode::Orbit { distance, .. } => {
+                        camera.set_orbit_distance(distance / MOVE_SPEED_STEP.powf(notches));
+                    }
+                }
+            }
+            winit::event::WindowEvent::MouseInput { state, button, .. } => match button {
+                MouseButton::Left => drag.rotating = state.is_pressed(),
+                MouseButton::Middle => drag.panning = state.is_pressed(),
+                _ => {}
+            },
+            winit::event::WindowEvent::CursorMoved { position, .. } => {
+                let cursor = Vec2::new(position.x as f32, position.y as f32);
+                let delta = cursor - drag.last_cursor.unwrap_or(cursor);
+                drag.last_cursor = Some(cursor);
+
+                // Dragging with a free cursor only steers the orbit camera:
+                if let CameraMode::Orbit { distance, .. } = camera.mode {
+                    if drag.rotating {
+                        camera.rotate(delta * ORBIT_DRAG_SENSITIVITY);
+                    }
+                    if drag.panning {
+                        // Scaled so the target keeps up with the cursor at any distance:
+                        let pan = delta * ORBIT_PAN_SENSITIVITY * distance;
+                        camera.translate((-pan.x, pan.y, 0.0));
+                    }
+                }
+            }
             _ => {}
         }
     }
 
+    let boost = if keys_pressed.contains(&KeyCode::ShiftLeft)
+        || keys_pressed.contains(&KeyCode::ShiftRight)
+    {
+        MOVE_BOOST
+    } else {
+        1.0
+    };
+    // Scaled by the frame time so the speed doesn't depend on the frame rate. Mouse look
+    // above is driven by event deltas, so already doesn't:
+    let ms = camera.move_speed * boost * time.delta() as f32;
     for key in keys_pressed.iter() {
-        const MOVE_SPEED: f64 = 3.0;
-        let ms = (MOVE_SPEED * dt.0) as f32;
         match key {
             KeyCode::KeyW => {
                 camera.translate((0.0, 0.0, ms));
@@ -97,6 +183,41 @@ fn camera_system(
             _ => {}
         };
     }
+
+    // Not uploaded yet, so still set if anything above moved the camera:
+    if camera.data.changed != 0
+        && let Some(mut pathtracer) = pathtracer
+    {
+        pathtracer.reset_accumulation();
+    }
+}
+
+// Mouse buttons held and the last cursor position, for turning cursor moves into drags.
+#[derive(Default)]
+pub struct CursorDrag {
+    last_cursor: Option<Vec2>,
+    rotating: bool,
+    panning: bool,
+}
+
+// Where F5/F9 save and load the camera pose.
+const CAMERA_POSE_PATH: &str = "camera.json";
+
+// The subset of CameraData that describes a viewpoint, as stored on disk.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct CameraPose {
+    pub position: [f32; 3],
+    pub forward: [f32; 3],
+    pub up: [f32; 3],
+    pub focal_length: f32,
+}
+
+// Returns (forward, up, right) rebuilt from forward/up so they are orthonormal.
+fn orthonormal_basis(f: Vec3, u: Vec3) -> (Vec3, Vec3, Vec3) {
+    let f = f.normalize();
+    let r = u.cross(f).normalize();
+    let u = f.cross(r);
+    (f, u, r)
 }
 
 #[repr(C)]
@@ -108,10 +229,14 @@ pub struct CameraData {
     pub _pad1: u32,
     pub up: [f32; 3],
     pub _pad2: u32,
-    pub dims: [f32; 2],
+    pub dims: [f32; 2], // Sensor half extents, x follows the aspect, see set_aspect
     pub focal_length: f32,
     pub changed: u32,
-    pub _pad3: [u32; 1],
+    pub aperture: f32,       // Lens diameter, 0.0 -> pinhole
+    pub focus_distance: f32, // Distance to the focal plane along forward
+    // Seconds the shutter stays open over each sample, moving instances blur across it:
+    pub shutter_time: f32, // 0.0 -> no motion blur
+    pub _pad3: u32,
 }
 
 impl CameraData {
@@ -122,9 +247,70 @@ impl CameraData {
             up: [0.0, 1.0, 0.0],
             dims: [1.0, 1.0],
             focal_length: 1.0,
+            aperture: 0.0,
+            focus_distance: 1.0,
             ..Default::default()
         }
     }
+
+    // The ray through `screen_pos` on the film, from (0, 0) at the top left to (1, 1) at
+    // the bottom right. Mirrors the ray sample.slang generates, through the lens centre,
+    // which is aimed the same way whatever the aperture.
+    pub fn ray(&self, screen_pos: Vec2) -> Ray {
+        let forward = Vec3::from(self.forward);
+        let up = Vec3::from(self.up);
+        let right = forward.cross(up);
+        let top_left = forward * self.focal_length + up * self.dims[1] - right * self.dims[0];
+        let offset =
+            -2.0 * up * self.dims[1] * screen_pos.y + 2.0 * right * self.dims[0] * screen_pos.x;
+        Ray {
+            pos: Vec3::from(self.position),
+            dir: (top_left + offset).normalize(),
+        }
+    }
+
+    // World to camera space, with x along right (forward × up, as `ray` has it), y up and z
+    // forward. Taken as the inverse of those axes rather than their transpose, so it agrees
+    // with `ray` even if up has drifted off perpendicular.
+    pub fn view_matrix(&self) -> Mat4 {
+        let forward = Vec3::from(self.forward);
+        let up = Vec3::from(self.up);
+        Mat4::from_cols(
+            forward.cross(up).extend(0.0),
+            up.extend(0.0),
+            forward.extend(0.0),
+            Vec3::from(self.position).extend(1.0),
+        )
+        .inverse()
+    }
+
+    // Camera to wgpu clip space for an output `aspect` wide per unit high, with depth
+    // running 0 at CAMERA_NEAR to 1 at infinity. The height comes from the sensor and focal
+    // length, so NDC (-1, 1) is the top left of the film `ray` takes (0, 0) as.
+    pub fn projection_matrix(&self, aspect: f32) -> Mat4 {
+        let fov_y = 2.0 * (self.dims[1] / self.focal_length).atan();
+        Mat4::perspective_infinite_lh(fov_y, aspect, CAMERA_NEAR)
+    }
+
+    pub fn view_projection(&self, aspect: f32) -> Mat4 {
+        self.projection_matrix(aspect) * self.view_matrix()
+    }
+}
+
+// Near plane of projection_matrix. The pathtracer itself has none.
+pub const CAMERA_NEAR: f32 = 0.01;
+
+// How input moves the camera, see Camera::orbit and Camera::fly.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum CameraMode {
+    // Looks around from where it is and moves along its own axes.
+    #[default]
+    Fly,
+    // Keeps `target` ahead of it at `distance`, looking around turns about the target.
+    Orbit {
+        target: Vec3,
+        distance: f32,
+    },
 }
 
 #[derive(Component)]
@@ -134,8 +320,29 @@ pub struct Camera {
     pub bind_group: wgpu::BindGroup,
     pub bind_group_layout: wgpu::BindGroupLayout,
     pub changed: bool,
+    // Free-fly speed in units per second, kept within MIN/MAX_MOVE_SPEED:
+    pub move_speed: f32,
+    pub mode: CameraMode,
 }
 
+pub const DEFAULT_MOVE_SPEED: f32 = 3.0;
+pub const MIN_MOVE_SPEED: f32 = 0.05;
+pub const MAX_MOVE_SPEED: f32 = 500.0;
+// Kept strictly inside (0, 180), where the focal length stays finite and positive:
+const MIN_FOV_Y: f32 = 0.01;
+const MAX_FOV_Y: f32 = 179.99;
+// Each scroll notch or +/- press scales the speed by this:
+const MOVE_SPEED_STEP: f32 = 1.25;
+// Held shift multiplies the speed by this:
+const MOVE_BOOST: f32 = 5.0;
+// F6 switches to orbiting the point this far ahead:
+pub const DEFAULT_ORBIT_DISTANCE: f32 = 5.0;
+pub const MIN_ORBIT_DISTANCE: f32 = 0.01;
+pub const MAX_ORBIT_DISTANCE: f32 = 10000.0;
+// Radians per pixel of left drag, and distances per pixel of middle drag:
+const ORBIT_DRAG_SENSITIVITY: f32 = 0.005;
+const ORBIT_PAN_SENSITIVITY: f32 = 0.001;
+
 impl Camera {
     pub fn new(device: &wgpu::Device, label: Option<&str>) -> Self {
         let label = label.unwrap_or_default();
@@ -187,9 +394,48 @@ impl Camera {
             bind_group,
             bind_group_layout,
             changed: false,
+            move_speed: DEFAULT_MOVE_SPEED,
+            mode: CameraMode::Fly,
         }
     }
 
+    // Starts orbiting the point `distance` ahead, so the view doesn't jump.
+    pub fn orbit(&mut self, distance: f32) {
+        let distance = distance.clamp(MIN_ORBIT_DISTANCE, MAX_ORBIT_DISTANCE);
+        let f = Vec3::from(self.data.forward).normalize();
+        let target = Vec3::from(self.data.position) + f * distance;
+        self.mode = CameraMode::Orbit { target, distance };
+    }
+
+    // Back to flying from wherever the orbit left the camera.
+    pub fn fly(&mut self) {
+        self.mode = CameraMode::Fly;
+    }
+
+    // Moves the orbit camera along its view towards or away from the target.
+    pub fn set_orbit_distance(&mut self, distance: f32) {
+        let CameraMode::Orbit { target, .. } = self.mode else {
+            return;
+        };
+        let distance = distance.clamp(MIN_ORBIT_DISTANCE, MAX_ORBIT_DISTANCE);
+        self.mode = CameraMode::Orbit { target, distance };
+        self.face_target();
+    }
+
+    // Puts the orbit camera back at its distance from the target along the view.
+    fn face_target(&mut self) {
+        if let CameraMode::Orbit { target, distance } = self.mode {
+            let f = Vec3::from(self.data.forward).normalize();
+            self.data.position = (target - f * distance).to_array();
+            self.data.changed = 1;
+            self.changed = true;
+        }
+    }
+
+    pub fn set_move_speed(&mut self, speed: f32) {
+        self.move_speed = speed.clamp(MIN_MOVE_SPEED, MAX_MOVE_SPEED);
+    }
+
     pub fn update(&mut self, queue: &wgpu::Queue) {
         if self.changed {
             queue.write_buffer(&self.uniform, 0, bytemuck::bytes_of(&self.data));
@@ -198,6 +444,46 @@ impl Camera {
         }
     }
 
+    // Matches the sensor to a `width` by `height` pixel output. The vertical field of view
+    // is kept, so a wider output sees more to the sides rather than squashing the image.
+    pub fn set_aspect(&mut self, width: u32, height: u32) {
+        self.data.dims[0] = self.data.dims[1] * width.max(1) as f32 / height.max(1) as f32;
+
+        self.data.changed = 1;
+        self.changed = true;
+    }
+
+    // Sets the vertical field of view by moving the focal length against the sensor's
+    // height. set_aspect only changes the width, so this holds across resizes.
+    pub fn set_fov_y(&mut self, degrees: f32) {
+        let half = degrees.clamp(MIN_FOV_Y, MAX_FOV_Y).to_radians() * 0.5;
+        self.data.focal_length = self.data.dims[1] / half.tan();
+
+        self.data.changed = 1;
+        self.changed = true;
+    }
+
+    pub fn fov_y_degrees(&self) -> f32 {
+        2.0 * (self.data.dims[1] / self.data.focal_length)
+            .atan()
+            .to_degrees()
+    }
+
+    pub fn set_lens(&mut self, aperture: f32, focus_distance: f32) {
+        self.data.aperture = aperture.max(0.0);
+        self.data.focus_distance = focus_distance;
+
+        self.data.changed = 1;
+        self.changed = true;
+    }
+
+    pub fn set_shutter_time(&mut self, shutter_time: f32) {
+        self.data.shutter_time = shutter_time.max(0.0);
+
+        self.data.changed = 1;
+        self.changed = true;
+    }
+
     pub fn translate(&mut self, dir: impl Into<glam::Vec3>) {
         let dir = dir.into();
         let f = glam::Vec3::from(self.data.forward);
@@ -205,9 +491,12 @@ impl Camera {
         let r = u.cross(f).normalize();
         let mut pos = glam::Vec3::from_slice(&self.data.position);
 
-        pos += dir.x * r;
-        pos += dir.y * u;
-        pos += dir.z * f;
+        let offset = dir.x * r + dir.y * u + dir.z * f;
+        pos += offset;
+        // Orbiting, the target is carried along, so this pans:
+        if let CameraMode::Orbit { target, .. } = &mut self.mode {
+            *target += offset;
+        }
 
         self.data.position = pos.to_array();
         self.data.changed = 1;
@@ -226,7 +515,7 @@ impl Camera {
 
         // To rotate about r for up/down, we must rebase
         // so that r is x axis.
-        let r = u.cross(f);
+        let (f, u, r) = orthonormal_basis(f, u);
         let m = glam::Mat3::from_axis_angle(r, delta.y);
         let f = m * f;
         let u = m * u;
@@ -236,5 +525,88 @@ impl Camera {
 
         self.data.changed = 1;
         self.changed = true;
+        // Orbiting, the eye swings round the target instead of turning in place:
+        self.face_target();
+    }
+
+    pub fn save_pose(&self, path: &Path) -> anyhow::Result<()> {
+        let pose = CameraPose {
+            position: self.data.position,
+            forward: self.data.forward,
+            up: self.data.up,
+            focal_length: self.data.focal_length,
+        };
+        std::fs::write(path, serde_json::to_string_pretty(&pose)?)?;
+        info!("Saved camera pose to {}", path.display());
+        Ok(())
+    }
+
+    pub fn load_pose(&mut self, path: &Path) -> anyhow::Result<()> {
+        let pose: CameraPose = serde_json::from_str(&std::fs::read_to_string(path)?)?;
+
+        // The file may have been hand edited, so don't trust the basis:
+        let (f, u, _) = orthonormal_basis(pose.forward.into(), pose.up.into());
+        if !f.is_finite() || !u.is_finite() {
+            anyhow::bail!(
+                "Camera pose in {} has a degenerate forward/up",
+                path.display()
+            );
+        }
+
+        self.data.position = pose.position;
+        self.data.forward = f.into();
+        self.data.up = u.into();
+        self.data.focal_length = pose.focal_length;
+        // The pose wins over the orbit, which picks up ahead of it:
+        if let CameraMode::Orbit { distance, .. } = self.mode {
+            self.orbit(distance);
+        }
+
+        self.data.changed = 1;
+        self.changed = true;
+        info!("Loaded camera pose from {}", path.display());
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use glam::{Vec3Swizzles, Vec4Swizzles};
+
+    use super::*;
+
+    #[test]
+    fn projection_matches_ray_ndc() {
+        let forward = Vec3::new(0.3, -0.2, 1.0).normalize();
+        let right = forward.cross(Vec3::Y).normalize();
+        let data = CameraData {
+            position: [1.0, 2.0, -3.0],
+            forward: forward.into(),
+            up: right.cross(forward).into(),
+            // A 16:10 film:
+            dims: [0.8, 0.5],
+            focal_length: 1.2,
+            ..CameraData::new()
+        };
+        let view_projection = data.view_projection(data.dims[0] / data.dims[1]);
+
+        for screen_pos in [
+            Vec2::new(0.5, 0.5),
+            Vec2::new(0.1, 0.2),
+            Vec2::new(0.9, 0.35),
+            Vec2::new(0.25, 0.95),
+        ] {
+            let ray = data.ray(screen_pos);
+            for distance in [0.5, 4.0, 100.0] {
+                let clip = view_projection * (ray.pos + ray.dir * distance).extend(1.0);
+                let ndc = clip.xyz() / clip.w;
+                let expected = Vec2::new(screen_pos.x * 2.0 - 1.0, 1.0 - screen_pos.y * 2.0);
+                assert!(
+                    (ndc.xy() - expected).abs().max_element() < 1e-4,
+                    "{screen_pos} at {distance}: {ndc}"
+                );
+                assert!((0.0..1.0).contains(&ndc.z), "Depth {}", ndc.z);
+            }
+        }
     }
 }
@@ -0,0 +1,305 @@
+use bevy_ecs::prelude::*;
+use glam::{Mat3, Quat, Vec3};
+use tracing::info;
+use winit::keyboard::KeyCode;
+
+use crate::{
+    app::BevyApp,
+    camera::{self, Camera, CameraData},
+    delta_time::Time,
+    pathtracer::Pathtracer,
+    schedule,
+    winnit::WinitWindowEvent,
+};
+
+pub fn initialize(app: &mut BevyApp) {
+    app.world.init_resource::<CameraPath>();
+    app.world.get_resource_or_init::<Schedules>().add_systems(
+        schedule::Update,
+        // Playback overrides whatever the input did this frame:
+        camera_path_system
+            .after(camera::camera_system)
+            .before(camera::camera_buffer_system),
+    );
+}
+
+// A pose on the path at `time` seconds from its start.
+#[derive(Clone, Copy, Debug)]
+pub struct CameraKeyframe {
+    pub time: f32,
+    pub pose: CameraData,
+}
+
+// Keyframed camera poses for fly-throughs. Positions follow a Catmull-Rom spline through
+// the keyframes and orientations are slerped between them. F7 records the camera as a
+// keyframe, F8 starts and stops playback.
+#[derive(Resource, Default)]
+pub struct CameraPath {
+    // In time order, as recorded:
+    pub keyframes: Vec<CameraKeyframe>,
+    // Plays on from the last keyframe back round to the first:
+    pub looping: bool,
+    // Time::elapsed when recording and playback started:
+    recording_start: Option<f64>,
+    playback_start: Option<f64>,
+}
+
+impl CameraPath {
+    // Appends the camera's pose, timed by how long after the first keyframe it's recorded.
+    pub fn record_keyframe(&mut self, camera: &Camera, elapsed: f64) {
+        let start = *self.recording_start.get_or_insert(elapsed);
+        // Keyframes at the same time would leave a segment nothing can land in:
+        let min_time = self
+            .keyframes
+            .last()
+            .map_or(0.0, |k| k.time + MIN_KEYFRAME_GAP);
+        let time = ((elapsed - start) as f32).max(min_time);
+        self.keyframes.push(CameraKeyframe {
+            time,
+            pose: camera.data,
+        });
+    }
+
+    pub fn clear(&mut self) {
+        self.keyframes.clear();
+        self.recording_start = None;
+        self.playback_start = None;
+    }
+
+    // Seconds from the first keyframe until playback ends, or until it's back at the
+    // first keyframe when looping.
+    pub fn duration(&self) -> f32 {
+        match (self.keyframes.first(), self.keyframes.last()) {
+            (Some(first), Some(last)) => last.time - first.time + self.closing_gap(),
+            _ => 0.0,
+        }
+    }
+
+    // Puts the camera where the path is `t` seconds in, held at the ends unless looping.
+    // The lens and sensor are left alone.
+    pub fn play(&self, camera: &mut Camera, t: f32) {
+        let Some((position, rotation)) = self.sample(t) else {
+            return;
+        };
+        camera.data.position = position.to_array();
+        camera.data.forward = (rotation * Vec3::Z).to_array();
+        camera.data.up = (rotation * Vec3::Y).to_array();
+
+        camera.data.changed = 1;
+        camera.changed = true;
+    }
+
+    // The closing segment from the last keyframe back to the first takes the mean keyframe
+    // spacing, so a loop doesn't hurry or linger at the seam.
+    fn closing_gap(&self) -> f32 {
+        let n = self.keyframes.len();
+        if !self.looping || n < 2 {
+            return 0.0;
+        }
+        (self.keyframes[n - 1].time - self.keyframes[0].time) / (n - 1) as f32
+    }
+
+    fn sample(&self, t: f32) -> Option<(Vec3, Quat)> {
+        let keys = &self.keyframes;
+        let n = keys.len();
+        let first = keys.first()?;
+        if n == 1 {
+            return Some(key_pose(first));
+        }
+
+        let duration = self.duration();
+        if duration <= 0.0 {
+            return Some(key_pose(first));
+        }
+        let t = if self.looping {
+            t.rem_euclid(duration)
+        } else {
+            t.clamp(0.0, duration)
+        };
+        let t = first.time + t;
+
+        // The segment starting at key i, the last one wraps round when looping:
+        let i = keys
+            .iter()
+            .rposition(|k| k.time <= t)
+            .unwrap_or(0)
+            .min(if self.looping { n - 1 } else { n - 2 });
+        let next = (i + 1) % n;
+        let end = if next == 0 {
+            keys[i].time + self.closing_gap()
+        } else {
+            keys[next].time
+        };
+        let u = ((t - keys[i].time) / (end - keys[i].time)).clamp(0.0, 1.0);
+
+        // Neighbours past the ends are the ends themselves unless looping:
+        let at = |j: isize| {
+            let j = if self.looping {
+                j.rem_euclid(n as isize)
+            } else {
+                j.clamp(0, n as isize - 1)
+            };
+            key_pose(&keys[j as usize])
+        };
+        let i = i as isize;
+        let (p0, _) = at(i - 1);
+        let (p1, q1) = at(i);
+        let (p2, q2) = at(i + 1);
+        let (p3, _) = at(i + 2);
+
+        // Rotating the unit axes keeps forward and up orthonormal at every sample:
+        Some((catmull_rom(p0, p1, p2, p3, u), q1.slerp(q2, u)))
+    }
+}
+
+// Keyframes recorded in quick succession are still kept this many seconds apart.
+const MIN_KEYFRAME_GAP: f32 = 0.01;
+
+// The keyframe's position and the rotation taking +Z to its forward and +Y to its up.
+fn key_pose(key: &CameraKeyframe) -> (Vec3, Quat) {
+    let f = Vec3::from(key.pose.forward).normalize();
+    let r = Vec3::from(key.pose.up).cross(f).normalize();
+    let u = f.cross(r);
+    (
+        Vec3::from(key.pose.position),
+        Quat::from_mat3(&Mat3::from_cols(r, u, f)).normalize(),
+    )
+}
+
+// Uniform Catmull-Rom between p1 and p2.
+fn catmull_rom(p0: Vec3, p1: Vec3, p2: Vec3, p3: Vec3, u: f32) -> Vec3 {
+    let u2 = u * u;
+    let u3 = u2 * u;
+    0.5 * ((2.0 * p1)
+        + (p2 - p0) * u
+        + (2.0 * p0 - 5.0 * p1 + 4.0 * p2 - p3) * u2
+        + (3.0 * p1 - p0 - 3.0 * p2 + p3) * u3)
+}
+
+fn camera_path_system(
+    mut we_reader: MessageReader<WinitWindowEvent>,
+    mut path: ResMut<CameraPath>,
+    mut cameras: Query<(&mut Camera, Option<&mut Pathtracer>)>,
+    time: Res<Time>,
+) {
+    // Paths are recorded from and played on the primary camera:
+    let Some((mut camera, pathtracer)) = cameras
+        .iter_mut()
+        .find(|(_, pt)| pt.as_ref().is_none_or(|pt| pt.is_primary))
+    else {
+        return;
+    };
+
+    for WinitWindowEvent(e) in we_reader.read() {
+        let winit::event::WindowEvent::KeyboardInput { event, .. } = e else {
+            continue;
+        };
+        if !event.state.is_pressed() || event.repeat {
+            continue;
+        }
+        match event.physical_key {
+            winit::keyboard::PhysicalKey::Code(KeyCode::F7) => {
+                path.playback_start = None;
+                path.record_keyframe(&camera, time.elapsed());
+                info!("Recorded camera keyframe {}", path.keyframes.len());
+            }
+            winit::keyboard::PhysicalKey::Code(KeyCode::F8) => {
+                path.playback_start = match path.playback_start {
+                    None if !path.keyframes.is_empty() => Some(time.elapsed()),
+                    _ => None,
+                };
+            }
+            _ => {}
+        }
+    }
+
+    let Some(start) = path.playback_start else {
+        return;
+    };
+    let t = (time.elapsed() - start) as f32;
+    path.play(&mut camera, t);
+    if !path.looping && t >= path.duration() {
+        path.playback_start = None;
+    }
+    if let Some(mut pathtracer) = pathtracer {
+        pathtracer.reset_accumulation();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Four poses a second apart, turning and climbing as they go round:
+    fn spiral(looping: bool) -> CameraPath {
+        let keyframes = (0..4)
+            .map(|i| {
+                let angle = i as f32 * std::f32::consts::FRAC_PI_2;
+                let forward = Quat::from_rotation_y(angle) * Vec3::new(0.0, -0.2, 1.0);
+                CameraKeyframe {
+                    time: i as f32,
+                    pose: CameraData {
+                        position: [angle.sin() * 4.0, i as f32, angle.cos() * 4.0],
+                        forward: forward.to_array(),
+                        up: [0.0, 1.0, 0.0],
+                        ..CameraData::new()
+                    },
+                }
+            })
+            .collect();
+        CameraPath {
+            keyframes,
+            looping,
+            ..Default::default()
+        }
+    }
+
+    fn same_pose((p0, q0): (Vec3, Quat), (p1, q1): (Vec3, Quat)) -> bool {
+        // q and -q are the same rotation:
+        p0.abs_diff_eq(p1, 1e-4) && q0.dot(q1).abs() > 1.0 - 1e-5
+    }
+
+    #[test]
+    fn samples_pass_through_keyframes() {
+        for looping in [false, true] {
+            let path = spiral(looping);
+            for key in &path.keyframes {
+                let sample = path.sample(key.time).unwrap();
+                assert!(same_pose(sample, key_pose(key)), "{looping} {}", key.time);
+            }
+        }
+    }
+
+    #[test]
+    fn frames_stay_orthonormal() {
+        let path = spiral(true);
+        for step in 0..=100 {
+            // The axes play gives the camera:
+            let (_, rotation) = path.sample(step as f32 * path.duration() / 100.0).unwrap();
+            let (forward, up) = (rotation * Vec3::Z, rotation * Vec3::Y);
+            assert!(forward.is_normalized() && up.is_normalized(), "{step}");
+            assert!(forward.dot(up).abs() < 1e-5, "{step}");
+        }
+    }
+
+    #[test]
+    fn loops_close_on_the_first_keyframe() {
+        let path = spiral(true);
+        // The closing segment takes the mean spacing, a second:
+        assert_eq!(path.duration(), 4.0);
+        let first = key_pose(&path.keyframes[0]);
+        assert!(same_pose(path.sample(4.0).unwrap(), first));
+        assert!(same_pose(path.sample(-4.0).unwrap(), first));
+
+        // Continuous across the seam:
+        let (before, _) = path.sample(4.0 - 1e-3).unwrap();
+        let (after, _) = path.sample(1e-3).unwrap();
+        assert!(before.distance(after) < 0.05, "{before} {after}");
+
+        // Held at the ends when it doesn't loop:
+        let path = spiral(false);
+        assert_eq!(path.duration(), 3.0);
+        let last = key_pose(&path.keyframes[3]);
+        assert!(same_pose(path.sample(10.0).unwrap(), last));
+    }
+}
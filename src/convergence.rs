@@ -0,0 +1,236 @@
+use bevy_ecs::prelude::*;
+use tracing::{debug, info};
+
+use crate::{
+    app::BevyApp,
+    delta_time::Time,
+    pathtracer::Pathtracer,
+    pathtracer_manager,
+    pathtracer_state::{PathtracerState, SAMPLE_CONVERGED, SampleSource},
+    render_resources::{RenderDevice, RenderQueue},
+    schedule,
+};
+
+pub fn initialize(app: &mut BevyApp) {
+    app.world.init_resource::<ConvergenceStats>();
+    app.world.init_resource::<Messages<CompletionEvent>>();
+    app.world.get_resource_or_init::<Schedules>().add_systems(
+        schedule::Update,
+        (
+            convergence_system.after(pathtracer_manager::pathtracer_phase_execute),
+            completion_log_system.after(convergence_system),
+        ),
+    );
+}
+
+// Seconds between readbacks of the sample counts. Each one copies every sample source,
+// so they're kept well apart.
+const CONVERGENCE_POLL_INTERVAL: f64 = 1.0;
+
+// Samples per pixel the primary pathtracer has accumulated, as of the last readback.
+// Zeroed whenever accumulation restarts. Shown in the window title, see window_title.
+#[derive(Resource, Clone, Copy, Debug, Default, PartialEq)]
+pub struct ConvergenceStats {
+    pub min_spp: u32,
+    pub max_spp: u32,
+    pub mean_spp: f32,
+    // Non-finite samples dropped over every pixel, see accumulateSample in sample.slang:
+    pub rejected: u64,
+}
+
+impl ConvergenceStats {
+    fn from_sources(sources: &[SampleSource]) -> Self {
+        if sources.is_empty() {
+            return Self::default();
+        }
+        // The cleanup pass starts each count at 1, so that's no samples yet:
+        let spp = sources.iter().map(|s| s.samples.saturating_sub(1));
+        Self {
+            min_spp: spp.clone().min().unwrap_or_default(),
+            max_spp: spp.clone().max().unwrap_or_default(),
+            mean_spp: (spp.map(u64::from).sum::<u64>() as f64 / sources.len() as f64) as f32,
+            rejected: sources.iter().map(|s| u64::from(s.rejected)).sum(),
+        }
+    }
+}
+
+// Written once per accumulation, at the first readback where every pixel of the primary
+// pathtracer is done: it has its target_spp, or with adaptive sampling on it has converged
+// to the target error or hit max_samples. Neither set, it never completes.
+#[derive(Message, Clone, Copy, Debug)]
+pub struct CompletionEvent {
+    pub pathtracer: Entity,
+    pub stats: ConvergenceStats,
+}
+
+// Whether every source is done, see CompletionEvent.
+fn is_complete(pt: &Pathtracer, sources: &[SampleSource]) -> bool {
+    let adaptive = pt.target_error > 0.0;
+    if !adaptive && pt.target_spp.is_none() {
+        return false;
+    }
+    sources.iter().all(|s| {
+        (adaptive && s.flags & SAMPLE_CONVERGED != 0)
+            || pt
+                .target_spp
+                .is_some_and(|spp| s.samples.saturating_sub(1) >= spp)
+    })
+}
+
+// A copy of the sample sources on its way back from the GPU.
+struct PendingReadback {
+    staging: wgpu::Buffer,
+    rx: crossbeam::channel::Receiver<Result<(), wgpu::BufferAsyncError>>,
+}
+
+#[derive(Default)]
+struct ConvergenceReadback {
+    pending: Option<PendingReadback>,
+    last_request: Option<f64>,
+    // Since accumulation last restarted:
+    completed: bool,
+}
+
+// Polls the pending readback without waiting on the GPU and asks for the next one once
+// the interval is up. The copy follows this frame's dispatch on the same queue.
+fn convergence_system(
+    mut stats: ResMut<ConvergenceStats>,
+    mut readback: Local<ConvergenceReadback>,
+    mut completions: MessageWriter<CompletionEvent>,
+    pathtracers: Query<(Entity, Ref<Pathtracer>, &PathtracerState)>,
+    device: Res<RenderDevice>,
+    queue: Res<RenderQueue>,
+    time: Res<Time>,
+) {
+    let Some((entity, pt, pts)) = pathtracers.iter().find(|(_, pt, _)| pt.is_primary) else {
+        return;
+    };
+    let readback = &mut *readback;
+    // A new target may not be met yet, frame counting doesn't show up as a change:
+    if pt.is_changed() {
+        readback.completed = false;
+    }
+
+    // The submission that cleared the accumulation has just gone out, anything read back
+    // from before it is stale:
+    if (1..=pt.iterations_per_frame.max(1)).contains(&pt.frame_index) {
+        readback.pending = None;
+        readback.last_request = None;
+        readback.completed = false;
+        *stats = ConvergenceStats::default();
+    }
+
+    if let Some(pending) = &readback.pending {
+        device.0.poll(wgpu::PollType::Poll).ok();
+        match pending.rx.try_recv() {
+            Ok(Ok(())) => {
+                let complete = {
+                    let mapped = pending.staging.slice(..).get_mapped_range();
+                    let sources = bytemuck::cast_slice(&mapped);
+                    let rejected = stats.rejected;
+                    *stats = ConvergenceStats::from_sources(sources);
+                    if stats.rejected > rejected {
+                        debug!(
+                            "Rejected {} non-finite samples since accumulation restarted",
+                            stats.rejected
+                        );
+                    }
+                    is_complete(&pt, sources)
+                };
+                if complete && !readback.completed {
+                    readback.completed = true;
+                    completions.write(CompletionEvent {
+                        pathtracer: entity,
+                        stats: *stats,
+                    });
+                }
+                pending.staging.unmap();
+                readback.pending = None;
+            }
+            Err(crossbeam::channel::TryRecvError::Empty) => {}
+            Ok(Err(_)) | Err(crossbeam::channel::TryRecvError::Disconnected) => {
+                readback.pending = None;
+            }
+        }
+    }
+
+    let due = readback
+        .last_request
+        .is_none_or(|t| time.elapsed() - t >= CONVERGENCE_POLL_INTERVAL);
+    if readback.pending.is_none() && due {
+        readback.last_request = Some(time.elapsed());
+        readback.pending = Some(request_readback(&device.0, &queue.0, pts));
+    }
+}
+
+fn request_readback(
+    device: &wgpu::Device,
+    queue: &wgpu::Queue,
+    pts: &PathtracerState,
+) -> PendingReadback {
+    let size = pts.sampling_data_buffer.size();
+    let staging = device.create_buffer(&wgpu::BufferDescriptor {
+        label: Some("Convergence Staging Buffer"),
+        size,
+        usage: wgpu::BufferUsages::MAP_READ | wgpu::BufferUsages::COPY_DST,
+        mapped_at_creation: false,
+    });
+
+    let mut encoder = device.create_command_encoder(&wgpu::CommandEncoderDescriptor {
+        label: Some("Convergence Encoder"),
+    });
+    encoder.copy_buffer_to_buffer(&pts.sampling_data_buffer, 0, &staging, 0, size);
+    queue.submit([encoder.finish()]);
+
+    let (tx, rx) = crossbeam::channel::bounded(1);
+    staging
+        .slice(..)
+        .map_async(wgpu::MapMode::Read, move |result| {
+            tx.send(result).ok();
+        });
+    PendingReadback { staging, rx }
+}
+
+fn completion_log_system(mut reader: MessageReader<CompletionEvent>) {
+    for e in reader.read() {
+        info!(
+            "Pathtracer {} complete, {} to {} spp, {:.1} on average",
+            e.pathtracer, e.stats.min_spp, e.stats.max_spp, e.stats.mean_spp
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A source as the cleanup pass leaves it and the sample pass counts into it:
+    fn source(samples: u32, rejected: u32) -> SampleSource {
+        SampleSource {
+            samples: samples + 1,
+            rejected,
+            ..bytemuck::Zeroable::zeroed()
+        }
+    }
+
+    #[test]
+    fn rejected_samples_are_counted_apart() {
+        // Three pixels of 8 samples each, two of which had NaN samples dropped:
+        let sources = [source(8, 0), source(8, 3), source(8, 1)];
+        let stats = ConvergenceStats::from_sources(&sources);
+        assert_eq!(
+            stats,
+            ConvergenceStats {
+                min_spp: 8,
+                max_spp: 8,
+                mean_spp: 8.0,
+                rejected: 4,
+            }
+        );
+
+        // A pixel whose every sample was dropped has none, not its rejections:
+        let stats = ConvergenceStats::from_sources(&[source(0, 5), source(4, 0)]);
+        assert_eq!((stats.min_spp, stats.max_spp, stats.rejected), (0, 4, 5));
+        assert_eq!(stats.mean_spp, 2.0);
+    }
+}
@@ -2,5 +2,44 @@ use std::time::Instant;
 
 use bevy_ecs::resource::Resource;
 
+// Longest frame time handed to systems, so a hitch like dragging the
+// window doesn't send the camera flying.
+const MAX_DELTA: f64 = 0.25;
+
+// Frame clock, ticked once before each Update. Never ticked headless, so
+// anything scaled by the delta stays still there.
 #[derive(Resource)]
-pub struct DeltaTime(pub f64);
+pub struct Time {
+    last_tick: Instant,
+    delta: f64,
+    elapsed: f64,
+}
+
+impl Default for Time {
+    fn default() -> Self {
+        Self {
+            last_tick: Instant::now(),
+            delta: 0.0,
+            elapsed: 0.0,
+        }
+    }
+}
+
+impl Time {
+    pub fn tick(&mut self) {
+        let now = Instant::now();
+        self.delta = (now - self.last_tick).as_secs_f64().min(MAX_DELTA);
+        self.elapsed += self.delta;
+        self.last_tick = now;
+    }
+
+    // Seconds since the previous tick.
+    pub fn delta(&self) -> f64 {
+        self.delta
+    }
+
+    // Seconds of ticks since the clock started.
+    pub fn elapsed(&self) -> f64 {
+        self.elapsed
+    }
+}
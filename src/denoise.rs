@@ -0,0 +1,120 @@
+use anyhow::Context;
+use bevy_ecs::prelude::*;
+use itertools::Itertools;
+use tracing::{error, info};
+use winit::{event::WindowEvent, keyboard::KeyCode};
+
+use crate::{
+    app::BevyApp,
+    pathtracer::{AovKind, Pathtracer, PathtracerOutput},
+    pathtracer_manager::pathtracer_phase_execute,
+    render::render_system,
+    render_resources::{RenderDevice, RenderQueue},
+    schedule,
+    winnit::WinitWindowEvent,
+};
+
+pub fn initialize(app: &mut BevyApp) {
+    app.world.get_resource_or_init::<Schedules>().add_systems(
+        schedule::Update,
+        (
+            denoise_system.after(pathtracer_phase_execute),
+            denoised_upload_system
+                .after(denoise_system)
+                .before(render_system),
+        ),
+    );
+}
+
+// The primary's output as OIDN left it, shown in place of the accumulated radiance until
+// accumulation restarts or the output is resized.
+#[derive(Component)]
+struct Denoised {
+    pixels: Vec<[f32; 4]>,
+    dims: (u32, u32),
+    // The pathtracer's frame_index when it was denoised:
+    frame_index: u32,
+}
+
+// N denoises the primary pathtracer's output once, from its mean radiance and first hit
+// albedo and normal AOVs. Only on demand, it blocks on the readback and the filter.
+fn denoise_system(
+    mut commands: Commands,
+    mut we_reader: MessageReader<WinitWindowEvent>,
+    query: Query<(Entity, &Pathtracer, &PathtracerOutput)>,
+    device: Res<RenderDevice>,
+    queue: Res<RenderQueue>,
+) {
+    let pressed = we_reader.read().any(|WinitWindowEvent(e)| match e {
+        WindowEvent::KeyboardInput { event, .. } => {
+            event.state.is_pressed() && !event.repeat && event.physical_key == KeyCode::KeyN
+        }
+        _ => false,
+    });
+    if !pressed {
+        return;
+    }
+
+    for (entity, pt, pto) in query.iter().filter(|(_, pt, _)| pt.is_primary) {
+        let denoised = pto.read_hdr(&device.0, &queue.0).and_then(|colour| {
+            let albedo = pto.read_aov(&device.0, &queue.0, AovKind::Albedo)?;
+            let normal = pto.read_aov(&device.0, &queue.0, AovKind::Normal)?;
+            denoise(pto.dims, &colour, &albedo, &normal)
+        });
+        match denoised {
+            Ok(pixels) => {
+                info!("Denoised {} frames", pt.frame_index);
+                commands.entity(entity).insert(Denoised {
+                    pixels,
+                    dims: pto.dims,
+                    frame_index: pt.frame_index,
+                });
+            }
+            Err(e) => error!("Failed to denoise: {e:#}"),
+        }
+    }
+}
+
+// Sampling carries on writing the output, so the denoised pixels are written back over
+// it every update before it's presented.
+fn denoised_upload_system(
+    mut commands: Commands,
+    query: Query<(Entity, &Pathtracer, &PathtracerOutput, &Denoised)>,
+    queue: Res<RenderQueue>,
+) {
+    for (entity, pt, pto, denoised) in query {
+        if pt.frame_index < denoised.frame_index || pto.dims != denoised.dims {
+            commands.entity(entity).remove::<Denoised>();
+            continue;
+        }
+        queue.0.write_buffer(
+            &pto.source_buffer,
+            0,
+            bytemuck::cast_slice(&denoised.pixels),
+        );
+    }
+}
+
+// Runs OIDN's ray tracing filter over HDR rgba `colour` guided by the albedo and normal,
+// all `dims` in size. Alpha is ignored and comes back as 1.0.
+pub fn denoise(
+    dims: (u32, u32),
+    colour: &[[f32; 4]],
+    albedo: &[[f32; 4]],
+    normal: &[[f32; 4]],
+) -> anyhow::Result<Vec<[f32; 4]>> {
+    let rgb = |pixels: &[[f32; 4]]| pixels.iter().flat_map(|p| [p[0], p[1], p[2]]).collect_vec();
+    let device = oidn::Device::new().context("Failed to create an OIDN device")?;
+    let mut output = vec![0.0; colour.len() * 3];
+    oidn::RayTracing::try_new(&device)?
+        .hdr(true)
+        .srgb(false)
+        .image_dimensions(dims.0 as usize, dims.1 as usize)
+        .albedo_normal(&rgb(albedo), &rgb(normal))
+        .filter(&rgb(colour), &mut output)
+        .context("OIDN failed to filter the output")?;
+    Ok(output
+        .chunks_exact(3)
+        .map(|c| [c[0], c[1], c[2], 1.0])
+        .collect())
+}
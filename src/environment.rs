@@ -0,0 +1,225 @@
+use std::path::{Path, PathBuf};
+
+use anyhow::Context;
+use bevy_ecs::prelude::*;
+use glam::Vec3;
+use tracing::info;
+
+use crate::{
+    app::BevyApp,
+    render_resources::{self, RenderDevice, RenderQueue},
+    schedule,
+};
+
+pub fn initialize(app: &mut BevyApp) {
+    app.world.get_resource_or_init::<Schedules>().add_systems(
+        schedule::PreStartup,
+        setup_environment.after(render_resources::setup_renderer),
+    );
+}
+
+// What rays that escape the scene see, treated as an infinitely distant emitter. Either
+// a plain colour, a sky gradient or an equirectangular radiance map.
+#[derive(Resource)]
+pub struct Environment {
+    pub texture: wgpu::Texture,
+    pub view: wgpu::TextureView,
+    // Scales whichever background is in use:
+    pub intensity: f32,
+    rotation: f32,
+    background: Background,
+}
+
+// Where the radiance of an escaping ray comes from.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Background {
+    Solid(Vec3),
+    // Blended by the ray's height, from straight down to straight up:
+    Gradient { top: Vec3, bottom: Vec3 },
+    // The radiance map:
+    Environment,
+}
+
+// The old flat sky, until a scene picks something else:
+pub const DEFAULT_BACKGROUND: Vec3 = Vec3::splat(10.0);
+
+// An environment as a scene describes it, see SceneBuilder::environment.
+#[derive(Clone, Debug, PartialEq)]
+pub struct EnvironmentDescriptor {
+    // An equirectangular .hdr or .exr, None for a black map:
+    pub map: Option<PathBuf>,
+    pub intensity: f32,
+    // About the Y axis, in radians:
+    pub rotation: f32,
+    pub background: Background,
+}
+
+#[repr(C)]
+#[derive(Copy, Clone, Debug, bytemuck::Pod, bytemuck::Zeroable, Default)]
+pub struct EnvironmentData {
+    pub intensity: f32,
+    pub rotation: f32,
+    pub background: u32, // See BACKGROUND_* in common.slang
+    pub _pad: u32,
+    pub top: [f32; 4], // Also the solid colour
+    pub bottom: [f32; 4],
+}
+
+fn setup_environment(mut commands: Commands, device: Res<RenderDevice>, queue: Res<RenderQueue>) {
+    commands.insert_resource(Environment::solid(&device.0, &queue.0, DEFAULT_BACKGROUND));
+}
+
+impl Environment {
+    // A plain colour background. The map bound alongside it is a black placeholder.
+    pub fn solid(device: &wgpu::Device, queue: &wgpu::Queue, radiance: Vec3) -> Self {
+        let mut environment = Self::constant(device, queue, Vec3::ZERO);
+        environment.background = Background::Solid(radiance);
+        environment
+    }
+
+    // A uniform environment map of the given radiance.
+    pub fn constant(device: &wgpu::Device, queue: &wgpu::Queue, radiance: Vec3) -> Self {
+        Self::from_texels(device, queue, (1, 1), &radiance.extend(1.0).to_array(), 1.0)
+    }
+
+    // Loads an equirectangular .hdr or .exr, +Y is the top row.
+    pub fn load(
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        path: &Path,
+        intensity: f32,
+    ) -> anyhow::Result<Self> {
+        let image = image::open(path)
+            .with_context(|| format!("Failed to load environment {}", path.display()))?
+            .into_rgba32f();
+
+        let max = device.limits().max_texture_dimension_2d;
+        if image.width() > max || image.height() > max {
+            anyhow::bail!(
+                "Environment {} is {}x{}, larger than the device limit of {max}",
+                path.display(),
+                image.width(),
+                image.height()
+            );
+        }
+
+        info!(
+            "Loaded environment {} ({}x{})",
+            path.display(),
+            image.width(),
+            image.height()
+        );
+        Ok(Self::from_texels(
+            device,
+            queue,
+            image.dimensions(),
+            image.as_raw(),
+            intensity,
+        ))
+    }
+
+    // Loads the descriptor's map, if it has one.
+    pub fn from_descriptor(
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        descriptor: &EnvironmentDescriptor,
+    ) -> anyhow::Result<Self> {
+        let mut environment = match &descriptor.map {
+            Some(path) => Self::load(device, queue, path, descriptor.intensity)?,
+            None => Self::constant(device, queue, Vec3::ZERO),
+        };
+        environment.apply(descriptor);
+        Ok(environment)
+    }
+
+    // Everything of the descriptor but its map, which only changes by loading another.
+    pub fn apply(&mut self, descriptor: &EnvironmentDescriptor) {
+        self.intensity = descriptor.intensity;
+        self.set_rotation(descriptor.rotation);
+        self.set_background(descriptor.background);
+    }
+
+    fn from_texels(
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        dims: (u32, u32),
+        texels: &[f32],
+        intensity: f32,
+    ) -> Self {
+        let size = wgpu::Extent3d {
+            width: dims.0,
+            height: dims.1,
+            depth_or_array_layers: 1,
+        };
+        let texture = device.create_texture(&wgpu::TextureDescriptor {
+            label: Some("Environment Texture"),
+            size,
+            mip_level_count: 1,
+            sample_count: 1,
+            dimension: wgpu::TextureDimension::D2,
+            format: wgpu::TextureFormat::Rgba32Float,
+            usage: wgpu::TextureUsages::TEXTURE_BINDING | wgpu::TextureUsages::COPY_DST,
+            view_formats: &[],
+        });
+
+        queue.write_texture(
+            wgpu::TexelCopyTextureInfo {
+                texture: &texture,
+                mip_level: 0,
+                origin: wgpu::Origin3d::ZERO,
+                aspect: wgpu::TextureAspect::All,
+            },
+            bytemuck::cast_slice(texels),
+            wgpu::TexelCopyBufferLayout {
+                offset: 0,
+                bytes_per_row: Some(4 * std::mem::size_of::<f32>() as u32 * dims.0),
+                rows_per_image: Some(dims.1),
+            },
+            size,
+        );
+
+        let view = texture.create_view(&wgpu::TextureViewDescriptor::default());
+
+        Self {
+            texture,
+            view,
+            intensity,
+            rotation: 0.0,
+            background: Background::Environment,
+        }
+    }
+
+    // Switching back to Background::Environment shows whatever map this was made with.
+    pub fn set_background(&mut self, background: Background) {
+        self.background = background;
+    }
+
+    pub fn background(&self) -> Background {
+        self.background
+    }
+
+    // Rotates the map about the Y axis, in radians.
+    pub fn set_rotation(&mut self, rotation: f32) {
+        self.rotation = rotation.rem_euclid(std::f32::consts::TAU);
+    }
+
+    pub fn rotation(&self) -> f32 {
+        self.rotation
+    }
+
+    pub fn data(&self) -> EnvironmentData {
+        let (background, top, bottom) = match self.background {
+            Background::Solid(colour) => (0, colour, colour),
+            Background::Gradient { top, bottom } => (1, top, bottom),
+            Background::Environment => (2, Vec3::ZERO, Vec3::ZERO),
+        };
+        EnvironmentData {
+            intensity: self.intensity,
+            rotation: self.rotation,
+            background,
+            top: top.extend(0.0).to_array(),
+            bottom: bottom.extend(0.0).to_array(),
+            ..Default::default()
+        }
+    }
+}
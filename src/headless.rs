@@ -0,0 +1,364 @@
+use std::{path::Path, time::Duration};
+
+use anyhow::Context;
+use bevy_ecs::{prelude::*, system::ScheduleSystem};
+use glam::Vec3;
+use itertools::Itertools;
+use tracing::warn;
+
+use crate::{
+    app::BevyApp,
+    binder::{self, SceneBindings},
+    bvh::BvhSettings,
+    camera::{self, Camera, CameraData},
+    camera_path::CameraPath,
+    delta_time::Time,
+    environment, material, memory,
+    mesh::{self, MeshServer},
+    pathtracer,
+    pathtracer::{Pathtracer, PathtracerOutput, TraceSettings},
+    pathtracer_manager::{self, PathtracerPhase},
+    render::DisplaySettings,
+    render_resources::{self, RenderDevice, RenderQueue, RendererBackends},
+    run_config::RunConfig,
+    schedule, texture, threadpool,
+    winnit::{WinitDeviceEvent, WinitWindowEvent},
+};
+
+// Headless renders are seeded, so the same scene, settings and sample count render the same
+// image. Adaptive sampling and outlier rejection go by statistics updated as samples land,
+// in whatever order they do, so runs using them can differ.
+pub const HEADLESS_SEED: u64 = 0;
+
+// Renders `scene` without a window, running `samples` accumulation passes
+// (one pathtracer dispatch each) before writing the output to `output` as a PNG.
+pub fn render_headless<M>(
+    scene: impl IntoScheduleConfigs<ScheduleSystem, M>,
+    camera: CameraData,
+    dims: (u32, u32),
+    samples: u32,
+    output: &Path,
+) -> anyhow::Result<()> {
+    let mut app = headless_app(
+        scene,
+        dims,
+        None,
+        HEADLESS_SEED,
+        &TraceSettings::default(),
+        |cam| cam.data = camera,
+    )?;
+    render_frame(&mut app, samples)?;
+    save_output(&mut app, dims, &DisplaySettings::default(), output)
+}
+
+// render_headless with the dims, target spp, output, backends, seed, trace, display and
+// BVH settings of a validated `config`.
+pub(crate) fn render_headless_config<M>(
+    scene: impl IntoScheduleConfigs<ScheduleSystem, M>,
+    camera: CameraData,
+    config: &RunConfig,
+) -> anyhow::Result<()> {
+    let (Some(output), Some(samples)) = (config.output.as_deref(), config.target_spp) else {
+        anyhow::bail!("Headless runs need an output path and a target samples per pixel");
+    };
+    let mut app = config_app(scene, camera, config)?;
+    render_frame(&mut app, samples)?;
+    save_output(&mut app, config.dims, &config.display, output)
+}
+
+// headless_app set up as `config` has it.
+fn config_app<M>(
+    scene: impl IntoScheduleConfigs<ScheduleSystem, M>,
+    camera: CameraData,
+    config: &RunConfig,
+) -> anyhow::Result<BevyApp> {
+    let seed = config.seed.unwrap_or(HEADLESS_SEED);
+    let mut app = headless_app(
+        scene,
+        config.dims,
+        config.backends,
+        seed,
+        &config.trace,
+        |cam| cam.data = camera,
+    )?;
+    // Read as the meshes start loading, on the first update:
+    app.world
+        .insert_resource(BvhSettings::preset(config.bvh_preset));
+    Ok(app)
+}
+
+// Renders `scene` from every `interval` seconds along `path`, from its first keyframe to
+// its end, as `frame_00000.png` onwards in `output_dir`. Each frame accumulates `samples`
+// passes from scratch.
+pub fn render_headless_path<M>(
+    scene: impl IntoScheduleConfigs<ScheduleSystem, M>,
+    path: &CameraPath,
+    dims: (u32, u32),
+    samples: u32,
+    interval: f32,
+    output_dir: &Path,
+) -> anyhow::Result<()> {
+    anyhow::ensure!(interval > 0.0, "Frame interval must be positive");
+    anyhow::ensure!(!path.keyframes.is_empty(), "Camera path has no keyframes");
+    std::fs::create_dir_all(output_dir)
+        .with_context(|| format!("Failed to create {}", output_dir.display()))?;
+
+    let mut app = headless_app(
+        scene,
+        dims,
+        None,
+        HEADLESS_SEED,
+        &TraceSettings::default(),
+        |cam| path.play(cam, 0.0),
+    )?;
+    // A looping path ends where it started, so that frame isn't repeated:
+    let frames = if path.looping {
+        (path.duration() / interval).ceil() as u32
+    } else {
+        (path.duration() / interval).floor() as u32 + 1
+    };
+    for frame in 0..frames.max(1) {
+        {
+            let mut query = app.world.query::<&mut Camera>();
+            let mut cam = query
+                .single_mut(&mut app.world)
+                .context("Expected a single camera")?;
+            path.play(&mut cam, frame as f32 * interval);
+        }
+        render_frame(&mut app, samples)?;
+        save_output(
+            &mut app,
+            dims,
+            &DisplaySettings::default(),
+            &output_dir.join(format!("frame_{frame:05}.png")),
+        )?;
+    }
+    Ok(())
+}
+
+// Sets up everything but the swapchain render and waits for the scene to load, with the
+// primary pathtracer at `dims` seeded with `seed`, tracing with `trace`, and its camera posed
+// by `pose`.
+fn headless_app<M>(
+    scene: impl IntoScheduleConfigs<ScheduleSystem, M>,
+    dims: (u32, u32),
+    backends: Option<wgpu::Backends>,
+    seed: u64,
+    trace: &TraceSettings,
+    pose: impl FnOnce(&mut Camera),
+) -> anyhow::Result<BevyApp> {
+    let mut app = BevyApp::new();
+    if let Some(backends) = backends {
+        app.world.insert_resource(RendererBackends(backends));
+    }
+
+    // Everything but the swapchain render:
+    threadpool::initialize(&mut app);
+    render_resources::initialize(&mut app);
+    environment::initialize(&mut app);
+    pathtracer::initialize(&mut app);
+    mesh::initialize(&mut app);
+    material::initialize(&mut app);
+    texture::initialize(&mut app);
+    binder::initialize(&mut app);
+    pathtracer_manager::initialize(&mut app);
+    camera::initialize(&mut app);
+    memory::initialize(&mut app);
+    app.world
+        .get_resource_or_init::<Schedules>()
+        .add_systems(schedule::Startup, scene);
+
+    // The camera reads winit input, there just won't ever be any:
+    app.world.init_resource::<Messages<WinitWindowEvent>>();
+    app.world.init_resource::<Messages<WinitDeviceEvent>>();
+    app.world.init_resource::<Time>();
+
+    app.startup();
+
+    {
+        let mut query = app.world.query::<(&mut Pathtracer, &mut Camera)>();
+        let (mut pt, mut cam) = query
+            .single_mut(&mut app.world)
+            .context("Expected a single pathtracer")?;
+        pt.set_seed(Some(seed));
+        pt.apply_trace_settings(trace);
+        pt.set_dims(dims);
+        pose(&mut cam);
+        cam.data.changed = 1;
+        cam.changed = true;
+    }
+
+    // Meshes are loaded on worker threads, so wait until there is a scene to trace:
+    while !is_ready(&mut app.world) {
+        app.run();
+        std::thread::sleep(Duration::from_millis(1));
+    }
+    // The rest of the scene still renders, without them:
+    for (id, e) in app.world.resource::<MeshServer>().failures() {
+        warn!("Rendering without {id:?}, which failed to load: {e}");
+    }
+
+    Ok(app)
+}
+
+// Runs `samples` accumulation passes, restarting if the camera moved since the last frame.
+// Updates trace the pathtracer's iterations per frame, the last only what's left.
+fn render_frame(app: &mut BevyApp, samples: u32) -> anyhow::Result<()> {
+    let device = app.world.resource::<RenderDevice>().0.clone();
+    let mut query = app.world.query::<&mut Pathtracer>();
+    let iterations = query
+        .single(&app.world)
+        .context("Expected a single pathtracer")?
+        .iterations_per_frame
+        .max(1);
+
+    // Only set on a change, so the pathtracer isn't flagged as changed every update:
+    let mut set_iterations = |app: &mut BevyApp, iterations: u32| -> anyhow::Result<()> {
+        let mut pt = query.single_mut(&mut app.world)?;
+        if pt.iterations_per_frame != iterations {
+            pt.set_iterations_per_frame(iterations);
+        }
+        Ok(())
+    };
+    let mut left = samples;
+    while left > 0 {
+        let traced = iterations.min(left);
+        set_iterations(app, traced)?;
+        app.run();
+        // Don't let submissions pile up faster than the GPU retires them:
+        device.poll(wgpu::PollType::wait_indefinitely())?;
+        left -= traced;
+    }
+    set_iterations(app, iterations)
+}
+
+// Tone maps the output as the render phase would with `display`.
+fn save_output(
+    app: &mut BevyApp,
+    dims: (u32, u32),
+    display: &DisplaySettings,
+    output: &Path,
+) -> anyhow::Result<()> {
+    let device = app.world.resource::<RenderDevice>().0.clone();
+    let queue = app.world.resource::<RenderQueue>().0.clone();
+
+    let mut query = app.world.query::<&PathtracerOutput>();
+    let pto = query
+        .single(&app.world)
+        .context("Expected a single pathtracer output")?;
+    let radiance = pto.read_hdr(&device, &queue)?;
+
+    // Output is mean radiance, so tone map it as the render phase would:
+    let bytes = radiance
+        .iter()
+        .flat_map(|r| {
+            let c = display.tonemap.apply(Vec3::from_slice(r), display.exposure);
+            let [r, g, b] = c.to_array().map(|c| (linear_to_srgb(c) * 255.0) as u8);
+            [r, g, b, u8::MAX]
+        })
+        .collect_vec();
+    let image = image::RgbaImage::from_raw(dims.0, dims.1, bytes)
+        .context("Output buffer does not match the pathtracer dims")?;
+    image
+        .save(output)
+        .with_context(|| format!("Failed to write {}", output.display()))?;
+
+    Ok(())
+}
+
+fn linear_to_srgb(c: f32) -> f32 {
+    if c <= 0.0031308 {
+        c * 12.92
+    } else {
+        1.055 * c.powf(1.0 / 2.4) - 0.055
+    }
+}
+
+// Every mesh is loaded or failed and bound, see MeshServer::all_loaded, and the pipelines
+// are built. A scene with no meshes, or only failed ones, binds all the same:
+fn is_ready(world: &mut World) -> bool {
+    world.resource::<MeshServer>().all_loaded()
+        && world.resource::<SceneBindings>().bind_group.is_some()
+        && world
+            .query::<&PathtracerPhase>()
+            .iter(world)
+            .next()
+            .is_some()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{pathtracer::Integrator, scenes};
+
+    // The mean radiance of each pixel after a headless run of `config` on the Cornell box.
+    fn render_hdr(config: &RunConfig) -> Vec<[f32; 4]> {
+        let index = scenes::builtin_scene("cornell").unwrap();
+        let mut app = config_app(
+            move |world: &mut World| scenes::spawn_builtin_scene(world, index),
+            CameraData::new(),
+            config,
+        )
+        .unwrap();
+        render_frame(&mut app, config.target_spp.unwrap()).unwrap();
+
+        let device = app.world.resource::<RenderDevice>().0.clone();
+        let queue = app.world.resource::<RenderQueue>().0.clone();
+        let mut query = app.world.query::<&PathtracerOutput>();
+        query
+            .single(&app.world)
+            .unwrap()
+            .read_hdr(&device, &queue)
+            .unwrap()
+    }
+
+    fn config(seed: u64) -> RunConfig {
+        RunConfig {
+            dims: (64, 64),
+            headless: true,
+            target_spp: Some(16),
+            seed: Some(seed),
+            ..Default::default()
+        }
+    }
+
+    // Needs a GPU adapter, run with --ignored where there is one:
+    #[test]
+    #[ignore]
+    fn same_seed_renders_the_same() {
+        let first = render_hdr(&config(7));
+        let second = render_hdr(&config(7));
+        assert!(
+            bytemuck::cast_slice::<_, u8>(&first) == bytemuck::cast_slice::<_, u8>(&second),
+            "Two renders seeded 7 differ"
+        );
+        assert_ne!(first, render_hdr(&config(8)));
+    }
+
+    // Russian roulette is unbiased, so up to the same depth the mean brightness should
+    // come out the same as the fixed depth reference, within the noise. Needs a GPU
+    // adapter too:
+    #[test]
+    #[ignore]
+    fn roulette_converges_to_fixed_depth() {
+        const DEPTH: u32 = 5;
+        let mean = |integrator| {
+            let mut config = config(7);
+            config.target_spp = Some(256);
+            config.trace.max_bounces = DEPTH;
+            config.trace.integrator = integrator;
+            let hdr = render_hdr(&config);
+            hdr.iter()
+                .map(|p| Vec3::new(p[0], p[1], p[2]))
+                .sum::<Vec3>()
+                / hdr.len() as f32
+        };
+
+        let roulette = mean(Integrator::PathRR);
+        let reference = mean(Integrator::PathFixedDepth { depth: DEPTH });
+        assert!(
+            (roulette - reference).abs().max_element() < 0.02 * reference.max_element(),
+            "Russian roulette's mean {roulette} is off the fixed depth's {reference}"
+        );
+    }
+}
@@ -1,9 +1,57 @@
+use bevy_ecs::component::Component;
+use glam::{Vec3, Vec4};
+
+// Instance::flags, matching INSTANCE_* in common.slang. Rays are traced with a mask of
+// these and skip any instance missing one of them, so a mask of 0 hits everything.
+pub const INSTANCE_VISIBLE_CAMERA: u32 = 1;
+pub const INSTANCE_CASTS_SHADOWS: u32 = 2;
+
 #[repr(C)]
-#[derive(Copy, Clone, Debug, bytemuck::Pod, bytemuck::Zeroable, Default)]
+#[derive(Copy, Clone, Debug, bytemuck::Pod, bytemuck::Zeroable)]
 pub struct Instance {
     pub transform_idx: u32,
     pub geometry_idx: u32,
     pub material_idx: u32,
+    pub flags: u32,
+    pub velocity: Vec4, // xyz
+}
+
+// World units per second an instance is moving at while the camera's shutter is open,
+// blurring it along the way. Only for motion blur, it doesn't move the transform. Planes
+// are infinite, so don't blur.
+#[derive(Component, Clone, Copy, Debug, Default, PartialEq)]
+pub struct Velocity(pub Vec3);
+
+// Which rays see an instance, on the same entity as its MeshId, Sphere or Plane. Left
+// off, it's seen by every ray.
+#[derive(Component, Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RayVisibility {
+    // Hidden from camera rays, but still seen in reflections and lit by bounced light:
+    pub visible_camera: bool,
+    // Whether it blocks shadow rays, so a hidden object can still be seen by its shadow:
+    pub casts_shadows: bool,
+}
+
+impl Default for RayVisibility {
+    fn default() -> Self {
+        Self {
+            visible_camera: true,
+            casts_shadows: true,
+        }
+    }
+}
+
+impl RayVisibility {
+    pub fn flags(&self) -> u32 {
+        let mut flags = 0;
+        if self.visible_camera {
+            flags |= INSTANCE_VISIBLE_CAMERA;
+        }
+        if self.casts_shadows {
+            flags |= INSTANCE_CASTS_SHADOWS;
+        }
+        flags
+    }
 }
 
 // pub struct Instances {
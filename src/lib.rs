@@ -1,20 +1,35 @@
+use bevy_ecs::world::World;
 use winit::event_loop::EventLoop;
 
-use crate::{app::BevyApp, winnit::WinitApp};
+use crate::{
+    app::BevyApp, render_resources::RendererBackends, scene_file::SceneFileWatch,
+    scenes::CurrentScene, winnit::WinitApp,
+};
 
 mod app;
 mod binder;
 mod blas;
+mod bloom;
+mod blue_noise;
 mod bvh;
 mod camera;
+mod camera_path;
+mod convergence;
+#[cfg(feature = "denoise")]
+mod denoise;
 mod dielectric;
 mod dims;
 mod emissive;
+mod environment;
+mod headless;
 // mod extension;
 mod instance;
 mod lambertian;
+mod light;
 // mod logic;
 mod material;
+mod material_edit;
+mod memory;
 mod mesh;
 mod metallic;
 // mod new_ray;
@@ -24,32 +39,113 @@ mod pathtracer_manager;
 mod queue;
 mod render;
 mod render_resources;
+mod run_config;
+mod scene_builder;
+mod scene_file;
 mod scenes;
 // mod shadow;
 mod delta_time;
 mod pathtracer_state;
+mod picking;
+mod plane;
+mod ply;
 mod schedule;
+mod screenshot;
+mod sphere;
 mod texture;
 mod threadpool;
 mod tlas;
 mod transform;
+mod traverse;
+mod window_title;
 mod winnit;
 
-pub fn run() -> anyhow::Result<()> {
+pub use bvh::BvhPreset;
+pub use camera::CameraData;
+pub use camera_path::{CameraKeyframe, CameraPath};
+pub use headless::{render_headless, render_headless_path};
+pub use pathtracer::{
+    Integrator, LightSampling, ReconstructionFilter, SamplingMode, TraceSettings,
+};
+pub use render::{DisplaySettings, ToneMapping, UpscaleFilter};
+pub use run_config::{RunConfig, SceneSource};
+pub use scene_builder::SceneBuilder;
+pub use scene_file::load_scene_file;
+pub use scenes::{area_lights_scene, boxes_scene, cornell_scene};
+
+// Opens a window tracing the default scene at 512x512 until it is closed, see
+// RunConfig::default.
+pub fn run_default() -> anyhow::Result<()> {
+    run(RunConfig::default())
+}
+
+// Opens a window tracing the scene until it is closed, or renders it headless, as
+// `config` sets up. Fails before starting if the config doesn't make sense.
+pub fn run(config: RunConfig) -> anyhow::Result<()> {
+    config.validate()?;
     tracing_subscriber::fmt::init();
 
+    let scene = config
+        .scene
+        .clone()
+        .or_else(|| scene_file::scene_file_var().map(SceneSource::File));
+    if config.headless {
+        return run_headless(&config, scene);
+    }
+
     let mut bevy_app = BevyApp::new();
+    bevy_app
+        .world
+        .insert_resource(pathtracer::InitialDims(config.dims));
+    bevy_app.world.insert_resource(pathtracer::InitialSampling {
+        target_spp: config.target_spp,
+        seed: config.seed,
+    });
+    bevy_app
+        .world
+        .insert_resource(pathtracer::InitialTrace(config.trace));
+    bevy_app
+        .world
+        .insert_resource(render::InitialDisplay(config.display));
+    bevy_app
+        .world
+        .insert_resource(bvh::BvhSettings::preset(config.bvh_preset));
+    if let Some(backends) = config.backends {
+        bevy_app.world.insert_resource(RendererBackends(backends));
+    }
+    match scene {
+        Some(SceneSource::Builtin(name)) => {
+            let index = run_config::builtin_scene_index(&name)?;
+            bevy_app.world.insert_resource(CurrentScene(index));
+        }
+        Some(SceneSource::File(path)) => {
+            bevy_app.world.insert_resource(SceneFileWatch::new(path));
+        }
+        None => {}
+    }
 
     threadpool::initialize(&mut bevy_app);
     render_resources::initialize(&mut bevy_app);
+    environment::initialize(&mut bevy_app);
     render::initialize(&mut bevy_app);
     pathtracer::initialize(&mut bevy_app);
     mesh::initialize(&mut bevy_app);
     material::initialize(&mut bevy_app);
+    texture::initialize(&mut bevy_app);
+    scene_file::initialize(&mut bevy_app);
     scenes::initialize(&mut bevy_app);
     binder::initialize(&mut bevy_app);
     pathtracer_manager::initialize(&mut bevy_app);
     camera::initialize(&mut bevy_app);
+    camera_path::initialize(&mut bevy_app);
+    convergence::initialize(&mut bevy_app);
+    #[cfg(feature = "denoise")]
+    denoise::initialize(&mut bevy_app);
+    memory::initialize(&mut bevy_app);
+    picking::initialize(&mut bevy_app);
+    material_edit::initialize(&mut bevy_app);
+    screenshot::initialize(&mut bevy_app);
+    window_title::initialize(&mut bevy_app);
 
     let event_loop = EventLoop::new()?;
     let mut app = WinitApp::new(bevy_app);
@@ -57,3 +153,32 @@ pub fn run() -> anyhow::Result<()> {
 
     Ok(())
 }
+
+// Renders `scene`, or the simple scene, from its own camera or the default one, to the
+// config's output.
+fn run_headless(config: &RunConfig, scene: Option<SceneSource>) -> anyhow::Result<()> {
+    match scene {
+        Some(SceneSource::File(path)) => {
+            let scene = load_scene_file(&path)?;
+            let camera = scene.camera_data().unwrap_or_else(CameraData::new);
+            headless::render_headless_config(
+                move |world: &mut World| {
+                    scene.spawn(world);
+                },
+                camera,
+                config,
+            )
+        }
+        scene => {
+            let index = match scene {
+                Some(SceneSource::Builtin(name)) => run_config::builtin_scene_index(&name)?,
+                _ => CurrentScene::default().0,
+            };
+            headless::render_headless_config(
+                move |world: &mut World| scenes::spawn_builtin_scene(world, index),
+                CameraData::new(),
+                config,
+            )
+        }
+    }
+}
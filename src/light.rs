@@ -0,0 +1,288 @@
+use bevy_ecs::component::Component;
+use glam::{Vec3, Vec4, Vec4Swizzles};
+use tracing::{debug, info, warn};
+
+use crate::{
+    instance::Instance,
+    material::Material,
+    mesh::{MeshServer, face_group},
+    plane::PLANE_GEOMETRY,
+    sphere::SPHERE_GEOMETRY,
+    transform::Transform,
+};
+
+// A light infinitely far away, like the sun.
+// Any number can be spawned, each is sampled at every hit.
+#[derive(Copy, Clone, Debug, Default, Component)]
+pub struct DirectionalLight {
+    pub direction: Vec3, // Direction the light travels
+    pub radiance: Vec3,
+}
+
+#[repr(C)]
+#[derive(Copy, Clone, Debug, bytemuck::Pod, bytemuck::Zeroable, Default)]
+pub struct DirectionalLightGPU {
+    pub direction: Vec4,
+    pub radiance: Vec4, // Zero for the padding entry when there are no lights
+}
+
+impl From<DirectionalLight> for DirectionalLightGPU {
+    fn from(light: DirectionalLight) -> Self {
+        DirectionalLightGPU {
+            direction: light.direction.normalize_or_zero().extend(0.0),
+            radiance: light.radiance.extend(0.0),
+        }
+    }
+}
+
+// A light emitting equally in every direction from a point, falling off with
+// the square of distance. A radius above zero samples a sphere instead,
+// softening the shadows it casts.
+#[derive(Copy, Clone, Debug, Default, Component)]
+pub struct PointLight {
+    pub position: Vec3,
+    pub intensity: Vec3, // Radiant intensity, per steradian
+    pub radius: f32,
+}
+
+#[repr(C)]
+#[derive(Copy, Clone, Debug, bytemuck::Pod, bytemuck::Zeroable, Default)]
+pub struct PointLightGPU {
+    pub position: Vec4,  // w is the radius
+    pub intensity: Vec4, // Zero for the padding entry when there are no lights
+}
+
+impl From<PointLight> for PointLightGPU {
+    fn from(light: PointLight) -> Self {
+        PointLightGPU {
+            position: light.position.extend(light.radius.max(0.0)),
+            intensity: light.intensity.extend(0.0),
+        }
+    }
+}
+
+// A point light restricted to a cone about `direction`. Full intensity within
+// `inner_angle` of it, fading smoothly to nothing at `outer_angle` (half angles, radians).
+#[derive(Copy, Clone, Debug, Default, Component)]
+pub struct SpotLight {
+    pub position: Vec3,
+    pub direction: Vec3, // Direction the cone points
+    pub intensity: Vec3,
+    pub inner_angle: f32,
+    pub outer_angle: f32,
+}
+
+#[repr(C)]
+#[derive(Copy, Clone, Debug, bytemuck::Pod, bytemuck::Zeroable, Default)]
+pub struct SpotLightGPU {
+    pub position: Vec4,
+    pub direction: Vec4,
+    pub intensity: Vec4, // Zero for the padding entry when there are no lights
+    pub cos_inner: f32,
+    pub cos_outer: f32,
+    pub _pad: [u32; 2],
+}
+
+impl From<SpotLight> for SpotLightGPU {
+    fn from(light: SpotLight) -> Self {
+        // An inner angle past the outer would invert the falloff:
+        let outer = light.outer_angle.max(0.0);
+        let inner = light.inner_angle.clamp(0.0, outer);
+        SpotLightGPU {
+            position: light.position.extend(1.0),
+            direction: light.direction.normalize_or_zero().extend(0.0),
+            intensity: light.intensity.extend(0.0),
+            cos_inner: inner.cos(),
+            cos_outer: outer.cos(),
+            _pad: [0; 2],
+        }
+    }
+}
+
+// An emissive mesh instance, sampled by next event estimation. One is chosen in proportion
+// to its power, then one of its triangles in proportion to its share of that, its world
+// space area times the emission of its face group's material.
+#[repr(C)]
+#[derive(Copy, Clone, Debug, bytemuck::Pod, bytemuck::Zeroable, Default)]
+pub struct LightSourceGPU {
+    pub instance: u32,
+    pub triangle_offset: u32, // Into the light triangle cdf
+    pub triangle_count: u32,
+    pub pdf: f32, // Chance of it being chosen, zero for the padding entry
+    pub cdf: f32, // Chance of it or one before it being chosen
+    pub _pad: [u32; 3],
+}
+
+// The emissive mesh instances as lights, for a scene bound as `instances`. Analytic
+// spheres and planes aren't sampled, only hit.
+#[derive(Default)]
+pub struct AreaLights {
+    pub sources: Vec<LightSourceGPU>,
+    // What each source emits, see LightPower:
+    pub powers: Vec<f32>,
+    // Per light, running over its geometry's faces in order, ending at 1:
+    pub triangle_cdf: Vec<f32>,
+    // Index into `sources` by instance id, u32::MAX if it isn't one:
+    pub instance_lights: Vec<u32>,
+}
+
+impl AreaLights {
+    pub fn new(
+        instances: &[Instance],
+        transforms: &[Transform],
+        materials: &[Material],
+        mesh_server: &MeshServer,
+    ) -> Self {
+        let mut lights = Self {
+            instance_lights: vec![u32::MAX; instances.len()],
+            ..Default::default()
+        };
+        for (instance_id, instance) in instances.iter().enumerate() {
+            if instance.geometry_idx == SPHERE_GEOMETRY || instance.geometry_idx == PLANE_GEOMETRY {
+                continue;
+            }
+            let Some(data) = mesh_server.geometry(instance.geometry_idx) else {
+                continue;
+            };
+            // What a unit of area of each face group emits, its material following the
+            // instance's:
+            let group_powers = (0..data.material_groups as usize)
+                .map(|g| {
+                    let material = materials.get(instance.material_idx as usize + g)?;
+                    if !material.is_emissive() {
+                        return None;
+                    }
+                    // Textured emission isn't shaded yet, so the constant colour is the power:
+                    let radiance = material.emissive.truncate() * material.emissive_power;
+                    let sides = if material.emissive_two_sided != 0 {
+                        2.0
+                    } else {
+                        1.0
+                    };
+                    Some(luminance(radiance) * sides)
+                })
+                .map(Option::unwrap_or_default)
+                .collect::<Vec<f32>>();
+            if group_powers.iter().all(|&p| p <= 0.0) {
+                continue;
+            }
+
+            let m = transforms[instance.transform_idx as usize].matrix();
+            let positions = &data.mesh.positions;
+            let powers = data.mesh.faces.iter().map(|&face| {
+                let [p0, p1, p2] = face
+                    .xyz()
+                    .to_array()
+                    .map(|i| m.transform_point3(positions[i as usize].xyz()));
+                let area = 0.5 * (p1 - p0).cross(p2 - p0).length();
+                area * group_powers[face_group(face) as usize]
+            });
+            let mut total = 0.0;
+            let cdf = powers
+                .map(|p| {
+                    total += p;
+                    total
+                })
+                .collect::<Vec<_>>();
+
+            if !(total > 0.0 && total.is_finite()) {
+                continue;
+            }
+
+            lights.instance_lights[instance_id] = lights.sources.len() as u32;
+            lights.sources.push(LightSourceGPU {
+                instance: instance_id as u32,
+                triangle_offset: lights.triangle_cdf.len() as u32,
+                triangle_count: cdf.len() as u32,
+                ..Default::default()
+            });
+            lights.triangle_cdf.extend(cdf.iter().map(|c| c / total));
+            lights.powers.push(total);
+        }
+
+        let total: f32 = lights.powers.iter().sum();
+        let mut cdf = 0.0;
+        for (light, power) in lights.sources.iter_mut().zip(&lights.powers) {
+            light.pdf = power / total;
+            cdf += light.pdf;
+            light.cdf = cdf;
+        }
+        // Rounding mustn't leave a sliver past the last light that nothing is picked from:
+        if let Some(last) = lights.sources.last_mut() {
+            last.cdf = 1.0;
+        }
+        lights
+    }
+}
+
+// Rec. 709, as the pathtracer weighs samples.
+fn luminance(c: Vec3) -> f32 {
+    c.dot(Vec3::new(0.2126, 0.7152, 0.0722))
+}
+
+// Each light next event estimation samples, by kind.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum LightKind {
+    // An emissive mesh, by instance id:
+    Area { instance: u32 },
+    Directional,
+    Point,
+    Spot,
+}
+
+// A light and what it emits, in luminance: emission times area (both sides for two sided
+// materials) for emissive meshes, intensity for point and spot lights and radiance for
+// directional ones. So only comparable within a kind, but zero is dark in any of them.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct LightPower {
+    pub kind: LightKind,
+    pub power: f32,
+}
+
+impl DirectionalLight {
+    pub fn power(&self) -> f32 {
+        luminance(self.radiance)
+    }
+}
+
+impl PointLight {
+    pub fn power(&self) -> f32 {
+        luminance(self.intensity)
+    }
+}
+
+impl SpotLight {
+    pub fn power(&self) -> f32 {
+        luminance(self.intensity)
+    }
+}
+
+// The sampled lights by kind, and each at debug level. Warns if none of them emit, leaving
+// only the background and anything hit by chance to light the scene.
+pub fn log_lights(lights: &[LightPower]) {
+    let kinds = [
+        ("emissive meshes", LightKind::Area { instance: 0 }),
+        ("directional", LightKind::Directional),
+        ("point", LightKind::Point),
+        ("spot", LightKind::Spot),
+    ];
+    let summary = kinds
+        .iter()
+        .map(|(name, kind)| {
+            let of_kind = lights
+                .iter()
+                .filter(|l| std::mem::discriminant(&l.kind) == std::mem::discriminant(kind));
+            let power: f32 = of_kind.clone().map(|l| l.power).sum();
+            format!("{} {name} ({power:.4})", of_kind.count())
+        })
+        .collect::<Vec<_>>()
+        .join(", ");
+    info!("Lights: {summary}");
+    for light in lights {
+        debug!("{:?}: power {:.4}", light.kind, light.power);
+    }
+
+    if !lights.iter().any(|l| l.power > 0.0) {
+        warn!("No light in the scene emits anything, next event estimation has nothing to sample");
+    }
+}
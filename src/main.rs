@@ -1,3 +1,215 @@
+use std::path::PathBuf;
+
+use clap::Parser;
+use raytracer::{
+    BvhPreset, DisplaySettings, Integrator, LightSampling, ReconstructionFilter, RunConfig,
+    SamplingMode, SceneSource, ToneMapping, TraceSettings, UpscaleFilter,
+};
+
+// Everything left out is as run_default, a window on the default scene.
+#[derive(Parser, Debug)]
+#[command(version, about = "A wavefront GPU path tracer")]
+struct Args {
+    #[arg(
+        long,
+        help = "A built in scene (simple, boxes, cornell, area lights) or a scene file"
+    )]
+    scene: Option<SceneSource>,
+    #[arg(long, default_value_t = 512)]
+    width: u32,
+    #[arg(long, default_value_t = 512)]
+    height: u32,
+    #[arg(
+        long,
+        help = "Samples per pixel, rendered headless, or reported once reached in a window"
+    )]
+    spp: Option<u32>,
+    #[arg(short, long, help = "PNG to write a headless render to")]
+    output: Option<PathBuf>,
+    #[arg(long, help = "Render to --output without a window, needs --spp")]
+    headless: bool,
+    #[arg(
+        long,
+        help = "Comma separated wgpu backends: vulkan, metal, dx12, gl [default: RENDERER_BACKEND, else all]"
+    )]
+    backend: Option<String>,
+    #[arg(long, help = "Seeds the sampling, so renders repeat exactly")]
+    seed: Option<u64>,
+    #[arg(
+        long,
+        help = "Surface interactions a path is shaded at, 1 is direct lighting only [default: 128]"
+    )]
+    max_bounces: Option<u32>,
+    #[arg(
+        long,
+        help = "path-rr, or fixed-depth:N for an unbiased reference of exactly N bounces [default: path-rr]"
+    )]
+    integrator: Option<Integrator>,
+    #[arg(
+        long,
+        help = "Where in each pixel camera rays start: random, stratified or blue-noise [default: stratified]"
+    )]
+    sampler: Option<SamplingMode>,
+    #[arg(
+        long,
+        help = "How samples are weighted into the pixels around them: box, tent or gaussian [default: box]"
+    )]
+    filter: Option<ReconstructionFilter>,
+    #[arg(
+        long,
+        help = "How points on emissive triangles are picked for next event estimation: area or solid-angle [default: solid-angle]"
+    )]
+    light_sampling: Option<LightSampling>,
+    #[arg(
+        long,
+        help = "Caps the brightest channel of light arriving after the first bounce, against fireflies"
+    )]
+    clamp_indirect: Option<f32>,
+    #[arg(
+        long,
+        help = "Rejects samples this many standard deviations brighter than their pixel's mean"
+    )]
+    outlier_rejection: Option<f32>,
+    #[arg(
+        long,
+        help = "Stops sampling pixels once their relative standard error is under this, 0 samples uniformly [default: 0]"
+    )]
+    target_error: Option<f32>,
+    #[arg(
+        long,
+        help = "Samples every pixel takes before adaptive sampling may stop it [default: 16]"
+    )]
+    min_samples: Option<u32>,
+    #[arg(
+        long,
+        help = "Samples adaptive sampling stops a pixel at regardless, 0 for no limit [default: 0]"
+    )]
+    max_samples: Option<u32>,
+    #[arg(
+        long,
+        help = "How far rays start off the surface they leave, per unit from the origin, against shadow acne [default: 1e-5]"
+    )]
+    ray_epsilon: Option<f32>,
+    #[arg(
+        long,
+        help = "Frames traced per update, converging faster at the cost of responsiveness [default: 1]"
+    )]
+    iterations_per_frame: Option<u32>,
+    #[arg(
+        long,
+        help = "Fraction of the window's resolution to trace at, following it as it resizes"
+    )]
+    render_scale: Option<f32>,
+    #[arg(
+        long,
+        help = "How radiance is mapped to the display: linear, reinhard or aces [default: aces]"
+    )]
+    tonemap: Option<ToneMapping>,
+    #[arg(
+        long,
+        allow_hyphen_values = true,
+        help = "Stops the radiance is scaled by before tone mapping [default: -2.5]"
+    )]
+    exposure: Option<f32>,
+    #[arg(
+        long,
+        help = "How a scaled render is stretched to the window: nearest or linear [default: linear]"
+    )]
+    upscale: Option<UpscaleFilter>,
+    #[arg(
+        long,
+        help = "Strength of the glow around bright areas, 0 for none [default: 0]"
+    )]
+    bloom: Option<f32>,
+    #[arg(
+        long,
+        help = "Exposed luminance the glow starts at, 1 being what clips to white [default: 1]"
+    )]
+    bloom_threshold: Option<f32>,
+    #[arg(
+        long,
+        help = "Acceleration structure leaf sizes: fast to build, balanced or quality to trace [default: balanced]"
+    )]
+    bvh_preset: Option<BvhPreset>,
+}
+
 fn main() -> anyhow::Result<()> {
-    raytracer::run()
+    let args = Args::parse();
+
+    let backends = match &args.backend {
+        Some(names) => {
+            let backends = wgpu::Backends::from_comma_list(names);
+            anyhow::ensure!(
+                !backends.is_empty(),
+                "No known backends in --backend {names:?}, expected any of vulkan, metal, dx12, gl"
+            );
+            Some(backends)
+        }
+        None => None,
+    };
+
+    let mut trace = TraceSettings::default();
+    if let Some(max_bounces) = args.max_bounces {
+        trace.max_bounces = max_bounces;
+    }
+    if let Some(integrator) = args.integrator {
+        trace.integrator = integrator;
+    }
+    if let Some(sampler) = args.sampler {
+        trace.sampling_mode = sampler;
+    }
+    if let Some(filter) = args.filter {
+        trace.reconstruction_filter = filter;
+    }
+    if let Some(light_sampling) = args.light_sampling {
+        trace.light_sampling = light_sampling;
+    }
+    trace.clamp_indirect = args.clamp_indirect;
+    trace.outlier_rejection = args.outlier_rejection;
+    if let Some(target_error) = args.target_error {
+        trace.target_error = target_error;
+    }
+    if let Some(min_samples) = args.min_samples {
+        trace.min_samples = min_samples;
+    }
+    if let Some(max_samples) = args.max_samples {
+        trace.max_samples = max_samples;
+    }
+    if let Some(ray_epsilon) = args.ray_epsilon {
+        trace.ray_epsilon = ray_epsilon;
+    }
+    if let Some(iterations) = args.iterations_per_frame {
+        trace.iterations_per_frame = iterations;
+    }
+    trace.render_scale = args.render_scale;
+
+    let mut display = DisplaySettings::default();
+    if let Some(tonemap) = args.tonemap {
+        display.tonemap = tonemap;
+    }
+    if let Some(exposure) = args.exposure {
+        display.exposure = exposure;
+    }
+    if let Some(upscale) = args.upscale {
+        display.upscale = upscale;
+    }
+    if let Some(intensity) = args.bloom {
+        display.bloom_intensity = intensity;
+    }
+    if let Some(threshold) = args.bloom_threshold {
+        display.bloom_threshold = threshold;
+    }
+
+    raytracer::run(RunConfig {
+        dims: (args.width, args.height),
+        backends,
+        scene: args.scene,
+        headless: args.headless,
+        target_spp: args.spp,
+        output: args.output,
+        seed: args.seed,
+        trace,
+        display,
+        bvh_preset: args.bvh_preset.unwrap_or_default(),
+    })
 }
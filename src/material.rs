@@ -1,7 +1,7 @@
 use std::collections::HashMap;
 
 use bevy_ecs::prelude::*;
-use glam::Vec4;
+use glam::{Vec3, Vec4};
 
 use crate::app::BevyApp;
 
@@ -12,16 +12,48 @@ pub fn initialize(app: &mut BevyApp) {
 #[repr(C)]
 #[derive(Copy, Clone, Debug, bytemuck::Pod, bytemuck::Zeroable, Component)]
 pub struct Material {
+    // Textures are TextureId::material_index(), 1-based into the TextureServer:
     pub colour_texture: u32,             // 0 -> use base colour
     pub emissive_texture: u32,           // 0 -> use base emissive
     pub metallic_roughness_texture: u32, // 0 -> use base metallic/roughness
     pub normal_texture: u32,             // 0 -> use mesh vertex normals
     pub colour: Vec4,                    // 0.0..=1.0 rgba
     pub emissive: Vec4,                  // 0.0..=1.0 rgba
-    pub metallic: f32,                   // 0.0..=1.0
-    pub roughness: f32,                  // 0.0..=1.0
+    // Beer-Lambert extinction per unit of distance inside a transmissive material, 0 is clear:
+    pub absorption: Vec4, // rgb
+    pub metallic: f32,    // 0.0..=1.0
+    pub roughness: f32,   // 0.0..=1.0
+    // Transmission refracts by ior about roughness-perturbed microfacets:
     pub ior: f32,
     pub transmission: f32, // 0.0..=1.0
+    // Stretches the metallic highlight along the surface tangent, 0 is isotropic:
+    pub anisotropy: f32, // 0.0..=1.0
+    // A thin dielectric coat over everything else, as on car paint:
+    pub clearcoat: f32,           // 0.0..=1.0
+    pub clearcoat_roughness: f32, // 0.0..=1.0
+    // Scales emissive, so the colour and the intensity can be set apart:
+    pub emissive_power: f32,
+    // 0 only emits from the side the normal faces, as seen by the shader's front_face:
+    pub emissive_two_sided: u32,
+    // A thin film over the surface, as on soap bubbles or oil slicks, iridescent by
+    // interference between its two faces. Nanometres thick, 0 has no film:
+    pub thin_film_thickness: f32,
+    pub thin_film_ior: f32,
+    // Light entering a translucent material, as wax, skin or marble, scatters about
+    // inside before it leaves. The chance of a path going in, 0 is opaque:
+    pub subsurface: f32, // 0.0..=1.0
+    // Mean distance between scattering inside, per channel:
+    pub subsurface_radius: Vec4, // rgb
+    // Oren-Nayar roughness of the diffuse lobe, 0 is lambertian. Apart from `roughness`,
+    // which only widens the specular highlights:
+    pub diffuse_roughness: f32, // 0.0..=1.0
+    // Bumps the shading normal by the slope of a heightfield's red channel. bump_scale is
+    // the height of white per unit of uv, 0 leaves the normal as it was:
+    pub height_texture: u32, // 0 -> flat
+    pub bump_scale: f32,
+    // 0 culls the back faces of its triangles, so rays from behind pass through. Ignored
+    // by transmissive and subsurface materials, which trace their insides:
+    pub double_sided: u32,
 }
 
 impl Default for Material {
@@ -33,10 +65,111 @@ impl Default for Material {
             normal_texture: Default::default(),
             colour: Default::default(),
             emissive: Default::default(),
+            absorption: Default::default(),
             metallic: Default::default(),
             roughness: Default::default(),
             ior: 1.5,
             transmission: Default::default(),
+            anisotropy: Default::default(),
+            clearcoat: Default::default(),
+            clearcoat_roughness: Default::default(),
+            emissive_power: 1.0,
+            emissive_two_sided: 0,
+            thin_film_thickness: 0.0,
+            thin_film_ior: 1.3,
+            subsurface: 0.0,
+            subsurface_radius: Vec4::new(0.1, 0.1, 0.1, 0.0),
+            diffuse_roughness: 0.0,
+            height_texture: 0,
+            bump_scale: 0.0,
+            double_sided: 1,
+        }
+    }
+}
+
+impl Material {
+    // Whether anything it's on can emit light.
+    pub fn is_emissive(&self) -> bool {
+        self.emissive_power > 0.0 && (self.emissive != Vec4::ZERO || self.emissive_texture > 0)
+    }
+
+    // Whether it lights the scene the same as `other`, everything the area lights are
+    // built from.
+    pub fn emits_like(&self, other: &Material) -> bool {
+        (
+            self.emissive,
+            self.emissive_power,
+            self.emissive_texture,
+            self.emissive_two_sided,
+        ) == (
+            other.emissive,
+            other.emissive_power,
+            other.emissive_texture,
+            other.emissive_two_sided,
+        )
+    }
+
+    // Only the constant factors are used, textures aren't loaded yet.
+    pub fn from_gltf(material: &gltf::Material) -> Self {
+        let pbr = material.pbr_metallic_roughness();
+        let emissive = Vec3::from_array(material.emissive_factor());
+
+        Self {
+            colour: Vec4::from_array(pbr.base_color_factor()),
+            emissive: emissive.extend(0.0),
+            emissive_power: material.emissive_strength().unwrap_or(1.0),
+            // Back faces of a double sided material are drawn, so they glow too:
+            emissive_two_sided: material.double_sided() as u32,
+            double_sided: material.double_sided() as u32,
+            metallic: pbr.metallic_factor(),
+            roughness: pbr.roughness_factor(),
+            ior: material.ior().unwrap_or(1.5),
+            transmission: material
+                .transmission()
+                .map(|t| t.transmission_factor())
+                .unwrap_or_default(),
+            ..Default::default()
+        }
+    }
+
+    // MTL describes Phong shading, so this is only a rough fit. Strong specular is taken as
+    // metal, the shininess exponent as the roughness with the same highlight width and
+    // dissolve as transmission. Ke isn't part of tobj's material, so it's read from the
+    // unknown parameters.
+    pub fn from_mtl(material: &tobj::Material) -> Self {
+        let diffuse = Vec3::from_array(material.diffuse.unwrap_or([1.0; 3]));
+        let specular = Vec3::from_array(material.specular.unwrap_or_default()).max_element();
+        let emissive = material
+            .unknown_param
+            .get("Ke")
+            .and_then(|ke| {
+                let ke = ke
+                    .split_whitespace()
+                    .map(str::parse::<f32>)
+                    .collect::<Result<Vec<_>, _>>()
+                    .ok()?;
+                (ke.len() == 3).then(|| Vec3::from_slice(&ke))
+            })
+            .unwrap_or_default();
+
+        Self {
+            colour: diffuse.extend(1.0),
+            emissive: emissive.extend(0.0),
+            // Dielectrics reflect around 4% head on:
+            metallic: ((specular - 0.04) / 0.96).clamp(0.0, 1.0),
+            // Blinn-Phong's exponent n matches a GGX alpha of sqrt(2 / (n + 2)), and the
+            // shader squares roughness into alpha:
+            roughness: material
+                .shininess
+                .map(|n| (2.0 / (n.max(0.0) + 2.0)).powf(0.25))
+                .unwrap_or(1.0),
+            // Exporters write 1 when they mean unset, which would have no fresnel at all:
+            ior: material
+                .optical_density
+                .filter(|&ni| ni > 1.0)
+                .unwrap_or(1.5),
+            transmission: 1.0 - material.dissolve.unwrap_or(1.0).clamp(0.0, 1.0),
+            ..Default::default()
         }
     }
 }
@@ -44,6 +177,11 @@ impl Default for Material {
 #[derive(Copy, Clone, Component, Debug, Hash, Eq, PartialEq)]
 pub struct MaterialId(usize);
 
+// The material of each of a mesh's face groups in order, see FACE_GROUP_SHIFT, in place of
+// the entity's own MaterialId. Groups past the end use the MaterialId.
+#[derive(Clone, Component, Debug, PartialEq, Eq)]
+pub struct MaterialGroups(pub Vec<MaterialId>);
+
 #[derive(Resource, Default)]
 pub struct MaterialServer {
     materials: Vec<Material>,
@@ -68,6 +206,12 @@ impl MaterialServer {
     pub fn get(&self, id: MaterialId) -> Option<&Material> {
         self.materials.get(id.0)
     }
+
+    // Edits in place. The binder notices by comparing against what it last uploaded, so
+    // this can be reached through bypass_change_detection to skip rebuilding the scene.
+    pub fn get_mut(&mut self, id: MaterialId) -> Option<&mut Material> {
+        self.materials.get_mut(id.0)
+    }
 }
 
 // use wesl::include_wesl;
@@ -0,0 +1,96 @@
+use bevy_ecs::prelude::*;
+use glam::Vec4;
+use tracing::info;
+use winit::{
+    event::WindowEvent,
+    keyboard::{KeyCode, PhysicalKey},
+};
+
+use crate::{
+    app::BevyApp,
+    binder::binder_system,
+    material::MaterialServer,
+    picking::{self, PickedMaterial},
+    schedule,
+    winnit::WinitWindowEvent,
+};
+
+pub fn initialize(app: &mut BevyApp) {
+    app.world.get_resource_or_init::<Schedules>().add_systems(
+        schedule::Update,
+        // Uploaded by the binder the same frame:
+        material_edit_system
+            .after(picking::pick_system)
+            .before(binder_system),
+    );
+}
+
+// Nudged by 1 and 2, 3 and 4, 5 and 6, and 7 and 8:
+const METALLIC_STEP: f32 = 0.1;
+const ROUGHNESS_STEP: f32 = 0.05;
+const EMISSIVE_POWER_STEP: f32 = 1.0;
+const IOR_STEP: f32 = 0.05;
+const MIN_IOR: f32 = 1.0;
+const MAX_IOR: f32 = 3.0;
+
+// Look-dev on the picked material, see PickedMaterial. 1 and 2 step the metallic down and
+// up, 3 and 4 the roughness, 5 and 6 the emissive power and 7 and 8 the ior. Held keys
+// repeat. Everything using the material changes with it and accumulation restarts.
+fn material_edit_system(
+    mut we_reader: MessageReader<WinitWindowEvent>,
+    picked: Res<PickedMaterial>,
+    mut material_server: ResMut<MaterialServer>,
+) {
+    let keys = we_reader
+        .read()
+        .filter_map(|WinitWindowEvent(e)| match e {
+            WindowEvent::KeyboardInput { event, .. } if event.state.is_pressed() => {
+                match event.physical_key {
+                    PhysicalKey::Code(key) => Some(key),
+                    _ => None,
+                }
+            }
+            _ => None,
+        })
+        .collect::<Vec<_>>();
+    let Some(id) = picked.0 else {
+        return;
+    };
+    // The binder finds the edit against what it last uploaded and writes over just this
+    // material, where a change to the server would rebuild every instance:
+    let Some(material) = material_server.bypass_change_detection().get_mut(id) else {
+        return;
+    };
+
+    let before = *material;
+    for key in keys {
+        match key {
+            KeyCode::Digit1 => material.metallic -= METALLIC_STEP,
+            KeyCode::Digit2 => material.metallic += METALLIC_STEP,
+            KeyCode::Digit3 => material.roughness -= ROUGHNESS_STEP,
+            KeyCode::Digit4 => material.roughness += ROUGHNESS_STEP,
+            KeyCode::Digit5 => material.emissive_power -= EMISSIVE_POWER_STEP,
+            KeyCode::Digit6 => {
+                // Nothing to scale without an emissive colour, so it glows in its own:
+                if material.emissive == Vec4::ZERO && material.emissive_texture == 0 {
+                    material.emissive = material.colour.truncate().extend(0.0);
+                }
+                material.emissive_power += EMISSIVE_POWER_STEP;
+            }
+            KeyCode::Digit7 => material.ior -= IOR_STEP,
+            KeyCode::Digit8 => material.ior += IOR_STEP,
+            _ => continue,
+        }
+        material.metallic = material.metallic.clamp(0.0, 1.0);
+        material.roughness = material.roughness.clamp(0.0, 1.0);
+        material.emissive_power = material.emissive_power.max(0.0);
+        material.ior = material.ior.clamp(MIN_IOR, MAX_IOR);
+    }
+
+    if bytemuck::bytes_of(&before) != bytemuck::bytes_of(material) {
+        info!(
+            "{id:?}: metallic {:.2}, roughness {:.2}, emissive power {:.1}, ior {:.2}",
+            material.metallic, material.roughness, material.emissive_power, material.ior
+        );
+    }
+}
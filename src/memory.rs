@@ -0,0 +1,169 @@
+use bevy_ecs::prelude::*;
+use glam::UVec4;
+use tracing::info;
+
+use crate::{
+    app::BevyApp,
+    binder::{SceneBindings, binder_system},
+    bvh::BVHNodeGPU,
+    environment::Environment,
+    mesh::{GPUVertexData, MeshId, MeshServer},
+    pathtracer::PathtracerOutput,
+    pathtracer_state::PathtracerState,
+    schedule,
+    texture::TextureServer,
+};
+
+pub fn initialize(app: &mut BevyApp) {
+    app.world.init_resource::<MemoryStats>();
+    app.world
+        .get_resource_or_init::<Schedules>()
+        .add_systems(schedule::Update, memory_stats_system.after(binder_system));
+}
+
+// Bytes requested for GPU buffers and textures, by what they hold. Drivers pad and align
+// on top of this, so it's a lower bound on the VRAM in use. Logged whenever it changes
+// once every mesh has loaded.
+#[derive(Resource, Clone, Debug, Default, PartialEq)]
+pub struct MemoryStats {
+    // Packed vertices, indices, BLAS nodes and geometry offsets:
+    pub meshes: u64,
+    pub tlas: u64,
+    // Instances, transforms, materials and lights:
+    pub scene: u64,
+    // Summed over every pathtracer:
+    pub paths: u64,
+    pub samples: u64,
+    pub outputs: u64,
+    // Material textures and the environment map:
+    pub textures: u64,
+    pub instances: u32,
+    pub triangles: u64,
+    // Largest first:
+    pub per_mesh: Vec<MeshMemory>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct MeshMemory {
+    pub mesh: MeshId,
+    pub instances: u32,
+    pub vertices: u32,
+    pub triangles: u32,
+    // Its share of the packed mesh buffers:
+    pub bytes: u64,
+}
+
+impl MemoryStats {
+    pub fn total(&self) -> u64 {
+        self.meshes
+            + self.tlas
+            + self.scene
+            + self.paths
+            + self.samples
+            + self.outputs
+            + self.textures
+    }
+
+    fn log(&self) {
+        info!(
+            "GPU memory: {} total, meshes {}, TLAS {}, scene {}, paths {}, samples {}, outputs {}, textures {}",
+            mb(self.total()),
+            mb(self.meshes),
+            mb(self.tlas),
+            mb(self.scene),
+            mb(self.paths),
+            mb(self.samples),
+            mb(self.outputs),
+            mb(self.textures),
+        );
+        if self.instances > 0 && self.triangles > 0 {
+            info!(
+                "{} instances at {} bytes each, {} triangles at {} bytes each",
+                self.instances,
+                (self.tlas + self.scene) / self.instances as u64,
+                self.triangles,
+                self.meshes / self.triangles,
+            );
+        }
+        for m in &self.per_mesh {
+            info!(
+                "{:?}: {}, {} triangles, {} vertices, {} instances",
+                m.mesh,
+                mb(m.bytes),
+                m.triangles,
+                m.vertices,
+                m.instances
+            );
+        }
+    }
+}
+
+fn mb(bytes: u64) -> String {
+    format!("{:.2} MB", bytes as f64 / (1 << 20) as f64)
+}
+
+// Bytes in every mip level of an uncompressed texture.
+pub fn texture_bytes(texture: &wgpu::Texture) -> u64 {
+    let texel = texture.format().block_copy_size(None).unwrap_or(0) as u64;
+    (0..texture.mip_level_count())
+        .map(|mip| {
+            let size = texture.size().mip_level_size(mip, texture.dimension());
+            size.width as u64 * size.height as u64 * size.depth_or_array_layers as u64 * texel
+        })
+        .sum()
+}
+
+fn memory_stats_system(
+    mut stats: ResMut<MemoryStats>,
+    mesh_server: Res<MeshServer>,
+    bindings: Res<SceneBindings>,
+    texture_server: Res<TextureServer>,
+    environment: Res<Environment>,
+    states: Query<&PathtracerState>,
+    outputs: Query<&PathtracerOutput>,
+) {
+    // Half loaded scenes would log at every step:
+    if mesh_server.is_loading() || bindings.bind_group.is_none() {
+        return;
+    }
+
+    let instances = &bindings.picking.instances;
+    let mut per_mesh = mesh_server
+        .packed_meshes()
+        .enumerate()
+        .map(|(geom_id, (mesh, data))| {
+            let vertices = data.mesh.positions.len();
+            let triangles = data.mesh.faces.len();
+            MeshMemory {
+                mesh,
+                instances: instances
+                    .iter()
+                    .filter(|i| i.geometry_idx as usize == geom_id)
+                    .count() as u32,
+                vertices: vertices as u32,
+                triangles: triangles as u32,
+                bytes: (vertices * size_of::<GPUVertexData>()
+                    + triangles * size_of::<UVec4>()
+                    + data.nodes.len() * size_of::<BVHNodeGPU>()) as u64,
+            }
+        })
+        .collect::<Vec<_>>();
+    per_mesh.sort_by_key(|m| std::cmp::Reverse(m.bytes));
+
+    let new = MemoryStats {
+        meshes: mesh_server.buffer_bytes(),
+        tlas: bindings.tlas_bytes,
+        scene: bindings.scene_bytes,
+        paths: states.iter().map(|s| s.path_bytes).sum(),
+        samples: states.iter().map(|s| s.sample_bytes).sum(),
+        outputs: outputs.iter().map(PathtracerOutput::buffer_bytes).sum(),
+        textures: texture_server.texture_bytes() + texture_bytes(&environment.texture),
+        instances: instances.len() as u32,
+        triangles: per_mesh.iter().map(|m| m.triangles as u64).sum(),
+        per_mesh,
+    };
+    if new != *stats {
+        new.log();
+        *stats = new;
+    }
+}
@@ -1,21 +1,31 @@
-use std::{collections::HashMap, sync::Arc};
+use std::{
+    collections::{HashMap, HashSet},
+    panic::{self, AssertUnwindSafe},
+    sync::Arc,
+};
 
+use anyhow::Context;
 use bevy_ecs::prelude::*;
-use crossbeam::channel::bounded;
-use glam::{UVec3, UVec4, Vec3, Vec4, Vec4Swizzles};
+use crossbeam::channel::{TryRecvError, bounded};
+use glam::{UVec3, UVec4, Vec2, Vec3, Vec4, Vec4Swizzles};
 use itertools::Itertools;
+use tracing::{error, info, warn};
 use wgpu::util::DeviceExt;
 
 use crate::{
     app::BevyApp,
     blas::BLAS,
-    bvh::{AABB, BVH, BVHNodeGPU},
-    render_resources::RenderDevice,
+    bvh::{AABB, BVH, BVHNodeGPU, BvhSettings},
+    material::Material,
+    ply,
+    render_resources::{RenderDevice, storage_budget},
     schedule::{self},
 };
 
 pub fn initialize(app: &mut BevyApp) {
     app.world.insert_resource(MeshServer::default());
+    app.world.init_resource::<BvhSettings>();
+    app.world.init_resource::<Messages<MeshesLoadedEvent>>();
     app.world
         .get_resource_or_init::<Schedules>()
         .add_systems(schedule::Update, mesh_loading_system);
@@ -25,8 +35,40 @@ pub fn initialize(app: &mut BevyApp) {
 pub struct Mesh {
     pub positions: Vec<Vec4>,
     pub normals: Vec<Vec4>,
+    // xyz tangent, w bitangent handedness:
+    pub tangents: Vec<Vec4>,
     pub faces: Vec<UVec4>,
-    // pub uv: Vec<UVec2>,
+    // One per vertex with (0, 0) at the top left of the image, or empty:
+    pub uvs: Vec<Vec2>,
+}
+
+// Relative to the size of the model, so it only welds copies of the same vertex: from_obj
+// scales it by the model's extent after normalizing, which is about 1 in the unit cube.
+pub const WELD_TOLERANCE: f32 = 1e-6;
+
+// Faces with less area than this times their longest edge squared have all but collinear
+// corners, so no normal to shade with. They're dropped on load.
+const DEGENERATE_AREA: f32 = 1e-7;
+
+// Set in a face's w, mirrors FACE_FLAT in common.slang.
+pub const FACE_FLAT: u32 = 1;
+
+// The rest of a face's w above the flags is its material group, mirrors FACE_GROUP_SHIFT
+// in common.slang. Instances give each group a material of its own, see MaterialGroups.
+pub const FACE_GROUP_SHIFT: u32 = 16;
+
+pub fn face_group(face: UVec4) -> u32 {
+    face.w >> FACE_GROUP_SHIFT
+}
+
+// How hit normals are found. Smooth interpolates the vertex normals, Flat uses the
+// geometric normal of each face, with vertices duplicated so none are shared.
+#[derive(Hash, Clone, Copy, PartialEq, Eq, Debug, Default, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ShadingMode {
+    #[default]
+    Smooth,
+    Flat,
 }
 
 #[repr(C)]
@@ -43,6 +85,21 @@ pub struct GPUVertexData {
     position: Vec4,
     normal: Vec4,
     uv: Vec4,
+    tangent: Vec4,
+}
+
+// Where an OBJ's vertices are put before its transform.
+#[derive(Hash, Clone, PartialEq, Eq, Debug, Default)]
+pub enum NormalizeMode {
+    // Centred on the file's mean vertex and scaled into the unit cube on its own, so
+    // every model loads at about the same size.
+    #[default]
+    UnitCube,
+    // Left as authored, so models made at the same scale keep their relative sizes.
+    None,
+    // Fitted into the unit cube as if the files named were one with this one, so the group
+    // keeps its relative sizes and placement. Its members should name the same files.
+    Joint(Vec<String>),
 }
 
 #[derive(Clone, Copy, Component, Debug, Eq, PartialEq, Hash)]
@@ -50,7 +107,12 @@ pub struct MeshId(usize);
 
 #[derive(Hash, Clone, PartialEq, Eq)]
 pub enum MeshDescriptor {
-    TOBJ(String),
+    // Each model tobj splits the file into, by object, group and material, is a material
+    // group of its faces, see obj_materials:
+    TOBJ(String, NormalizeMode),
+    // Primitives are indexed across every mesh in the file, in document order:
+    Gltf { path: String, primitive: usize },
+    Ply(String),
     Rect,
     Cube,
 }
@@ -59,95 +121,213 @@ pub struct MeshData {
     pub nodes: Vec<BVHNodeGPU>,
     pub mesh: Mesh,
     pub aabb: AABB,
+    // One past the highest face group, so at least 1:
+    pub material_groups: u32,
+}
+
+// Where a mesh is in loading, see MeshServer::status.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum MeshStatus {
+    // Queued or on a worker thread:
+    Loading,
+    // Packed into the mesh buffers:
+    Loaded,
+    // The loader's error, or what it panicked with. Instances of it are left out of the
+    // scene. It's never retried, but loading the descriptor again queues a new mesh.
+    Failed(String),
+    // Not an id the server gave out, or dropped by unload_unused:
+    Unloaded,
+}
+
+// Written by mesh_loading_system once the last pending mesh is done and the buffers are
+// regenerated, with the binder yet to pick them up that frame. Failed meshes count as done.
+#[derive(Message, Clone, Copy, Debug)]
+pub struct MeshesLoadedEvent {
+    pub loaded: usize,
+    pub failed: usize,
 }
 
 pub struct MeshLoading {
     descriptor: MeshDescriptor,
+    shading: ShadingMode,
     id: MeshId,
-    rx: Option<crossbeam::channel::Receiver<MeshData>>,
+    rx: Option<crossbeam::channel::Receiver<Result<MeshData, String>>>,
 }
 
 #[derive(Resource, Default)]
 pub struct MeshServer {
     loading: Vec<MeshLoading>,
     data: Vec<Option<MeshData>>,
+    failed: HashMap<MeshId, String>,
     counter: usize,
-    by_desc: HashMap<MeshDescriptor, MeshId>,
+    by_desc: HashMap<(MeshDescriptor, ShadingMode), MeshId>,
     node_buffer: Option<wgpu::Buffer>,
     vertex_buffer: Option<wgpu::Buffer>,
     index_buffer: Option<wgpu::Buffer>,
     offset_buffer: Option<wgpu::Buffer>,
     aabbs: Vec<AABB>,
     mesh_id_to_geom_id: HashMap<usize, u32>,
+    // The mesh id packed at each geometry index:
+    geom_id_to_mesh_id: Vec<usize>,
 }
 
-fn mesh_loading_system(mut mesh_server: ResMut<MeshServer>, device: Res<RenderDevice>) {
-    let MeshServer { loading, data, .. } = mesh_server.bypass_change_detection();
-
-    let mut changed = false;
-    loading.retain_mut(|l| {
-        if let Some(rx) = &l.rx {
-            if let Ok(d) = rx.try_recv() {
-                data[l.id.0] = Some(d);
-                changed = true;
-                false
-            } else {
-                true
-            }
-        } else {
-            l.start();
-            true
-        }
-    });
-
-    if changed {
+pub fn mesh_loading_system(
+    mut mesh_server: ResMut<MeshServer>,
+    bvh_settings: Res<BvhSettings>,
+    device: Res<RenderDevice>,
+    mut loaded_writer: MessageWriter<MeshesLoadedEvent>,
+) {
+    let pending = !mesh_server.all_loaded();
+    let changed = mesh_server
+        .bypass_change_detection()
+        .poll_loading(bvh_settings.blas_leaf_size);
+
+    // A scene of spheres and planes still binds the mesh buffers, see regenerate_buffer:
+    if changed || (mesh_server.node_buffer.is_none() && mesh_server.all_loaded()) {
         mesh_server.regenerate_buffer(device.0.clone());
         mesh_server.set_changed();
     }
+
+    if pending && mesh_server.all_loaded() {
+        let event = MeshesLoadedEvent {
+            loaded: mesh_server.data.iter().flatten().count(),
+            failed: mesh_server.failed.len(),
+        };
+        info!(
+            "Meshes loaded: {} loaded, {} failed",
+            event.loaded, event.failed
+        );
+        loaded_writer.write(event);
+    }
 }
 
 impl MeshLoading {
-    fn start(&mut self) {
+    fn start(&mut self, leaf_size: usize) {
         if self.rx.is_some() {
             return;
         }
 
-        let (tx, rx) = bounded::<MeshData>(1);
-        self.rx = Some(rx);
-
-        rayon::spawn({
-            // let device = device.clone();
-            let descriptor = self.descriptor.clone();
-            move || {
-                let mut load_options = tobj::GPU_LOAD_OPTIONS;
-                load_options.single_index = false;
-                let mesh = match &descriptor {
-                    MeshDescriptor::TOBJ(s) => {
-                        Mesh::from_model(&tobj::load_obj(s, &load_options).unwrap().0[0].mesh)
-                    }
-                    MeshDescriptor::Rect => Mesh::rect(),
-                    MeshDescriptor::Cube => Mesh::cube(),
-                };
-
-                let blas = BLAS::new(mesh);
-                let aabb = blas.node_bounds(0);
-                let mesh = blas.mesh;
-                let nodes = blas
-                    .nodes
-                    .into_iter()
-                    .map(|node| BVHNodeGPU::from(node))
-                    .collect_vec();
+        let descriptor = self.descriptor.clone();
+        let shading = self.shading;
+        self.rx = Some(spawn_loader(move || {
+            load_mesh_data(&descriptor, shading, leaf_size)
+        }));
+    }
+}
 
-                tx.send(MeshData { nodes, mesh, aabb })
-                    .expect("Expected to send mesh data");
+// Runs `load` on the thread pool, sending back its error or what it panicked with as the
+// mesh's failure. Rayon would abort the process on a panic otherwise.
+fn spawn_loader(
+    load: impl FnOnce() -> anyhow::Result<MeshData> + Send + 'static,
+) -> crossbeam::channel::Receiver<Result<MeshData, String>> {
+    let (tx, rx) = bounded::<Result<MeshData, String>>(1);
+    rayon::spawn(move || {
+        let result = match panic::catch_unwind(AssertUnwindSafe(load)) {
+            Ok(result) => result.map_err(|e| format!("{e:#}")),
+            Err(payload) => {
+                let message = payload
+                    .downcast_ref::<&str>()
+                    .map(|s| s.to_string())
+                    .or_else(|| payload.downcast_ref::<String>().cloned())
+                    .unwrap_or_else(|| "unknown cause".to_owned());
+                Err(format!("The loader panicked: {message}"))
             }
-        });
+        };
+        // Nothing is waiting on it if the server's gone:
+        let _ = tx.send(result);
+    });
+    rx
+}
+
+fn load_mesh_data(
+    descriptor: &MeshDescriptor,
+    shading: ShadingMode,
+    leaf_size: usize,
+) -> anyhow::Result<MeshData> {
+    let mut mesh = match descriptor {
+        MeshDescriptor::TOBJ(path, normalize) => Mesh::from_obj(path, normalize)?,
+        MeshDescriptor::Gltf { path, primitive } => Mesh::from_gltf(path, *primitive)?,
+        MeshDescriptor::Ply(path) => Mesh::from_ply(path)?,
+        MeshDescriptor::Rect => Mesh::rect(),
+        MeshDescriptor::Cube => Mesh::cube(),
+    };
+    if shading == ShadingMode::Flat {
+        mesh.flatten();
     }
+
+    let material_groups = mesh.faces.iter().map(|&f| face_group(f)).max();
+    let material_groups = material_groups.map_or(1, |g| g + 1);
+
+    let blas = BLAS::new(mesh, leaf_size);
+    let aabb = blas.node_bounds(0);
+    let mesh = blas.mesh;
+    let nodes = blas
+        .nodes
+        .into_iter()
+        .map(|node| BVHNodeGPU::from(node))
+        .collect_vec();
+
+    Ok(MeshData {
+        nodes,
+        mesh,
+        aabb,
+        material_groups,
+    })
+}
+
+fn load_obj(path: &str) -> anyhow::Result<(Vec<tobj::Model>, Vec<tobj::Material>)> {
+    let mut load_options = tobj::GPU_LOAD_OPTIONS;
+    load_options.single_index = false;
+    let (models, materials) =
+        tobj::load_obj(path, &load_options).with_context(|| format!("Failed to load {path}"))?;
+    // A missing or broken MTL file leaves the models without materials:
+    let materials = materials.unwrap_or_else(|e| {
+        warn!("Failed to load the materials of {path}: {e}");
+        Vec::new()
+    });
+    Ok((models, materials))
+}
+
+// The translated MTL material of each face group of MeshDescriptor::TOBJ, or None for
+// models given none. Parses the whole file, where the mesh is loaded again on a worker
+// thread.
+pub fn obj_materials(path: &str) -> anyhow::Result<Vec<Option<Material>>> {
+    let (models, materials) = load_obj(path)?;
+    if models.is_empty() {
+        anyhow::bail!("{path} has no models");
+    }
+    Ok(models
+        .iter()
+        .map(|m| {
+            m.mesh
+                .material_id
+                .and_then(|id| materials.get(id))
+                .map(Material::from_mtl)
+        })
+        .collect_vec())
+}
+
+// The translated material of each primitive of a glTF file, indexed as
+// MeshDescriptor::Gltf's. Reads the document alone, the buffers are loaded on a worker
+// thread with the mesh.
+pub fn gltf_materials(path: &str) -> anyhow::Result<Vec<Material>> {
+    let document = gltf::Gltf::open(path).with_context(|| format!("Failed to open {path}"))?;
+    Ok(document
+        .meshes()
+        .flat_map(|m| m.primitives())
+        .map(|p| Material::from_gltf(&p.material()))
+        .collect_vec())
 }
 
 impl MeshServer {
     pub fn load_mesh(&mut self, descriptor: MeshDescriptor) -> MeshId {
-        if let Some(id) = self.by_desc.get(&descriptor) {
+        self.load_mesh_shaded(descriptor, ShadingMode::Smooth)
+    }
+
+    // Each shading mode of a descriptor is a distinct mesh.
+    pub fn load_mesh_shaded(&mut self, descriptor: MeshDescriptor, shading: ShadingMode) -> MeshId {
+        let key = (descriptor, shading);
+        if let Some(id) = self.by_desc.get(&key) {
             return *id;
         }
         let id = MeshId(self.counter);
@@ -155,15 +335,83 @@ impl MeshServer {
         self.counter += 1;
 
         self.loading.push(MeshLoading {
-            descriptor: descriptor.clone(),
+            descriptor: key.0.clone(),
+            shading,
             id,
             rx: None,
         });
 
-        self.by_desc.insert(descriptor, id);
+        self.by_desc.insert(key, id);
         id
     }
 
+    // Starts queued loads and takes in finished ones, returning whether any mesh loaded.
+    fn poll_loading(&mut self, leaf_size: usize) -> bool {
+        let Self {
+            loading,
+            data,
+            failed,
+            by_desc,
+            ..
+        } = self;
+
+        let mut changed = false;
+        loading.retain_mut(|l| {
+            let Some(rx) = &l.rx else {
+                l.start(leaf_size);
+                return true;
+            };
+            match rx.try_recv() {
+                Ok(Ok(d)) => {
+                    data[l.id.0] = Some(d);
+                    changed = true;
+                    false
+                }
+                Err(TryRecvError::Empty) => true,
+                result => {
+                    // The loader always sends, short of the pool being torn down:
+                    let e = match result {
+                        Ok(Err(e)) => e,
+                        _ => "The loader stopped without a result".to_owned(),
+                    };
+                    error!("Failed to load {:?}: {e}", l.id);
+                    failed.insert(l.id, e);
+                    // So loading it again tries again:
+                    by_desc.remove(&(l.descriptor.clone(), l.shading));
+                    false
+                }
+            }
+        });
+        changed
+    }
+
+    pub fn status(&self, id: MeshId) -> MeshStatus {
+        if let Some(e) = self.failed.get(&id) {
+            MeshStatus::Failed(e.clone())
+        } else if self.loading.iter().any(|l| l.id == id) {
+            MeshStatus::Loading
+        } else if self.mesh_data(id).is_some() {
+            MeshStatus::Loaded
+        } else {
+            MeshStatus::Unloaded
+        }
+    }
+
+    // Meshes still queued or on a worker thread:
+    pub fn pending(&self) -> usize {
+        self.loading.len()
+    }
+
+    // Whether every mesh asked for is loaded or failed, so the mesh buffers hold all there
+    // is to trace. Failures are logged and left out of the scene, see failures.
+    pub fn all_loaded(&self) -> bool {
+        !self.is_loading()
+    }
+
+    pub fn failures(&self) -> impl Iterator<Item = (MeshId, &str)> {
+        self.failed.iter().map(|(id, e)| (*id, e.as_str()))
+    }
+
     pub fn mesh_data(&self, id: MeshId) -> Option<&MeshData> {
         if id.0 >= self.data.len() {
             return None;
@@ -187,6 +435,31 @@ impl MeshServer {
         &self.offset_buffer
     }
 
+    // Bytes in the packed vertex, index, BVH node and offset buffers.
+    pub fn buffer_bytes(&self) -> u64 {
+        [
+            &self.vertex_buffer,
+            &self.index_buffer,
+            &self.node_buffer,
+            &self.offset_buffer,
+        ]
+        .into_iter()
+        .flatten()
+        .map(wgpu::Buffer::size)
+        .sum()
+    }
+
+    // The meshes in the packed buffers, in geometry index order.
+    pub fn packed_meshes(&self) -> impl Iterator<Item = (MeshId, &MeshData)> {
+        self.geom_id_to_mesh_id
+            .iter()
+            .filter_map(|&id| Some((MeshId(id), self.data[id].as_ref()?)))
+    }
+
+    pub fn is_loading(&self) -> bool {
+        !self.loading.is_empty()
+    }
+
     pub fn aabbs(&self) -> &Vec<AABB> {
         &self.aabbs
     }
@@ -195,6 +468,40 @@ impl MeshServer {
         self.mesh_id_to_geom_id.get(&id.0).copied()
     }
 
+    // The loaded mesh an instance's geometry index refers to, as last packed.
+    pub fn geometry(&self, geom_id: u32) -> Option<&MeshData> {
+        let mesh_id = *self.geom_id_to_mesh_id.get(geom_id as usize)?;
+        self.data.get(mesh_id)?.as_ref()
+    }
+
+    // Drops the loaded meshes not in `used` and repacks the rest, so a scene's meshes don't
+    // stay on the GPU once it's gone. Meshes still loading are kept, and loading a dropped
+    // descriptor again gives it a new id.
+    pub fn unload_unused(&mut self, used: &HashSet<MeshId>, device: Arc<wgpu::Device>) {
+        let Self {
+            data,
+            by_desc,
+            loading,
+            failed,
+            ..
+        } = self;
+        failed.retain(|id, _| used.contains(id));
+        let mut unloaded = false;
+        for (id, mesh) in data.iter_mut().enumerate() {
+            if mesh.is_some() && !used.contains(&MeshId(id)) {
+                *mesh = None;
+                unloaded = true;
+            }
+        }
+        if !unloaded {
+            return;
+        }
+        by_desc.retain(|_, id| data[id.0].is_some() || loading.iter().any(|l| l.id == *id));
+
+        // With nothing left to pack this binds just the padding:
+        self.regenerate_buffer(device);
+    }
+
     pub fn regenerate_buffer(&mut self, device: Arc<wgpu::Device>) {
         let mut vertices = Vec::new();
         let mut indices = Vec::new();
@@ -202,9 +509,16 @@ impl MeshServer {
         let mut aabbs = Vec::new();
 
         let mut mesh_id_to_geom_id = HashMap::new();
+        let mut geom_id_to_mesh_id = Vec::new();
         let mut geom_id: u32 = 0;
         let mut offsets = Vec::new();
 
+        // Meshes that would grow a buffer past what the device can bind are left out, so
+        // smaller GPUs still get as much of the scene as fits:
+        let budget = storage_budget(&device.limits());
+        let fits =
+            |len: usize, extra: usize, stride: usize| ((len + extra) * stride) as u64 <= budget;
+
         for (mesh_id, mesh_data) in self
             .data
             .iter()
@@ -214,11 +528,26 @@ impl MeshServer {
             let Mesh {
                 positions,
                 normals,
+                tangents,
                 faces,
+                uvs,
             } = mesh_data.mesh.clone();
 
+            if !(fits(vertices.len(), positions.len(), size_of::<GPUVertexData>())
+                && fits(indices.len(), faces.len(), size_of::<UVec4>())
+                && fits(nodes.len(), mesh_data.nodes.len(), size_of::<BVHNodeGPU>()))
+            {
+                warn!(
+                    "Mesh {mesh_id} doesn't fit in the device's {budget} byte storage buffers, skipping it"
+                );
+                continue;
+            }
+            // Vertices without uvs all sample the top left texel:
+            let uvs = uvs.into_iter().chain(std::iter::repeat(Vec2::ZERO));
+
             // Map the mesh id to geometry id for packing:
             mesh_id_to_geom_id.insert(mesh_id, geom_id);
+            geom_id_to_mesh_id.push(mesh_id);
             geom_id += 1;
 
             // Produce offset for start of this geometry in each buffer:
@@ -235,10 +564,13 @@ impl MeshServer {
                 positions
                     .into_iter()
                     .zip(normals)
-                    .map(|(position, normal)| GPUVertexData {
+                    .zip(tangents)
+                    .zip(uvs)
+                    .map(|(((position, normal), tangent), uv)| GPUVertexData {
                         position,
                         normal,
-                        uv: Vec4::ZERO,
+                        uv: uv.extend(0.0).extend(0.0),
+                        tangent,
                     })
                     .collect_vec()
                     .as_slice(),
@@ -247,9 +579,19 @@ impl MeshServer {
         }
 
         self.mesh_id_to_geom_id = mesh_id_to_geom_id;
+        self.geom_id_to_mesh_id = geom_id_to_mesh_id;
 
         self.aabbs = aabbs;
 
+        // Can't bind an empty buffer. With no geometry index pointing at it the padding is
+        // never read:
+        if geom_id == 0 {
+            vertices.push(GPUVertexData::default());
+            indices.push(UVec4::ZERO);
+            nodes.push(BVHNodeGPU::default());
+            offsets.push(GeometryOffsets::default());
+        }
+
         self.node_buffer = Some(
             device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
                 label: Some("Mesh BVHNode Buffer"),
@@ -284,6 +626,41 @@ impl MeshServer {
     }
 }
 
+// Centres points on the mean of a set and scales them down uniformly, such that the set's
+// furthest along any axis is on the unit cube. A set collapsed to a point is left
+// unscaled.
+#[derive(Clone, Copy, Debug)]
+pub struct UnitCubeFit {
+    center: Vec3,
+    extent: f32,
+}
+
+impl UnitCubeFit {
+    // Leaves the points where they are:
+    pub const IDENTITY: Self = Self {
+        center: Vec3::ZERO,
+        extent: 1.0,
+    };
+
+    pub fn of(all: &[Vec3]) -> Self {
+        if all.is_empty() {
+            return Self::IDENTITY;
+        }
+        let center = all.iter().sum::<Vec3>() / (all.len() as f32);
+
+        let extent = all
+            .iter()
+            .map(|p| (p - center).abs().max_element())
+            .fold(0.0, f32::max);
+        let extent = if extent.is_normal() { extent } else { 1.0 };
+        Self { center, extent }
+    }
+
+    pub fn apply(&self, p: Vec3) -> Vec4 {
+        ((p - self.center) / self.extent).extend(1.0)
+    }
+}
+
 impl Mesh {
     pub fn new(positions: Vec<Vec4>, indices: Vec<u32>, normals: Vec<Vec4>) -> Self {
         let faces = indices
@@ -291,44 +668,94 @@ impl Mesh {
             .into_iter()
             .map(|p| UVec3::from_slice(p).extend(0))
             .collect_vec();
+        let faces = Self::drop_degenerate(&positions, faces);
 
         let normals = if normals.len() >= positions.len() && !normals.is_empty() {
             normals
         } else {
-            Self::compute_vertex_normals_ccw(&positions, &indices)
+            Self::compute_vertex_normals_ccw(&positions, &Self::face_indices(&faces))
         };
 
-        Self {
+        let mut mesh = Self {
             positions,
             normals,
+            tangents: Vec::new(),
             faces,
+            uvs: Vec::new(),
+        };
+        mesh.compute_tangents();
+        mesh
+    }
+
+    // Every model of the file, each its own face group in order. A joint group's other
+    // files are parsed too, for their bounds.
+    pub fn from_obj(path: &str, normalize: &NormalizeMode) -> anyhow::Result<Self> {
+        let (models, _) = load_obj(path)?;
+        if models.is_empty() {
+            anyhow::bail!("{path} has no models");
         }
+        let positions = |models: &[tobj::Model]| {
+            models
+                .iter()
+                .flat_map(|m| m.mesh.positions.chunks_exact(3))
+                .map(Vec3::from_slice)
+                .collect_vec()
+        };
+        let own = UnitCubeFit::of(&positions(&models));
+        let fit = match normalize {
+            NormalizeMode::UnitCube => own,
+            NormalizeMode::None => UnitCubeFit::IDENTITY,
+            NormalizeMode::Joint(group) => {
+                let mut all = positions(&models);
+                for other in group.iter().filter(|&other| other != path) {
+                    all.extend(positions(&load_obj(other)?.0));
+                }
+                UnitCubeFit::of(&all)
+            }
+        };
+        // The model's extent once fitted, so a tiny or huge one left as authored welds
+        // like it would in the unit cube:
+        let weld_tolerance = WELD_TOLERANCE * own.extent / fit.extent;
+
+        let mut mesh = Self::default();
+        for (i, model) in models.iter().enumerate() {
+            mesh.append(Self::from_model(model, fit, weld_tolerance), i as u32);
+        }
+        if mesh.faces.is_empty() {
+            anyhow::bail!("{path} has no triangles");
+        }
+        Ok(mesh)
+    }
+
+    // Adds `other`, all in group 0, as material group `group`. If only one of the two has uvs, the
+    // other's vertices are given zeros.
+    fn append(&mut self, mut other: Mesh, group: u32) {
+        let offset = self.positions.len() as u32;
+        if self.uvs.is_empty() != other.uvs.is_empty() {
+            self.uvs.resize(self.positions.len(), Vec2::ZERO);
+            other.uvs.resize(other.positions.len(), Vec2::ZERO);
+        }
+        self.faces.extend(
+            other
+                .faces
+                .into_iter()
+                .map(|f| (f.xyz() + offset).extend(f.w | group << FACE_GROUP_SHIFT)),
+        );
+        self.positions.extend(other.positions);
+        self.normals.extend(other.normals);
+        self.tangents.extend(other.tangents);
+        self.uvs.extend(other.uvs);
     }
 
-    pub fn from_model(model: &tobj::Mesh) -> Self {
+    // Vertices within `weld_tolerance` of each other in every attribute are merged. Moved
+    // by `fit`, which the file's other models share so they stay where they were relative
+    // to each other.
+    pub fn from_model(model: &tobj::Model, fit: UnitCubeFit, weld_tolerance: f32) -> Self {
+        let model = &model.mesh;
         let positions = model
             .positions
             .chunks_exact(3)
-            .map(|chunk| Vec3::from_slice(chunk).extend(0.0))
-            .collect_vec();
-
-        let len = positions.len();
-        let center: Vec4 = positions.iter().sum::<Vec4>() / (len as f32);
-
-        let positions = positions.into_iter().map(|p| p - center).collect_vec();
-
-        // Calculate the greatest distance from center
-        // so we can scale down such that furthest point is on the unit cube
-        let extent: Vec4 = positions
-            .iter()
-            .copied()
-            .reduce(|acc, p| p.max(acc))
-            .unwrap_or_default();
-
-        let positions = positions
-            .into_iter()
-            .map(|p| p.xyz() / extent.xyz())
-            .map(|p| p.extend(1.0))
+            .map(|p| fit.apply(Vec3::from_slice(p)))
             .collect_vec();
 
         let faces = model
@@ -336,6 +763,7 @@ impl Mesh {
             .chunks_exact(3)
             .map(|chunk| UVec3::from_slice(chunk).extend(0))
             .collect_vec();
+        let faces = Self::drop_degenerate(&positions, faces);
 
         let normals = if model.normals.len() >= model.positions.len() && !model.normals.is_empty() {
             model
@@ -344,14 +772,353 @@ impl Mesh {
                 .map(|c| Vec3::from_slice(c).extend(0.0))
                 .collect_vec()
         } else {
-            Self::compute_vertex_normals_ccw(&positions, &model.indices)
+            Self::compute_vertex_normals_ccw(&positions, &Self::face_indices(&faces))
         };
 
-        Self {
+        // Without single_index the uvs only line up with the positions if they share indices.
+        // OBJ puts v = 0 at the bottom of the image, so flip it:
+        let uvs = if model.texcoords.len() / 2 >= positions.len()
+            && (model.texcoord_indices.is_empty() || model.texcoord_indices == model.indices)
+        {
+            model
+                .texcoords
+                .chunks_exact(2)
+                .map(|c| Vec2::new(c[0], 1.0 - c[1]))
+                .collect_vec()
+        } else {
+            Vec::new()
+        };
+
+        let mut mesh = Self {
             positions,
             normals,
+            tangents: Vec::new(),
             faces,
+            uvs,
+        };
+        mesh.remove_unused_vertices();
+        mesh.weld(weld_tolerance);
+        mesh
+    }
+
+    // The faces without those of near zero area, see DEGENERATE_AREA, or with non-finite
+    // corners. They'd only give the BVH empty leaves and the shading NaN normals.
+    fn drop_degenerate(positions: &[Vec4], faces: Vec<UVec4>) -> Vec<UVec4> {
+        let total = faces.len();
+        let faces = faces
+            .into_iter()
+            .filter(|face| {
+                let [p0, p1, p2] = face.xyz().to_array().map(|i| positions[i as usize].xyz());
+                let (e0, e1, e2) = (p1 - p0, p2 - p1, p0 - p2);
+                let longest = e0
+                    .length_squared()
+                    .max(e1.length_squared())
+                    .max(e2.length_squared());
+                // Also false for NaN:
+                0.5 * e0.cross(-e2).length() > DEGENERATE_AREA * longest
+            })
+            .collect_vec();
+        if faces.len() < total {
+            warn!(
+                "Dropped {} degenerate triangles of {total}, leaving {}",
+                total - faces.len(),
+                faces.len()
+            );
         }
+        faces
+    }
+
+    fn face_indices(faces: &[UVec4]) -> Vec<u32> {
+        faces.iter().flat_map(|f| f.xyz().to_array()).collect_vec()
+    }
+
+    // Drops the vertices no face uses, as those left by drop_degenerate. Attributes not
+    // given per vertex are cleared.
+    fn remove_unused_vertices(&mut self) {
+        let mut remap = vec![u32::MAX; self.positions.len()];
+        let mut used = Vec::new();
+        for face in &mut self.faces {
+            let corners = face.xyz().to_array().map(|i| {
+                if remap[i as usize] == u32::MAX {
+                    remap[i as usize] = used.len() as u32;
+                    used.push(i as usize);
+                }
+                remap[i as usize]
+            });
+            *face = UVec3::from_array(corners).extend(face.w);
+        }
+        if used.len() == self.positions.len() {
+            return;
+        }
+
+        fn keep<T: Copy>(values: &mut Vec<T>, used: &[usize], count: usize) {
+            *values = if values.len() >= count {
+                used.iter().map(|&i| values[i]).collect_vec()
+            } else {
+                Vec::new()
+            };
+        }
+        let count = self.positions.len();
+        keep(&mut self.positions, &used, count);
+        keep(&mut self.normals, &used, count);
+        keep(&mut self.tangents, &used, count);
+        keep(&mut self.uvs, &used, count);
+    }
+
+    // The points fitted into the unit cube, see UnitCubeFit.
+    fn fit_unit_cube(positions: Vec<Vec3>) -> Vec<Vec4> {
+        let fit = UnitCubeFit::of(&positions);
+        positions.into_iter().map(|p| fit.apply(p)).collect_vec()
+    }
+
+    // Fitted into the unit cube like OBJ models. Normals are computed unless every vertex
+    // has one.
+    pub fn from_ply(path: &str) -> anyhow::Result<Self> {
+        let ply = ply::read_ply(std::path::Path::new(path))?;
+        if ply.indices.is_empty() {
+            anyhow::bail!("{path} has no triangles");
+        }
+        let positions = Self::fit_unit_cube(ply.positions);
+        let normals = ply
+            .normals
+            .into_iter()
+            .map(|n| n.normalize_or_zero().extend(0.0))
+            .collect_vec();
+        let mesh = Self::new(positions, ply.indices, normals);
+        if mesh.faces.is_empty() {
+            anyhow::bail!("{path} has only degenerate triangles");
+        }
+        Ok(mesh)
+    }
+
+    pub fn from_gltf(path: &str, primitive: usize) -> anyhow::Result<Self> {
+        let (document, buffers, _) =
+            gltf::import(path).with_context(|| format!("Failed to import {path}"))?;
+
+        let p = document
+            .meshes()
+            .flat_map(|m| m.primitives())
+            .nth(primitive)
+            .with_context(|| format!("{path} has no primitive {primitive}"))?;
+
+        if p.mode() != gltf::mesh::Mode::Triangles {
+            anyhow::bail!("{path} primitive {primitive} is not a triangle list");
+        }
+
+        let reader = p.reader(|buffer| Some(&buffers[buffer.index()]));
+
+        let positions = reader
+            .read_positions()
+            .with_context(|| format!("{path} primitive {primitive} has no positions"))?
+            .map(|p| Vec3::from_array(p).extend(1.0))
+            .collect_vec();
+
+        // Non-indexed primitives are an implicit triangle list:
+        let indices = match reader.read_indices() {
+            Some(indices) => indices.into_u32().collect_vec(),
+            None => (0..positions.len() as u32).collect_vec(),
+        };
+
+        // Missing normals are left empty so `new` computes them:
+        let normals = reader
+            .read_normals()
+            .map(|n| n.map(|n| Vec3::from_array(n).extend(0.0)).collect_vec())
+            .unwrap_or_default();
+
+        let mut mesh = Self::new(positions, indices, normals);
+        if let Some(uvs) = reader.read_tex_coords(0) {
+            mesh.uvs = uvs.into_f32().map(Vec2::from_array).collect_vec();
+            mesh.compute_tangents();
+        }
+        if mesh.faces.is_empty() {
+            anyhow::bail!("{path} primitive {primitive} has only degenerate triangles");
+        }
+
+        Ok(mesh)
+    }
+
+    // Per-vertex tangents by Lengyel's method, orthonormalised against the normals.
+    // Without uvs (one per vertex) a planar projection along each normal's
+    // dominant axis is used instead.
+    pub fn compute_tangents(&mut self) {
+        let uvs = if self.uvs.len() >= self.positions.len() {
+            self.uvs.clone()
+        } else {
+            self.planar_uvs()
+        };
+
+        let mut tan = vec![Vec3::ZERO; self.positions.len()];
+        let mut bitan = vec![Vec3::ZERO; self.positions.len()];
+
+        for face in &self.faces {
+            let [i0, i1, i2] = face.xyz().to_array().map(|i| i as usize);
+
+            let e1 = (self.positions[i1] - self.positions[i0]).xyz();
+            let e2 = (self.positions[i2] - self.positions[i0]).xyz();
+            let d1 = uvs[i1] - uvs[i0];
+            let d2 = uvs[i2] - uvs[i0];
+
+            // Zero-area uv triangles have no defined tangent frame:
+            let det = d1.x * d2.y - d2.x * d1.y;
+            if det.abs() <= f32::EPSILON {
+                continue;
+            }
+            let r = 1.0 / det;
+
+            let s = (e1 * d2.y - e2 * d1.y) * r;
+            let t = (e2 * d1.x - e1 * d2.x) * r;
+            if !s.is_finite() || !t.is_finite() {
+                continue;
+            }
+
+            for i in [i0, i1, i2] {
+                tan[i] += s;
+                bitan[i] += t;
+            }
+        }
+
+        self.tangents = self
+            .normals
+            .iter()
+            .zip(tan.into_iter().zip(bitan))
+            .map(|(n, (t, b))| {
+                let n = n.xyz();
+                let t = (t - n * n.dot(t)).try_normalize();
+                // Vertices no triangle contributed to still need a valid frame:
+                let t = t.unwrap_or_else(|| n.any_orthonormal_vector());
+                let w = if n.cross(t).dot(b) < 0.0 { -1.0 } else { 1.0 };
+                t.extend(w)
+            })
+            .collect_vec();
+    }
+
+    // Merges each vertex into the first kept one within `tolerance` of it in every
+    // component of its position, normal and uv, remapping the faces onto the survivors.
+    // Positions are bucketed into cells of size `tolerance` and the cells around a vertex's
+    // own are searched too, so close vertices either side of a cell boundary still merge.
+    // Zero or less only merges exact copies. Tangents are recomputed for the welded mesh.
+    pub fn weld(&mut self, tolerance: f32) {
+        let tolerance = tolerance.max(0.0);
+        let cell = |p: Vec4| {
+            p.xyz().to_array().map(|v| {
+                if tolerance > 0.0 {
+                    (v / tolerance).floor() as i64
+                } else {
+                    v.to_bits() as i64
+                }
+            })
+        };
+        let has_uvs = self.uvs.len() >= self.positions.len();
+        let uv = |i: usize| if has_uvs { self.uvs[i] } else { Vec2::ZERO };
+        let close = |a: usize, b: usize| {
+            (self.positions[a] - self.positions[b]).abs().max_element() <= tolerance
+                && (self.normals[a] - self.normals[b]).abs().max_element() <= tolerance
+                && (uv(a) - uv(b)).abs().max_element() <= tolerance
+        };
+
+        // Of the kept vertices, by their original index:
+        let mut cells = HashMap::<[i64; 3], Vec<usize>>::new();
+        let mut new_index = vec![0; self.positions.len()];
+        let mut kept = Vec::new();
+        let mut remap = Vec::with_capacity(self.positions.len());
+        for i in 0..self.positions.len() {
+            let [x, y, z] = cell(self.positions[i]);
+            let survivor = (-1..=1)
+                .cartesian_product(-1..=1)
+                .cartesian_product(-1..=1)
+                .filter_map(|((dx, dy), dz)| cells.get(&[x + dx, y + dy, z + dz]))
+                .flatten()
+                .copied()
+                .filter(|&j| close(i, j))
+                .min();
+            let index = match survivor {
+                Some(j) => new_index[j],
+                None => {
+                    cells.entry([x, y, z]).or_default().push(i);
+                    new_index[i] = kept.len() as u32;
+                    kept.push(i);
+                    new_index[i]
+                }
+            };
+            remap.push(index);
+        }
+        let positions = kept.iter().map(|&i| self.positions[i]).collect_vec();
+        let normals = kept.iter().map(|&i| self.normals[i]).collect_vec();
+        let uvs = if has_uvs {
+            kept.iter().map(|&i| self.uvs[i]).collect_vec()
+        } else {
+            Vec::new()
+        };
+
+        for face in &mut self.faces {
+            let [i0, i1, i2] = face.xyz().to_array().map(|i| remap[i as usize]);
+            *face = UVec4::new(i0, i1, i2, face.w);
+        }
+        self.positions = positions;
+        self.normals = normals;
+        self.uvs = uvs;
+        self.compute_tangents();
+    }
+
+    // Gives every face its own three vertices carrying the face normal, so nothing
+    // is shared with its neighbours. Faces keep their order and group and are flagged flat.
+    pub fn flatten(&mut self) {
+        let mut positions = Vec::with_capacity(self.faces.len() * 3);
+        let mut normals = Vec::with_capacity(self.faces.len() * 3);
+        let mut tangents = Vec::with_capacity(self.faces.len() * 3);
+        let has_uvs = self.uvs.len() >= self.positions.len();
+        let mut uvs = Vec::with_capacity(if has_uvs { self.faces.len() * 3 } else { 0 });
+
+        for face in &self.faces {
+            let [i0, i1, i2] = face.xyz().to_array().map(|i| i as usize);
+            let e1 = (self.positions[i1] - self.positions[i0]).xyz();
+            let e2 = (self.positions[i2] - self.positions[i0]).xyz();
+            // Degenerate faces can't be hit anyway, keep their smooth normal:
+            let n = e1
+                .cross(e2)
+                .try_normalize()
+                .unwrap_or(self.normals[i0].xyz());
+
+            for i in [i0, i1, i2] {
+                positions.push(self.positions[i]);
+                normals.push(n.extend(0.0));
+                if has_uvs {
+                    uvs.push(self.uvs[i]);
+                }
+                // Keep the uv derived tangents, re-orthonormalised to the face:
+                let t = self.tangents[i];
+                let t3 = (t.xyz() - n * n.dot(t.xyz()))
+                    .try_normalize()
+                    .unwrap_or_else(|| n.any_orthonormal_vector());
+                tangents.push(t3.extend(t.w));
+            }
+        }
+
+        self.faces = (0..self.faces.len() as u32)
+            .zip(&self.faces)
+            .map(|(f, face)| UVec4::new(f * 3, f * 3 + 1, f * 3 + 2, face.w | FACE_FLAT))
+            .collect_vec();
+        self.positions = positions;
+        self.normals = normals;
+        self.tangents = tangents;
+        self.uvs = uvs;
+    }
+
+    fn planar_uvs(&self) -> Vec<Vec2> {
+        self.positions
+            .iter()
+            .zip(&self.normals)
+            .map(|(p, n)| {
+                let n = n.xyz().abs();
+                if n.x >= n.y && n.x >= n.z {
+                    p.zy()
+                } else if n.y >= n.z {
+                    p.xz()
+                } else {
+                    p.xy()
+                }
+            })
+            .collect_vec()
     }
 
     fn compute_vertex_normals_ccw(positions: &Vec<Vec4>, indices: &[u32]) -> Vec<Vec4> {
@@ -429,11 +1196,20 @@ impl Mesh {
 
         let faces = vec![UVec4::new(0, 1, 2, 0), UVec4::new(0, 2, 3, 0)];
 
-        Self {
+        let mut mesh = Self {
             positions,
             normals,
+            tangents: Vec::new(),
             faces,
-        }
+            uvs: vec![
+                Vec2::new(0.0, 1.0),
+                Vec2::new(1.0, 1.0),
+                Vec2::new(1.0, 0.0),
+                Vec2::new(0.0, 0.0),
+            ],
+        };
+        mesh.compute_tangents();
+        mesh
     }
 
     pub fn cube() -> Self {
@@ -528,10 +1304,337 @@ impl Mesh {
         .map(UVec4::from_array)
         .collect_vec();
 
-        Self {
+        let mut mesh = Self {
             positions,
             normals,
+            tangents: Vec::new(),
             faces,
+            uvs: Vec::new(),
+        };
+        // Each face covers the whole image once:
+        mesh.uvs = mesh
+            .planar_uvs()
+            .into_iter()
+            .map(|uv| Vec2::new(uv.x + 0.5, 0.5 - uv.y))
+            .collect_vec();
+        mesh.compute_tangents();
+        mesh
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use super::*;
+    use crate::traverse::Ray;
+
+    const TEAPOT: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/assets/teapot.obj");
+
+    // Polls as mesh_loading_system does, without the buffers it would regenerate for the GPU:
+    fn settle(server: &mut MeshServer) {
+        let start = Instant::now();
+        while !server.all_loaded() {
+            assert!(
+                start.elapsed() < Duration::from_secs(30),
+                "Meshes never settled"
+            );
+            server.poll_loading(4);
+            std::thread::sleep(Duration::from_millis(1));
+        }
+    }
+
+    #[test]
+    fn gltf_primitives_load_as_their_own_meshes() {
+        // An indexed quad with normals and a non-indexed quad without:
+        let path = concat!(env!("CARGO_MANIFEST_DIR"), "/assets/two_quads.gltf");
+        let mut server = MeshServer::default();
+        let ids = (0..2)
+            .map(|primitive| {
+                server.load_mesh(MeshDescriptor::Gltf {
+                    path: path.to_owned(),
+                    primitive,
+                })
+            })
+            .collect_vec();
+        assert_ne!(ids[0], ids[1]);
+        settle(&mut server);
+
+        for &id in &ids {
+            assert_eq!(server.status(id), MeshStatus::Loaded);
+            let data = server.mesh_data(id).unwrap();
+            assert_eq!(data.mesh.faces.len(), 2);
+            assert_eq!(data.mesh.normals.len(), data.mesh.positions.len());
+            assert!(data.mesh.normals.iter().all(|n| n.xyz().is_normalized()));
+        }
+
+        let materials = gltf_materials(path).unwrap();
+        assert_eq!(materials.len(), 2);
+        assert_eq!(materials[0].colour, Vec4::new(1.0, 0.0, 0.0, 1.0));
+        assert_eq!(materials[1].metallic, 1.0);
+    }
+
+    #[test]
+    fn failed_loads_settle_beside_good_ones() {
+        let mut server = MeshServer::default();
+        let cube = server.load_mesh(MeshDescriptor::Cube);
+        let missing = server.load_mesh(MeshDescriptor::Ply("no/such/mesh.ply".to_owned()));
+
+        // A loader that panics, queued as load_mesh_shaded would:
+        let panicked = MeshId(server.counter);
+        server.counter += 1;
+        server.data.push(None);
+        server.loading.push(MeshLoading {
+            descriptor: MeshDescriptor::Rect,
+            shading: ShadingMode::Smooth,
+            id: panicked,
+            rx: Some(spawn_loader(|| panic!("Bad mesh"))),
+        });
+
+        assert_eq!(server.status(cube), MeshStatus::Loading);
+        assert_eq!(server.pending(), 3);
+        settle(&mut server);
+
+        assert_eq!(server.status(cube), MeshStatus::Loaded);
+        assert_eq!(server.mesh_data(cube).unwrap().mesh.faces.len(), 12);
+        let MeshStatus::Failed(e) = server.status(missing) else {
+            panic!("Expected the missing mesh to fail");
+        };
+        assert!(e.contains("no/such/mesh.ply"), "{e}");
+        let MeshStatus::Failed(e) = server.status(panicked) else {
+            panic!("Expected the panicking loader to fail");
+        };
+        assert!(e.contains("Bad mesh"), "{e}");
+        assert_eq!(server.failures().count(), 2);
+
+        // A failed descriptor is loaded again as a new mesh:
+        let retried = server.load_mesh(MeshDescriptor::Ply("no/such/mesh.ply".to_owned()));
+        assert_ne!(retried, missing);
+        assert_eq!(server.status(retried), MeshStatus::Loading);
+    }
+
+    #[test]
+    fn weld_merges_across_cell_boundaries() {
+        // 0 and 1 are closer than the tolerance but in different cells, 4 is just too far:
+        let positions = [
+            Vec3::new(0.099, 0.0, 0.0),
+            Vec3::new(0.101, 0.0, 0.0),
+            Vec3::new(0.5, 0.0, 0.0),
+            Vec3::new(0.0, 0.5, 0.0),
+            Vec3::new(0.115, 0.0, 0.0),
+        ];
+        let mut mesh = Mesh {
+            positions: positions.map(|p| p.extend(1.0)).to_vec(),
+            normals: vec![Vec4::Z; positions.len()],
+            tangents: Vec::new(),
+            faces: vec![
+                UVec4::new(0, 2, 3, 0),
+                UVec4::new(1, 2, 3, 0),
+                UVec4::new(4, 2, 3, 0),
+            ],
+            uvs: Vec::new(),
+        };
+        mesh.weld(0.01);
+        assert_eq!(mesh.positions.len(), 4);
+        assert_eq!(mesh.faces[0], mesh.faces[1]);
+        assert_ne!(mesh.faces[0], mesh.faces[2]);
+        assert_eq!(mesh.tangents.len(), 4);
+    }
+
+    #[test]
+    fn weld_shrinks_teapot_without_changing_hits() {
+        let welded = Mesh::from_obj(TEAPOT, &NormalizeMode::UnitCube).unwrap();
+        // Three vertices of its own for every face, as OBJs without single_index load:
+        let corners = welded
+            .faces
+            .iter()
+            .flat_map(|f| f.xyz().to_array().map(|i| i as usize))
+            .collect_vec();
+        let mut exploded = Mesh {
+            positions: corners.iter().map(|&i| welded.positions[i]).collect(),
+            normals: corners.iter().map(|&i| welded.normals[i]).collect(),
+            tangents: Vec::new(),
+            faces: (0..welded.faces.len() as u32)
+                .zip(&welded.faces)
+                .map(|(f, face)| UVec4::new(f * 3, f * 3 + 1, f * 3 + 2, face.w))
+                .collect(),
+            uvs: if welded.uvs.is_empty() {
+                Vec::new()
+            } else {
+                corners.iter().map(|&i| welded.uvs[i]).collect()
+            },
+        };
+        exploded.compute_tangents();
+        let exploded_vertices = exploded.positions.len();
+        let unwelded = BLAS::new(exploded.clone(), 4);
+
+        exploded.weld(WELD_TOLERANCE);
+        assert_eq!(exploded.positions.len(), welded.positions.len());
+        assert!(
+            exploded.positions.len() * 4 < exploded_vertices,
+            "Only welded {exploded_vertices} vertices down to {}",
+            exploded.positions.len()
+        );
+
+        let rewelded = BLAS::new(exploded, 4);
+        let mut hits = 0;
+        for (x, y) in (-8..=8).cartesian_product(-8..=8) {
+            let ray = Ray {
+                pos: Vec3::new(x as f32 * 0.06, y as f32 * 0.06, 2.0),
+                dir: Vec3::NEG_Z,
+            };
+            let (a, b) = (unwelded.intersect(ray), rewelded.intersect(ray));
+            assert_eq!(
+                a.map(|h| (h.triangle_id, h.t, h.normal)),
+                b.map(|h| (h.triangle_id, h.t, h.normal)),
+                "{ray:?}"
+            );
+            hits += a.is_some() as usize;
+        }
+        assert!(hits > 100, "Only {hits} rays hit the teapot");
+    }
+
+    #[test]
+    fn unit_cube_fit_keeps_proportions() {
+        // A 4 x 1 x 0.5 box well off the origin, fitted as an OBJ model would be:
+        let size = Vec3::new(4.0, 1.0, 0.5);
+        let offset = Vec3::new(10.0, -3.0, 2.0);
+        let cube = Mesh::cube();
+        let positions = cube
+            .positions
+            .iter()
+            .map(|p| p.xyz() * size + offset)
+            .collect_vec();
+        let model = tobj::Model::new(
+            tobj::Mesh {
+                positions: positions.iter().flat_map(|p| p.to_array()).collect(),
+                indices: cube.faces.iter().flat_map(|f| f.xyz().to_array()).collect(),
+                ..Default::default()
+            },
+            "box".to_owned(),
+        );
+        let mesh = Mesh::from_model(&model, UnitCubeFit::of(&positions), WELD_TOLERANCE);
+
+        let (lb, ub) = bounds(&mesh);
+        let extent = ub - lb;
+        assert!(
+            (extent / extent.x - size / size.x).abs().max_element() < 1e-5,
+            "{extent}"
+        );
+        // Centred, with the longest axis reaching the unit cube's faces:
+        assert!((lb + ub).abs().max_element() < 1e-5, "{lb} {ub}");
+        assert!((ub.x - 1.0).abs() < 1e-5, "{ub}");
+
+        // A model collapsed to a point can't be scaled, so it's only moved:
+        let fit = UnitCubeFit::of(&[offset; 3]);
+        assert_eq!(fit.apply(offset), Vec4::W);
+        assert_eq!(fit.apply(offset + Vec3::X), Vec3::X.extend(1.0));
+    }
+
+    fn bounds(mesh: &Mesh) -> (Vec3, Vec3) {
+        mesh.positions
+            .iter()
+            .fold((Vec3::INFINITY, Vec3::NEG_INFINITY), |(lb, ub), p| {
+                (lb.min(p.xyz()), ub.max(p.xyz()))
+            })
+    }
+
+    #[test]
+    fn joint_normalizing_keeps_relative_sizes() {
+        let suzanne = concat!(env!("CARGO_MANIFEST_DIR"), "/assets/suzanne.obj");
+        let group = NormalizeMode::Joint(vec![TEAPOT.to_owned(), suzanne.to_owned()]);
+        let width = |path, mode| {
+            let (lb, ub) = bounds(&Mesh::from_obj(path, mode).unwrap());
+            (ub - lb).x
+        };
+
+        // Fitted on their own, each model fills the cube:
+        for path in [TEAPOT, suzanne] {
+            let (lb, ub) = bounds(&Mesh::from_obj(path, &NormalizeMode::UnitCube).unwrap());
+            assert!((lb.min_element().abs().max(ub.max_element()) - 1.0).abs() < 1e-4);
+        }
+
+        // Fitted jointly they keep the proportions they were authored with, and only the
+        // bigger of the two reaches the cube's faces:
+        let authored = width(TEAPOT, &NormalizeMode::None) / width(suzanne, &NormalizeMode::None);
+        let joint = width(TEAPOT, &group) / width(suzanne, &group);
+        assert!((joint / authored - 1.0).abs() < 1e-4, "{joint} {authored}");
+        assert!(width(TEAPOT, &group).max(width(suzanne, &group)) <= 2.0 + 1e-4);
+        assert!(width(TEAPOT, &group).min(width(suzanne, &group)) < 1.9);
+    }
+
+    #[test]
+    fn degenerate_faces_drop_with_their_vertices() {
+        let positions = [
+            // Kept:
+            [0.0, 0.0, 0.0],
+            [1.0, 0.0, 0.0],
+            [0.0, 1.0, 0.0],
+            // A sliver with collinear corners:
+            [0.0, 0.0, 1.0],
+            [1.0, 0.0, 1.0],
+            [2.0, 0.0, 1.0],
+            // A corner that isn't a number:
+            [0.0, 0.0, 2.0],
+            [f32::NAN, 0.0, 2.0],
+            [0.0, 1.0, 2.0],
+            // Kept:
+            [0.0, 0.0, 3.0],
+            [1.0, 0.0, 3.0],
+            [0.0, 1.0, 3.0],
+        ];
+        let normals = (0..positions.len())
+            .map(|i| Vec3::new(0.0, 0.0, 1.0 + i as f32).normalize())
+            .collect_vec();
+        let uvs = (0..positions.len())
+            .map(|i| Vec2::new(i as f32 / 16.0, 0.0))
+            .collect_vec();
+        let model = tobj::Model::new(
+            tobj::Mesh {
+                positions: positions.iter().flatten().copied().collect(),
+                normals: normals.iter().flat_map(|n| n.to_array()).collect(),
+                texcoords: uvs.iter().flat_map(|uv| uv.to_array()).collect(),
+                indices: (0..positions.len() as u32).collect(),
+                ..Default::default()
+            },
+            "slivers".to_owned(),
+        );
+        let mesh = Mesh::from_model(&model, UnitCubeFit::IDENTITY, 0.0);
+
+        assert_eq!(mesh.faces, [UVec4::new(0, 1, 2, 0), UVec4::new(3, 4, 5, 0)]);
+        let kept = [0, 1, 2, 9, 10, 11];
+        assert_eq!(mesh.positions.len(), kept.len());
+        assert_eq!(mesh.normals.len(), kept.len());
+        assert_eq!(mesh.tangents.len(), kept.len());
+        assert_eq!(mesh.uvs.len(), kept.len());
+        for (new, &old) in kept.iter().enumerate() {
+            assert_eq!(mesh.positions[new], Vec3::from(positions[old]).extend(1.0));
+            assert_eq!(mesh.normals[new], normals[old].extend(0.0));
+            // Flipped to put v = 0 at the top:
+            assert_eq!(mesh.uvs[new], Vec2::new(uvs[old].x, 1.0));
+        }
+    }
+
+    #[test]
+    fn cube_faces_wind_outwards() {
+        let cube = Mesh::cube();
+        assert_eq!(cube.faces.len(), 12);
+        for face in &cube.faces {
+            let [p0, p1, p2] = face
+                .xyz()
+                .to_array()
+                .map(|i| cube.positions[i as usize].xyz());
+            let normal = (p1 - p0).cross(p2 - p0).normalize();
+            let centre = (p0 + p1 + p2) / 3.0;
+            // From the cube's centre at the origin out through the face:
+            assert!(normal.dot(centre) > 0.0, "{face} faces inwards");
+            for i in face.xyz().to_array() {
+                let vertex_normal = cube.normals[i as usize].xyz();
+                assert!(
+                    normal.dot(vertex_normal) > 1.0 - 1e-6,
+                    "{face} winds against its normal {vertex_normal}"
+                );
+            }
         }
     }
 }
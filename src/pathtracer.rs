@@ -1,147 +1,742 @@
+use std::path::Path;
+
+use anyhow::Context;
 use bevy_ecs::prelude::*;
+use glam::Vec4;
+use tracing::{error, info};
 use wgpu::util::DeviceExt;
+use winit::{event::WindowEvent, keyboard::KeyCode};
 
 use crate::{
-    app::BevyApp, camera::Camera, pathtracer_state::PathtracerState,
-    render_resources::RenderDevice, schedule,
+    app::BevyApp,
+    camera::Camera,
+    render_resources::{RenderDevice, RenderQueue, RenderSurface, read_buffer},
+    schedule,
+    winnit::WinitWindowEvent,
 };
 
 #[derive(Component)]
 pub struct Pathtracer {
     pub is_primary: bool,
     pub dims: (u32, u32),
+    // Fraction of the window's resolution to trace at, following it as it resizes. None
+    // keeps `dims` as set. See set_render_scale:
+    pub render_scale: Option<f32>,
     pub threads: u32,
+    // Frames accumulated into the output since the last reset:
+    pub frame_index: u32,
+    // Frames traced per update, all in the one submission. See set_iterations_per_frame:
+    pub iterations_per_frame: u32,
+    pub sampling_mode: SamplingMode,
+    pub reconstruction_filter: ReconstructionFilter,
+    pub light_sampling: LightSampling,
+    pub integrator: Integrator,
+    // Surface interactions a path is shaded at before it terminates, 1 is direct lighting only.
+    // Integrator::PathFixedDepth has its own:
+    pub max_bounces: u32,
+    // Firefly suppression, both biased so off by default. See set_clamp_indirect
+    // and set_outlier_rejection:
+    pub clamp_indirect: Option<f32>,
+    pub outlier_rejection: Option<f32>,
+    // Adaptive sampling, see set_adaptive_sampling. A target error of 0 samples uniformly:
+    pub target_error: f32,
+    pub min_samples: u32,
+    pub max_samples: u32,
+    // How far rays start off the surface they leave, see set_ray_epsilon:
+    pub ray_epsilon: f32,
+    // Samples per pixel accumulation is complete at, see set_target_spp and CompletionEvent:
+    pub target_spp: Option<u32>,
+    // Seeds the initial RNG states and sample order, None seeds from entropy. See set_seed:
+    pub seed: Option<u64>,
+}
+
+// Draws a non-primary pathtracer over the primary's image, in `rect` of the window as x, y,
+// width and height from its top left, each a fraction of its size. The pathtracer follows
+// the rect's size in pixels (times its render scale, if it has one) as the window resizes.
+#[derive(Component, Clone, Copy, Debug)]
+pub struct Viewport {
+    pub rect: [f32; 4],
+}
+
+// Where F11 puts the second view, the top right quarter of the window:
+const SPLIT_VIEWPORT_RECT: [f32; 4] = [0.5, 0.0, 0.5, 0.5];
+
+pub const DEFAULT_MAX_BOUNCES: u32 = 128;
+pub const DEFAULT_MIN_SAMPLES: u32 = 16;
+// Around a hundred float steps at any distance, enough for the corners of the triangles
+// around a hit:
+pub const DEFAULT_RAY_EPSILON: f32 = 1e-5;
+
+// How the subpixel position of each camera ray is chosen.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum SamplingMode {
+    // Uniformly random within the pixel.
+    Random,
+    // Each run of 16 samples covers every cell of a 4x4 grid over the
+    // pixel once, jittered within the cell.
+    #[default]
+    Stratified,
+    // From a tiled blue noise mask, so neighbouring pixels take well spread samples and
+    // the noise at a few samples per pixel is fine grained. Also covers the lens, the
+    // shutter and the first hit's light and bounce samples, see sampleRandom in
+    // pathtracer.slang.
+    BlueNoise,
+}
+
+// How paths are traced to the end.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum Integrator {
+    // Up to max_bounces, with paths past RUSSIAN_ROULETTE_DEPTH ended at random the less
+    // light they carry and the survivors weighted up, and clamping and outlier rejection
+    // if set. Only those two bias the image.
+    #[default]
+    PathRR,
+    // Exactly `depth` bounces unless the path leaves the scene, with no roulette, clamping
+    // or outlier rejection. The unbiased reference the others should converge to, slower
+    // and noisier.
+    PathFixedDepth {
+        depth: u32,
+    },
+}
+
+// Bounces a path takes before Integrator::PathRR may end it.
+pub const RUSSIAN_ROULETTE_DEPTH: u32 = 3;
+
+// How each sample is weighted into the pixels around where it landed.
+// Tent and Gaussian reach the neighbouring pixels within a radius of one pixel.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum ReconstructionFilter {
+    // Only the pixel the sample landed in, equally weighted.
+    #[default]
+    Box,
+    Tent,
+    Gaussian,
+}
+
+// How next event estimation picks a point on an emissive triangle. Both converge to the
+// same image, solid angle with less noise from large or nearby lights.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum LightSampling {
+    // Uniformly over the triangle's area.
+    Area,
+    // Uniformly over the solid angle it subtends, falling back to area for triangles
+    // too small or far away for that to be accurate.
+    #[default]
+    SolidAngle,
+}
+
+// Per pixel values of the first hit along each camera ray, whatever the path does after.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum AovKind {
+    // Base colour, or the environment's radiance for rays that escape.
+    Albedo,
+    // World space, facing back along the ray. Zero for rays that escape.
+    Normal,
+    // Distance along the camera's forward axis, in every channel. Zero for rays that escape.
+    Depth,
+    // TLAS and BLAS nodes the camera ray visited finding its hit, in every channel. Capped
+    // at MAX_TRAVERSAL_STEPS in traverse.slang.
+    Traversal,
 }
 
 #[derive(Component)]
 pub struct PathtracerOutput {
     pub source_bind_group_layout: wgpu::BindGroupLayout,
     pub source_bind_group: wgpu::BindGroup,
+    // Mean radiance per pixel as rgba32float, tone mapped on presentation:
     pub source_buffer: wgpu::Buffer,
-    pub out_texture: wgpu::Texture,
-    pub out_sampler: wgpu::Sampler,
+    // First hit AOVs per pixel, see AovKind. The sum in xyz and sample count in w:
+    pub albedo_buffer: wgpu::Buffer,
+    pub normal_buffer: wgpu::Buffer,
+    pub depth_buffer: wgpu::Buffer,
+    pub traversal_buffer: wgpu::Buffer,
+    pub dims: (u32, u32),
+}
+
+// Resolution the primary pathtracer is created with.
+#[derive(Resource, Clone, Copy)]
+pub struct InitialDims(pub (u32, u32));
+
+impl Default for InitialDims {
+    fn default() -> Self {
+        Self((512, 512))
+    }
+}
+
+// Sampling the primary pathtracer is created with, see Pathtracer::set_target_spp and
+// Pathtracer::set_seed.
+#[derive(Resource, Clone, Copy, Default)]
+pub struct InitialSampling {
+    pub target_spp: Option<u32>,
+    pub seed: Option<u64>,
+}
+
+// How the primary pathtracer traces, as RunConfig sets it up. Each is passed to the setter
+// of the same name, see Pathtracer::apply_trace_settings.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct TraceSettings {
+    pub max_bounces: u32,
+    pub integrator: Integrator,
+    pub sampling_mode: SamplingMode,
+    pub reconstruction_filter: ReconstructionFilter,
+    pub light_sampling: LightSampling,
+    // Firefly suppression, both biased so off by default:
+    pub clamp_indirect: Option<f32>,
+    pub outlier_rejection: Option<f32>,
+    // Adaptive sampling, off at a target error of 0. See Pathtracer::set_adaptive_sampling:
+    pub target_error: f32,
+    pub min_samples: u32,
+    pub max_samples: u32,
+    // See Pathtracer::set_ray_epsilon:
+    pub ray_epsilon: f32,
+    // See Pathtracer::set_iterations_per_frame:
+    pub iterations_per_frame: u32,
+    // Fraction of the window's resolution to trace at. See Pathtracer::set_render_scale:
+    pub render_scale: Option<f32>,
+}
+
+impl Default for TraceSettings {
+    fn default() -> Self {
+        Self {
+            max_bounces: DEFAULT_MAX_BOUNCES,
+            integrator: Integrator::default(),
+            sampling_mode: SamplingMode::default(),
+            reconstruction_filter: ReconstructionFilter::default(),
+            light_sampling: LightSampling::default(),
+            clamp_indirect: None,
+            outlier_rejection: None,
+            target_error: 0.0,
+            min_samples: DEFAULT_MIN_SAMPLES,
+            max_samples: 0,
+            ray_epsilon: DEFAULT_RAY_EPSILON,
+            iterations_per_frame: 1,
+            render_scale: None,
+        }
+    }
 }
 
+// Trace settings the primary pathtracer is created with.
+#[derive(Resource, Clone, Copy, Default)]
+pub struct InitialTrace(pub TraceSettings);
+
 pub fn initialize(app: &mut BevyApp) {
     app.world
         .get_resource_or_init::<Schedules>()
         .add_systems(schedule::Startup, setup_pathtracer)
-        .add_systems(schedule::Update, pathtracer_output_sync_system);
+        .add_systems(
+            schedule::Update,
+            (
+                render_scale_system,
+                pathtracer_output_sync_system.after(render_scale_system),
+                exr_capture_system,
+                viewport_toggle_system.before(render_scale_system),
+            ),
+        );
 }
 
-fn setup_pathtracer(mut commands: Commands, device: Res<RenderDevice>) {
-    commands.spawn((
-        Pathtracer {
-            is_primary: true,
-            dims: (512, 512),
-            threads: 512 * 512,
-        },
-        Camera::new(&device.0, Some("Camera")),
-    ));
+// Where F12 saves the primary pathtracer's output, and its first hit albedo
+// and normal AOVs alongside for an external denoiser.
+const EXR_CAPTURE_PATH: &str = "capture.exr";
+const EXR_ALBEDO_PATH: &str = "capture_albedo.exr";
+const EXR_NORMAL_PATH: &str = "capture_normal.exr";
+
+fn exr_capture_system(
+    mut we_reader: MessageReader<WinitWindowEvent>,
+    query: Query<(&Pathtracer, &PathtracerOutput)>,
+    device: Res<RenderDevice>,
+    queue: Res<RenderQueue>,
+) {
+    let capture = we_reader.read().any(|WinitWindowEvent(e)| match e {
+        WindowEvent::KeyboardInput { event, .. } => {
+            event.state.is_pressed() && !event.repeat && event.physical_key == KeyCode::F12
+        }
+        _ => false,
+    });
+    if !capture {
+        return;
+    }
+
+    for (_, pto) in query.iter().filter(|(pt, _)| pt.is_primary) {
+        let path = Path::new(EXR_CAPTURE_PATH);
+        let saved = pto.save_exr(&device.0, &queue.0, path).and_then(|()| {
+            let albedo = pto.read_aov(&device.0, &queue.0, AovKind::Albedo)?;
+            write_exr(Path::new(EXR_ALBEDO_PATH), pto.dims, albedo)?;
+            let normal = pto.read_aov(&device.0, &queue.0, AovKind::Normal)?;
+            write_exr(Path::new(EXR_NORMAL_PATH), pto.dims, normal)
+        });
+        match saved {
+            Ok(()) => info!("Saved output and AOVs to {}", path.display()),
+            Err(e) => error!("Failed to save output: {e:#}"),
+        }
+    }
+}
+
+// Writes rgba pixels as a 32 bit float EXR.
+pub fn write_exr(path: &Path, dims: (u32, u32), pixels: Vec<[f32; 4]>) -> anyhow::Result<()> {
+    let image = image::Rgba32FImage::from_raw(dims.0, dims.1, pixels.into_flattened())
+        .context("Pixels do not match the dims")?;
+    image
+        .save_with_format(path, image::ImageFormat::OpenExr)
+        .with_context(|| format!("Failed to write {}", path.display()))
+}
+
+fn setup_pathtracer(
+    mut commands: Commands,
+    device: Res<RenderDevice>,
+    initial_dims: Option<Res<InitialDims>>,
+    initial_sampling: Option<Res<InitialSampling>>,
+    initial_trace: Option<Res<InitialTrace>>,
+) {
+    let dims = initial_dims.map(|d| *d).unwrap_or_default().0;
+    let sampling = initial_sampling.map(|s| *s).unwrap_or_default();
+    let trace = initial_trace.map(|t| *t).unwrap_or_default().0;
+    let mut pathtracer = Pathtracer::new(dims, true);
+    pathtracer.set_target_spp(sampling.target_spp);
+    pathtracer.set_seed(sampling.seed);
+    pathtracer.apply_trace_settings(&trace);
+    commands.spawn((pathtracer, Camera::new(&device.0, Some("Camera"))));
+}
+
+// F11 opens a second view from where the primary camera is, tracing and accumulating on
+// its own in SPLIT_VIEWPORT_RECT, or closes it again.
+fn viewport_toggle_system(
+    mut commands: Commands,
+    mut we_reader: MessageReader<WinitWindowEvent>,
+    device: Res<RenderDevice>,
+    surface: Option<Res<RenderSurface>>,
+    viewports: Query<Entity, With<Viewport>>,
+    cameras: Query<(&Pathtracer, &Camera)>,
+) {
+    let presses = we_reader
+        .read()
+        .filter(|WinitWindowEvent(e)| match e {
+            WindowEvent::KeyboardInput { event, .. } => {
+                event.state.is_pressed() && !event.repeat && event.physical_key == KeyCode::F11
+            }
+            _ => false,
+        })
+        .count();
+    if presses % 2 == 0 {
+        return;
+    }
+
+    if !viewports.is_empty() {
+        for id in viewports {
+            commands.entity(id).despawn();
+        }
+        info!("Closed the second viewport");
+        return;
+    }
+
+    let Some((_, primary)) = cameras.iter().find(|(pt, _)| pt.is_primary) else {
+        return;
+    };
+    let viewport = Viewport {
+        rect: SPLIT_VIEWPORT_RECT,
+    };
+    // Sized for the window straight away, rather than rebuilt a frame later:
+    let dims = surface
+        .map(|s| viewport.pixel_rect(s.size.into()))
+        .map_or((1, 1), |[_, _, w, h]| (w, h));
+    let mut camera = Camera::new(&device.0, Some("Viewport Camera"));
+    camera.data = primary.data;
+    camera.mode = primary.mode;
+    camera.move_speed = primary.move_speed;
+    commands.spawn((Pathtracer::new(dims, false), camera, viewport));
+    info!("Opened a second viewport");
+}
+
+// Keeps scaled pathtracers at their fraction of the surface's size, and viewports at their
+// rect's.
+fn render_scale_system(
+    surface: Option<Res<RenderSurface>>,
+    query: Query<(&mut Pathtracer, Option<&Viewport>)>,
+) {
+    let Some(surface) = surface.filter(|s| s.is_surface_configured) else {
+        return;
+    };
+    for (mut pt, viewport) in query {
+        let size = match viewport {
+            Some(viewport) => {
+                let [_, _, w, h] = viewport.pixel_rect(surface.size.into());
+                (w, h)
+            }
+            None => surface.size.into(),
+        };
+        // Viewports always follow the window, at full resolution unless scaled:
+        let Some(scale) = pt.render_scale.or(viewport.map(|_| 1.0)) else {
+            continue;
+        };
+        let dims = (
+            ((size.0 as f32 * scale).round() as u32).max(1),
+            ((size.1 as f32 * scale).round() as u32).max(1),
+        );
+        // Only touched on a change, so the output isn't flagged as resized every frame:
+        if dims != pt.dims {
+            pt.set_dims(dims);
+        }
+    }
 }
 
 pub fn pathtracer_output_sync_system(
     mut commands: Commands,
     device: Res<RenderDevice>,
-    query: Query<(Entity, &Pathtracer), Changed<Pathtracer>>,
+    query: Query<(Entity, &Pathtracer, Option<&PathtracerOutput>), Changed<Pathtracer>>,
+    mut cameras: Query<&mut Camera>,
 ) {
-    for (id, pt) in query.iter() {
+    for (id, pt, pto) in query.iter() {
+        // Only a resize needs a new output, anything else would throw away accumulation.
+        // The state is rebuilt on its own, see pathtracer_phase_sync:
+        if pto.is_some_and(|pto| pto.dims == pt.dims) {
+            continue;
+        }
+
+        if let Ok(mut camera) = cameras.get_mut(id) {
+            camera.set_aspect(pt.dims.0, pt.dims.1);
+        }
+
         commands
             .entity(id)
-            .insert(PathtracerOutput::new(&device.0, pt.dims))
-            .insert(PathtracerState::new(&device.0, pt.dims, pt.threads));
+            .insert(PathtracerOutput::new(&device.0, pt.dims));
+    }
+}
+
+impl Pathtracer {
+    pub fn new(dims: (u32, u32), is_primary: bool) -> Self {
+        let dims = (dims.0.max(1), dims.1.max(1));
+        Self {
+            is_primary,
+            dims,
+            render_scale: None,
+            threads: dims.0 * dims.1,
+            frame_index: 0,
+            iterations_per_frame: 1,
+            sampling_mode: SamplingMode::default(),
+            reconstruction_filter: ReconstructionFilter::default(),
+            light_sampling: LightSampling::default(),
+            integrator: Integrator::default(),
+            max_bounces: DEFAULT_MAX_BOUNCES,
+            clamp_indirect: None,
+            outlier_rejection: None,
+            target_error: 0.0,
+            min_samples: DEFAULT_MIN_SAMPLES,
+            max_samples: 0,
+            ray_epsilon: DEFAULT_RAY_EPSILON,
+            target_spp: None,
+            seed: None,
+        }
+    }
+
+    // Applies every setting in `settings`, from a RunConfig or the command line.
+    pub fn apply_trace_settings(&mut self, settings: &TraceSettings) {
+        self.set_max_bounces(settings.max_bounces);
+        self.set_integrator(settings.integrator);
+        self.set_sampling_mode(settings.sampling_mode);
+        self.set_reconstruction_filter(settings.reconstruction_filter);
+        self.set_light_sampling(settings.light_sampling);
+        self.set_clamp_indirect(settings.clamp_indirect);
+        self.set_outlier_rejection(settings.outlier_rejection);
+        self.set_adaptive_sampling(
+            settings.target_error,
+            settings.min_samples,
+            settings.max_samples,
+        );
+        self.set_ray_epsilon(settings.ray_epsilon);
+        self.set_iterations_per_frame(settings.iterations_per_frame);
+        self.set_render_scale(settings.render_scale);
+    }
+
+    // Resizes the output, its buffers are recreated on the next update.
+    pub fn set_dims(&mut self, dims: (u32, u32)) {
+        let dims = (dims.0.max(1), dims.1.max(1));
+        if dims == self.dims {
+            return;
+        }
+        self.dims = dims;
+        self.threads = dims.0 * dims.1;
+        self.reset_accumulation();
+    }
+
+    // Traces at `scale` times the window's resolution from the next update, shown
+    // upscaled. None stops following the window, leaving the dims as they are.
+    pub fn set_render_scale(&mut self, scale: Option<f32>) {
+        self.render_scale = scale.filter(|s| s.is_finite() && *s > 0.0);
+    }
+
+    // Fixes the samples' randoms and the sample order so runs can be reproduced, see
+    // HEADLESS_SEED, rebuilding the pathtracer's buffers on the next update.
+    pub fn set_seed(&mut self, seed: Option<u64>) {
+        if seed != self.seed {
+            self.seed = seed;
+            self.reset_accumulation();
+        }
+    }
+
+    // Samples already accumulated were weighted for the old filter, so start over.
+    pub fn set_reconstruction_filter(&mut self, filter: ReconstructionFilter) {
+        if filter != self.reconstruction_filter {
+            self.reconstruction_filter = filter;
+            self.reset_accumulation();
+        }
+    }
+
+    // Converges to the same image, but the samples so far were taken the old way.
+    pub fn set_sampling_mode(&mut self, mode: SamplingMode) {
+        if mode != self.sampling_mode {
+            self.sampling_mode = mode;
+            self.reset_accumulation();
+        }
+    }
+
+    // Only the noise changes, but the samples so far were taken the old way.
+    pub fn set_light_sampling(&mut self, sampling: LightSampling) {
+        if sampling != self.light_sampling {
+            self.light_sampling = sampling;
+            self.reset_accumulation();
+        }
+    }
+
+    // Path depth changes what the image converges to, so start over.
+    pub fn set_max_bounces(&mut self, max_bounces: u32) {
+        let max_bounces = max_bounces.max(1);
+        if max_bounces != self.max_bounces {
+            self.max_bounces = max_bounces;
+            self.reset_accumulation();
+        }
+    }
+
+    // The samples so far were traced the old way, so start over. The fixed depth reference
+    // ignores max_bounces, clamping and outlier rejection rather than clearing them.
+    pub fn set_integrator(&mut self, integrator: Integrator) {
+        let integrator = match integrator {
+            Integrator::PathFixedDepth { depth } => Integrator::PathFixedDepth {
+                depth: depth.max(1),
+            },
+            integrator => integrator,
+        };
+        if integrator != self.integrator {
+            self.integrator = integrator;
+            self.reset_accumulation();
+        }
+    }
+
+    // Caps the brightest channel of any light arriving after the first bounce at
+    // `max_radiance`. Directly visible emitters and direct lighting are left alone.
+    pub fn set_clamp_indirect(&mut self, max_radiance: Option<f32>) {
+        let max_radiance = max_radiance.filter(|r| *r > 0.0);
+        if max_radiance != self.clamp_indirect {
+            self.clamp_indirect = max_radiance;
+            self.reset_accumulation();
+        }
+    }
+
+    // Rejects samples whose luminance is more than `sigma` standard deviations above
+    // their pixel's running mean.
+    pub fn set_outlier_rejection(&mut self, sigma: Option<f32>) {
+        let sigma = sigma.filter(|s| *s > 0.0);
+        if sigma != self.outlier_rejection {
+            self.outlier_rejection = sigma;
+            self.reset_accumulation();
+        }
+    }
+
+    // Rays leaving a surface start `epsilon` off it per unit the hit is from the world
+    // origin, and at least `epsilon`, as float error grows with distance. Too small and
+    // they hit the triangles around the one they left, speckling it with shadow acne. Too
+    // large and light leaks in where objects meet. 0 only stops them hitting the same
+    // triangle again.
+    pub fn set_ray_epsilon(&mut self, epsilon: f32) {
+        let epsilon = epsilon.max(0.0);
+        if epsilon != self.ray_epsilon {
+            self.ray_epsilon = epsilon;
+            self.reset_accumulation();
+        }
+    }
+
+    // Stops sampling pixels once the standard error of their mean luminance, relative to
+    // the mean, is below `target_error`, spending the samples on noisier pixels instead.
+    // Pixels take at least `min_samples` and at most `max_samples` (0 for no limit) first.
+    pub fn set_adaptive_sampling(&mut self, target_error: f32, min_samples: u32, max_samples: u32) {
+        let target_error = target_error.max(0.0);
+        if (target_error, min_samples, max_samples)
+            != (self.target_error, self.min_samples, self.max_samples)
+        {
+            self.target_error = target_error;
+            self.min_samples = min_samples;
+            self.max_samples = max_samples;
+            self.reset_accumulation();
+        }
+    }
+
+    // Accumulation is complete once every pixel has `spp` samples, or with adaptive
+    // sampling has converged, see CompletionEvent. Sampling carries on past it regardless.
+    pub fn set_target_spp(&mut self, spp: Option<u32>) {
+        self.target_spp = spp;
+    }
+
+    // Traces `iterations` frames every update before the output is presented, converging
+    // a still view faster at the cost of a slower interactive one. 1 traces one per update.
+    pub fn set_iterations_per_frame(&mut self, iterations: u32) {
+        self.iterations_per_frame = iterations.max(1);
+    }
+
+    // Starts accumulating from scratch on the next dispatch.
+    pub fn reset_accumulation(&mut self) {
+        self.frame_index = 0;
+    }
+}
+
+impl Viewport {
+    // The rect in pixels of a surface of `size`, as x, y, width and height. Kept within the
+    // surface and at least a pixel across.
+    pub fn pixel_rect(&self, size: (u32, u32)) -> [u32; 4] {
+        let (width, height) = (size.0.max(1), size.1.max(1));
+        let [x, y, w, h] = self.rect;
+        let x = ((x * width as f32).round() as u32).min(width - 1);
+        let y = ((y * height as f32).round() as u32).min(height - 1);
+        let w = ((w * width as f32).round() as u32).clamp(1, width - x);
+        let h = ((h * height as f32).round() as u32).clamp(1, height - y);
+        [x, y, w, h]
     }
 }
 
 impl PathtracerOutput {
+    pub fn buffer_bytes(&self) -> u64 {
+        [
+            &self.source_buffer,
+            &self.albedo_buffer,
+            &self.normal_buffer,
+            &self.depth_buffer,
+            &self.traversal_buffer,
+        ]
+        .map(wgpu::Buffer::size)
+        .iter()
+        .sum()
+    }
+
     fn new(device: &wgpu::Device, dims: (u32, u32)) -> Self {
         let source_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
             label: Some("LogicPhase Output"),
-            contents: bytemuck::cast_slice(&(0..(dims.0 * dims.1)).collect::<Vec<_>>()),
+            contents: bytemuck::cast_slice(&vec![Vec4::ZERO; (dims.0 * dims.1) as usize]),
             usage: wgpu::BufferUsages::COPY_SRC | wgpu::BufferUsages::STORAGE,
         });
 
-        let size = wgpu::Extent3d {
-            width: dims.0,
-            height: dims.1,
-            depth_or_array_layers: 1,
+        let aov_buffer = |label| {
+            device.create_buffer(&wgpu::BufferDescriptor {
+                label: Some(label),
+                usage: wgpu::BufferUsages::COPY_SRC | wgpu::BufferUsages::STORAGE,
+                size: (dims.0 * dims.1) as u64 * std::mem::size_of::<Vec4>() as u64,
+                mapped_at_creation: false,
+            })
         };
-
-        let out_texture = device.create_texture(&wgpu::TextureDescriptor {
-            label: Some("Surface Texture"),
-            size,
-            mip_level_count: 1,
-            sample_count: 1,
-            dimension: wgpu::TextureDimension::D2,
-            format: wgpu::TextureFormat::Rgba8UnormSrgb,
-            usage: wgpu::TextureUsages::TEXTURE_BINDING | wgpu::TextureUsages::COPY_DST,
-            view_formats: &[],
-        });
-
-        let out_sampler = device.create_sampler(&wgpu::SamplerDescriptor {
-            address_mode_u: wgpu::AddressMode::ClampToEdge,
-            address_mode_v: wgpu::AddressMode::ClampToEdge,
-            address_mode_w: wgpu::AddressMode::ClampToEdge,
-            mag_filter: wgpu::FilterMode::Nearest,
-            min_filter: wgpu::FilterMode::Nearest,
-            mipmap_filter: wgpu::FilterMode::Nearest,
-            ..Default::default()
-        });
+        let albedo_buffer = aov_buffer("Albedo AOV");
+        let normal_buffer = aov_buffer("Normal AOV");
+        let depth_buffer = aov_buffer("Depth AOV");
+        let traversal_buffer = aov_buffer("Traversal AOV");
 
         let source_bind_group_layout =
             device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
                 label: Some("Output Bind Group Layout"),
-                entries: &[wgpu::BindGroupLayoutEntry {
-                    binding: 0,
-                    visibility: wgpu::ShaderStages::COMPUTE,
-                    ty: wgpu::BindingType::Buffer {
-                        ty: wgpu::BufferBindingType::Storage { read_only: false },
-                        has_dynamic_offset: false,
-                        min_binding_size: None,
-                    },
-                    count: None,
-                }],
+                entries: &(0..5)
+                    .map(|i| wgpu::BindGroupLayoutEntry {
+                        binding: i,
+                        visibility: wgpu::ShaderStages::COMPUTE,
+                        ty: wgpu::BindingType::Buffer {
+                            ty: wgpu::BufferBindingType::Storage { read_only: false },
+                            has_dynamic_offset: false,
+                            min_binding_size: None,
+                        },
+                        count: None,
+                    })
+                    .collect::<Vec<_>>(),
             });
 
         let source_bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
             label: Some("Output Bind Group"),
             layout: &source_bind_group_layout,
-            entries: &[wgpu::BindGroupEntry {
-                binding: 0,
-                resource: source_buffer.as_entire_binding(),
-            }],
+            entries: &[
+                &source_buffer,
+                &albedo_buffer,
+                &normal_buffer,
+                &depth_buffer,
+                &traversal_buffer,
+            ]
+            .iter()
+            .enumerate()
+            .map(|(i, buffer)| wgpu::BindGroupEntry {
+                binding: i as u32,
+                resource: buffer.as_entire_binding(),
+            })
+            .collect::<Vec<_>>(),
         });
 
         Self {
             source_bind_group_layout,
             source_bind_group,
             source_buffer,
-            out_texture,
-            out_sampler,
+            albedo_buffer,
+            normal_buffer,
+            depth_buffer,
+            traversal_buffer,
+            dims,
         }
     }
 
-    pub fn copy_to_texture(&self, encoder: &mut wgpu::CommandEncoder) {
-        encoder.copy_buffer_to_texture(
-            wgpu::TexelCopyBufferInfoBase {
-                buffer: &self.source_buffer,
-                layout: wgpu::TexelCopyBufferLayout {
-                    offset: 0,
-                    bytes_per_row: Some(4 * self.out_texture.size().width),
-                    rows_per_image: Some(self.out_texture.size().height),
-                },
-            },
-            wgpu::TexelCopyTextureInfoBase {
-                texture: &self.out_texture,
-                mip_level: 0,
-                origin: wgpu::Origin3d::ZERO,
-                aspect: wgpu::TextureAspect::All,
-            },
-            wgpu::Extent3d {
-                width: self.out_texture.size().width,
-                height: self.out_texture.size().height,
-                depth_or_array_layers: 1,
-            },
-        );
+    pub fn aov_buffer(&self, kind: AovKind) -> &wgpu::Buffer {
+        match kind {
+            AovKind::Albedo => &self.albedo_buffer,
+            AovKind::Normal => &self.normal_buffer,
+            AovKind::Depth => &self.depth_buffer,
+            AovKind::Traversal => &self.traversal_buffer,
+        }
+    }
+
+    // Copies an AOV back as the per pixel mean over its samples. Pixels without a
+    // sample yet are zero, and alpha is always 1.0.
+    pub fn read_aov(
+        &self,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        kind: AovKind,
+    ) -> anyhow::Result<Vec<[f32; 4]>> {
+        let bytes = read_buffer(
+            device,
+            queue,
+            self.aov_buffer(kind),
+            (self.dims.0 * self.dims.1) as u64 * std::mem::size_of::<Vec4>() as u64,
+        )?;
+        Ok(bytemuck::pod_collect_to_vec::<u8, [f32; 4]>(&bytes)
+            .into_iter()
+            .map(|[x, y, z, n]| {
+                let n = n.max(1.0);
+                [x / n, y / n, z / n, 1.0]
+            })
+            .collect())
+    }
+
+    // Copies the mean radiance back, blocking until the GPU has finished writing it.
+    // Alpha is always 1.0.
+    pub fn read_hdr(
+        &self,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+    ) -> anyhow::Result<Vec<[f32; 4]>> {
+        let bytes = read_buffer(
+            device,
+            queue,
+            &self.source_buffer,
+            (self.dims.0 * self.dims.1) as u64 * std::mem::size_of::<Vec4>() as u64,
+        )?;
+        Ok(bytemuck::pod_collect_to_vec(&bytes))
+    }
+
+    // Writes the linear, un-tonemapped radiance as a 32 bit float EXR.
+    pub fn save_exr(
+        &self,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        path: &Path,
+    ) -> anyhow::Result<()> {
+        write_exr(path, self.dims, self.read_hdr(device, queue)?)
     }
 }
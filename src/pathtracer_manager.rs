@@ -1,4 +1,5 @@
 use bevy_ecs::prelude::*;
+use tracing::debug;
 use wesl::include_wesl;
 use wgpu::{CommandBuffer, include_spirv, util::DeviceExt};
 
@@ -6,22 +7,61 @@ use crate::{
     app::BevyApp,
     binder::{SceneBindings, binder_system},
     camera::Camera,
-    pathtracer::{Pathtracer, PathtracerOutput, pathtracer_output_sync_system},
-    pathtracer_state::PathtracerState,
+    pathtracer::{
+        Integrator, Pathtracer, PathtracerOutput, RUSSIAN_ROULETTE_DEPTH,
+        pathtracer_output_sync_system,
+    },
+    pathtracer_state::{FrameData, PathtracerState},
     render::render_system,
     render_resources::{RenderDevice, RenderQueue, RenderSurface},
     schedule,
 };
 
+// Threads per workgroup the compute shaders are built with, from RAYTRACER_WORKGROUP_SIZE
+// at build time or 64 by default. build.rs passes the same value to slangc.
+pub const WORKGROUP_SIZE: u32 = match u32::from_str_radix(env!("RAYTRACER_WORKGROUP_SIZE"), 10) {
+    Ok(size) => size,
+    Err(_) => panic!("RAYTRACER_WORKGROUP_SIZE is not a whole number"),
+};
+
+// How the pathtracer's compute work is split into workgroups. The size is compiled into the
+// shaders, so it's read only here, rebuild with RAYTRACER_WORKGROUP_SIZE set to tune it.
+#[derive(Resource, Clone, Copy, Debug)]
+pub struct ComputeSettings {
+    workgroup_size: u32,
+}
+
+impl Default for ComputeSettings {
+    fn default() -> Self {
+        Self {
+            workgroup_size: WORKGROUP_SIZE,
+        }
+    }
+}
+
+impl ComputeSettings {
+    pub fn workgroup_size(&self) -> u32 {
+        self.workgroup_size
+    }
+
+    // Enough workgroups for `threads` invocations.
+    pub fn workgroups(&self, threads: u32) -> u32 {
+        threads.div_ceil(self.workgroup_size)
+    }
+}
+
 #[derive(Component)]
 pub struct PathtracerPhase {
+    compute_settings: ComputeSettings,
     sample_main_pipeline: wgpu::ComputePipeline,
     sample_cleanup_pipeline: wgpu::ComputePipeline,
+    sample_resolve_pipeline: wgpu::ComputePipeline,
     ray_extend_pipeline: wgpu::ComputePipeline,
     shade_pipeline: wgpu::ComputePipeline,
 }
 
 pub fn initialize(app: &mut BevyApp) {
+    app.world.init_resource::<ComputeSettings>();
     app.world.get_resource_or_init::<Schedules>().add_systems(
         schedule::Update,
         (
@@ -36,31 +76,59 @@ pub fn initialize(app: &mut BevyApp) {
     );
 }
 
+// Rebuilds the state when the dims, threads or seed it was made with change, and the
+// pipelines along with a new output or state. A pathtracer flagged as changed for anything
+// else, such as resetting its accumulation, keeps both.
 fn pathtracer_phase_sync(
     pathtracer_query: Query<
         (
             Entity,
             &Pathtracer,
-            &PathtracerOutput,
+            Ref<PathtracerOutput>,
             Option<&mut PathtracerState>,
             Option<&mut PathtracerPhase>,
             &Camera,
         ), // add camera component here pls :)
-        Changed<PathtracerOutput>,
+        Or<(Changed<PathtracerOutput>, Changed<Pathtracer>)>,
     >,
     mut commands: Commands,
     device: Res<RenderDevice>,
     scene_bindings: Res<SceneBindings>,
+    compute_settings: Res<ComputeSettings>,
 ) {
-    // Update all the path tracer states to be reset:
     for (e, pt, pto, pts, ptp, camera) in pathtracer_query {
-        let new_pts = PathtracerState::new(&device.0, pt.dims, pt.threads);
-        let new_ptp = PathtracerPhase::new(&device.0, &pto, &scene_bindings, &new_pts, camera);
+        let stale_state = pts.as_ref().is_none_or(|pts| {
+            pts.dims != pt.dims || pts.threads != pt.threads || pts.seed != pt.seed
+        });
+        if !stale_state && !pto.is_changed() {
+            continue;
+        }
 
-        if let Some(mut pts) = pts {
-            *pts = new_pts;
-        } else {
-            commands.entity(e).insert(new_pts);
+        // Expensive, so only when what it's sized and seeded from changes:
+        let new_pts = stale_state.then(|| {
+            debug!("Built the state for pathtracer {e}");
+            PathtracerState::new(&device.0, pt.dims, pt.threads, pt.seed)
+        });
+        let new_ptp = PathtracerPhase::new(
+            &device.0,
+            &pto,
+            &scene_bindings,
+            new_pts
+                .as_ref()
+                .or(pts.as_deref())
+                .expect("A stale state is rebuilt above"),
+            camera,
+            &compute_settings,
+        );
+        // Only on a new output or state, the scene layout they're built against never changes:
+        debug!("Built the pipelines for pathtracer {e}");
+
+        match (new_pts, pts) {
+            (Some(new_pts), Some(mut pts)) => *pts = new_pts,
+            (Some(new_pts), None) => {
+                commands.entity(e).insert(new_pts);
+            }
+            (None, _) => {}
         }
 
         if let Some(mut ptp) = ptp {
@@ -71,11 +139,11 @@ fn pathtracer_phase_sync(
     }
 }
 
-fn pathtracer_phase_execute(
+pub fn pathtracer_phase_execute(
     device: Res<RenderDevice>,
     queue: Res<RenderQueue>,
     query: Query<(
-        &Pathtracer,
+        &mut Pathtracer,
         &PathtracerOutput,
         &PathtracerState,
         &PathtracerPhase,
@@ -87,39 +155,116 @@ fn pathtracer_phase_execute(
         return;
     }
 
-    for (pt, pto, pts, ptp, camera) in query {
+    for (mut pt, pto, pts, ptp, camera) in query {
+        let iterations = pt.iterations_per_frame.max(1);
+        // The fixed depth reference turns off everything that ends paths early or biases
+        // them:
+        let (max_bounces, russian_roulette_depth, clamp_indirect, outlier_sigma) =
+            match pt.integrator {
+                Integrator::PathRR => (
+                    pt.max_bounces,
+                    RUSSIAN_ROULETTE_DEPTH,
+                    pt.clamp_indirect.unwrap_or_default(),
+                    pt.outlier_rejection.unwrap_or_default(),
+                ),
+                Integrator::PathFixedDepth { depth } => (depth.max(1), 0, 0.0, 0.0),
+            };
+        // One per iteration, only the index differs:
+        let frames = (0..iterations)
+            .map(|i| FrameData {
+                index: pt.frame_index.wrapping_add(i),
+                sampling_mode: pt.sampling_mode as u32,
+                reconstruction_filter: pt.reconstruction_filter as u32,
+                max_bounces,
+                clamp_indirect,
+                outlier_sigma,
+                target_error: pt.target_error,
+                min_samples: pt.min_samples,
+                max_samples: pt.max_samples,
+                light_sampling: pt.light_sampling as u32,
+                ray_epsilon: pt.ray_epsilon,
+                russian_roulette_depth,
+                seed: pts.rng_seed,
+                _pad: [0; 3],
+            })
+            .collect::<Vec<_>>();
+        queue
+            .0
+            .write_buffer(&pts.frame_buffer, 0, bytemuck::bytes_of(&frames[0]));
+        // Queue writes all land before the submission, so the later iterations' frames are
+        // copied in between their passes instead:
+        let later_frames = (iterations > 1).then(|| {
+            device
+                .0
+                .create_buffer_init(&wgpu::util::BufferInitDescriptor {
+                    label: Some("Frame Staging Buffer"),
+                    contents: bytemuck::cast_slice(&frames[1..]),
+                    usage: wgpu::BufferUsages::COPY_SRC,
+                })
+        });
+
         let mut encoder = device
             .0
             .create_command_encoder(&wgpu::CommandEncoderDescriptor {
                 label: Some("Render Encoder"),
             });
 
-        let mut compute_pass = encoder.begin_compute_pass(&wgpu::ComputePassDescriptor {
-            label: Some("Compute Pass"),
-            timestamp_writes: None,
-        });
-
-        compute_pass.set_pipeline(&ptp.sample_cleanup_pipeline);
-        compute_pass.set_bind_group(0, scene_bindings.bind_group.as_ref().unwrap(), &[]);
-        compute_pass.set_bind_group(1, &pts.bind_group, &[]);
-        compute_pass.set_bind_group(2, &camera.bind_group, &[]);
-        compute_pass.set_bind_group(3, &pto.source_bind_group, &[]);
-        compute_pass.dispatch_workgroups(4096.min((pt.dims.0 * pt.dims.1).div_ceil(64)), 1, 1);
-
-        compute_pass.set_pipeline(&ptp.sample_main_pipeline);
-        compute_pass.dispatch_workgroups(pt.threads.div_ceil(64), 1, 1);
-
-        compute_pass.set_pipeline(&ptp.ray_extend_pipeline);
-        compute_pass.dispatch_workgroups(pt.threads.div_ceil(64), 1, 1);
-
-        compute_pass.set_pipeline(&ptp.shade_pipeline);
-        compute_pass.dispatch_workgroups(pt.threads.div_ceil(64), 1, 1);
+        for i in 0..iterations {
+            if let Some(later_frames) = &later_frames
+                && i > 0
+            {
+                let size = std::mem::size_of::<FrameData>() as u64;
+                encoder.copy_buffer_to_buffer(
+                    later_frames,
+                    (i - 1) as u64 * size,
+                    &pts.frame_buffer,
+                    0,
+                    size,
+                );
+            }
+
+            let mut compute_pass = encoder.begin_compute_pass(&wgpu::ComputePassDescriptor {
+                label: Some("Compute Pass"),
+                timestamp_writes: None,
+            });
 
-        drop(compute_pass);
+            compute_pass.set_pipeline(&ptp.sample_cleanup_pipeline);
+            compute_pass.set_bind_group(0, scene_bindings.bind_group.as_ref().unwrap(), &[]);
+            compute_pass.set_bind_group(2, &camera.bind_group, &[]);
+            compute_pass.set_bind_group(3, &pto.source_bind_group, &[]);
+            for (index, group) in pts.groups() {
+                compute_pass.set_bind_group(index, &group.bind_group, &[]);
+            }
+            compute_pass.dispatch_workgroups(
+                4096.min(ptp.compute_settings.workgroups(pt.dims.0 * pt.dims.1)),
+                1,
+                1,
+            );
+
+            compute_pass.set_pipeline(&ptp.sample_main_pipeline);
+            compute_pass.dispatch_workgroups(ptp.compute_settings.workgroups(pt.threads), 1, 1);
+
+            compute_pass.set_pipeline(&ptp.sample_resolve_pipeline);
+            compute_pass.dispatch_workgroups(
+                4096.min(ptp.compute_settings.workgroups(pt.dims.0 * pt.dims.1)),
+                1,
+                1,
+            );
+
+            compute_pass.set_pipeline(&ptp.ray_extend_pipeline);
+            compute_pass.dispatch_workgroups(ptp.compute_settings.workgroups(pt.threads), 1, 1);
+
+            compute_pass.set_pipeline(&ptp.shade_pipeline);
+            compute_pass.dispatch_workgroups(ptp.compute_settings.workgroups(pt.threads), 1, 1);
+        }
 
         let command = encoder.finish();
 
         queue.0.submit([command]);
+
+        // Counting frames isn't a change to the pathtracer's configuration:
+        let pt = pt.bypass_change_detection();
+        pt.frame_index = pt.frame_index.wrapping_add(iterations);
     }
 }
 
@@ -199,7 +344,16 @@ impl PathtracerPhase {
         scene_bindings: &SceneBindings,
         pathtracer_state: &PathtracerState,
         camera: &Camera,
+        compute_settings: &ComputeSettings,
     ) -> Self {
+        let workgroup_size = compute_settings.workgroup_size();
+        let limits = device.limits();
+        assert!(
+            workgroup_size <= limits.max_compute_invocations_per_workgroup
+                && workgroup_size <= limits.max_compute_workgroup_size_x,
+            "Workgroups of {workgroup_size} are too big for the device"
+        );
+
         let sample_shader =
             device.create_shader_module(include_spirv!(concat!(env!("OUT_DIR"), "/sample.spv")));
 
@@ -209,14 +363,26 @@ impl PathtracerPhase {
         let shade_shader =
             device.create_shader_module(include_spirv!(concat!(env!("OUT_DIR"), "/shade.spv")));
 
+        let mut bind_group_layouts = vec![
+            scene_bindings.bind_group_layout.as_ref().unwrap(),
+            &camera.bind_group_layout,
+            &pathtracer_output.source_bind_group_layout,
+        ];
+        let mut groups = pathtracer_state.groups();
+        groups.sort_by_key(|(index, _)| *index);
+        for (index, group) in groups {
+            bind_group_layouts.insert(index as usize, &group.layout);
+        }
+        assert!(
+            bind_group_layouts.len() as u32 <= limits.max_bind_groups,
+            "The pathtracer needs {} bind groups, the device has {}",
+            bind_group_layouts.len(),
+            limits.max_bind_groups
+        );
+
         let pipeline_layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
             label: Some("Pathtracer Pipeline Layout"),
-            bind_group_layouts: &[
-                scene_bindings.bind_group_layout.as_ref().unwrap(),
-                &pathtracer_state.bind_group_layout,
-                &camera.bind_group_layout,
-                &pathtracer_output.source_bind_group_layout,
-            ],
+            bind_group_layouts: &bind_group_layouts,
             push_constant_ranges: &[],
         });
 
@@ -246,6 +412,19 @@ impl PathtracerPhase {
                 cache: None,
             });
 
+        let sample_resolve_pipeline =
+            device.create_compute_pipeline(&wgpu::ComputePipelineDescriptor {
+                label: Some("Pathtracer Sample Resolve Pipeline"),
+                layout: Some(&pipeline_layout),
+                module: &sample_shader,
+                entry_point: Some("sampleResolve"),
+                compilation_options: wgpu::PipelineCompilationOptions {
+                    constants: &[],
+                    zero_initialize_workgroup_memory: false,
+                },
+                cache: None,
+            });
+
         let ray_extend_pipeline =
             device.create_compute_pipeline(&wgpu::ComputePipelineDescriptor {
                 label: Some("Pathtracer Ray Extend Pipeline"),
@@ -272,8 +451,10 @@ impl PathtracerPhase {
         });
 
         PathtracerPhase {
+            compute_settings: *compute_settings,
             sample_main_pipeline,
             sample_cleanup_pipeline,
+            sample_resolve_pipeline,
             ray_extend_pipeline,
             shade_pipeline,
         }
@@ -2,10 +2,13 @@ use bevy_ecs::component::Component;
 use bytemuck::Zeroable;
 use glam::{UVec4, Vec4};
 use itertools::Itertools;
-use rand::{Rng, seq::SliceRandom};
+use rand::{Rng, SeedableRng, rngs::StdRng, seq::SliceRandom};
 use wgpu::util::DeviceExt;
 
-use crate::queue;
+use crate::{
+    blue_noise::{BLUE_NOISE_SIZE, blue_noise_mask},
+    queue,
+};
 
 #[repr(C)]
 #[derive(Copy, Clone, Debug, bytemuck::Zeroable, bytemuck::Pod)]
@@ -22,14 +25,14 @@ pub struct HitRecord {
     pub triangle_id: u32,
     pub instance_id: u32,
     pub front_face: u32,
-    pub _pad: [u32; 1],
+    pub material: u32,
 }
 
 #[repr(C)]
 #[derive(Copy, Clone, Debug, bytemuck::Zeroable, bytemuck::Pod)]
 pub struct Ray {
     pub position: Vec4,
-    pub direction: Vec4,
+    pub direction: Vec4, // w is the time into the shutter
 }
 
 #[repr(C)]
@@ -42,6 +45,10 @@ pub struct Sample {
     pub bounces: u32,
     pub sample_id: u32,
     pub _pad2: [u32; 2],
+    pub bsdf_pdf: f32,
+    pub blue_noise_dim: u32,
+    pub blue_noise_frame: u32,
+    pub _pad3: u32,
 }
 
 #[repr(C)]
@@ -68,6 +75,33 @@ pub struct SampleSource {
     pub out_pos: [u32; 2],
     pub samples: u32,
     pub flags: u32,
+    // Samples dropped for a NaN or infinite radiance, not counted in `samples`:
+    pub rejected: u32,
+    pub _pad: u32,
+}
+
+// SampleSource flags, matching SampleFlag in common.slang. Set once adaptive sampling has
+// stopped sampling the source:
+pub const SAMPLE_CONVERGED: u32 = 1;
+
+// Progressive accumulation state, index 0 clears the running sums.
+#[repr(C)]
+#[derive(Copy, Clone, Debug, bytemuck::Zeroable, bytemuck::Pod, Default)]
+pub struct FrameData {
+    pub index: u32,
+    pub sampling_mode: u32,
+    pub reconstruction_filter: u32,
+    pub max_bounces: u32,
+    pub clamp_indirect: f32, // 0 -> off
+    pub outlier_sigma: f32,  // 0 -> off
+    pub target_error: f32,   // 0 -> off
+    pub min_samples: u32,
+    pub max_samples: u32, // 0 -> unbounded
+    pub light_sampling: u32,
+    pub ray_epsilon: f32,
+    pub russian_roulette_depth: u32, // 0 -> off
+    pub seed: u32,                   // See PathtracerState::rng_seed
+    pub _pad: [u32; 3],
 }
 
 #[derive(Component)]
@@ -89,25 +123,109 @@ pub struct PathtracerState {
     pub shadow_queue: queue::Queue,
     pub material_queue: queue::Queue,
 
-    pub bind_group_layout: wgpu::BindGroupLayout,
+    pub frame_buffer: wgpu::Buffer,
+    // Bytes in every buffer above, including those only the bind group holds on to.
+    // Split into paths (rays, hits and queues) and per pixel sampling state:
+    pub path_bytes: u64,
+    pub sample_bytes: u64,
+    pub dims: (u32, u32),
+    pub threads: u32,
+    pub seed: Option<u64>,
+    // Each sample's RNG is seeded on the GPU from this, which sample it is and the frame it's
+    // spawned in, so isn't tied to the path slot it happens to be traced in. Drawn from
+    // `seed` when it's set:
+    pub rng_seed: u32,
+
+    // Bound at the *_GROUP indices:
+    pub paths: StateGroup,
+    pub sampling: StateGroup,
+    pub queues: StateGroup,
+    pub frame: StateGroup,
+}
+
+// Where the state's groups sit in the pathtracer pipelines, around the scene (0), the
+// camera (2) and the output (3). Must match shaders/pathtracer.slang.
+pub const PATH_GROUP: u32 = 1;
+pub const SAMPLING_GROUP: u32 = 4;
+pub const QUEUE_GROUP: u32 = 5;
+pub const FRAME_GROUP: u32 = 6;
+
+// One bind group of the pathtracer state and its layout.
+pub struct StateGroup {
+    pub layout: wgpu::BindGroupLayout,
     pub bind_group: wgpu::BindGroup,
 }
 
+impl StateGroup {
+    // Read-write storage buffers are bound from 0 in order, then the uniforms after them.
+    fn new(
+        device: &wgpu::Device,
+        label: &str,
+        storage: &[&wgpu::Buffer],
+        uniforms: &[&wgpu::Buffer],
+    ) -> Self {
+        let entries = storage
+            .iter()
+            .map(|_| wgpu::BufferBindingType::Storage { read_only: false })
+            .chain(uniforms.iter().map(|_| wgpu::BufferBindingType::Uniform))
+            .enumerate()
+            .map(|(i, ty)| wgpu::BindGroupLayoutEntry {
+                binding: i as u32,
+                visibility: wgpu::ShaderStages::COMPUTE,
+                ty: wgpu::BindingType::Buffer {
+                    ty,
+                    has_dynamic_offset: false,
+                    min_binding_size: None,
+                },
+                count: None,
+            })
+            .collect_vec();
+        let layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            label: Some(&format!("{label} Bind Group Layout")),
+            entries: &entries,
+        });
+
+        let entries = storage
+            .iter()
+            .chain(uniforms)
+            .enumerate()
+            .map(|(i, buffer)| wgpu::BindGroupEntry {
+                binding: i as u32,
+                resource: buffer.as_entire_binding(),
+            })
+            .collect_vec();
+        let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            label: Some(&format!("{label} Bind Group")),
+            layout: &layout,
+            entries: &entries,
+        });
+
+        Self { layout, bind_group }
+    }
+}
+
 impl PathtracerState {
-    pub fn new(device: &wgpu::Device, dims: (u32, u32), threads: u32) -> Self {
-        let mut rng = rand::rng();
+    // The state's groups with the index each is bound at.
+    pub fn groups(&self) -> [(u32, &StateGroup); 4] {
+        [
+            (PATH_GROUP, &self.paths),
+            (SAMPLING_GROUP, &self.sampling),
+            (QUEUE_GROUP, &self.queues),
+            (FRAME_GROUP, &self.frame),
+        ]
+    }
+
+    // With a seed, the samples' randoms and the sample order are the same on every run.
+    pub fn new(device: &wgpu::Device, dims: (u32, u32), threads: u32, seed: Option<u64>) -> Self {
+        let mut rng = match seed {
+            Some(seed) => StdRng::seed_from_u64(seed),
+            None => StdRng::from_rng(&mut rand::rng()),
+        };
+        let rng_seed = rng.random();
         let samples: Vec<_> = (0..=threads).map(|_| Sample::zeroed()).collect();
 
-        let random_states: Vec<_> = (0..=threads)
-            .map(|_| RandomState {
-                random_state: [
-                    rng.random_range(1000..=u32::MAX),
-                    rng.random_range(1000..=u32::MAX),
-                    rng.random_range(1000..=u32::MAX),
-                    rng.random_range(1000..=u32::MAX),
-                ],
-            })
-            .collect();
+        // Seeded as each sample is spawned into its slot, see seedRandom in pathtracer.slang:
+        let random_states: Vec<_> = (0..=threads).map(|_| RandomState::zeroed()).collect();
 
         let sample_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
             label: Some("Path Buffer"),
@@ -164,6 +282,12 @@ impl PathtracerState {
             usage: wgpu::BufferUsages::UNIFORM,
         });
 
+        let frame_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some("Frame Buffer"),
+            contents: bytemuck::bytes_of(&FrameData::default()),
+            usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+        });
+
         // Sampling buffers:
         let sampling_counter_buffer =
             device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
@@ -172,33 +296,13 @@ impl PathtracerState {
                 contents: bytemuck::bytes_of(&[0u32, 0u32]),
             });
 
-        let tile_size = 128;
-        let mut data = (0..dims.0 / tile_size)
-            .cartesian_product(0..dims.1 / tile_size)
-            .collect_vec();
-
-        data.shuffle(&mut rand::rng());
-
-        let mut data = data
-            .into_iter()
-            .flat_map(|(x, y)| {
-                ((x * tile_size)..(x * tile_size + tile_size))
-                    .cartesian_product((y * tile_size)..(y * tile_size + tile_size))
-                    .map(|(x, y)| SampleSource {
-                        screen_pos: [x as f32 / dims.0 as f32, y as f32 / dims.1 as f32],
-                        out_pos: [x, y],
-                        samples: 0,
-                        flags: 0,
-                    })
-            })
-            .collect_vec();
-        data.shuffle(&mut rand::rng());
-        // data.sort_by_key(|d| (d.out_pos[0] / 256, d.out_pos[1] / 256));
+        let data = sample_sources(dims, &mut rng);
 
         let sampling_source_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
             label: Some("Sample Data Buffer"),
             contents: bytemuck::cast_slice(&data),
-            usage: wgpu::BufferUsages::STORAGE,
+            // Copied out for the convergence readout:
+            usage: wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_SRC,
         });
 
         let sampling_sum_buffer = device.create_buffer(&wgpu::BufferDescriptor {
@@ -215,124 +319,99 @@ impl PathtracerState {
             mapped_at_creation: false,
         });
 
+        // Read by every pathtracer, but cheap enough to keep a copy each:
+        let blue_noise_mask = blue_noise_mask();
+        debug_assert_eq!(blue_noise_mask.len(), BLUE_NOISE_SIZE * BLUE_NOISE_SIZE);
+        let blue_noise_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some("Blue Noise Buffer"),
+            contents: bytemuck::cast_slice(blue_noise_mask),
+            usage: wgpu::BufferUsages::STORAGE,
+        });
+
         let terminate_queue = queue::Queue::new(&device, threads, Some("Terminate Queue"), true);
         let extension_queue = queue::Queue::new(&device, threads, Some("Extension Queue"), false);
         let shade_queue = queue::Queue::new(&device, threads, Some("Shade Queue"), false);
         let connect_queue = queue::Queue::new(&device, threads, Some("Connect Queue"), false);
 
-        let mut bgles = (0..18)
-            .map(|i| wgpu::BindGroupLayoutEntry {
-                binding: i,
-                visibility: wgpu::ShaderStages::COMPUTE,
-                ty: wgpu::BindingType::Buffer {
-                    ty: wgpu::BufferBindingType::Storage { read_only: false },
-                    has_dynamic_offset: false,
-                    min_binding_size: None,
-                },
-                count: None,
-            })
-            .collect_vec();
-        bgles.push(wgpu::BindGroupLayoutEntry {
-            binding: 18,
-            visibility: wgpu::ShaderStages::COMPUTE,
-            ty: wgpu::BindingType::Buffer {
-                ty: wgpu::BufferBindingType::Uniform,
-                has_dynamic_offset: false,
-                min_binding_size: None,
-            },
-            count: None,
-        });
-        let bind_group_layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
-            label: Some("Pathtracer State Bind Group Layout"),
-            entries: &bgles,
-        });
-
-        let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
-            label: Some("Pathtracer State Bind Group"),
-            layout: &bind_group_layout,
-            entries: &[
-                // Sample instance state + rays + hits:
-                wgpu::BindGroupEntry {
-                    binding: 0,
-                    resource: sample_buffer.as_entire_binding(),
-                },
-                wgpu::BindGroupEntry {
-                    binding: 1,
-                    resource: extension_rays_buffer.as_entire_binding(),
-                },
-                wgpu::BindGroupEntry {
-                    binding: 2,
-                    resource: connect_rays_buffer.as_entire_binding(),
-                },
-                wgpu::BindGroupEntry {
-                    binding: 3,
-                    resource: extension_hit_records_buffer.as_entire_binding(),
-                },
-                wgpu::BindGroupEntry {
-                    binding: 4,
-                    resource: connect_hit_records_buffer.as_entire_binding(),
-                },
-                // Random:
-                wgpu::BindGroupEntry {
-                    binding: 5,
-                    resource: random_state_buffer.as_entire_binding(),
-                },
-                // Sampling:
-                wgpu::BindGroupEntry {
-                    binding: 6,
-                    resource: sampling_counter_buffer.as_entire_binding(),
-                },
-                wgpu::BindGroupEntry {
-                    binding: 7,
-                    resource: sampling_source_buffer.as_entire_binding(),
-                },
-                wgpu::BindGroupEntry {
-                    binding: 8,
-                    resource: sampling_sum_buffer.as_entire_binding(),
-                },
-                wgpu::BindGroupEntry {
-                    binding: 9,
-                    resource: sampling_std_buffer.as_entire_binding(),
-                },
-                // All the queues ever:
-                wgpu::BindGroupEntry {
-                    binding: 10,
-                    resource: extension_queue.counter_uniform.as_entire_binding(),
-                },
-                wgpu::BindGroupEntry {
-                    binding: 11,
-                    resource: extension_queue.queue_buffer.as_entire_binding(),
-                },
-                wgpu::BindGroupEntry {
-                    binding: 12,
-                    resource: connect_queue.counter_uniform.as_entire_binding(),
-                },
-                wgpu::BindGroupEntry {
-                    binding: 13,
-                    resource: connect_queue.queue_buffer.as_entire_binding(),
-                },
-                wgpu::BindGroupEntry {
-                    binding: 14,
-                    resource: terminate_queue.counter_uniform.as_entire_binding(),
-                },
-                wgpu::BindGroupEntry {
-                    binding: 15,
-                    resource: terminate_queue.queue_buffer.as_entire_binding(),
-                },
-                wgpu::BindGroupEntry {
-                    binding: 16,
-                    resource: shade_queue.counter_uniform.as_entire_binding(),
-                },
-                wgpu::BindGroupEntry {
-                    binding: 17,
-                    resource: shade_queue.queue_buffer.as_entire_binding(),
-                },
-                wgpu::BindGroupEntry {
-                    binding: 18,
-                    resource: dims_buffer.as_entire_binding(),
-                },
+        let paths = StateGroup::new(
+            device,
+            "Pathtracer Paths",
+            &[
+                &sample_buffer,
+                &extension_rays_buffer,
+                &connect_rays_buffer,
+                &extension_hit_records_buffer,
+                &connect_hit_records_buffer,
+                &random_state_buffer,
             ],
-        });
+            &[],
+        );
+        let sampling = StateGroup::new(
+            device,
+            "Pathtracer Sampling",
+            &[
+                &sampling_counter_buffer,
+                &sampling_source_buffer,
+                &sampling_sum_buffer,
+                &sampling_std_buffer,
+                &blue_noise_buffer,
+            ],
+            &[],
+        );
+        // Each queue is its counter then its indices:
+        let queues = StateGroup::new(
+            device,
+            "Pathtracer Queues",
+            &[
+                &extension_queue,
+                &connect_queue,
+                &terminate_queue,
+                &shade_queue,
+            ]
+            .map(|q| [&q.counter_uniform, &q.queue_buffer])
+            .concat(),
+            &[],
+        );
+        let frame = StateGroup::new(
+            device,
+            "Pathtracer Frame",
+            &[],
+            &[&dims_buffer, &frame_buffer],
+        );
+
+        let path_bytes = [
+            &sample_buffer,
+            &random_state_buffer,
+            &extension_rays_buffer,
+            &connect_rays_buffer,
+            &extension_hit_records_buffer,
+            &connect_hit_records_buffer,
+            &shadow_data_buffer,
+            &dims_buffer,
+            &frame_buffer,
+        ]
+        .map(wgpu::Buffer::size)
+        .iter()
+        .sum::<u64>()
+            + [
+                &terminate_queue,
+                &extension_queue,
+                &shade_queue,
+                &connect_queue,
+            ]
+            .map(queue::Queue::buffer_bytes)
+            .iter()
+            .sum::<u64>();
+        let sample_bytes = [
+            &sampling_counter_buffer,
+            &sampling_source_buffer,
+            &sampling_sum_buffer,
+            &sampling_std_buffer,
+            &blue_noise_buffer,
+        ]
+        .map(wgpu::Buffer::size)
+        .iter()
+        .sum();
 
         Self {
             path_buffer: sample_buffer,
@@ -347,8 +426,76 @@ impl PathtracerState {
             extension_queue,
             shadow_queue: connect_queue,
             material_queue: shade_queue,
-            bind_group_layout,
-            bind_group,
+            frame_buffer,
+            path_bytes,
+            sample_bytes,
+            dims,
+            threads,
+            seed,
+            rng_seed,
+            paths,
+            sampling,
+            queues,
+            frame,
+        }
+    }
+}
+
+// Side length of the square tiles the sample sources are shuffled in.
+const SAMPLE_TILE_SIZE: u32 = 128;
+
+// One source per pixel, shuffled by tile and then within the whole image.
+// Edge tiles are cut short when the dims aren't a multiple of the tile size.
+fn sample_sources(dims: (u32, u32), rng: &mut impl Rng) -> Vec<SampleSource> {
+    let tile_size = SAMPLE_TILE_SIZE;
+    let mut tiles = (0..dims.0.div_ceil(tile_size))
+        .cartesian_product(0..dims.1.div_ceil(tile_size))
+        .collect_vec();
+
+    tiles.shuffle(rng);
+
+    let mut data = tiles
+        .into_iter()
+        .flat_map(|(x, y)| {
+            ((x * tile_size)..(x * tile_size + tile_size).min(dims.0))
+                .cartesian_product((y * tile_size)..(y * tile_size + tile_size).min(dims.1))
+                .map(|(x, y)| SampleSource {
+                    screen_pos: [x as f32 / dims.0 as f32, y as f32 / dims.1 as f32],
+                    out_pos: [x, y],
+                    samples: 0,
+                    flags: 0,
+                    rejected: 0,
+                    _pad: 0,
+                })
+        })
+        .collect_vec();
+    data.shuffle(rng);
+    // data.sort_by_key(|d| (d.out_pos[0] / 256, d.out_pos[1] / 256));
+
+    debug_assert_eq!(data.len(), (dims.0 * dims.1) as usize);
+    data
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn one_source_per_pixel() {
+        let mut rng = StdRng::seed_from_u64(0);
+        // Exact tiles, cut short edge tiles, thinner than a tile and a single pixel:
+        for dims in [(512, 512), (500, 500), (1920, 1080), (129, 7), (1, 1)] {
+            let sources = sample_sources(dims, &mut rng);
+            assert_eq!(sources.len(), (dims.0 * dims.1) as usize, "{dims:?}");
+
+            let mut seen = vec![false; sources.len()];
+            for s in &sources {
+                let [x, y] = s.out_pos;
+                assert!(x < dims.0 && y < dims.1, "{dims:?}: {x}, {y}");
+                let i = (y * dims.0 + x) as usize;
+                assert!(!seen[i], "{dims:?}: {x}, {y} twice");
+                seen[i] = true;
+            }
         }
     }
 }
@@ -0,0 +1,271 @@
+use bevy_ecs::{prelude::*, system::SystemParam};
+use glam::Vec2;
+use tracing::info;
+use winit::event::{MouseButton, WindowEvent};
+
+use crate::{
+    app::BevyApp,
+    binder::SceneBindings,
+    bvh::BVHNodeGPU,
+    camera::Camera,
+    instance::{INSTANCE_VISIBLE_CAMERA, Instance},
+    material::{Material, MaterialId},
+    mesh::{MeshServer, face_group},
+    pathtracer::Pathtracer,
+    render_resources::RenderSurface,
+    schedule,
+    transform::Transform,
+    traverse::{self, Hit, Ray},
+    winnit::WinitWindowEvent,
+};
+
+pub fn initialize(app: &mut BevyApp) {
+    app.world.init_resource::<PickedMaterial>();
+    app.world
+        .get_resource_or_init::<Schedules>()
+        .add_systems(schedule::Update, pick_system);
+}
+
+// The material of the instance last right clicked, None after clicking on nothing. See
+// material_edit for changing it live.
+#[derive(Resource, Default)]
+pub struct PickedMaterial(pub Option<MaterialId>);
+
+// The scene as the binder last bound it, kept on the CPU so the instance under the cursor
+// can be found without reading anything back from the GPU.
+#[derive(Default)]
+pub struct PickScene {
+    pub tlas_nodes: Vec<BVHNodeGPU>,
+    pub tlas_instance_ids: Vec<usize>,
+    pub instances: Vec<Instance>,
+    pub transforms: Vec<Transform>,
+    // Instance ids of the planes, possibly just u32::MAX padding:
+    pub plane_instances: Vec<u32>,
+    // Indexed by Instance::material_idx:
+    pub materials: Vec<Material>,
+    pub material_ids: Vec<MaterialId>,
+}
+
+impl PickScene {
+    // The first hit along `ray`, as the pathtracer would find it tracing with `mask`, some
+    // of Instance::flags.
+    pub fn intersect(&self, ray: Ray, mask: u32, mesh_server: &MeshServer) -> Option<Hit> {
+        let mut t = f32::MAX;
+        let mut hit = None;
+        if !self.tlas_nodes.is_empty() {
+            hit = traverse::tlas_first_hit(
+                &self.tlas_nodes,
+                &self.tlas_instance_ids,
+                &self.instances,
+                &self.transforms,
+                |g| {
+                    let data = mesh_server.geometry(g as u32)?;
+                    Some((data.nodes.as_slice(), &data.mesh))
+                },
+                ray,
+                mask,
+                &mut t,
+            );
+        }
+        let planes = traverse::planes_first_hit(
+            &self.plane_instances,
+            &self.instances,
+            &self.transforms,
+            ray,
+            mask,
+            &mut t,
+        );
+        planes.or(hit)
+    }
+
+    // The material of the hit's face group, only a mesh's faces having more than one.
+    pub fn material(&self, hit: &Hit, mesh_server: &MeshServer) -> Option<(MaterialId, &Material)> {
+        let instance = self.instances.get(hit.instance_id as usize)?;
+        let group = mesh_server
+            .geometry(instance.geometry_idx)
+            .and_then(|d| d.mesh.faces.get(hit.triangle_id as usize))
+            .map_or(0, |&f| face_group(f));
+        let idx = (instance.material_idx + group) as usize;
+        Some((*self.material_ids.get(idx)?, self.materials.get(idx)?))
+    }
+}
+
+// Casts rays from the primary pathtracer's camera into the bound scene.
+#[derive(SystemParam)]
+pub struct Picker<'w, 's> {
+    bindings: Res<'w, SceneBindings>,
+    mesh_server: Res<'w, MeshServer>,
+    cameras: Query<'w, 's, (&'static Camera, &'static Pathtracer)>,
+}
+
+impl Picker<'_, '_> {
+    // The hit seen at (`screen_x`, `screen_y`) in the primary pathtracer's output, in its
+    // pixels from the top left. Pixel centres are at .5. `None` on a miss.
+    pub fn pick_hit(&self, screen_x: f32, screen_y: f32) -> Option<Hit> {
+        let (camera, pt) = self.cameras.iter().find(|(_, pt)| pt.is_primary)?;
+        let screen_pos = Vec2::new(screen_x / pt.dims.0 as f32, screen_y / pt.dims.1 as f32);
+        let ray = camera.data.ray(screen_pos);
+        // What's under the cursor is what the camera sees:
+        self.bindings
+            .picking
+            .intersect(ray, INSTANCE_VISIBLE_CAMERA, &self.mesh_server)
+    }
+
+    pub fn pick_scene(&self) -> &PickScene {
+        &self.bindings.picking
+    }
+
+    fn primary_dims(&self) -> Option<(u32, u32)> {
+        self.cameras
+            .iter()
+            .find(|(_, pt)| pt.is_primary)
+            .map(|(_, pt)| pt.dims)
+    }
+}
+
+// Right clicking logs the instance under a free cursor and its material. Left and middle
+// drags already steer the orbit camera.
+pub fn pick_system(
+    mut we_reader: MessageReader<WinitWindowEvent>,
+    mut cursor: Local<Option<Vec2>>,
+    picker: Picker,
+    surface: Res<RenderSurface>,
+    mut picked: ResMut<PickedMaterial>,
+) {
+    for WinitWindowEvent(e) in we_reader.read() {
+        match e {
+            WindowEvent::CursorMoved { position, .. } => {
+                *cursor = Some(Vec2::new(position.x as f32, position.y as f32));
+            }
+            WindowEvent::MouseInput { state, button, .. }
+                if state.is_pressed() && *button == MouseButton::Right =>
+            {
+                let (Some(cursor), Some(dims)) = (*cursor, picker.primary_dims()) else {
+                    continue;
+                };
+                let surface = (surface.size.width, surface.size.height);
+                let Some(p) = surface_to_output(cursor, surface, dims) else {
+                    info!("Picked nothing, the cursor is outside the image");
+                    picked.0 = None;
+                    continue;
+                };
+                let Some(hit) = picker.pick_hit(p.x, p.y) else {
+                    info!("Picked nothing");
+                    picked.0 = None;
+                    continue;
+                };
+                let id = hit.instance_id;
+                info!(
+                    "Picked instance {id}, triangle {} at distance {}",
+                    hit.triangle_id, hit.t
+                );
+                let material = picker.pick_scene().material(&hit, &picker.mesh_server);
+                picked.0 = material.map(|(id, _)| id);
+                if let Some((material_id, material)) = material {
+                    info!("Instance {id} has {material_id:?}: {material:?}");
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+// The point of the pathtracer output drawn at `cursor` on the surface, undoing the fit
+// render.slang scales it in with. `None` over the bars either side.
+fn surface_to_output(cursor: Vec2, surface: (u32, u32), dims: (u32, u32)) -> Option<Vec2> {
+    let surface = Vec2::new(surface.0.max(1) as f32, surface.1.max(1) as f32);
+    let dims = Vec2::new(dims.0 as f32, dims.1 as f32);
+    let fit = surface / dims;
+    let scale = fit.x.min(fit.y);
+    let offset = (surface - dims * scale) * 0.5;
+    let p = (cursor - offset) / scale;
+    (p.cmpge(Vec2::ZERO).all() && p.cmplt(dims).all()).then_some(p)
+}
+
+#[cfg(test)]
+mod tests {
+    use glam::{Quat, Vec3, Vec4};
+
+    use super::*;
+    use crate::{camera::CameraData, instance::INSTANCE_CASTS_SHADOWS};
+
+    #[test]
+    fn cursor_maps_past_the_bars() {
+        // A square output drawn on a wide surface has bars left and right:
+        let surface = (200, 100);
+        assert_eq!(
+            surface_to_output(Vec2::new(40.0, 50.0), surface, (100, 100)),
+            None
+        );
+        assert_eq!(
+            surface_to_output(Vec2::new(160.0, 50.0), surface, (100, 100)),
+            None
+        );
+        assert_eq!(
+            surface_to_output(Vec2::new(100.0, 50.0), surface, (100, 100)),
+            Some(Vec2::new(50.0, 50.0))
+        );
+        // And a wide output on a square surface above and below, scaled down to fit:
+        assert_eq!(
+            surface_to_output(Vec2::new(50.0, 10.0), (100, 100), (200, 100)),
+            None
+        );
+        assert_eq!(
+            surface_to_output(Vec2::new(25.0, 50.0), (100, 100), (200, 100)),
+            Some(Vec2::new(50.0, 50.0))
+        );
+    }
+
+    #[test]
+    fn picks_the_floor_and_misses_the_sky() {
+        // 60 degrees from the top of the film to the bottom:
+        let half_height = 30f32.to_radians().tan();
+        let camera = CameraData {
+            dims: [half_height, half_height],
+            ..CameraData::new()
+        };
+        let top = camera.ray(Vec2::new(0.5, 0.0));
+        assert!((top.dir.angle_between(Vec3::Z) - 30f32.to_radians()).abs() < 1e-5);
+
+        // A floor a unit below the camera:
+        let scene = PickScene {
+            instances: vec![Instance {
+                transform_idx: 0,
+                geometry_idx: 0,
+                material_idx: 0,
+                flags: INSTANCE_VISIBLE_CAMERA,
+                velocity: Vec4::ZERO,
+            }],
+            transforms: vec![Transform::from_trs(Vec3::NEG_Y, Quat::IDENTITY, Vec3::ONE)],
+            plane_instances: vec![0],
+            ..Default::default()
+        };
+        let mesh_server = MeshServer::default();
+
+        let hit = scene
+            .intersect(
+                camera.ray(Vec2::new(0.5, 1.0)),
+                INSTANCE_VISIBLE_CAMERA,
+                &mesh_server,
+            )
+            .expect("Expected to hit the floor");
+        assert_eq!(hit.instance_id, 0);
+        // 30 degrees below the horizon:
+        assert!((hit.t - 2.0).abs() < 1e-4, "t {}", hit.t);
+        assert!(
+            scene
+                .intersect(top, INSTANCE_VISIBLE_CAMERA, &mesh_server)
+                .is_none()
+        );
+        // The floor doesn't cast shadows, so shadow rays pass through it:
+        assert!(
+            scene
+                .intersect(
+                    camera.ray(Vec2::new(0.5, 1.0)),
+                    INSTANCE_CASTS_SHADOWS,
+                    &mesh_server
+                )
+                .is_none()
+        );
+    }
+}
@@ -0,0 +1,33 @@
+use bevy_ecs::component::Component;
+use glam::{Quat, Vec3};
+
+use crate::transform::Transform;
+
+// Geometry index marking an instance as an infinite plane. Planes have no bounds, so they
+// are tested alongside the TLAS rather than in it. Matches GEOMETRY_PLANE in common.slang.
+pub const PLANE_GEOMETRY: u32 = u32::MAX - 1;
+
+// An infinite, two sided plane through `point`, intersected analytically. Spawned with a
+// MaterialId, but no Transform.
+#[derive(Component, Clone, Copy, Debug, PartialEq)]
+pub struct Plane {
+    pub point: Vec3,
+    pub normal: Vec3,
+}
+
+impl Plane {
+    pub fn new(point: Vec3, normal: Vec3) -> Self {
+        Self { point, normal }
+    }
+
+    // The transform of the instance it's traced as, the object space XZ plane turned so
+    // +Y is the normal.
+    pub fn instance_transform(&self) -> Transform {
+        let normal = self.normal.try_normalize().unwrap_or(Vec3::Y);
+        Transform::from_trs(
+            self.point,
+            Quat::from_rotation_arc(Vec3::Y, normal),
+            Vec3::ONE,
+        )
+    }
+}
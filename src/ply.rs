@@ -0,0 +1,390 @@
+use std::path::Path;
+
+use anyhow::Context;
+use glam::Vec3;
+
+// What the mesh loader needs from a .ply file. Faces with more than three vertices are
+// split into a fan around their first, so `indices` is always a triangle list.
+pub struct PlyMesh {
+    pub positions: Vec<Vec3>,
+    // Empty unless every vertex has nx, ny and nz:
+    pub normals: Vec<Vec3>,
+    pub indices: Vec<u32>,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Format {
+    Ascii,
+    BinaryLittleEndian,
+    BinaryBigEndian,
+}
+
+#[derive(Clone, Copy)]
+enum Scalar {
+    I8,
+    U8,
+    I16,
+    U16,
+    I32,
+    U32,
+    F32,
+    F64,
+}
+
+enum Property {
+    Scalar(Scalar, String),
+    // The count's type, then the items':
+    List(Scalar, Scalar, String),
+}
+
+struct Element {
+    name: String,
+    count: usize,
+    properties: Vec<Property>,
+}
+
+impl Scalar {
+    fn parse(name: &str) -> anyhow::Result<Self> {
+        Ok(match name {
+            "char" | "int8" => Self::I8,
+            "uchar" | "uint8" => Self::U8,
+            "short" | "int16" => Self::I16,
+            "ushort" | "uint16" => Self::U16,
+            "int" | "int32" => Self::I32,
+            "uint" | "uint32" => Self::U32,
+            "float" | "float32" => Self::F32,
+            "double" | "float64" => Self::F64,
+            _ => anyhow::bail!("Unknown property type {name}"),
+        })
+    }
+
+    fn size(self) -> usize {
+        match self {
+            Self::I8 | Self::U8 => 1,
+            Self::I16 | Self::U16 => 2,
+            Self::I32 | Self::U32 | Self::F32 => 4,
+            Self::F64 => 8,
+        }
+    }
+}
+
+impl Property {
+    fn name(&self) -> &str {
+        match self {
+            Self::Scalar(_, name) | Self::List(_, _, name) => name,
+        }
+    }
+}
+
+pub fn read_ply(path: &Path) -> anyhow::Result<PlyMesh> {
+    let bytes =
+        std::fs::read(path).with_context(|| format!("Failed to read {}", path.display()))?;
+    parse_ply(&bytes).with_context(|| format!("Failed to parse {}", path.display()))
+}
+
+fn parse_ply(bytes: &[u8]) -> anyhow::Result<PlyMesh> {
+    let (format, elements, body) = parse_header(bytes)?;
+    let mut values = match format {
+        Format::Ascii => Values::Ascii(
+            std::str::from_utf8(body)
+                .context("ASCII body is not valid UTF-8")?
+                .split_ascii_whitespace(),
+        ),
+        _ => Values::Binary {
+            data: body,
+            big_endian: format == Format::BinaryBigEndian,
+        },
+    };
+
+    let mut positions = Vec::new();
+    let mut normals = Vec::new();
+    let mut indices = Vec::new();
+    let mut has_normals = false;
+
+    // Elements are stored in header order and each has to be read through to get past
+    // it, whether it's used or not:
+    for element in &elements {
+        let index_of = |name: &str| element.properties.iter().position(|p| p.name() == name);
+        match element.name.as_str() {
+            "vertex" => {
+                let xyz = ["x", "y", "z"].map(index_of);
+                let [Some(x), Some(y), Some(z)] = xyz else {
+                    anyhow::bail!("Vertices have no x, y and z");
+                };
+                let normal = match ["nx", "ny", "nz"].map(index_of) {
+                    [Some(nx), Some(ny), Some(nz)] => Some([nx, ny, nz]),
+                    _ => None,
+                };
+                has_normals = normal.is_some();
+
+                let mut row = vec![0.0; element.properties.len()];
+                for _ in 0..element.count {
+                    for (value, property) in row.iter_mut().zip(&element.properties) {
+                        *value = values.read_property(property)?;
+                    }
+                    positions.push(Vec3::new(row[x] as f32, row[y] as f32, row[z] as f32));
+                    if let Some([nx, ny, nz]) = normal {
+                        normals.push(Vec3::new(row[nx] as f32, row[ny] as f32, row[nz] as f32));
+                    }
+                }
+            }
+            "face" => {
+                let list = index_of("vertex_indices")
+                    .or_else(|| index_of("vertex_index"))
+                    .context("Faces have no vertex_indices")?;
+                let mut face = Vec::new();
+                for _ in 0..element.count {
+                    for (i, property) in element.properties.iter().enumerate() {
+                        match property {
+                            Property::List(count, item, _) if i == list => {
+                                face.clear();
+                                let n = values.read(*count)? as usize;
+                                for _ in 0..n {
+                                    face.push(values.read(*item)? as u32);
+                                }
+                            }
+                            _ => {
+                                values.read_property(property)?;
+                            }
+                        }
+                    }
+                    // Points and lines have nothing to trace:
+                    for i in 1..face.len().saturating_sub(1) {
+                        indices.extend([face[0], face[i], face[i + 1]]);
+                    }
+                }
+            }
+            _ => {
+                for _ in 0..element.count {
+                    for property in &element.properties {
+                        values.read_property(property)?;
+                    }
+                }
+            }
+        }
+    }
+
+    if let Some(i) = indices.iter().find(|&&i| i as usize >= positions.len()) {
+        anyhow::bail!(
+            "Face index {i} is out of range of {} vertices",
+            positions.len()
+        );
+    }
+    if !has_normals {
+        normals.clear();
+    }
+    Ok(PlyMesh {
+        positions,
+        normals,
+        indices,
+    })
+}
+
+// The format, the elements in order and the body after end_header.
+fn parse_header(bytes: &[u8]) -> anyhow::Result<(Format, Vec<Element>, &[u8])> {
+    const END: &[u8] = b"end_header";
+    let end = bytes
+        .windows(END.len())
+        .position(|w| w == END)
+        .context("No end_header")?;
+    // The body starts after the line ending, which may be \r\n:
+    let mut body = end + END.len();
+    if bytes.get(body) == Some(&b'\r') {
+        body += 1;
+    }
+    if bytes.get(body) == Some(&b'\n') {
+        body += 1;
+    }
+
+    let header = std::str::from_utf8(&bytes[..end]).context("Header is not valid UTF-8")?;
+    let mut lines = header.lines().map(str::trim);
+    if lines.next() != Some("ply") {
+        anyhow::bail!("Not a PLY file");
+    }
+
+    let mut format = None;
+    let mut elements: Vec<Element> = Vec::new();
+    for line in lines {
+        let words = line.split_ascii_whitespace().collect::<Vec<_>>();
+        match words.as_slice() {
+            ["format", f, _] => {
+                format = Some(match *f {
+                    "ascii" => Format::Ascii,
+                    "binary_little_endian" => Format::BinaryLittleEndian,
+                    "binary_big_endian" => Format::BinaryBigEndian,
+                    _ => anyhow::bail!("Unknown format {f}"),
+                });
+            }
+            ["element", name, count] => elements.push(Element {
+                name: name.to_string(),
+                count: count.parse().context("Bad element count")?,
+                properties: Vec::new(),
+            }),
+            ["property", "list", count, item, name] => elements
+                .last_mut()
+                .context("Property before any element")?
+                .properties
+                .push(Property::List(
+                    Scalar::parse(count)?,
+                    Scalar::parse(item)?,
+                    name.to_string(),
+                )),
+            ["property", ty, name] => elements
+                .last_mut()
+                .context("Property before any element")?
+                .properties
+                .push(Property::Scalar(Scalar::parse(ty)?, name.to_string())),
+            ["comment", ..] | ["obj_info", ..] | [] => {}
+            _ => anyhow::bail!("Unexpected header line {line:?}"),
+        }
+    }
+
+    Ok((format.context("No format line")?, elements, &bytes[body..]))
+}
+
+// Property values one at a time, from either encoding of the body.
+enum Values<'a> {
+    Ascii(std::str::SplitAsciiWhitespace<'a>),
+    Binary { data: &'a [u8], big_endian: bool },
+}
+
+impl Values<'_> {
+    // Lists are skipped over, reading as zero.
+    fn read_property(&mut self, property: &Property) -> anyhow::Result<f64> {
+        match property {
+            Property::Scalar(ty, _) => self.read(*ty),
+            Property::List(count, item, _) => {
+                let n = self.read(*count)? as usize;
+                for _ in 0..n {
+                    self.read(*item)?;
+                }
+                Ok(0.0)
+            }
+        }
+    }
+
+    fn read(&mut self, ty: Scalar) -> anyhow::Result<f64> {
+        match self {
+            Values::Ascii(tokens) => {
+                let token = tokens.next().context("Body ends early")?;
+                token
+                    .parse::<f64>()
+                    .with_context(|| format!("Bad value {token:?}"))
+            }
+            Values::Binary { data, big_endian } => {
+                let size = ty.size();
+                if data.len() < size {
+                    anyhow::bail!("Body ends early");
+                }
+                let (head, rest) = data.split_at(size);
+                *data = rest;
+
+                let mut b = [0u8; 8];
+                b[..size].copy_from_slice(head);
+                if *big_endian {
+                    b[..size].reverse();
+                }
+                Ok(match ty {
+                    Scalar::I8 => b[0] as i8 as f64,
+                    Scalar::U8 => b[0] as f64,
+                    Scalar::I16 => i16::from_le_bytes([b[0], b[1]]) as f64,
+                    Scalar::U16 => u16::from_le_bytes([b[0], b[1]]) as f64,
+                    Scalar::I32 => i32::from_le_bytes([b[0], b[1], b[2], b[3]]) as f64,
+                    Scalar::U32 => u32::from_le_bytes([b[0], b[1], b[2], b[3]]) as f64,
+                    Scalar::F32 => f32::from_le_bytes([b[0], b[1], b[2], b[3]]) as f64,
+                    Scalar::F64 => f64::from_le_bytes(b),
+                })
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A unit quad in z = 0, facing +z, with an edge element to skip between the vertices and
+    // the face.
+    const ASCII: &str = "ply
+format ascii 1.0
+comment A quad
+element vertex 4
+property float x
+property float y
+property float z
+property float nx
+property float ny
+property float nz
+element edge 1
+property int vertex1
+property list uchar int crease
+element face 1
+property list uchar int vertex_indices
+end_header
+0 0 0 0 0 1
+1 0 0 0 0 1
+1 1 0 0 0 1
+0 1 0 0 0 1
+0 2 3 4
+4 0 1 2 3
+";
+
+    fn check_quad(mesh: &PlyMesh) {
+        assert_eq!(
+            mesh.positions,
+            [Vec3::ZERO, Vec3::X, Vec3::new(1.0, 1.0, 0.0), Vec3::Y]
+        );
+        assert_eq!(mesh.normals, [Vec3::Z; 4]);
+        // Fanned around the first corner:
+        assert_eq!(mesh.indices, [0, 1, 2, 0, 2, 3]);
+    }
+
+    #[test]
+    fn ascii_and_binary_read_the_same_quad() {
+        check_quad(&parse_ply(ASCII.as_bytes()).unwrap());
+
+        let header = ASCII[..ASCII.find("end_header\n").unwrap()]
+            .replace("format ascii", "format binary_little_endian");
+        let mut bytes = format!("{header}end_header\n").into_bytes();
+        for p in [Vec3::ZERO, Vec3::X, Vec3::new(1.0, 1.0, 0.0), Vec3::Y] {
+            for v in [p, Vec3::Z].into_iter().flat_map(|v| v.to_array()) {
+                bytes.extend(v.to_le_bytes());
+            }
+        }
+        bytes.extend(0i32.to_le_bytes());
+        bytes.push(2);
+        bytes.extend([3i32, 4].into_iter().flat_map(i32::to_le_bytes));
+        bytes.push(4);
+        bytes.extend([0i32, 1, 2, 3].into_iter().flat_map(i32::to_le_bytes));
+        check_quad(&parse_ply(&bytes).unwrap());
+    }
+
+    #[test]
+    fn normals_are_optional() {
+        let ply = "ply
+format ascii 1.0
+element vertex 3
+property float x
+property float y
+property float z
+element face 1
+property list uchar uint vertex_index
+end_header
+0 0 0
+1 0 0
+0 1 0
+3 0 1 2
+";
+        let mesh = parse_ply(ply.as_bytes()).unwrap();
+        assert_eq!(mesh.positions.len(), 3);
+        assert!(mesh.normals.is_empty());
+        assert_eq!(mesh.indices, [0, 1, 2]);
+    }
+
+    #[test]
+    fn out_of_range_faces_fail() {
+        let ply = ASCII.replace("4 0 1 2 3", "4 0 1 2 4");
+        let e = parse_ply(ply.as_bytes()).err().unwrap();
+        assert!(e.to_string().contains("out of range"), "{e}");
+    }
+}
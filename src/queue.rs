@@ -10,6 +10,10 @@ pub struct Queue {
 }
 
 impl Queue {
+    pub fn buffer_bytes(&self) -> u64 {
+        self.counter_uniform.size() + self.queue_buffer.size()
+    }
+
     pub fn new(device: &wgpu::Device, size: u32, label: Option<&str>, start_full: bool) -> Self {
         let contents = if start_full {
             &[size, size]
@@ -1,18 +1,34 @@
 use bevy_ecs::prelude::*;
+use glam::{Mat3, Vec3};
+use itertools::Itertools;
+use tracing::info;
 use wesl::include_wesl;
 use wgpu::{CommandBuffer, include_spirv, util::DeviceExt};
+use winit::{
+    event::WindowEvent,
+    keyboard::{KeyCode, PhysicalKey},
+};
 
 use crate::{
     app::BevyApp,
-    pathtracer::{Pathtracer, PathtracerOutput},
+    bloom::{Bloom, DEFAULT_BLOOM_THRESHOLD},
+    pathtracer::{AovKind, Pathtracer, PathtracerOutput, Viewport},
     render_resources::{RenderDevice, RenderQueue, RenderSurface},
     schedule,
+    screenshot::Screenshots,
+    winnit::WinitWindowEvent,
 };
 
 pub fn initialize(app: &mut BevyApp) {
     app.world.get_resource_or_init::<Schedules>().add_systems(
         schedule::Update,
-        (render_sync_system, render_system.after(render_sync_system)),
+        (
+            render_sync_system,
+            render_aov_system.after(render_sync_system),
+            render_heatmap_system.after(render_aov_system),
+            render_display_system.after(render_heatmap_system),
+            render_system.after(render_display_system),
+        ),
     );
 }
 
@@ -65,105 +81,393 @@ const VERTICES: &[Vertex] = &[
 
 const INDICES: &[u16] = &[0, 2, 1, 0, 3, 2];
 
+// Maps accumulated radiance onto the displayable 0.0..=1.0 range.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum ToneMapping {
+    // Clamp, bright values clip to white.
+    Linear,
+    // c / (1 + c)
+    Reinhard,
+    // Stephen Hill's fit of the ACES reference rendering transform.
+    #[default]
+    Aces,
+}
+
+// How the output is scaled up (or down) to fill the surface.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum UpscaleFilter {
+    // Crisp, blocky pixels.
+    Nearest,
+    // Bilinear between neighbouring pixels, after tone mapping.
+    #[default]
+    Linear,
+}
+
+// Exposure in stops, applied to the radiance before the operator.
+pub const DEFAULT_EXPOSURE: f32 = -2.5;
+
+// How the primary's output is displayed, as RunConfig sets it up. Headless renders are tone
+// mapped the same way. See RenderPhase::apply_display_settings.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct DisplaySettings {
+    pub tonemap: ToneMapping,
+    // In stops:
+    pub exposure: f32,
+    pub upscale: UpscaleFilter,
+    // Off at an intensity of 0. See RenderPhase::set_bloom:
+    pub bloom_threshold: f32,
+    pub bloom_intensity: f32,
+}
+
+impl Default for DisplaySettings {
+    fn default() -> Self {
+        Self {
+            tonemap: ToneMapping::default(),
+            exposure: DEFAULT_EXPOSURE,
+            upscale: UpscaleFilter::default(),
+            bloom_threshold: DEFAULT_BLOOM_THRESHOLD,
+            bloom_intensity: 0.0,
+        }
+    }
+}
+
+// Display settings the render phase is created with.
+#[derive(Resource, Clone, Copy, Default)]
+pub struct InitialDisplay(pub DisplaySettings);
+
+// What the display keybinds nudge, to log when one of them changes.
+#[derive(Clone, Copy, PartialEq)]
+struct DisplayState {
+    exposure: f32,
+    gamma: f32,
+    chromatic_aberration: f32,
+    vignette: f32,
+}
+
+// Nudged by the [ and ] keys:
+const EXPOSURE_STEP: f32 = 0.5;
+
+// Display gamma, applied after the operator. 2.2 leaves the surface's sRGB encoding as is.
+pub const DEFAULT_GAMMA: f32 = 2.2;
+// Nudged by the , and . keys:
+const GAMMA_STEP: f32 = 0.1;
+const MIN_GAMMA: f32 = 0.5;
+const MAX_GAMMA: f32 = 5.0;
+
+// Lens effects on the displayed image, both off at 0. Chromatic aberration pushes red out
+// and blue in by this fraction of each pixel's distance from the centre, nudged by ; and
+// '. Vignette darkens by this much at the corners, nudged by K and L.
+const CHROMATIC_ABERRATION_STEP: f32 = 0.0025;
+const MAX_CHROMATIC_ABERRATION: f32 = 0.05;
+const VIGNETTE_STEP: f32 = 0.1;
+
+#[repr(C)]
+#[derive(Copy, Clone, Debug, bytemuck::Pod, bytemuck::Zeroable)]
+struct ToneMapData {
+    dims: [u32; 2],
+    exposure: f32,
+    op: u32,
+    aov: u32, // 0 -> beauty, else 1 + AovKind
+    filter: u32,
+    surface: [u32; 2],
+    gamma: f32,
+    bloom_intensity: f32, // 0 -> off, the bloom buffer is stale
+    chromatic_aberration: f32,
+    vignette: f32,
+    _pad: [u32; 3],
+}
+
+// What one pathtracer's output is drawn with, its own tone map uniform since each is drawn
+// to a different size, and its own glow.
+struct OutputBindings {
+    bind_group: wgpu::BindGroup,
+    tonemap_buffer: wgpu::Buffer,
+    // Only built once bloom is turned on, RenderPhase::bloom_placeholder is bound until then:
+    bloom: Option<Bloom>,
+    dims: (u32, u32),
+}
+
+// A viewport's bindings, drawn with the primary's display settings. See Viewport:
+#[derive(Component)]
+pub struct ViewportBindings(OutputBindings);
+
 #[derive(Resource)]
 pub struct RenderPhase {
     render_pipeline: wgpu::RenderPipeline,
+    bind_group_layout: wgpu::BindGroupLayout,
     vertex_buffer: wgpu::Buffer,
     index_buffer: wgpu::Buffer,
-    bind_group: wgpu::BindGroup,
+    // The primary's, drawn over the whole surface:
+    primary: OutputBindings,
+    tonemap: ToneMapping,
+    exposure: f32,
+    gamma: f32,
+    // Glow around radiance above the threshold (in exposed radiance), added before the
+    // operator. An intensity of 0 skips the bloom passes entirely. See set_bloom:
+    bloom_threshold: f32,
+    bloom_intensity: f32,
+    // Bound in place of the glow of outputs without one, never read as the intensity is 0:
+    bloom_placeholder: wgpu::Buffer,
+    // See set_lens_effects:
+    chromatic_aberration: f32,
+    vignette: f32,
+    // Shown in place of the beauty pass, for debugging:
+    aov: Option<AovKind>,
+    upscale: UpscaleFilter,
 }
 
+// A viewport's output, and its bindings unless it hasn't been bound yet:
+type ViewportOutput<'a> = (
+    Entity,
+    &'a Pathtracer,
+    Ref<'a, PathtracerOutput>,
+    Option<&'a ViewportBindings>,
+);
+
+// Rebinds the primary and the viewports to their outputs whenever they're rebuilt, or to
+// build their glow once bloom is turned on.
 fn render_sync_system(
     mut commands: Commands,
     device: Res<RenderDevice>,
-    query: Query<(&Pathtracer, &PathtracerOutput), Changed<PathtracerOutput>>,
+    primaries: Query<(&Pathtracer, Ref<PathtracerOutput>)>,
+    viewports: Query<ViewportOutput, With<Viewport>>,
     surface: Res<RenderSurface>,
-    render_phase: Option<ResMut<RenderPhase>>,
+    mut render_phase: Option<ResMut<RenderPhase>>,
+    initial_display: Option<Res<InitialDisplay>>,
 ) {
-    for (pt, pto) in query {
-        if !pt.is_primary {
-            continue;
+    // If there are multiple primaries just use the first:
+    let mut new_phase = None;
+    if let Some((_, pto)) = primaries.iter().find(|(pt, _)| pt.is_primary) {
+        match &mut render_phase {
+            Some(rp) => {
+                if pto.is_changed() || rp.needs_bloom(&rp.primary) {
+                    rp.primary = rp.bind_output(&device.0, &pto);
+                }
+            }
+            None => {
+                let mut rp = RenderPhase::new(&device.0, &surface.config, &pto);
+                rp.apply_display_settings(&initial_display.map(|d| *d).unwrap_or_default().0);
+                new_phase = Some(rp);
+            }
         }
+    }
 
-        let mut rp = RenderPhase::new(&device.0, &surface.config, pto);
-        if let Some(mut old_rp) = render_phase {
-            std::mem::swap(&mut *old_rp, &mut rp);
-        } else {
-            commands.insert_resource(rp);
+    // Viewports are bound against the render phase's layout, so wait for there to be one:
+    let Some(rp) = render_phase.as_deref().or(new_phase.as_ref()) else {
+        return;
+    };
+    for (id, pt, pto, bindings) in viewports {
+        let unbound = bindings.is_none_or(|b| pto.is_changed() || rp.needs_bloom(&b.0));
+        if pt.is_primary || !unbound {
+            continue;
         }
+        commands
+            .entity(id)
+            .insert(ViewportBindings(rp.bind_output(&device.0, &pto)));
+    }
+
+    if let Some(rp) = new_phase {
+        commands.insert_resource(rp);
+    }
+}
+
+// F3 cycles the display through the AOVs and back to the beauty pass.
+fn render_aov_system(
+    mut we_reader: MessageReader<WinitWindowEvent>,
+    render_phase: Option<ResMut<RenderPhase>>,
+) {
+    let presses = we_reader
+        .read()
+        .filter(|WinitWindowEvent(e)| match e {
+            WindowEvent::KeyboardInput { event, .. } => {
+                event.state.is_pressed() && !event.repeat && event.physical_key == KeyCode::F3
+            }
+            _ => false,
+        })
+        .count();
+    let Some(mut render_phase) = render_phase else {
+        return;
+    };
+
+    for _ in 0..presses {
+        render_phase.aov = match render_phase.aov {
+            None => Some(AovKind::Albedo),
+            Some(AovKind::Albedo) => Some(AovKind::Normal),
+            Some(AovKind::Normal) => Some(AovKind::Depth),
+            Some(AovKind::Depth) | Some(AovKind::Traversal) => None,
+        };
+    }
+}
+
+// F4 toggles the traversal heatmap, for seeing where the BVHs are expensive to walk.
+fn render_heatmap_system(
+    mut we_reader: MessageReader<WinitWindowEvent>,
+    render_phase: Option<ResMut<RenderPhase>>,
+) {
+    let presses = we_reader
+        .read()
+        .filter(|WinitWindowEvent(e)| match e {
+            WindowEvent::KeyboardInput { event, .. } => {
+                event.state.is_pressed() && !event.repeat && event.physical_key == KeyCode::F4
+            }
+            _ => false,
+        })
+        .count();
+    let Some(mut render_phase) = render_phase else {
+        return;
+    };
 
-        // If there are multiple primaries just use the first... TODO making all of this work properly is a later problem
-        break;
+    for _ in 0..presses {
+        render_phase.aov = match render_phase.aov {
+            Some(AovKind::Traversal) => None,
+            _ => Some(AovKind::Traversal),
+        };
     }
 }
 
+// [ and ] step the exposure down and up, , and . the display gamma, ; and ' the chromatic
+// aberration and K and L the vignette. Held keys repeat.
+fn render_display_system(
+    mut we_reader: MessageReader<WinitWindowEvent>,
+    render_phase: Option<ResMut<RenderPhase>>,
+) {
+    let keys = we_reader
+        .read()
+        .filter_map(|WinitWindowEvent(e)| match e {
+            WindowEvent::KeyboardInput { event, .. } if event.state.is_pressed() => {
+                match event.physical_key {
+                    PhysicalKey::Code(key) => Some(key),
+                    _ => None,
+                }
+            }
+            _ => None,
+        })
+        .collect::<Vec<_>>();
+    let Some(mut render_phase) = render_phase else {
+        return;
+    };
+
+    // Snapped to whole steps, so stepping back down lands on exactly 0:
+    let nudge = |value: f32, steps: f32, step: f32| ((value / step).round() + steps) * step;
+    for key in keys {
+        let display = render_phase.display_state();
+        let DisplayState {
+            gamma,
+            chromatic_aberration: aberration,
+            vignette,
+            ..
+        } = display;
+        match key {
+            KeyCode::BracketLeft => render_phase.exposure -= EXPOSURE_STEP,
+            KeyCode::BracketRight => render_phase.exposure += EXPOSURE_STEP,
+            KeyCode::Comma => render_phase.set_gamma(gamma - GAMMA_STEP),
+            KeyCode::Period => render_phase.set_gamma(gamma + GAMMA_STEP),
+            KeyCode::Semicolon => render_phase
+                .set_lens_effects(nudge(aberration, -1.0, CHROMATIC_ABERRATION_STEP), vignette),
+            KeyCode::Quote => render_phase
+                .set_lens_effects(nudge(aberration, 1.0, CHROMATIC_ABERRATION_STEP), vignette),
+            KeyCode::KeyK => {
+                render_phase.set_lens_effects(aberration, nudge(vignette, -1.0, VIGNETTE_STEP))
+            }
+            KeyCode::KeyL => {
+                render_phase.set_lens_effects(aberration, nudge(vignette, 1.0, VIGNETTE_STEP))
+            }
+            _ => continue,
+        }
+        if display != render_phase.display_state() {
+            let DisplayState {
+                exposure,
+                gamma,
+                chromatic_aberration: aberration,
+                vignette,
+            } = render_phase.display_state();
+            info!(
+                "Exposure {exposure:+.1} stops, gamma {gamma:.1}, chromatic aberration {aberration:.4}, vignette {vignette:.1}"
+            );
+        }
+    }
+}
+
+// Draws the primary over the whole surface, then each viewport over its rect.
 pub fn render_system(
     device: Res<RenderDevice>,
     queue: Res<RenderQueue>,
-    query: Query<(&Pathtracer, &PathtracerOutput)>,
+    viewports: Query<(&Pathtracer, &Viewport, &ViewportBindings)>,
     surface: Res<RenderSurface>,
     render_phase: If<Res<RenderPhase>>,
+    mut screenshots: Option<ResMut<Screenshots>>,
 ) {
-    for (pt, pto) in query {
-        if !pt.is_primary {
-            continue;
-        }
+    let mut encoder = device
+        .0
+        .create_command_encoder(&wgpu::CommandEncoderDescriptor {
+            label: Some("Render Encoder"),
+        });
 
-        let mut encoder = device
-            .0
-            .create_command_encoder(&wgpu::CommandEncoderDescriptor {
-                label: Some("Render Encoder"),
-            });
+    let size = surface.size.into();
+    render_phase.prepare(&queue.0, &mut encoder, &render_phase.primary, size);
+    let viewports = viewports
+        .iter()
+        .filter(|(pt, _, _)| !pt.is_primary)
+        .map(|(_, viewport, bindings)| (viewport.pixel_rect(size), &bindings.0))
+        .collect_vec();
+    for &([_, _, w, h], output) in &viewports {
+        render_phase.prepare(&queue.0, &mut encoder, output, (w, h));
+    }
 
-        pto.copy_to_texture(&mut encoder);
-
-        let surface_texture = surface.surface.get_current_texture().unwrap();
-        let surface_view = surface_texture
-            .texture
-            .create_view(&wgpu::TextureViewDescriptor::default());
-
-        let mut render_pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
-            label: Some("Render Pass"),
-            color_attachments: &[Some(wgpu::RenderPassColorAttachment {
-                view: &surface_view,
-                resolve_target: None,
-                ops: wgpu::Operations {
-                    load: wgpu::LoadOp::Clear(wgpu::Color {
-                        r: 0.1,
-                        g: 0.2,
-                        b: 0.3,
-                        a: 1.0,
-                    }),
-                    store: wgpu::StoreOp::Store,
-                },
-                depth_slice: None,
-            })],
-            depth_stencil_attachment: None,
-            occlusion_query_set: None,
-            timestamp_writes: None,
-        });
+    let surface_texture = surface.surface.get_current_texture().unwrap();
+    let surface_view = surface_texture
+        .texture
+        .create_view(&wgpu::TextureViewDescriptor::default());
 
-        render_pass.set_pipeline(&render_phase.render_pipeline);
-        render_pass.set_bind_group(0, &render_phase.bind_group, &[]);
-        render_pass.set_vertex_buffer(0, render_phase.vertex_buffer.slice(..));
-        render_pass.set_index_buffer(
-            render_phase.index_buffer.slice(..),
-            wgpu::IndexFormat::Uint16,
-        );
+    let mut render_pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+        label: Some("Render Pass"),
+        color_attachments: &[Some(wgpu::RenderPassColorAttachment {
+            view: &surface_view,
+            resolve_target: None,
+            ops: wgpu::Operations {
+                // The quad covers the surface, drawing its own bars:
+                load: wgpu::LoadOp::Clear(wgpu::Color::BLACK),
+                store: wgpu::StoreOp::Store,
+            },
+            depth_slice: None,
+        })],
+        depth_stencil_attachment: None,
+        occlusion_query_set: None,
+        timestamp_writes: None,
+    });
+
+    render_pass.set_pipeline(&render_phase.render_pipeline);
+    render_pass.set_vertex_buffer(0, render_phase.vertex_buffer.slice(..));
+    render_pass.set_index_buffer(
+        render_phase.index_buffer.slice(..),
+        wgpu::IndexFormat::Uint16,
+    );
+    render_pass.set_bind_group(0, &render_phase.primary.bind_group, &[]);
+    render_pass.draw_indexed(0..(INDICES.len() as u32), 0, 0..1);
+
+    // The quad's texture coordinates are relative to the viewport, so each is drawn as if
+    // its rect were the whole surface:
+    for ([x, y, w, h], output) in viewports {
+        render_pass.set_viewport(x as f32, y as f32, w as f32, h as f32, 0.0, 1.0);
+        render_pass.set_bind_group(0, &output.bind_group, &[]);
         render_pass.draw_indexed(0..(INDICES.len() as u32), 0, 0..1);
+    }
 
-        drop(render_pass);
+    drop(render_pass);
 
-        let command = encoder.finish();
+    if let Some(screenshots) = &mut screenshots {
+        screenshots.capture(&device.0, &mut encoder, &surface_texture.texture);
+    }
 
-        queue.0.submit([command]);
+    let command = encoder.finish();
 
-        surface_texture.present();
+    queue.0.submit([command]);
 
-        // If there are multiple primaries just use the first... TODO later problem properly
-        // making all of this work lol
-        break;
+    if let Some(screenshots) = &mut screenshots {
+        screenshots.map();
     }
+
+    surface_texture.present();
 }
 
 impl RenderPhase {
@@ -172,46 +476,93 @@ impl RenderPhase {
         config: &wgpu::SurfaceConfiguration,
         pto: &PathtracerOutput,
     ) -> Self {
-        let view = pto
-            .out_texture
-            .create_view(&wgpu::TextureViewDescriptor::default());
-
         let bind_group_layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
             entries: &[
                 wgpu::BindGroupLayoutEntry {
                     binding: 0,
                     visibility: wgpu::ShaderStages::FRAGMENT,
-                    ty: wgpu::BindingType::Texture {
-                        multisampled: false,
-                        view_dimension: wgpu::TextureViewDimension::D2,
-                        sample_type: wgpu::TextureSampleType::Float { filterable: true },
+                    ty: wgpu::BindingType::Buffer {
+                        ty: wgpu::BufferBindingType::Storage { read_only: true },
+                        has_dynamic_offset: false,
+                        min_binding_size: None,
                     },
                     count: None,
                 },
                 wgpu::BindGroupLayoutEntry {
                     binding: 1,
                     visibility: wgpu::ShaderStages::FRAGMENT,
-                    ty: wgpu::BindingType::Sampler(wgpu::SamplerBindingType::Filtering),
+                    ty: wgpu::BindingType::Buffer {
+                        ty: wgpu::BufferBindingType::Uniform,
+                        has_dynamic_offset: false,
+                        min_binding_size: None,
+                    },
                     count: None,
                 },
-            ],
-            label: Some("texture_bind_group_layout"),
-        });
-
-        let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
-            layout: &bind_group_layout,
-            entries: &[
-                wgpu::BindGroupEntry {
-                    binding: 0,
-                    resource: wgpu::BindingResource::TextureView(&view),
+                // AOVs, in AovKind order:
+                wgpu::BindGroupLayoutEntry {
+                    binding: 2,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Buffer {
+                        ty: wgpu::BufferBindingType::Storage { read_only: true },
+                        has_dynamic_offset: false,
+                        min_binding_size: None,
+                    },
+                    count: None,
                 },
-                wgpu::BindGroupEntry {
-                    binding: 1,
-                    resource: wgpu::BindingResource::Sampler(&pto.out_sampler),
+                wgpu::BindGroupLayoutEntry {
+                    binding: 3,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Buffer {
+                        ty: wgpu::BufferBindingType::Storage { read_only: true },
+                        has_dynamic_offset: false,
+                        min_binding_size: None,
+                    },
+                    count: None,
+                },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 4,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Buffer {
+                        ty: wgpu::BufferBindingType::Storage { read_only: true },
+                        has_dynamic_offset: false,
+                        min_binding_size: None,
+                    },
+                    count: None,
+                },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 5,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Buffer {
+                        ty: wgpu::BufferBindingType::Storage { read_only: true },
+                        has_dynamic_offset: false,
+                        min_binding_size: None,
+                    },
+                    count: None,
+                },
+                // Bloom:
+                wgpu::BindGroupLayoutEntry {
+                    binding: 6,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Buffer {
+                        ty: wgpu::BufferBindingType::Storage { read_only: true },
+                        has_dynamic_offset: false,
+                        min_binding_size: None,
+                    },
+                    count: None,
                 },
             ],
-            label: Some("diffuse_bind_group"),
+            label: Some("Render Bind Group Layout"),
+        });
+
+        // Bloom starts off, see set_bloom:
+        let bloom_placeholder = device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("Bloom Placeholder Buffer"),
+            size: std::mem::size_of::<[f32; 4]>() as u64,
+            usage: wgpu::BufferUsages::STORAGE,
+            mapped_at_creation: false,
         });
+        let primary =
+            OutputBindings::new(device, &bind_group_layout, pto, None, &bloom_placeholder);
 
         // Load the shaders
         let render_shader =
@@ -279,9 +630,216 @@ impl RenderPhase {
 
         Self {
             render_pipeline,
+            bind_group_layout,
             vertex_buffer,
             index_buffer,
+            primary,
+            tonemap: ToneMapping::default(),
+            exposure: DEFAULT_EXPOSURE,
+            gamma: DEFAULT_GAMMA,
+            bloom_threshold: DEFAULT_BLOOM_THRESHOLD,
+            bloom_intensity: 0.0,
+            bloom_placeholder,
+            chromatic_aberration: 0.0,
+            vignette: 0.0,
+            aov: None,
+            upscale: UpscaleFilter::default(),
+        }
+    }
+
+    fn bind_output(&self, device: &wgpu::Device, pto: &PathtracerOutput) -> OutputBindings {
+        let bloom = (self.bloom_intensity > 0.0).then(|| Bloom::new(device, pto));
+        OutputBindings::new(
+            device,
+            &self.bind_group_layout,
+            pto,
+            bloom,
+            &self.bloom_placeholder,
+        )
+    }
+
+    // Whether bloom is on but `output` was bound before it was, without a glow.
+    fn needs_bloom(&self, output: &OutputBindings) -> bool {
+        self.bloom_intensity > 0.0 && output.bloom.is_none()
+    }
+
+    // Writes the output's tone map uniform for drawing it to a target of `surface`, and
+    // builds its glow if bloom is on.
+    fn prepare(
+        &self,
+        queue: &wgpu::Queue,
+        encoder: &mut wgpu::CommandEncoder,
+        output: &OutputBindings,
+        surface: (u32, u32),
+    ) {
+        queue.write_buffer(
+            &output.tonemap_buffer,
+            0,
+            bytemuck::bytes_of(&self.tonemap_data(output, surface)),
+        );
+        if let Some(bloom) = output.bloom.as_ref().filter(|_| self.bloom_intensity > 0.0) {
+            bloom.encode(queue, encoder, self.bloom_threshold / self.exposure.exp2());
+        }
+    }
+
+    pub fn apply_display_settings(&mut self, settings: &DisplaySettings) {
+        self.set_tonemap(settings.tonemap, settings.exposure);
+        self.set_upscale_filter(settings.upscale);
+        self.set_bloom(settings.bloom_threshold, settings.bloom_intensity);
+    }
+
+    // Exposure is in stops.
+    pub fn set_tonemap(&mut self, op: ToneMapping, exposure: f32) {
+        self.tonemap = op;
+        self.exposure = exposure;
+    }
+
+    pub fn set_gamma(&mut self, gamma: f32) {
+        self.gamma = gamma.clamp(MIN_GAMMA, MAX_GAMMA);
+    }
+
+    // Adds `intensity` times the blurred radiance above `threshold` on before tone mapping,
+    // the threshold being luminance after exposure. 0 turns it off. Each output's glow is
+    // built by render_sync_system the first time it's turned on.
+    pub fn set_bloom(&mut self, threshold: f32, intensity: f32) {
+        self.bloom_threshold = threshold.max(0.0);
+        self.bloom_intensity = intensity.max(0.0);
+    }
+
+    // Chromatic aberration offsets red outwards and blue inwards by that fraction of the
+    // distance from the centre, so it grows towards the edges. Vignette darkens towards
+    // the corners, by that fraction at them. Both are exactly nothing at 0.
+    pub fn set_lens_effects(&mut self, chromatic_aberration: f32, vignette: f32) {
+        self.chromatic_aberration = chromatic_aberration.clamp(0.0, MAX_CHROMATIC_ABERRATION);
+        self.vignette = vignette.clamp(0.0, 1.0);
+    }
+
+    fn display_state(&self) -> DisplayState {
+        DisplayState {
+            exposure: self.exposure,
+            gamma: self.gamma,
+            chromatic_aberration: self.chromatic_aberration,
+            vignette: self.vignette,
+        }
+    }
+
+    pub fn set_upscale_filter(&mut self, filter: UpscaleFilter) {
+        self.upscale = filter;
+    }
+
+    // `surface` is the size of the target `output` is drawn to.
+    fn tonemap_data(&self, output: &OutputBindings, surface: (u32, u32)) -> ToneMapData {
+        let dims = output.dims;
+        ToneMapData {
+            dims: [dims.0, dims.1],
+            exposure: self.exposure,
+            op: self.tonemap as u32,
+            aov: self.aov.map_or(0, |kind| kind as u32 + 1),
+            filter: self.upscale as u32,
+            surface: [surface.0.max(1), surface.1.max(1)],
+            gamma: self.gamma,
+            // Until render_sync_system gets to building its glow:
+            bloom_intensity: if output.bloom.is_some() {
+                self.bloom_intensity
+            } else {
+                0.0
+            },
+            chromatic_aberration: self.chromatic_aberration,
+            vignette: self.vignette,
+            _pad: [0; 3],
+        }
+    }
+}
+
+impl OutputBindings {
+    // Binds `placeholder` as the glow without `bloom`.
+    fn new(
+        device: &wgpu::Device,
+        layout: &wgpu::BindGroupLayout,
+        pto: &PathtracerOutput,
+        bloom: Option<Bloom>,
+        placeholder: &wgpu::Buffer,
+    ) -> Self {
+        let tonemap_buffer = device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("Tone Map Buffer"),
+            size: std::mem::size_of::<ToneMapData>() as u64,
+            usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        });
+
+        let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            layout,
+            entries: &[
+                wgpu::BindGroupEntry {
+                    binding: 0,
+                    resource: pto.source_buffer.as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 1,
+                    resource: tonemap_buffer.as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 2,
+                    resource: pto.albedo_buffer.as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 3,
+                    resource: pto.normal_buffer.as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 4,
+                    resource: pto.depth_buffer.as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 5,
+                    resource: pto.traversal_buffer.as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 6,
+                    resource: bloom
+                        .as_ref()
+                        .map_or(placeholder, |bloom| &bloom.output)
+                        .as_entire_binding(),
+                },
+            ],
+            label: Some("Render Bind Group"),
+        });
+
+        Self {
             bind_group,
+            tonemap_buffer,
+            bloom,
+            dims: pto.dims,
+        }
+    }
+}
+
+impl ToneMapping {
+    // CPU mirror of the render shader, for output that never reaches the surface.
+    // Returns linear rgb in 0.0..=1.0.
+    pub fn apply(self, radiance: Vec3, exposure: f32) -> Vec3 {
+        let c = radiance * exposure.exp2();
+        match self {
+            ToneMapping::Linear => c.clamp(Vec3::ZERO, Vec3::ONE),
+            ToneMapping::Reinhard => c / (Vec3::ONE + c),
+            ToneMapping::Aces => {
+                let m1 = Mat3::from_cols_array_2d(&[
+                    [0.59719, 0.35458, 0.04823],
+                    [0.07600, 0.90834, 0.01566],
+                    [0.02840, 0.13383, 0.83770],
+                ])
+                .transpose();
+                let m2 = Mat3::from_cols_array_2d(&[
+                    [1.60475, -0.53108, -0.07367],
+                    [-0.10208, 1.10813, -0.00605],
+                    [-0.00327, -0.07276, 1.07602],
+                ])
+                .transpose();
+                let v = m1 * c;
+                let a = v * (v + 0.0245786) - 0.000090537;
+                let b = v * (0.983729 * v + 0.432951) + 0.238081;
+                (m2 * (a / b)).clamp(Vec3::ZERO, Vec3::ONE)
+            }
         }
     }
 }
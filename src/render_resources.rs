@@ -1,8 +1,10 @@
 use std::sync::Arc;
 
-use crate::{app::BevyApp, schedule, winnit::WinitWindow};
+use crate::{app::BevyApp, schedule, texture::MAX_TEXTURES, winnit::WinitWindow};
 
+use anyhow::Context;
 use bevy_ecs::prelude::*;
+use tracing::{info, warn};
 use winit::dpi::PhysicalSize;
 
 #[derive(Resource, Clone)]
@@ -34,39 +36,149 @@ pub fn initialize(app: &mut BevyApp) {
         .add_systems(schedule::PreStartup, setup_renderer);
 }
 
-fn setup_renderer(mut commands: Commands, window: Option<Res<WinitWindow>>) {
-    let rt = tokio::runtime::Runtime::new().unwrap();
+// Comma separated wgpu backend names (vulkan, metal, dx12, gl) to pick the adapter from.
+const RENDERER_BACKEND_VAR: &str = "RENDERER_BACKEND";
+
+// Backends picked when the app is set up, in place of RENDERER_BACKEND.
+#[derive(Resource, Clone, Copy, Debug)]
+pub struct RendererBackends(pub wgpu::Backends);
+
+// Every backend wgpu supports well on this platform unless RendererBackends or
+// RENDERER_BACKEND narrows it.
+fn backends(requested: Option<&RendererBackends>) -> wgpu::Backends {
+    if let Some(RendererBackends(backends)) = requested {
+        return *backends;
+    }
+    let Ok(names) = std::env::var(RENDERER_BACKEND_VAR) else {
+        return wgpu::Backends::PRIMARY;
+    };
+    let backends = wgpu::Backends::from_comma_list(&names);
+    if backends.is_empty() {
+        warn!("No known backends in {RENDERER_BACKEND_VAR}={names:?}, using the defaults");
+        return wgpu::Backends::PRIMARY;
+    }
+    backends
+}
+
+// Tried in order until one finds an adapter, ending with the software fallback.
+const ADAPTER_ATTEMPTS: [(wgpu::PowerPreference, bool); 3] = [
+    (wgpu::PowerPreference::HighPerformance, false),
+    (wgpu::PowerPreference::LowPower, false),
+    (wgpu::PowerPreference::HighPerformance, true),
+];
+
+fn request_adapter(
+    rt: &tokio::runtime::Runtime,
+    instance: &wgpu::Instance,
+    backends: wgpu::Backends,
+    surface: Option<&wgpu::Surface>,
+) -> anyhow::Result<wgpu::Adapter> {
+    for (power_preference, force_fallback_adapter) in ADAPTER_ATTEMPTS {
+        info!(
+            "Requesting an adapter on {backends:?} with {power_preference:?} (fallback: {force_fallback_adapter})"
+        );
+        match rt.block_on(instance.request_adapter(&wgpu::RequestAdapterOptions {
+            power_preference,
+            compatible_surface: surface,
+            force_fallback_adapter,
+        })) {
+            Ok(adapter) => return Ok(adapter),
+            Err(e) => warn!(
+                "No adapter with {power_preference:?} (fallback: {force_fallback_adapter}): {e}"
+            ),
+        }
+    }
+    anyhow::bail!(
+        "No compatible GPU adapter on {backends:?}, tried HighPerformance, LowPower and the \
+         fallback adapter. Check a driver is installed, or pick other backends with \
+         {RENDERER_BACKEND_VAR}"
+    )
+}
+
+// Enough for large scenes, and trimmed to whatever the adapter supports.
+const DESIRED_STORAGE_BUFFER_SIZE: u32 = 402653184; // 384 MiB
+
+fn required_limits(adapter: &wgpu::Limits) -> wgpu::Limits {
+    let mut limits = wgpu::Limits::defaults();
+    limits.max_bind_groups = 8.min(adapter.max_bind_groups);
+    limits.max_storage_buffer_binding_size =
+        DESIRED_STORAGE_BUFFER_SIZE.min(adapter.max_storage_buffer_binding_size);
+    limits.max_buffer_size = (DESIRED_STORAGE_BUFFER_SIZE as u64).min(adapter.max_buffer_size);
+    limits.max_binding_array_elements_per_shader_stage =
+        1000.min(adapter.max_binding_array_elements_per_shader_stage);
+    limits.max_storage_buffers_per_shader_stage =
+        100.min(adapter.max_storage_buffers_per_shader_stage);
+    // A sampler per material texture slot:
+    limits.max_samplers_per_shader_stage =
+        (MAX_TEXTURES as u32 + 1).min(adapter.max_samplers_per_shader_stage);
+    limits.max_binding_array_sampler_elements_per_shader_stage =
+        (MAX_TEXTURES as u32).min(adapter.max_binding_array_sampler_elements_per_shader_stage);
+
+    if storage_budget(&limits) < DESIRED_STORAGE_BUFFER_SIZE as u64 {
+        warn!(
+            "Adapter caps storage buffers at {} MiB, meshes past that are left out of the scene",
+            storage_budget(&limits) >> 20
+        );
+    }
+    limits
+}
+
+// Largest storage buffer the device can bind in one go, in bytes.
+pub fn storage_budget(limits: &wgpu::Limits) -> u64 {
+    (limits.max_storage_buffer_binding_size as u64).min(limits.max_buffer_size)
+}
+
+pub fn setup_renderer(
+    mut commands: Commands,
+    window: Option<Res<WinitWindow>>,
+    requested_backends: Option<Res<RendererBackends>>,
+) -> Result {
+    let rt = tokio::runtime::Runtime::new().context("Failed to start the renderer runtime")?;
 
     // Configure rendering stuff:
+    let backends = backends(requested_backends.as_deref());
     let instance = wgpu::Instance::new(&wgpu::InstanceDescriptor {
-        backends: wgpu::Backends::VULKAN,
+        backends,
         ..Default::default()
     });
 
     let surface = window
         .as_ref()
-        .map(|w| instance.create_surface(w.0.clone()).unwrap());
+        .map(|w| instance.create_surface(w.0.clone()))
+        .transpose()
+        .context("Failed to create the window surface")?;
 
-    let adapter = rt
-        .block_on(instance.request_adapter(&wgpu::RequestAdapterOptions {
-            power_preference: wgpu::PowerPreference::HighPerformance,
-            compatible_surface: surface.as_ref(),
-            force_fallback_adapter: false,
-        }))
-        .unwrap();
+    let adapter = request_adapter(&rt, &instance, backends, surface.as_ref())?;
 
-    let mut limits = wgpu::Limits::defaults();
-    limits.max_bind_groups = 8;
-    limits.max_storage_buffer_binding_size = 402653184;
-    limits.max_buffer_size = 402653184;
-    limits.max_binding_array_elements_per_shader_stage = 1000;
-    limits.max_storage_buffers_per_shader_stage = 100;
+    let adapter_info = adapter.get_info();
+    info!("Using {} on {:?}", adapter_info.name, adapter_info.backend);
+    // The shaders are compiled to SPIR-V, which naga has to translate for anything else.
+    // That covers most of them, but binding arrays are patchy outside Vulkan:
+    if adapter_info.backend != wgpu::Backend::Vulkan {
+        warn!(
+            "Shaders are SPIR-V, translating them for {:?} may fail",
+            adapter_info.backend
+        );
+    }
+
+    let limits = required_limits(&adapter.limits());
 
     let required_features = wgpu::Features::empty()
         .union(wgpu::Features::SAMPLED_TEXTURE_AND_STORAGE_BUFFER_ARRAY_NON_UNIFORM_INDEXING)
         .union(wgpu::Features::BUFFER_BINDING_ARRAY)
+        .union(wgpu::Features::TEXTURE_BINDING_ARRAY)
         .union(wgpu::Features::STORAGE_RESOURCE_BINDING_ARRAY);
 
+    let missing = required_features - adapter.features();
+    if !missing.is_empty() {
+        return Err(anyhow::anyhow!(
+            "{} on {:?} is missing required features {missing:?}",
+            adapter_info.name,
+            adapter_info.backend
+        )
+        .into());
+    }
+
     let (device, queue) = rt
         .block_on(adapter.request_device(&wgpu::DeviceDescriptor {
             label: None,
@@ -76,7 +188,7 @@ fn setup_renderer(mut commands: Commands, window: Option<Res<WinitWindow>>) {
             memory_hints: wgpu::MemoryHints::Performance,
             trace: wgpu::Trace::Off,
         }))
-        .unwrap();
+        .with_context(|| format!("Failed to open a device on {}", adapter_info.name))?;
 
     if let (Some(surface), Some(window)) = (surface, window) {
         let size = window.0.inner_size();
@@ -89,8 +201,12 @@ fn setup_renderer(mut commands: Commands, window: Option<Res<WinitWindow>>) {
             .copied()
             .unwrap_or(surface_caps.formats[0]);
 
+        // Copied from for screenshots, where the surface allows it:
+        let usage = wgpu::TextureUsages::RENDER_ATTACHMENT
+            | (surface_caps.usages & wgpu::TextureUsages::COPY_SRC);
+
         let config = wgpu::SurfaceConfiguration {
-            usage: wgpu::TextureUsages::RENDER_ATTACHMENT,
+            usage,
             format: surface_format,
             width: size.width,
             height: size.height,
@@ -108,9 +224,46 @@ fn setup_renderer(mut commands: Commands, window: Option<Res<WinitWindow>>) {
         });
     }
 
-    commands.insert_resource(RenderAdapterInfo(adapter.get_info()));
+    commands.insert_resource(RenderAdapterInfo(adapter_info));
     commands.insert_resource(RenderAdapter(Arc::new(adapter)));
     commands.insert_resource(RenderInstance(Arc::new(instance)));
     commands.insert_resource(RenderQueue(Arc::new(queue)));
     commands.insert_resource(RenderDevice(Arc::new(device)));
+    Ok(())
+}
+
+// Copies `size` bytes from the start of `buffer` back to the CPU, blocking until done.
+// `buffer` must have been created with COPY_SRC.
+pub fn read_buffer(
+    device: &wgpu::Device,
+    queue: &wgpu::Queue,
+    buffer: &wgpu::Buffer,
+    size: u64,
+) -> anyhow::Result<Vec<u8>> {
+    let staging = device.create_buffer(&wgpu::BufferDescriptor {
+        label: Some("Readback Staging Buffer"),
+        size,
+        usage: wgpu::BufferUsages::MAP_READ | wgpu::BufferUsages::COPY_DST,
+        mapped_at_creation: false,
+    });
+
+    let mut encoder = device.create_command_encoder(&wgpu::CommandEncoderDescriptor {
+        label: Some("Readback Encoder"),
+    });
+    encoder.copy_buffer_to_buffer(buffer, 0, &staging, 0, size);
+    queue.submit([encoder.finish()]);
+
+    let (tx, rx) = crossbeam::channel::bounded(1);
+    let slice = staging.slice(..);
+    slice.map_async(wgpu::MapMode::Read, move |result| {
+        tx.send(result).ok();
+    });
+
+    // The map callback only fires once the copy has actually completed:
+    device.poll(wgpu::PollType::wait_indefinitely())?;
+    rx.recv()??;
+
+    let bytes = slice.get_mapped_range().to_vec();
+    staging.unmap();
+    Ok(bytes)
 }
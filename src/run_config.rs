@@ -0,0 +1,401 @@
+use std::{convert::Infallible, path::PathBuf, str::FromStr};
+
+use anyhow::{Context, ensure};
+use itertools::Itertools;
+
+use crate::{
+    bvh::BvhPreset,
+    pathtracer::{Integrator, LightSampling, ReconstructionFilter, SamplingMode, TraceSettings},
+    render::{DisplaySettings, ToneMapping, UpscaleFilter},
+    scenes,
+};
+
+// Where the scene traced comes from.
+#[derive(Clone, Debug)]
+pub enum SceneSource {
+    // One of the scenes F10 steps through, by name, e.g. "cornell".
+    Builtin(String),
+    // A scene file, see load_scene_file. Watched and reloaded on save in a window.
+    File(PathBuf),
+}
+
+// A built in scene's name, else a path to a scene file.
+impl FromStr for SceneSource {
+    type Err = Infallible;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match scenes::builtin_scene(s) {
+            Some(_) => Self::Builtin(s.to_owned()),
+            None => Self::File(PathBuf::from(s)),
+        })
+    }
+}
+
+// Everything run sets up before tracing. Left at its defaults it opens a 512x512 window
+// on the scene RAYTRACER_SCENE names, or the simple scene, on the backends
+// RENDERER_BACKEND names, or every primary one.
+#[derive(Clone, Debug)]
+pub struct RunConfig {
+    pub dims: (u32, u32),
+    // Overrides RENDERER_BACKEND:
+    pub backends: Option<wgpu::Backends>,
+    // Overrides RAYTRACER_SCENE:
+    pub scene: Option<SceneSource>,
+    // Renders `target_spp` passes to `output` without a window, then returns. Both are
+    // required.
+    pub headless: bool,
+    // In a window sampling carries on past it, see Pathtracer::set_target_spp.
+    pub target_spp: Option<u32>,
+    // A PNG, only written headless.
+    pub output: Option<PathBuf>,
+    // Seeds the primary pathtracer, see Pathtracer::set_seed. Headless runs are always
+    // seeded, with HEADLESS_SEED unless this is set.
+    pub seed: Option<u64>,
+    // How the primary pathtracer traces, headless or in a window:
+    pub trace: TraceSettings,
+    // How the output is tone mapped, in a window or written headless:
+    pub display: DisplaySettings,
+    // Leaf sizes of the acceleration structures, see BvhSettings:
+    pub bvh_preset: BvhPreset,
+}
+
+impl Default for RunConfig {
+    fn default() -> Self {
+        Self {
+            dims: (512, 512),
+            backends: None,
+            scene: None,
+            headless: false,
+            target_spp: None,
+            output: None,
+            seed: None,
+            trace: TraceSettings::default(),
+            display: DisplaySettings::default(),
+            bvh_preset: BvhPreset::default(),
+        }
+    }
+}
+
+impl RunConfig {
+    // Checked by run before anything starts.
+    pub fn validate(&self) -> anyhow::Result<()> {
+        ensure!(
+            self.dims.0 > 0 && self.dims.1 > 0,
+            "Resolution must be at least 1x1, got {}x{}",
+            self.dims.0,
+            self.dims.1
+        );
+        if let Some(backends) = self.backends {
+            ensure!(!backends.is_empty(), "No backends to pick an adapter from");
+        }
+        match &self.scene {
+            Some(SceneSource::Builtin(name)) => {
+                builtin_scene_index(name)?;
+            }
+            Some(SceneSource::File(path)) => {
+                ensure!(
+                    path.is_file(),
+                    "No scene file {}, and the built in scenes are {}",
+                    path.display(),
+                    scenes::builtin_scene_names().join(", ")
+                )
+            }
+            None => {}
+        }
+        ensure!(
+            self.target_spp != Some(0),
+            "Target samples per pixel must be at least 1"
+        );
+        self.validate_trace()?;
+        ensure!(
+            self.display.exposure.is_finite(),
+            "The exposure must be finite"
+        );
+        ensure!(
+            self.display.bloom_threshold.is_finite() && self.display.bloom_threshold >= 0.0,
+            "The bloom threshold must be 0 or more, got {}",
+            self.display.bloom_threshold
+        );
+        ensure!(
+            self.display.bloom_intensity.is_finite() && self.display.bloom_intensity >= 0.0,
+            "The bloom intensity must be 0 or more, got {}",
+            self.display.bloom_intensity
+        );
+
+        if self.headless {
+            ensure!(
+                self.output.is_some(),
+                "Headless runs need an output path to write the render to"
+            );
+            ensure!(
+                self.target_spp.is_some(),
+                "Headless runs need a target samples per pixel to stop at"
+            );
+            // There's no window to follow, the resolution is the output's:
+            ensure!(
+                self.trace.render_scale.is_none(),
+                "Headless runs render at the resolution given, drop the render scale"
+            );
+            // Bloom is a pass of the window's render phase, which headless runs don't have:
+            ensure!(
+                self.display.bloom_intensity == 0.0,
+                "Bloom is only drawn in a window, drop it for headless runs"
+            );
+        } else {
+            ensure!(
+                self.output.is_none(),
+                "Only headless runs write an output, set headless or drop the output path"
+            );
+        }
+        Ok(())
+    }
+
+    fn validate_trace(&self) -> anyhow::Result<()> {
+        let trace = &self.trace;
+        ensure!(trace.max_bounces > 0, "Max bounces must be at least 1");
+        if let Integrator::PathFixedDepth { depth } = trace.integrator {
+            ensure!(depth > 0, "A fixed depth must be at least 1 bounce");
+        }
+        ensure!(
+            trace.clamp_indirect.is_none_or(|r| r > 0.0),
+            "Indirect radiance must be clamped above 0"
+        );
+        ensure!(
+            trace.outlier_rejection.is_none_or(|s| s > 0.0),
+            "Outliers must be rejected above 0 standard deviations"
+        );
+        ensure!(
+            trace.target_error.is_finite() && trace.target_error >= 0.0,
+            "The target error must be 0 or more, got {}",
+            trace.target_error
+        );
+        ensure!(
+            trace.max_samples == 0 || trace.max_samples >= trace.min_samples,
+            "Max samples {} is under min samples {}",
+            trace.max_samples,
+            trace.min_samples
+        );
+        ensure!(
+            trace.ray_epsilon.is_finite() && trace.ray_epsilon >= 0.0,
+            "The ray epsilon must be 0 or more, got {}",
+            trace.ray_epsilon
+        );
+        ensure!(
+            trace.iterations_per_frame > 0,
+            "Iterations per frame must be at least 1"
+        );
+        ensure!(
+            trace.render_scale.is_none_or(|s| s.is_finite() && s > 0.0),
+            "The render scale must be above 0"
+        );
+        Ok(())
+    }
+}
+
+// "path-rr", or "fixed-depth:N" for exactly N bounces.
+impl FromStr for Integrator {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Some(depth) = s.strip_prefix("fixed-depth:") {
+            let depth = depth
+                .parse()
+                .with_context(|| format!("Expected a whole number of bounces in {s:?}"))?;
+            return Ok(Self::PathFixedDepth { depth });
+        }
+        ensure!(
+            s == "path-rr",
+            "No integrator {s:?}, expected path-rr or fixed-depth:N"
+        );
+        Ok(Self::PathRR)
+    }
+}
+
+impl FromStr for SamplingMode {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        parse_named(
+            "sampling mode",
+            s,
+            &[
+                ("random", Self::Random),
+                ("stratified", Self::Stratified),
+                ("blue-noise", Self::BlueNoise),
+            ],
+        )
+    }
+}
+
+impl FromStr for ReconstructionFilter {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        parse_named(
+            "reconstruction filter",
+            s,
+            &[
+                ("box", Self::Box),
+                ("tent", Self::Tent),
+                ("gaussian", Self::Gaussian),
+            ],
+        )
+    }
+}
+
+impl FromStr for LightSampling {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        parse_named(
+            "light sampling",
+            s,
+            &[("area", Self::Area), ("solid-angle", Self::SolidAngle)],
+        )
+    }
+}
+
+impl FromStr for ToneMapping {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        parse_named(
+            "tone mapping",
+            s,
+            &[
+                ("linear", Self::Linear),
+                ("reinhard", Self::Reinhard),
+                ("aces", Self::Aces),
+            ],
+        )
+    }
+}
+
+impl FromStr for BvhPreset {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        parse_named(
+            "BVH preset",
+            s,
+            &[
+                ("fast", Self::Fast),
+                ("balanced", Self::Balanced),
+                ("quality", Self::Quality),
+            ],
+        )
+    }
+}
+
+impl FromStr for UpscaleFilter {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        parse_named(
+            "upscale filter",
+            s,
+            &[("nearest", Self::Nearest), ("linear", Self::Linear)],
+        )
+    }
+}
+
+// The value `s` names, for options spelt out on the command line.
+fn parse_named<T: Copy>(kind: &str, s: &str, names: &[(&str, T)]) -> anyhow::Result<T> {
+    names
+        .iter()
+        .find(|(name, _)| *name == s)
+        .map(|(_, value)| *value)
+        .with_context(|| {
+            format!(
+                "No {kind} {s:?}, expected one of {}",
+                names.iter().map(|(name, _)| name).join(", ")
+            )
+        })
+}
+
+// Index into the built in scenes of the one called `name`, see scenes::CurrentScene.
+pub(crate) fn builtin_scene_index(name: &str) -> anyhow::Result<usize> {
+    scenes::builtin_scene(name).with_context(|| {
+        format!(
+            "No built in scene {name:?}, expected one of {}",
+            scenes::builtin_scene_names().join(", ")
+        )
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn headless() -> RunConfig {
+        RunConfig {
+            headless: true,
+            target_spp: Some(16),
+            output: Some(PathBuf::from("out.png")),
+            ..Default::default()
+        }
+    }
+
+    fn rejects(config: RunConfig, message: &str) {
+        let e = config.validate().unwrap_err();
+        assert!(e.to_string().contains(message), "{e}");
+    }
+
+    #[test]
+    fn validate_catches_bad_combinations() {
+        RunConfig::default().validate().unwrap();
+        headless().validate().unwrap();
+
+        rejects(
+            RunConfig {
+                output: None,
+                ..headless()
+            },
+            "need an output path",
+        );
+        rejects(
+            RunConfig {
+                headless: false,
+                target_spp: None,
+                ..headless()
+            },
+            "Only headless runs write an output",
+        );
+        rejects(
+            RunConfig {
+                target_spp: Some(0),
+                ..headless()
+            },
+            "Target samples per pixel must be at least 1",
+        );
+
+        let mut bloom = headless();
+        bloom.display.bloom_intensity = 0.5;
+        rejects(bloom, "Bloom is only drawn in a window");
+
+        let mut samples = RunConfig::default();
+        samples.trace.min_samples = 8;
+        samples.trace.max_samples = 4;
+        rejects(samples, "Max samples 4 is under min samples 8");
+    }
+
+    #[test]
+    fn options_parse_by_name() {
+        assert_eq!("path-rr".parse::<Integrator>().unwrap(), Integrator::PathRR);
+        assert_eq!(
+            "fixed-depth:6".parse::<Integrator>().unwrap(),
+            Integrator::PathFixedDepth { depth: 6 }
+        );
+        assert!("fixed-depth:six".parse::<Integrator>().is_err());
+        assert_eq!(
+            "blue-noise".parse::<SamplingMode>().unwrap(),
+            SamplingMode::BlueNoise
+        );
+
+        let e = "sobol".parse::<SamplingMode>().unwrap_err();
+        assert_eq!(
+            e.to_string(),
+            "No sampling mode \"sobol\", expected one of random, stratified, blue-noise"
+        );
+    }
+}
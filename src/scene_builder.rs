@@ -0,0 +1,639 @@
+use bevy_ecs::prelude::*;
+use glam::Vec3;
+use tracing::error;
+
+use crate::{
+    camera::{Camera, CameraData},
+    environment::{Environment, EnvironmentDescriptor},
+    instance::{RayVisibility, Velocity},
+    material::{Material, MaterialGroups, MaterialId, MaterialServer},
+    mesh::{MeshDescriptor, MeshId, MeshServer, NormalizeMode, ShadingMode, obj_materials},
+    plane::Plane,
+    render_resources::{RenderDevice, RenderQueue},
+    sphere::Sphere,
+    texture::{TextureId, TextureKind, TextureSampling, TextureServer},
+    transform::Transform,
+};
+
+// Spawns one instance of a loaded mesh. Meshes are shared between instances through their
+// id, while each instance keeps whichever material it is given.
+pub trait SpawnInstance {
+    fn spawn_instance(
+        &mut self,
+        mesh: MeshId,
+        material: MaterialId,
+        transform: Transform,
+    ) -> Entity;
+
+    fn spawn_sphere(
+        &mut self,
+        sphere: Sphere,
+        material: MaterialId,
+        transform: Transform,
+    ) -> Entity;
+
+    fn spawn_plane(&mut self, plane: Plane, material: MaterialId) -> Entity;
+}
+
+impl SpawnInstance for World {
+    fn spawn_instance(
+        &mut self,
+        mesh: MeshId,
+        material: MaterialId,
+        transform: Transform,
+    ) -> Entity {
+        self.spawn((transform, material, mesh)).id()
+    }
+
+    fn spawn_sphere(
+        &mut self,
+        sphere: Sphere,
+        material: MaterialId,
+        transform: Transform,
+    ) -> Entity {
+        self.spawn((transform, material, sphere)).id()
+    }
+
+    fn spawn_plane(&mut self, plane: Plane, material: MaterialId) -> Entity {
+        self.spawn((material, plane)).id()
+    }
+}
+
+impl SpawnInstance for Commands<'_, '_> {
+    fn spawn_instance(
+        &mut self,
+        mesh: MeshId,
+        material: MaterialId,
+        transform: Transform,
+    ) -> Entity {
+        self.spawn((transform, material, mesh)).id()
+    }
+
+    fn spawn_sphere(
+        &mut self,
+        sphere: Sphere,
+        material: MaterialId,
+        transform: Transform,
+    ) -> Entity {
+        self.spawn((transform, material, sphere)).id()
+    }
+
+    fn spawn_plane(&mut self, plane: Plane, material: MaterialId) -> Entity {
+        self.spawn((material, plane)).id()
+    }
+}
+
+#[derive(PartialEq)]
+enum SceneShape {
+    Mesh(MeshDescriptor, ShadingMode),
+    Sphere(Sphere),
+    Plane(Plane),
+}
+
+struct SceneEntry {
+    shape: SceneShape,
+    material: Material,
+    // Of each face group, see MaterialGroups, empty to use `material` for all:
+    groups: Vec<Material>,
+    transform: Transform,
+    visibility: RayVisibility,
+    velocity: Velocity,
+}
+
+// Collects objects to spawn, so scenes can be described without touching the ECS.
+// Meshes are deduplicated by the mesh server, materials by value.
+#[derive(Default)]
+pub struct SceneBuilder {
+    entries: Vec<SceneEntry>,
+    camera: Option<CameraData>,
+    environment: Option<EnvironmentDescriptor>,
+    // Loaded into the world's TextureServer on a build, see SceneBuilder::texture:
+    textures: Vec<(String, TextureKind, TextureSampling)>,
+}
+
+impl SceneBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn add(
+        &mut self,
+        mesh: MeshDescriptor,
+        material: Material,
+        transform: Transform,
+    ) -> &mut Self {
+        self.add_shaded(mesh, ShadingMode::Smooth, material, transform)
+    }
+
+    pub fn add_shaded(
+        &mut self,
+        mesh: MeshDescriptor,
+        shading: ShadingMode,
+        material: Material,
+        transform: Transform,
+    ) -> &mut Self {
+        self.entries.push(SceneEntry {
+            shape: SceneShape::Mesh(mesh, shading),
+            material,
+            groups: Vec::new(),
+            transform,
+            visibility: RayVisibility::default(),
+            velocity: Velocity::default(),
+        });
+        self
+    }
+
+    // An OBJ file as one mesh, each of its models with its MTL material or `fallback` if it
+    // has none. Placed by `normalize` before `transform`.
+    pub fn add_obj(
+        &mut self,
+        path: &str,
+        normalize: NormalizeMode,
+        fallback: Material,
+        transform: Transform,
+    ) -> anyhow::Result<&mut Self> {
+        let materials = obj_materials(path)?
+            .into_iter()
+            .map(|m| m.unwrap_or(fallback))
+            .collect::<Vec<_>>();
+        Ok(self
+            .add(
+                MeshDescriptor::TOBJ(path.to_owned(), normalize),
+                materials[0],
+                transform,
+            )
+            .material_groups(materials))
+    }
+
+    // An analytic sphere centred on the transform's translation.
+    pub fn add_sphere(
+        &mut self,
+        sphere: Sphere,
+        material: Material,
+        transform: Transform,
+    ) -> &mut Self {
+        self.entries.push(SceneEntry {
+            shape: SceneShape::Sphere(sphere),
+            material,
+            groups: Vec::new(),
+            transform,
+            visibility: RayVisibility::default(),
+            velocity: Velocity::default(),
+        });
+        self
+    }
+
+    // An infinite plane, placed by its own point and normal rather than a transform.
+    pub fn add_plane(&mut self, plane: Plane, material: Material) -> &mut Self {
+        self.entries.push(SceneEntry {
+            shape: SceneShape::Plane(plane),
+            material,
+            groups: Vec::new(),
+            transform: Transform::default(),
+            visibility: RayVisibility::default(),
+            velocity: Velocity::default(),
+        });
+        self
+    }
+
+    // The material of each face group of the mesh added last, in place of its own.
+    pub fn material_groups(&mut self, materials: Vec<Material>) -> &mut Self {
+        if let Some(entry) = self.entries.last_mut() {
+            entry.groups = materials;
+        }
+        self
+    }
+
+    // Which rays see the object added last.
+    pub fn visibility(&mut self, visibility: RayVisibility) -> &mut Self {
+        if let Some(entry) = self.entries.last_mut() {
+            entry.visibility = visibility;
+        }
+        self
+    }
+
+    // How fast the object added last moves while the shutter is open, for motion blur.
+    pub fn velocity(&mut self, velocity: Vec3) -> &mut Self {
+        if let Some(entry) = self.entries.last_mut() {
+            entry.velocity = Velocity(velocity);
+        }
+        self
+    }
+
+    // The pose and lens every existing camera is given when the scene is built.
+    pub fn camera(&mut self, data: CameraData) -> &mut Self {
+        self.camera = Some(data);
+        self
+    }
+
+    pub fn camera_data(&self) -> Option<CameraData> {
+        self.camera
+    }
+
+    // A texture for the scene's materials, loaded when it's built. The index is what the
+    // materials given to this builder refer to it by, 1-based as TextureId::material_index,
+    // and is swapped for the loaded texture's on a build.
+    pub fn texture(&mut self, path: &str, kind: TextureKind, sampling: TextureSampling) -> u32 {
+        let texture = (path.to_owned(), kind, sampling);
+        let i = match self.textures.iter().position(|t| *t == texture) {
+            Some(i) => i,
+            None => {
+                self.textures.push(texture);
+                self.textures.len() - 1
+            }
+        };
+        i as u32 + 1
+    }
+
+    // Replaces the world's environment when the scene is built. Without one the scene
+    // keeps whatever environment is already there.
+    pub fn environment(&mut self, descriptor: EnvironmentDescriptor) -> &mut Self {
+        self.environment = Some(descriptor);
+        self
+    }
+
+    // Spawns every entry, returning their entities in the order they were added.
+    pub fn build(
+        &self,
+        world: &mut World,
+        mesh_server: &mut MeshServer,
+        material_server: &mut MaterialServer,
+    ) -> Vec<Entity> {
+        let textures = self.load_textures(world);
+        let mut materials = Vec::new();
+        let entities = self
+            .entries
+            .iter()
+            .map(|entry| {
+                let material = entry.material(&textures);
+                let material = dedup_material(&mut materials, material_server, material);
+                entry.spawn(
+                    world,
+                    mesh_server,
+                    &textures,
+                    &mut materials,
+                    material_server,
+                    material,
+                )
+            })
+            .collect();
+        self.apply_camera(world);
+        self.apply_environment(world, None);
+        entities
+    }
+
+    // Changes what `previous` built as `entities` into this scene, rather than building it
+    // from scratch. Entries are matched by position: one with the same shape as before
+    // keeps its entity and only has a changed transform or material replaced, anything
+    // else is respawned. The camera is left where it is.
+    pub fn update(
+        &self,
+        previous: &SceneBuilder,
+        entities: &[Entity],
+        world: &mut World,
+        mesh_server: &mut MeshServer,
+        material_server: &mut MaterialServer,
+    ) -> Vec<Entity> {
+        let textures = self.load_textures(world);
+        // Already loaded, so this only looks them up:
+        let old_textures = previous.load_textures(world);
+        let mut materials = Vec::new();
+        let updated = self
+            .entries
+            .iter()
+            .enumerate()
+            .map(|(i, entry)| {
+                let old = previous.entries.get(i).zip(entities.get(i));
+                if let Some((old, &entity)) = old
+                    && old.shape == entry.shape
+                    && world.get_entity(entity).is_ok()
+                {
+                    let material = entry.material(&textures);
+                    if bytemuck::bytes_of(&old.material(&old_textures))
+                        != bytemuck::bytes_of(&material)
+                    {
+                        let material = dedup_material(&mut materials, material_server, material);
+                        world.entity_mut(entity).insert(material);
+                    }
+                    // Planes are placed by their shape, not a transform:
+                    if !matches!(entry.shape, SceneShape::Plane(_))
+                        && bytemuck::bytes_of(&old.transform)
+                            != bytemuck::bytes_of(&entry.transform)
+                    {
+                        world.entity_mut(entity).insert(entry.transform);
+                    }
+                    if !materials_equal(&old.groups(&old_textures), &entry.groups(&textures)) {
+                        entry.insert_groups(
+                            world,
+                            entity,
+                            &textures,
+                            &mut materials,
+                            material_server,
+                        );
+                    }
+                    if old.visibility != entry.visibility {
+                        world.entity_mut(entity).insert(entry.visibility);
+                    }
+                    if old.velocity != entry.velocity {
+                        world.entity_mut(entity).insert(entry.velocity);
+                    }
+                    return entity;
+                }
+
+                if let Some((_, &entity)) = old {
+                    world.despawn(entity);
+                }
+                let material = entry.material(&textures);
+                let material = dedup_material(&mut materials, material_server, material);
+                entry.spawn(
+                    world,
+                    mesh_server,
+                    &textures,
+                    &mut materials,
+                    material_server,
+                    material,
+                )
+            })
+            .collect();
+
+        for &entity in entities.iter().skip(self.entries.len()) {
+            world.despawn(entity);
+        }
+        self.apply_environment(world, previous.environment.as_ref());
+        updated
+    }
+
+    // The material index of each of the builder's textures, 0 for any that failed to load
+    // or without a TextureServer to load them into.
+    fn load_textures(&self, world: &mut World) -> Vec<u32> {
+        let Some(mut texture_server) = world.get_resource_mut::<TextureServer>() else {
+            return vec![0; self.textures.len()];
+        };
+        self.textures
+            .iter()
+            .map(|(path, kind, sampling)| {
+                texture_server
+                    .load_texture(path, *kind, *sampling)
+                    .inspect_err(|e| error!("{e:#}"))
+                    .map_or(0, TextureId::material_index)
+            })
+            .collect()
+    }
+
+    // Only reloads the map when it's a different file than `previous` had.
+    fn apply_environment(&self, world: &mut World, previous: Option<&EnvironmentDescriptor>) {
+        let Some(descriptor) = &self.environment else {
+            return;
+        };
+        if previous.is_some_and(|p| p.map == descriptor.map)
+            && let Some(mut environment) = world.get_resource_mut::<Environment>()
+        {
+            // Only flagged as changed when it is, so the binder doesn't rewrite it:
+            let before = (
+                environment.intensity,
+                environment.rotation(),
+                environment.background(),
+            );
+            let current = environment.bypass_change_detection();
+            current.apply(descriptor);
+            if (current.intensity, current.rotation(), current.background()) != before {
+                environment.set_changed();
+            }
+            return;
+        }
+
+        let device = &world.resource::<RenderDevice>().0;
+        let queue = &world.resource::<RenderQueue>().0;
+        match Environment::from_descriptor(device, queue, descriptor) {
+            Ok(environment) => world.insert_resource(environment),
+            Err(e) => error!("{e:#}, keeping the current environment"),
+        }
+    }
+
+    fn apply_camera(&self, world: &mut World) {
+        if let Some(data) = self.camera {
+            let mut cameras = world.query::<&mut Camera>();
+            for mut camera in cameras.iter_mut(world) {
+                // The sensor size belongs to the pathtracer, not the scene:
+                camera.data = CameraData {
+                    dims: camera.data.dims,
+                    changed: 1,
+                    ..data
+                };
+                camera.changed = true;
+            }
+        }
+    }
+
+    // Builds against the world's own servers, e.g. from an exclusive system.
+    pub fn spawn(&self, world: &mut World) -> Vec<Entity> {
+        world.resource_scope(|world, mut mesh_server: Mut<MeshServer>| {
+            world.resource_scope(|world, mut material_server: Mut<MaterialServer>| {
+                self.build(world, &mut mesh_server, &mut material_server)
+            })
+        })
+    }
+}
+
+impl SceneEntry {
+    // The entry's material with the builder's texture indices swapped for `textures`.
+    fn material(&self, textures: &[u32]) -> Material {
+        resolve_textures(self.material, textures)
+    }
+
+    fn groups(&self, textures: &[u32]) -> Vec<Material> {
+        self.groups
+            .iter()
+            .map(|&m| resolve_textures(m, textures))
+            .collect()
+    }
+
+    fn spawn(
+        &self,
+        world: &mut World,
+        mesh_server: &mut MeshServer,
+        textures: &[u32],
+        materials: &mut Vec<(Material, MaterialId)>,
+        material_server: &mut MaterialServer,
+        material: MaterialId,
+    ) -> Entity {
+        let entity = match &self.shape {
+            SceneShape::Mesh(mesh, shading) => {
+                let mesh = mesh_server.load_mesh_shaded(mesh.clone(), *shading);
+                world.spawn_instance(mesh, material, self.transform)
+            }
+            SceneShape::Sphere(sphere) => world.spawn_sphere(*sphere, material, self.transform),
+            SceneShape::Plane(plane) => world.spawn_plane(*plane, material),
+        };
+        // Left off at their defaults, seen by everything and still, as most objects are:
+        if self.visibility != RayVisibility::default() {
+            world.entity_mut(entity).insert(self.visibility);
+        }
+        if self.velocity != Velocity::default() {
+            world.entity_mut(entity).insert(self.velocity);
+        }
+        if !self.groups.is_empty() {
+            self.insert_groups(world, entity, textures, materials, material_server);
+        }
+        entity
+    }
+
+    // Replaces the entity's MaterialGroups with this entry's, removing them if it has none.
+    fn insert_groups(
+        &self,
+        world: &mut World,
+        entity: Entity,
+        textures: &[u32],
+        materials: &mut Vec<(Material, MaterialId)>,
+        material_server: &mut MaterialServer,
+    ) {
+        if self.groups.is_empty() {
+            world.entity_mut(entity).remove::<MaterialGroups>();
+            return;
+        }
+        let groups = self
+            .groups(textures)
+            .into_iter()
+            .map(|m| dedup_material(materials, material_server, m))
+            .collect();
+        world.entity_mut(entity).insert(MaterialGroups(groups));
+    }
+}
+
+// Swaps SceneBuilder::texture indices for the loaded textures'. Materials that weren't given
+// any of the builder's textures are left as they are.
+fn resolve_textures(mut material: Material, textures: &[u32]) -> Material {
+    for index in [
+        &mut material.colour_texture,
+        &mut material.emissive_texture,
+        &mut material.metallic_roughness_texture,
+        &mut material.normal_texture,
+        &mut material.height_texture,
+    ] {
+        if *index > 0
+            && let Some(&loaded) = textures.get(*index as usize - 1)
+        {
+            *index = loaded;
+        }
+    }
+    material
+}
+
+fn materials_equal(a: &[Material], b: &[Material]) -> bool {
+    bytemuck::cast_slice::<_, u8>(a) == bytemuck::cast_slice::<_, u8>(b)
+}
+
+// Materials are deduplicated by value within one build.
+fn dedup_material(
+    materials: &mut Vec<(Material, MaterialId)>,
+    material_server: &mut MaterialServer,
+    material: Material,
+) -> MaterialId {
+    if let Some((_, id)) = materials
+        .iter()
+        .find(|(m, _)| bytemuck::bytes_of(m) == bytemuck::bytes_of(&material))
+    {
+        return *id;
+    }
+    let id = material_server.add_material(material);
+    materials.push((material, id));
+    id
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use super::*;
+
+    #[test]
+    fn instances_share_a_mesh_but_not_materials() {
+        let mut world = World::new();
+        world.init_resource::<MeshServer>();
+        world.init_resource::<MaterialServer>();
+
+        let mut builder = SceneBuilder::new();
+        for i in 0..3 {
+            let material = Material {
+                colour: Vec3::splat(i as f32 * 0.25).extend(1.0),
+                ..Default::default()
+            };
+            let transform = Transform::new(Vec3::ONE, Vec3::ZERO, Vec3::X * i as f32);
+            builder.add(MeshDescriptor::Cube, material, transform);
+        }
+        let entities = builder.spawn(&mut world);
+        assert_eq!(entities.len(), 3);
+
+        let mut query = world.query::<(&MeshId, &MaterialId, &Transform)>();
+        let instances = entities
+            .iter()
+            .map(|&e| query.get(&world, e).unwrap())
+            .collect::<Vec<_>>();
+        let meshes = instances
+            .iter()
+            .map(|(m, _, _)| **m)
+            .collect::<HashSet<_>>();
+        let materials = instances
+            .iter()
+            .map(|(_, m, _)| **m)
+            .collect::<HashSet<_>>();
+        assert_eq!(meshes.len(), 1);
+        assert_eq!(world.resource::<MeshServer>().pending(), 1);
+        assert_eq!(materials.len(), 3);
+        let material_server = world.resource::<MaterialServer>();
+        for (i, &id) in instances.iter().map(|(_, m, _)| *m).enumerate() {
+            let colour = material_server.get(id).unwrap().colour;
+            assert_eq!(colour.x, i as f32 * 0.25);
+        }
+        let translations = instances
+            .iter()
+            .map(|(_, _, t)| t.translation.x)
+            .collect::<Vec<_>>();
+        assert_eq!(translations, [0.0, 1.0, 2.0]);
+    }
+
+    // A cube, a sphere and a rect, the cube at `x` and the sphere of `radius`:
+    fn reloaded(x: f32, radius: f32, rect: bool) -> SceneBuilder {
+        let mut builder = SceneBuilder::new();
+        let transform = Transform::new(Vec3::ONE, Vec3::ZERO, Vec3::X * x);
+        builder.add(MeshDescriptor::Cube, Material::default(), transform);
+        builder.add_sphere(Sphere { radius }, Material::default(), Transform::default());
+        if rect {
+            builder.add(
+                MeshDescriptor::Rect,
+                Material::default(),
+                Transform::default(),
+            );
+        }
+        builder
+    }
+
+    #[test]
+    fn reloads_keep_what_only_moved() {
+        let mut world = World::new();
+        let mut mesh_server = MeshServer::default();
+        let mut material_server = MaterialServer::default();
+
+        let before = reloaded(0.0, 1.0, true);
+        let old = before.build(&mut world, &mut mesh_server, &mut material_server);
+        let after = reloaded(2.0, 0.5, false);
+        let new = after.update(
+            &before,
+            &old,
+            &mut world,
+            &mut mesh_server,
+            &mut material_server,
+        );
+
+        // The moved cube keeps its entity with the new transform:
+        assert_eq!(new.len(), 2);
+        assert_eq!(new[0], old[0]);
+        assert_eq!(world.get::<Transform>(new[0]).unwrap().translation.x, 2.0);
+        // A new radius is a new shape, so the sphere is respawned:
+        assert_ne!(new[1], old[1]);
+        assert!(world.get_entity(old[1]).is_err());
+        assert_eq!(world.get::<Sphere>(new[1]).unwrap().radius, 0.5);
+        // And the rect that's gone from the file is despawned:
+        assert!(world.get_entity(old[2]).is_err());
+    }
+}
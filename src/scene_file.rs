@@ -0,0 +1,693 @@
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
+
+use anyhow::Context;
+use bevy_ecs::prelude::*;
+use glam::{Vec3, Vec4};
+use ron::extensions::Extensions;
+use serde::Deserialize;
+use tracing::{error, info, warn};
+
+use crate::{
+    app::BevyApp,
+    camera::CameraData,
+    delta_time::Time,
+    environment::{Background, DEFAULT_BACKGROUND, EnvironmentDescriptor},
+    instance::RayVisibility,
+    material::{Material, MaterialServer},
+    mesh::{self, MeshDescriptor, MeshServer, NormalizeMode, ShadingMode},
+    pathtracer::Pathtracer,
+    plane::Plane,
+    scene_builder::SceneBuilder,
+    scenes, schedule,
+    sphere::Sphere,
+    texture::{TextureAddress, TextureFilter, TextureKind, TextureSampling},
+    transform::Transform,
+};
+
+// Names a scene file to load in place of the built in scenes, reloaded whenever it's saved.
+const SCENE_FILE_VAR: &str = "RAYTRACER_SCENE";
+
+// Watches the SceneFileWatch inserted before, if there is one, see RunConfig::scene.
+pub fn initialize(app: &mut BevyApp) {
+    if !app.world.contains_resource::<SceneFileWatch>() {
+        return;
+    }
+    app.world
+        .get_resource_or_init::<Schedules>()
+        .add_systems(schedule::Update, scene_file_watch_system);
+}
+
+// The scene file RAYTRACER_SCENE names, if it's set.
+pub fn scene_file_var() -> Option<PathBuf> {
+    std::env::var_os(SCENE_FILE_VAR).map(PathBuf::from)
+}
+
+// A scene as stored on disk, one entry per object spawned by the builder. Stored as RON, or
+// as JSON like the camera pose files, e.g.
+// { "camera": { "position": [0, 0, -5] },
+//   "environment": { "map": "assets/sky.hdr", "rotation": 1.57, "background": "map" },
+//   "objects": [{ "mesh": { "obj": "assets/dragon.obj" }, "material": { "roughness": 0.2 } }] }
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct SceneFile {
+    #[serde(default)]
+    pub camera: Option<SceneCamera>,
+    // Left out, the environment is left as it is:
+    #[serde(default)]
+    pub environment: Option<SceneEnvironment>,
+    pub objects: Vec<SceneObject>,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct SceneObject {
+    pub mesh: MeshSource,
+    #[serde(default)]
+    pub shading: ShadingMode,
+    // OBJ only, meshes of other formats are always fitted into the unit cube:
+    #[serde(default)]
+    pub normalize: SceneNormalize,
+    // Left out, an OBJ's models keep their MTL materials, a glTF primitive its PBR
+    // material and anything else is given SceneMaterial's defaults:
+    #[serde(default)]
+    pub material: Option<SceneMaterial>,
+    #[serde(default)]
+    pub transform: SceneTransform,
+    // Hidden from the camera, as for compositing, it's only seen by its shadow, reflections
+    // and the light it bounces:
+    #[serde(default = "default_true")]
+    pub visible_camera: bool,
+    #[serde(default = "default_true")]
+    pub casts_shadows: bool,
+    // World units per second, blurred across the camera's shutter_time:
+    #[serde(default)]
+    pub velocity: [f32; 3],
+}
+
+// Where an OBJ's vertices are put before its transform, see NormalizeMode. The objects
+// of a joint group are fitted into the unit cube together, e.g. a building and the chair
+// inside it both given "normalize": { "joint": "house" }.
+#[derive(Deserialize, Debug, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum SceneNormalize {
+    #[default]
+    UnitCube,
+    None,
+    Joint(String),
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum MeshSource {
+    Obj(String),
+    Ply(String),
+    Gltf {
+        path: String,
+        #[serde(default)]
+        primitive: usize,
+    },
+    Cube,
+    Rect,
+    // Analytic, so `shading` doesn't apply:
+    Sphere {
+        #[serde(default = "default_radius")]
+        radius: f32,
+    },
+    // Infinite, so `transform` doesn't apply either:
+    Plane {
+        #[serde(default)]
+        point: [f32; 3],
+        #[serde(default = "default_normal")]
+        normal: [f32; 3],
+    },
+}
+
+fn default_true() -> bool {
+    true
+}
+
+fn default_radius() -> f32 {
+    1.0
+}
+
+fn default_normal() -> [f32; 3] {
+    [0.0, 1.0, 0.0]
+}
+
+// Any field left out falls back to `Material::default()`. Textures are image paths, all
+// read with the one filter and wrap, e.g.
+// { "colour_texture": "assets/checker.png", "texture_filter": "nearest", "texture_wrap": "mirror" }
+#[derive(Deserialize, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct SceneMaterial {
+    pub colour_texture: Option<String>,
+    pub emissive_texture: Option<String>,
+    pub metallic_roughness_texture: Option<String>,
+    pub normal_texture: Option<String>,
+    // A heightfield in its red channel, bumped by bump_scale per unit of uv:
+    pub height_texture: Option<String>,
+    pub bump_scale: f32,
+    pub texture_filter: TextureFilter,
+    pub texture_wrap: TextureAddress,
+    pub colour: [f32; 4],
+    pub emissive: [f32; 3],
+    pub emissive_power: f32,
+    pub emissive_two_sided: bool,
+    pub absorption: [f32; 3],
+    pub metallic: f32,
+    pub roughness: f32,
+    pub ior: f32,
+    pub transmission: f32,
+    pub anisotropy: f32,
+    pub clearcoat: f32,
+    pub clearcoat_roughness: f32,
+    pub thin_film_thickness: f32, // nanometres
+    pub thin_film_ior: f32,
+    pub subsurface: f32,
+    pub subsurface_radius: [f32; 3],
+    pub diffuse_roughness: f32,
+    pub double_sided: bool,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct SceneTransform {
+    pub scale: [f32; 3],
+    pub rotation: [f32; 3], // XYZ euler angles in radians
+    pub translation: [f32; 3],
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct SceneCamera {
+    pub position: [f32; 3],
+    pub forward: [f32; 3],
+    pub up: [f32; 3],
+    pub focal_length: f32,
+    pub aperture: f32,
+    pub focus_distance: f32,
+    pub shutter_time: f32,
+}
+
+impl Default for SceneMaterial {
+    fn default() -> Self {
+        let m = Material::default();
+        Self {
+            colour_texture: None,
+            emissive_texture: None,
+            metallic_roughness_texture: None,
+            normal_texture: None,
+            height_texture: None,
+            bump_scale: m.bump_scale,
+            texture_filter: TextureFilter::default(),
+            texture_wrap: TextureAddress::default(),
+            colour: m.colour.to_array(),
+            emissive: m.emissive.truncate().to_array(),
+            emissive_power: m.emissive_power,
+            emissive_two_sided: m.emissive_two_sided != 0,
+            absorption: m.absorption.truncate().to_array(),
+            metallic: m.metallic,
+            roughness: m.roughness,
+            ior: m.ior,
+            transmission: m.transmission,
+            anisotropy: m.anisotropy,
+            clearcoat: m.clearcoat,
+            clearcoat_roughness: m.clearcoat_roughness,
+            thin_film_thickness: m.thin_film_thickness,
+            thin_film_ior: m.thin_film_ior,
+            subsurface: m.subsurface,
+            subsurface_radius: m.subsurface_radius.truncate().to_array(),
+            diffuse_roughness: m.diffuse_roughness,
+            double_sided: m.double_sided != 0,
+        }
+    }
+}
+
+// Left without a background, the map is shown if there is one and the default flat sky
+// otherwise.
+#[derive(Deserialize, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct SceneEnvironment {
+    pub map: Option<String>,
+    pub intensity: f32,
+    pub rotation: f32, // about Y in radians
+    pub background: Option<SceneBackground>,
+}
+
+// See Background, e.g. { "gradient": { "top": [0.5, 0.7, 1], "bottom": [1, 1, 1] } }.
+#[derive(Deserialize, Debug, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+pub enum SceneBackground {
+    Solid([f32; 3]),
+    Gradient { top: [f32; 3], bottom: [f32; 3] },
+    Map,
+}
+
+impl Default for SceneEnvironment {
+    fn default() -> Self {
+        Self {
+            map: None,
+            intensity: 1.0,
+            rotation: 0.0,
+            background: None,
+        }
+    }
+}
+
+impl Default for SceneTransform {
+    fn default() -> Self {
+        Self {
+            scale: [1.0; 3],
+            rotation: [0.0; 3],
+            translation: [0.0; 3],
+        }
+    }
+}
+
+impl Default for SceneCamera {
+    fn default() -> Self {
+        let c = CameraData::new();
+        Self {
+            position: c.position,
+            forward: c.forward,
+            up: c.up,
+            focal_length: c.focal_length,
+            aperture: c.aperture,
+            focus_distance: c.focus_distance,
+            shutter_time: c.shutter_time,
+        }
+    }
+}
+
+impl SceneMaterial {
+    // Its textures are added to `builder`, loaded when it's built. Like meshes, make sure
+    // they are there first.
+    fn to_material(&self, builder: &mut SceneBuilder) -> anyhow::Result<Material> {
+        let sampling = TextureSampling {
+            filter: self.texture_filter,
+            address: self.texture_wrap,
+        };
+        let mut texture = |path: &Option<String>, kind| match path {
+            Some(path) if Path::new(path).is_file() => Ok(builder.texture(path, kind, sampling)),
+            Some(path) => Err(anyhow::anyhow!("Texture file {path} does not exist")),
+            None => Ok(0),
+        };
+        Ok(Material {
+            colour_texture: texture(&self.colour_texture, TextureKind::Colour)?,
+            emissive_texture: texture(&self.emissive_texture, TextureKind::Colour)?,
+            metallic_roughness_texture: texture(
+                &self.metallic_roughness_texture,
+                TextureKind::Data,
+            )?,
+            normal_texture: texture(&self.normal_texture, TextureKind::Data)?,
+            height_texture: texture(&self.height_texture, TextureKind::Data)?,
+            ..Material::from(self)
+        })
+    }
+}
+
+impl From<&SceneMaterial> for Material {
+    fn from(m: &SceneMaterial) -> Self {
+        Self {
+            colour: Vec4::from_array(m.colour),
+            emissive: Vec3::from_array(m.emissive).extend(0.0),
+            emissive_power: m.emissive_power,
+            emissive_two_sided: m.emissive_two_sided as u32,
+            absorption: Vec3::from_array(m.absorption).extend(0.0),
+            metallic: m.metallic,
+            roughness: m.roughness,
+            ior: m.ior,
+            transmission: m.transmission,
+            anisotropy: m.anisotropy,
+            clearcoat: m.clearcoat,
+            clearcoat_roughness: m.clearcoat_roughness,
+            thin_film_thickness: m.thin_film_thickness,
+            thin_film_ior: m.thin_film_ior,
+            subsurface: m.subsurface,
+            subsurface_radius: Vec3::from_array(m.subsurface_radius).extend(0.0),
+            diffuse_roughness: m.diffuse_roughness,
+            bump_scale: m.bump_scale,
+            double_sided: m.double_sided as u32,
+            ..Default::default()
+        }
+    }
+}
+
+impl From<&SceneTransform> for Transform {
+    fn from(t: &SceneTransform) -> Self {
+        Transform::new(t.scale.into(), t.rotation.into(), t.translation.into())
+    }
+}
+
+impl SceneCamera {
+    fn to_camera_data(&self) -> anyhow::Result<CameraData> {
+        let f = Vec3::from(self.forward).normalize();
+        let u = Vec3::from(self.up);
+        let r = u.cross(f).normalize();
+        if !f.is_finite() || !r.is_finite() {
+            anyhow::bail!("Camera has a degenerate forward/up");
+        }
+
+        Ok(CameraData {
+            position: self.position,
+            forward: f.into(),
+            up: f.cross(r).into(),
+            focal_length: self.focal_length,
+            aperture: self.aperture.max(0.0),
+            focus_distance: self.focus_distance,
+            shutter_time: self.shutter_time.max(0.0),
+            ..CameraData::new()
+        })
+    }
+}
+
+impl SceneEnvironment {
+    // The map is loaded when the scene is built, so make sure it's there first.
+    fn to_descriptor(&self) -> anyhow::Result<EnvironmentDescriptor> {
+        let map = self.map.as_ref().map(PathBuf::from);
+        if let Some(map) = &map {
+            anyhow::ensure!(
+                map.is_file(),
+                "Environment map {} does not exist",
+                map.display()
+            );
+        }
+        let background = match (self.background, &map) {
+            (Some(SceneBackground::Solid(colour)), _) => Background::Solid(colour.into()),
+            (Some(SceneBackground::Gradient { top, bottom }), _) => Background::Gradient {
+                top: top.into(),
+                bottom: bottom.into(),
+            },
+            (Some(SceneBackground::Map), None) => {
+                anyhow::bail!("A map background needs a map to show")
+            }
+            (Some(SceneBackground::Map), Some(_)) | (None, Some(_)) => Background::Environment,
+            (None, None) => Background::Solid(DEFAULT_BACKGROUND),
+        };
+        Ok(EnvironmentDescriptor {
+            background,
+            map,
+            intensity: self.intensity,
+            rotation: self.rotation,
+        })
+    }
+}
+
+impl MeshSource {
+    // A missing mesh file would only fail once a worker got to it, see MeshStatus::Failed.
+    // Checking first gives an error naming the scene file before anything is queued.
+    fn to_descriptor(&self, normalize: NormalizeMode) -> anyhow::Result<MeshDescriptor> {
+        let check = |path: &String| {
+            if Path::new(path).is_file() {
+                Ok(path.clone())
+            } else {
+                Err(anyhow::anyhow!("Mesh file {path} does not exist"))
+            }
+        };
+
+        Ok(match self {
+            MeshSource::Obj(path) => MeshDescriptor::TOBJ(check(path)?, normalize),
+            MeshSource::Ply(path) => MeshDescriptor::Ply(check(path)?),
+            MeshSource::Gltf { path, primitive } => MeshDescriptor::Gltf {
+                path: check(path)?,
+                primitive: *primitive,
+            },
+            MeshSource::Cube => MeshDescriptor::Cube,
+            MeshSource::Rect => MeshDescriptor::Rect,
+            MeshSource::Sphere { .. } => anyhow::bail!("Spheres aren't meshes"),
+            MeshSource::Plane { .. } => anyhow::bail!("Planes aren't meshes"),
+        })
+    }
+}
+
+// RON for a .ron file, JSON for anything else.
+fn parse_scene(path: &Path, text: &str) -> anyhow::Result<SceneFile> {
+    if path
+        .extension()
+        .is_some_and(|e| e.eq_ignore_ascii_case("ron"))
+    {
+        // So optional fields can be given without wrapping them in Some(..):
+        let options = ron::Options::default().with_default_extension(Extensions::IMPLICIT_SOME);
+        Ok(options.from_str(text)?)
+    } else {
+        Ok(serde_json::from_str(text)?)
+    }
+}
+
+pub fn load_scene_file(path: &Path) -> anyhow::Result<SceneBuilder> {
+    let text = std::fs::read_to_string(path)
+        .with_context(|| format!("Failed to read scene {}", path.display()))?;
+    let scene = parse_scene(path, &text)
+        .with_context(|| format!("Failed to parse scene {}", path.display()))?;
+
+    // Every OBJ in each joint group:
+    let mut joint = HashMap::<&str, Vec<String>>::new();
+    for object in &scene.objects {
+        if let (MeshSource::Obj(obj), SceneNormalize::Joint(group)) =
+            (&object.mesh, &object.normalize)
+        {
+            joint.entry(group).or_default().push(obj.clone());
+        }
+    }
+    for paths in joint.values_mut() {
+        paths.sort();
+        paths.dedup();
+    }
+
+    let mut builder = SceneBuilder::new();
+    for (i, object) in scene.objects.iter().enumerate() {
+        let context = || format!("Object {i} in scene {}", path.display());
+        let material = object
+            .material
+            .as_ref()
+            .map(|m| m.to_material(&mut builder))
+            .transpose()
+            .with_context(context)?;
+        let visibility = RayVisibility {
+            visible_camera: object.visible_camera,
+            casts_shadows: object.casts_shadows,
+        };
+        let velocity = Vec3::from(object.velocity);
+        match &object.mesh {
+            MeshSource::Sphere { radius } => {
+                builder.add_sphere(
+                    Sphere::new(*radius),
+                    material.unwrap_or_default(),
+                    (&object.transform).into(),
+                );
+            }
+            MeshSource::Plane { point, normal } => {
+                builder.add_plane(
+                    Plane::new(Vec3::from(*point), Vec3::from(*normal)),
+                    material.unwrap_or_default(),
+                );
+            }
+            MeshSource::Obj(obj) => {
+                let normalize = match &object.normalize {
+                    SceneNormalize::UnitCube => NormalizeMode::UnitCube,
+                    SceneNormalize::None => NormalizeMode::None,
+                    SceneNormalize::Joint(group) => {
+                        NormalizeMode::Joint(joint[group.as_str()].clone())
+                    }
+                };
+                let mesh = object.mesh.to_descriptor(normalize).with_context(context)?;
+                let groups = match material {
+                    Some(_) => Vec::new(),
+                    None => mesh::obj_materials(obj)
+                        .with_context(context)?
+                        .into_iter()
+                        .map(Option::unwrap_or_default)
+                        .collect(),
+                };
+                builder
+                    .add_shaded(
+                        mesh,
+                        object.shading,
+                        material.or(groups.first().copied()).unwrap_or_default(),
+                        (&object.transform).into(),
+                    )
+                    .material_groups(groups);
+            }
+            MeshSource::Gltf { path, primitive } => {
+                let mesh = object
+                    .mesh
+                    .to_descriptor(NormalizeMode::default())
+                    .with_context(context)?;
+                let material = match material {
+                    Some(material) => material,
+                    None => mesh::gltf_materials(path)
+                        .with_context(context)?
+                        .get(*primitive)
+                        .copied()
+                        .unwrap_or_default(),
+                };
+                builder.add_shaded(mesh, object.shading, material, (&object.transform).into());
+            }
+            _ => {
+                let mesh = object
+                    .mesh
+                    .to_descriptor(NormalizeMode::default())
+                    .with_context(context)?;
+                builder.add_shaded(
+                    mesh,
+                    object.shading,
+                    material.unwrap_or_default(),
+                    (&object.transform).into(),
+                );
+            }
+        }
+        builder.visibility(visibility).velocity(velocity);
+    }
+    if let Some(camera) = &scene.camera {
+        let data = camera
+            .to_camera_data()
+            .with_context(|| format!("Camera in scene {}", path.display()))?;
+        builder.camera(data);
+    }
+    if let Some(environment) = &scene.environment {
+        let descriptor = environment
+            .to_descriptor()
+            .with_context(|| format!("Environment in scene {}", path.display()))?;
+        builder.environment(descriptor);
+    }
+
+    Ok(builder)
+}
+
+// Seconds between checks of the file's modification time, and how long it has to stay the
+// same before it's reloaded, so a burst of saves only reloads once.
+const SCENE_POLL_INTERVAL: f64 = 0.25;
+const SCENE_RELOAD_DEBOUNCE: f64 = 0.3;
+
+// A scene file kept in sync with the world. Saving it updates only the objects that
+// changed, see SceneBuilder::update, and a version that fails to load leaves the last
+// good one in place. The file's camera is only used on the first load.
+#[derive(Resource)]
+pub struct SceneFileWatch {
+    pub path: PathBuf,
+    // The last version that loaded and the entities it spawned:
+    loaded: Option<(SceneBuilder, Vec<Entity>)>,
+    // Modification time of the last version tried, whether it loaded or not:
+    tried: Option<SystemTime>,
+    // A newer modification time and when it was first seen:
+    pending: Option<(SystemTime, f64)>,
+    last_poll: Option<f64>,
+    missing: bool,
+}
+
+impl SceneFileWatch {
+    pub fn new(path: PathBuf) -> Self {
+        Self {
+            path,
+            loaded: None,
+            tried: None,
+            pending: None,
+            last_poll: None,
+            missing: false,
+        }
+    }
+
+    fn poll(&mut self, world: &mut World) {
+        let now = world.resource::<Time>().elapsed();
+        if self
+            .last_poll
+            .is_some_and(|t| now - t < SCENE_POLL_INTERVAL)
+        {
+            return;
+        }
+        self.last_poll = Some(now);
+
+        let modified = match std::fs::metadata(&self.path).and_then(|m| m.modified()) {
+            Ok(modified) => modified,
+            Err(e) => {
+                if !self.missing {
+                    warn!("Can't watch scene {}: {e}", self.path.display());
+                    self.missing = true;
+                }
+                return;
+            }
+        };
+        self.missing = false;
+        if self.tried == Some(modified) {
+            self.pending = None;
+            return;
+        }
+
+        // The first load doesn't wait, later ones wait for the saves to settle:
+        if self.tried.is_some() {
+            match self.pending {
+                Some((pending, since)) if pending == modified => {
+                    if now - since < SCENE_RELOAD_DEBOUNCE {
+                        return;
+                    }
+                }
+                _ => {
+                    self.pending = Some((modified, now));
+                    return;
+                }
+            }
+        }
+        self.pending = None;
+        self.tried = Some(modified);
+        self.reload(world);
+    }
+
+    fn reload(&mut self, world: &mut World) {
+        let scene = match load_scene_file(&self.path) {
+            Ok(scene) => scene,
+            Err(e) => {
+                error!("{e:#}, keeping the last scene that loaded");
+                return;
+            }
+        };
+
+        let entities = world.resource_scope(|world, mut mesh_server: Mut<MeshServer>| {
+            world.resource_scope(|world, mut material_server: Mut<MaterialServer>| {
+                match &self.loaded {
+                    Some((previous, entities)) => scene.update(
+                        previous,
+                        entities,
+                        world,
+                        &mut mesh_server,
+                        &mut material_server,
+                    ),
+                    None => scene.build(world, &mut mesh_server, &mut material_server),
+                }
+            })
+        });
+        scenes::unload_unused_meshes(world);
+        // Swapped materials aren't noticed by the binder's change checks:
+        for mut pt in world.query::<&mut Pathtracer>().iter_mut(world) {
+            pt.reset_accumulation();
+        }
+
+        info!("Loaded scene {}", self.path.display());
+        self.loaded = Some((scene, entities));
+    }
+}
+
+fn scene_file_watch_system(world: &mut World) {
+    world.resource_scope(|world, mut watch: Mut<SceneFileWatch>| watch.poll(world));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SCENES: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/assets/scenes");
+
+    #[test]
+    fn ron_and_json_scenes_match() {
+        let parse = |name: &str| {
+            let path = Path::new(SCENES).join(name);
+            parse_scene(&path, &std::fs::read_to_string(&path).unwrap()).unwrap()
+        };
+        let (ron, json) = (parse("dragon.ron"), parse("dragon.json"));
+        assert_eq!(ron.objects.len(), 3);
+        assert_eq!(format!("{ron:?}"), format!("{json:?}"));
+    }
+}
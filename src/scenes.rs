@@ -1,20 +1,138 @@
-use std::f32;
+use std::{
+    collections::HashSet,
+    f32::{self, consts::PI},
+};
 
 use crate::{
     app::BevyApp,
+    light::{DirectionalLight, PointLight, SpotLight},
     material::{Material, MaterialServer},
-    mesh::{MeshDescriptor, MeshServer},
+    mesh::{MeshDescriptor, MeshId, MeshServer, NormalizeMode},
+    plane::Plane,
+    render_resources::RenderDevice,
+    scene_builder::{SceneBuilder, SpawnInstance},
+    scene_file::SceneFileWatch,
     schedule,
+    sphere::Sphere,
+    texture::{TextureId, TextureKind, TextureSampling, TextureServer},
     transform::Transform,
+    winnit::WinitWindowEvent,
 };
 
-use bevy_ecs::prelude::*;
+use bevy_ecs::{prelude::*, system::RunSystemOnce};
 use glam::{Vec3, Vec4};
+use tracing::{error, info, warn};
+use winit::{event::WindowEvent, keyboard::KeyCode};
 
 pub fn initialize(app: &mut BevyApp) {
-    app.world
-        .get_resource_or_init::<Schedules>()
-        .add_systems(schedule::Startup, simple_scene);
+    // A watched scene file takes the place of the built in scenes:
+    let watching = not(resource_exists::<SceneFileWatch>);
+    app.world.init_resource::<CurrentScene>();
+    let mut schedules = app.world.get_resource_or_init::<Schedules>();
+    schedules.add_systems(
+        schedule::Startup,
+        spawn_current_scene.run_if(watching.clone()),
+    );
+    schedules.add_systems(schedule::Update, scene_cycle_system.run_if(watching));
+}
+
+type SpawnScene = fn(&mut World);
+
+// The built in scene spawned at startup, and then the one F10 last switched to, as an
+// index into BUILTIN_SCENES.
+#[derive(Resource, Default, Clone, Copy, Debug)]
+pub struct CurrentScene(pub usize);
+
+// The scenes F10 steps through, starting from the one spawned at startup.
+const BUILTIN_SCENES: [(&str, SpawnScene); 4] = [
+    ("simple", |world| {
+        if let Err(e) = world.run_system_once(simple_scene) {
+            error!("Failed to spawn the simple scene: {e}");
+        }
+    }),
+    ("boxes", |world| {
+        boxes_scene().spawn(world);
+    }),
+    ("cornell", |world| {
+        cornell_scene().spawn(world);
+    }),
+    ("area lights", |world| {
+        area_lights_scene().spawn(world);
+    }),
+];
+
+// Index into BUILTIN_SCENES of the scene called `name`.
+pub fn builtin_scene(name: &str) -> Option<usize> {
+    BUILTIN_SCENES.iter().position(|(n, _)| *n == name)
+}
+
+pub fn builtin_scene_names() -> Vec<&'static str> {
+    BUILTIN_SCENES.iter().map(|(name, _)| *name).collect()
+}
+
+pub fn spawn_builtin_scene(world: &mut World, index: usize) {
+    let (_, spawn) = BUILTIN_SCENES[index];
+    spawn(world);
+}
+
+fn spawn_current_scene(world: &mut World) {
+    let current = world.resource::<CurrentScene>().0;
+    spawn_builtin_scene(world, current);
+}
+
+// Everything a scene spawns, as opposed to the cameras and pathtracers looking at it:
+type SceneObject = Or<(
+    With<MeshId>,
+    With<Sphere>,
+    With<Plane>,
+    With<DirectionalLight>,
+    With<PointLight>,
+    With<SpotLight>,
+)>;
+
+// F10 swaps the scene for the next built in one. Despawning fires the binder's removal
+// checks, so the TLAS is rebuilt and accumulation restarts, and meshes only the old scene
+// used are dropped from the mesh buffers.
+fn scene_cycle_system(
+    mut commands: Commands,
+    mut we_reader: MessageReader<WinitWindowEvent>,
+    mut current: ResMut<CurrentScene>,
+    objects: Query<Entity, SceneObject>,
+) {
+    let pressed = we_reader.read().any(|WinitWindowEvent(e)| match e {
+        WindowEvent::KeyboardInput { event, .. } => {
+            event.state.is_pressed() && !event.repeat && event.physical_key == KeyCode::F10
+        }
+        _ => false,
+    });
+    if !pressed {
+        return;
+    }
+
+    current.0 = (current.0 + 1) % BUILTIN_SCENES.len();
+    let (name, spawn) = BUILTIN_SCENES[current.0];
+    info!("Switching to the {name} scene");
+
+    for entity in &objects {
+        commands.entity(entity).despawn();
+    }
+    commands.queue(move |world: &mut World| {
+        spawn(world);
+        unload_unused_meshes(world);
+    });
+}
+
+// Drops meshes no entity uses any more from the mesh buffers, after a scene was replaced.
+pub fn unload_unused_meshes(world: &mut World) {
+    let used = world
+        .query::<&MeshId>()
+        .iter(world)
+        .copied()
+        .collect::<HashSet<_>>();
+    let device = world.resource::<RenderDevice>().0.clone();
+    world
+        .resource_mut::<MeshServer>()
+        .unload_unused(&used, device);
 }
 
 fn spawn_cornell(
@@ -67,15 +185,15 @@ fn spawn_cornell(
     let rect_mesh = mesh_server.load_mesh(MeshDescriptor::Rect);
 
     // Floor:
-    commands.spawn((
-        Transform {
-            scale: Vec4::new(dims.x, dims.z, 1.0, 0.0),
-            rotation: Vec4::new(f32::consts::FRAC_PI_2, 0.0, 0.0, 0.0),
-            translation: Vec4::new(0.0, -dims.y / 2.0, 0.0, 1.0) + pos.extend(0.0),
-        },
-        gray_material,
+    commands.spawn_instance(
         rect_mesh,
-    ));
+        gray_material,
+        Transform::new(
+            Vec3::new(dims.x, dims.z, 1.0),
+            Vec3::new(f32::consts::FRAC_PI_2, 0.0, 0.0),
+            Vec3::new(0.0, -dims.y / 2.0, 0.0) + pos,
+        ),
+    );
 
     // Ceiling:
     // commands.spawn((
@@ -100,47 +218,51 @@ fn spawn_cornell(
     // ));
 
     // Back Wall:
-    commands.spawn((
-        Transform {
-            scale: Vec4::new(dims.x, dims.y, 1.0, 0.0),
-            rotation: Vec4::new(f32::consts::PI * 2.0, 0.0, 0.0, 0.0),
-            translation: Vec4::new(0.0, 0.0, dims.z / 2.0, 1.0) + pos.extend(0.0),
-        },
-        gray_material,
+    commands.spawn_instance(
         rect_mesh,
-    ));
+        gray_material,
+        Transform::new(
+            Vec3::new(dims.x, dims.y, 1.0),
+            Vec3::new(f32::consts::PI * 2.0, 0.0, 0.0),
+            Vec3::new(0.0, 0.0, dims.z / 2.0) + pos,
+        ),
+    );
 
     // Red Wall:
-    commands.spawn((
-        Transform {
-            scale: Vec4::new(dims.z, dims.y, 1.0, 0.0),
-            rotation: Vec4::new(0.0, f32::consts::FRAC_PI_2, 0.0, 0.0),
-            translation: Vec4::new(dims.x / 2.0, 0.0, 0.0, 1.0) + pos.extend(0.0),
-        },
-        red_material,
+    commands.spawn_instance(
         rect_mesh,
-    ));
+        red_material,
+        Transform::new(
+            Vec3::new(dims.z, dims.y, 1.0),
+            Vec3::new(0.0, f32::consts::FRAC_PI_2, 0.0),
+            Vec3::new(dims.x / 2.0, 0.0, 0.0) + pos,
+        ),
+    );
 
     // Green Wall:
-    commands.spawn((
-        Transform {
-            scale: Vec4::new(dims.z, dims.y, 1.0, 0.0),
-            rotation: Vec4::new(0.0, -f32::consts::FRAC_PI_2, 0.0, 0.0),
-            translation: Vec4::new(-dims.x / 2.0, 0.0, 0.0, 1.0) + pos.extend(0.0),
-        },
-        green_material,
+    commands.spawn_instance(
         rect_mesh,
-    ));
+        green_material,
+        Transform::new(
+            Vec3::new(dims.z, dims.y, 1.0),
+            Vec3::new(0.0, -f32::consts::FRAC_PI_2, 0.0),
+            Vec3::new(-dims.x / 2.0, 0.0, 0.0) + pos,
+        ),
+    );
 }
 
 fn simple_scene(
     mut commands: Commands,
     mut mesh_server: ResMut<MeshServer>,
     mut material_server: ResMut<MaterialServer>,
+    mut texture_server: ResMut<TextureServer>,
 ) {
     let cube_mesh = mesh_server.load_mesh(MeshDescriptor::Cube);
     // let rect_mesh = mesh_server.load_mesh(MeshDescriptor::Rect);
-    let dragon_mesh = mesh_server.load_mesh(MeshDescriptor::TOBJ("./assets/dragon.obj".to_owned()));
+    let dragon_mesh = mesh_server.load_mesh(MeshDescriptor::TOBJ(
+        "./assets/dragon.obj".to_owned(),
+        NormalizeMode::UnitCube,
+    ));
     let gold_material = material_server.add_material(Material {
         colour: Vec4::new(1.0, 0.99, 0.0, 1.0),
         metallic: 0.0,
@@ -171,16 +293,44 @@ fn simple_scene(
         Vec3::new(0.0, 0.0, 3.0),
     );
     commands.spawn((
-        Transform {
-            scale: Vec4::ONE,
-            rotation: Vec4::ZERO,
-            translation: Vec4::new(0.0, -0.89, 2.75, 0.0),
-        },
+        Transform::new(Vec3::ONE, Vec3::ZERO, Vec3::new(0.0, -0.89, 2.75)),
         // gold_material,
         glass_material,
         // cube_mesh,
         dragon_mesh,
     ));
+
+    // A checkered quad just in front of the back wall, to show off textures:
+    match texture_server.load_texture(
+        "./assets/checker.png",
+        TextureKind::Colour,
+        TextureSampling::default(),
+    ) {
+        Ok(checker) => {
+            // Its own squares as a heightfield too, raising the white ones:
+            let height = texture_server
+                .load_texture(
+                    "./assets/checker.png",
+                    TextureKind::Data,
+                    TextureSampling::default(),
+                )
+                .map_or(0, TextureId::material_index);
+            let checker_material = material_server.add_material(Material {
+                colour: Vec4::ONE,
+                colour_texture: checker.material_index(),
+                height_texture: height,
+                bump_scale: 0.002,
+                roughness: 1.0,
+                ..Default::default()
+            });
+            commands.spawn_instance(
+                mesh_server.load_mesh(MeshDescriptor::Rect),
+                checker_material,
+                Transform::new(Vec3::ONE * 1.2, Vec3::ZERO, Vec3::new(0.0, 0.4, 4.49)),
+            );
+        }
+        Err(err) => warn!("Failed to load checker texture: {err:#}"),
+    }
     // commands.spawn((
     //     Transform {
     //         scale: Vec4::new(3.0, 0.5, 3.0, 1.0),
@@ -192,6 +342,224 @@ fn simple_scene(
     // ));
 }
 
+fn lambertian(colour: Vec3) -> Material {
+    Material {
+        colour: colour.extend(1.0),
+        roughness: 1.0,
+        ..Default::default()
+    }
+}
+
+// The walled room shared by `cornell_scene` and `boxes_scene`, open at the front with
+// two long lights either side of the ceiling:
+fn add_room(builder: &mut SceneBuilder, light: Vec3, offset: Vec3) {
+    let half = 5.0;
+    let depth = 10.0;
+    let z_mid = depth * 0.5;
+
+    let gray = lambertian(Vec3::new(0.73, 0.73, 0.73));
+    let red = lambertian(Vec3::new(0.65, 0.05, 0.05));
+    let green = lambertian(Vec3::new(0.12, 0.45, 0.15));
+    let light = Material {
+        colour: Vec4::ONE,
+        emissive: light.extend(0.0),
+        roughness: 1.0,
+        ..Default::default()
+    };
+
+    let wall = Vec3::new(half * 2.0, half * 2.0, 1.0);
+    builder
+        // Back wall:
+        .add(
+            MeshDescriptor::Rect,
+            gray,
+            Transform::new(wall, Vec3::ZERO, Vec3::new(0.0, 0.0, depth) + offset),
+        )
+        // Floor:
+        .add(
+            MeshDescriptor::Rect,
+            gray,
+            Transform::new(
+                wall,
+                Vec3::new(PI * 0.5, 0.0, 0.0),
+                Vec3::new(0.0, -half, z_mid) + offset,
+            ),
+        )
+        // Ceiling:
+        .add(
+            MeshDescriptor::Rect,
+            gray,
+            Transform::new(
+                wall,
+                Vec3::new(-PI * 0.5, 0.0, 0.0),
+                Vec3::new(0.0, half, z_mid) + offset,
+            ),
+        )
+        // Ceiling lights:
+        .add(
+            MeshDescriptor::Cube,
+            light,
+            Transform::new(
+                Vec3::new(1.0, 0.5, 6.0),
+                Vec3::ZERO,
+                Vec3::new(-half + 1.0, half - 0.25, half) + offset,
+            ),
+        )
+        .add(
+            MeshDescriptor::Cube,
+            light,
+            Transform::new(
+                Vec3::new(1.0, 0.5, 6.0),
+                Vec3::ZERO,
+                Vec3::new(half - 1.0, half - 0.25, half) + offset,
+            ),
+        )
+        // Left wall:
+        .add(
+            MeshDescriptor::Rect,
+            red,
+            Transform::new(
+                wall,
+                Vec3::new(0.0, -PI * 0.5, 0.0), // +Z -> +X
+                Vec3::new(-half, 0.0, z_mid) + offset,
+            ),
+        )
+        // Right wall:
+        .add(
+            MeshDescriptor::Rect,
+            green,
+            Transform::new(
+                wall,
+                Vec3::new(0.0, PI * 0.5, 0.0), // +Z -> -X
+                Vec3::new(half, 0.0, z_mid) + offset,
+            ),
+        );
+}
+
+pub fn cornell_scene() -> SceneBuilder {
+    let half = 5.0;
+    let offset = Vec3::new(0.0, 0.0, half);
+
+    let mut builder = SceneBuilder::new();
+    add_room(&mut builder, Vec3::new(0.5, 0.8, 0.9) * 800.0, offset);
+
+    let gold = Material {
+        colour: Vec4::new(0.0, 0.83, 1.0, 1.0),
+        metallic: 1.0,
+        roughness: 0.2,
+        ..Default::default()
+    };
+    // Gold wherever the OBJ has no material of its own:
+    if let Err(e) = builder.add_obj(
+        "./assets/dragon.obj",
+        NormalizeMode::UnitCube,
+        gold,
+        Transform::new(
+            Vec3::splat(6.0),
+            Vec3::new(0.0, PI * 0.25, 0.0),
+            Vec3::new(0.0, -half + 1.7, half) + offset,
+        ),
+    ) {
+        error!("{e:#}");
+    }
+    builder
+}
+
+pub fn boxes_scene() -> SceneBuilder {
+    let half = 5.0;
+    let offset = Vec3::new(0.0, 0.0, half);
+
+    let mut builder = SceneBuilder::new();
+    add_room(&mut builder, Vec3::ONE * 800.0, offset);
+
+    let mirror = Material {
+        colour: Vec4::ONE,
+        metallic: 1.0,
+        roughness: 0.01,
+        ..Default::default()
+    };
+    let blue = lambertian(Vec3::new(0.05, 0.10, 0.60));
+    builder
+        .add(
+            MeshDescriptor::Cube,
+            mirror,
+            Transform::new(
+                Vec3::new(2.5, 6.0, 2.5),
+                Vec3::new(0.0, PI * -0.4, 0.0),
+                Vec3::new(-1.0, -half + 3.0, half + 2.0) + offset,
+            ),
+        )
+        .add(
+            MeshDescriptor::Cube,
+            blue,
+            Transform::new(
+                Vec3::new(2.5, 2.99, 2.5),
+                Vec3::new(0.0, PI * -0.1, 0.0),
+                Vec3::new(0.4, -half + 1.5, half - 1.8) + offset,
+            ),
+        );
+    builder
+}
+
+// A wide dim panel and a tiny bright cube of about the same power over a floor, the case
+// next event estimation by area handles worst. Compare Pathtracer::set_light_sampling.
+pub fn area_lights_scene() -> SceneBuilder {
+    let mut builder = SceneBuilder::new();
+
+    let gray = lambertian(Vec3::new(0.73, 0.73, 0.73));
+    let panel = Material {
+        colour: Vec4::ONE,
+        emissive: Vec4::new(1.0, 0.9, 0.8, 0.0),
+        emissive_power: 5.0,
+        roughness: 1.0,
+        ..Default::default()
+    };
+    let bulb = Material {
+        colour: Vec4::ONE,
+        emissive: Vec4::new(0.6, 0.8, 1.0, 0.0),
+        emissive_power: 750.0,
+        roughness: 1.0,
+        ..Default::default()
+    };
+
+    builder
+        // Floor, facing up:
+        .add(
+            MeshDescriptor::Rect,
+            gray,
+            Transform::new(
+                Vec3::new(20.0, 20.0, 1.0),
+                Vec3::new(-PI * 0.5, 0.0, 0.0),
+                Vec3::new(0.0, -2.0, 8.0),
+            ),
+        )
+        // Facing down, it only emits from the front:
+        .add(
+            MeshDescriptor::Rect,
+            panel,
+            Transform::new(
+                Vec3::new(6.0, 6.0, 1.0),
+                Vec3::new(PI * 0.5, 0.0, 0.0),
+                Vec3::new(-3.0, 4.0, 8.0),
+            ),
+        )
+        .add(
+            MeshDescriptor::Cube,
+            bulb,
+            Transform::new(Vec3::splat(0.2), Vec3::ZERO, Vec3::new(3.0, 1.0, 8.0)),
+        )
+        .add(
+            MeshDescriptor::Cube,
+            gray,
+            Transform::new(
+                Vec3::new(1.5, 3.0, 1.5),
+                Vec3::new(0.0, PI * 0.2, 0.0),
+                Vec3::new(0.5, -0.5, 9.0),
+            ),
+        );
+    builder
+}
+
 // use core::f32;
 // use std::collections::HashMap;
 // use std::f32::consts::PI;
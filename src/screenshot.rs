@@ -0,0 +1,241 @@
+use std::{
+    path::{Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::Context;
+use bevy_ecs::prelude::*;
+use tracing::{error, info, warn};
+use winit::{event::WindowEvent, keyboard::KeyCode};
+
+use crate::{
+    app::BevyApp, render, render_resources::RenderDevice, schedule, winnit::WinitWindowEvent,
+};
+
+pub fn initialize(app: &mut BevyApp) {
+    app.world.init_resource::<Screenshots>();
+    app.world.get_resource_or_init::<Schedules>().add_systems(
+        schedule::Update,
+        (
+            screenshot_key_system.before(render::render_system),
+            screenshot_readback_system.after(render::render_system),
+        ),
+    );
+}
+
+// Overrides where screenshots are written, relative to the working directory.
+const SCREENSHOT_DIR_VAR: &str = "RAYTRACER_SCREENSHOT_DIR";
+const DEFAULT_SCREENSHOT_DIR: &str = "screenshots";
+
+// Swapchain grabs, saved as `screenshot_<unix_timestamp>.png` in `dir`.
+#[derive(Resource)]
+pub struct Screenshots {
+    pub dir: PathBuf,
+    requested: bool,
+    pending: Option<PendingScreenshot>,
+}
+
+// A copy of the swapchain that is on its way back from the GPU.
+struct PendingScreenshot {
+    staging: wgpu::Buffer,
+    dims: (u32, u32),
+    padded_bytes_per_row: u32,
+    bgra: bool,
+    path: PathBuf,
+    rx: Option<crossbeam::channel::Receiver<Result<(), wgpu::BufferAsyncError>>>,
+}
+
+impl Default for Screenshots {
+    fn default() -> Self {
+        let dir = std::env::var_os(SCREENSHOT_DIR_VAR)
+            .map(PathBuf::from)
+            .unwrap_or_else(|| PathBuf::from(DEFAULT_SCREENSHOT_DIR));
+        Self {
+            dir,
+            requested: false,
+            pending: None,
+        }
+    }
+}
+
+// F2 asks the next render for a screenshot.
+fn screenshot_key_system(
+    mut we_reader: MessageReader<WinitWindowEvent>,
+    mut screenshots: ResMut<Screenshots>,
+) {
+    let pressed = we_reader.read().any(|WinitWindowEvent(e)| match e {
+        WindowEvent::KeyboardInput { event, .. } => {
+            event.state.is_pressed() && !event.repeat && event.physical_key == KeyCode::F2
+        }
+        _ => false,
+    });
+    if pressed {
+        screenshots.requested = true;
+    }
+}
+
+// Picks up a mapped screenshot without waiting on the GPU, leaving the encoding and
+// writing to a worker thread so the render loop never blocks on it.
+fn screenshot_readback_system(mut screenshots: ResMut<Screenshots>, device: Res<RenderDevice>) {
+    let Some(pending) = &screenshots.pending else {
+        return;
+    };
+    let Some(rx) = &pending.rx else {
+        return;
+    };
+
+    device.0.poll(wgpu::PollType::Poll).ok();
+    let result = match rx.try_recv() {
+        Ok(result) => result,
+        Err(crossbeam::channel::TryRecvError::Empty) => return,
+        Err(crossbeam::channel::TryRecvError::Disconnected) => Err(wgpu::BufferAsyncError),
+    };
+    let pending = screenshots
+        .pending
+        .take()
+        .expect("Expected a pending screenshot");
+    if let Err(e) = result {
+        error!("Failed to map screenshot: {e}");
+        return;
+    }
+
+    let pixels = pending.unpad();
+    rayon::spawn(
+        move || match write_png(&pending.path, pending.dims, pixels) {
+            Ok(()) => info!("Saved screenshot to {}", pending.path.display()),
+            Err(e) => error!("Failed to save screenshot: {e:#}"),
+        },
+    );
+}
+
+impl Screenshots {
+    // Copies `texture` into a staging buffer if a screenshot was asked for. Call after the
+    // frame has been drawn into it, in the same encoder, then `map` once submitted.
+    pub fn capture(
+        &mut self,
+        device: &wgpu::Device,
+        encoder: &mut wgpu::CommandEncoder,
+        texture: &wgpu::Texture,
+    ) {
+        // Only one screenshot in flight, later requests wait their turn:
+        if !self.requested || self.pending.is_some() {
+            return;
+        }
+        self.requested = false;
+
+        if !texture.usage().contains(wgpu::TextureUsages::COPY_SRC) {
+            warn!("The surface can't be copied from, so screenshots aren't supported");
+            return;
+        }
+        let bgra = match texture.format() {
+            wgpu::TextureFormat::Bgra8Unorm | wgpu::TextureFormat::Bgra8UnormSrgb => true,
+            wgpu::TextureFormat::Rgba8Unorm | wgpu::TextureFormat::Rgba8UnormSrgb => false,
+            format => {
+                warn!("Screenshots of a {format:?} surface aren't supported");
+                return;
+            }
+        };
+        let path = match self.next_path() {
+            Ok(path) => path,
+            Err(e) => {
+                error!("Failed to take screenshot: {e:#}");
+                return;
+            }
+        };
+
+        // Texture copies need rows aligned to 256 bytes, stripped again on the CPU:
+        let dims = (texture.width(), texture.height());
+        let padded_bytes_per_row =
+            (4 * dims.0).next_multiple_of(wgpu::COPY_BYTES_PER_ROW_ALIGNMENT);
+        let staging = device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("Screenshot Staging Buffer"),
+            size: (padded_bytes_per_row * dims.1) as u64,
+            usage: wgpu::BufferUsages::MAP_READ | wgpu::BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        });
+
+        encoder.copy_texture_to_buffer(
+            wgpu::TexelCopyTextureInfo {
+                texture,
+                mip_level: 0,
+                origin: wgpu::Origin3d::ZERO,
+                aspect: wgpu::TextureAspect::All,
+            },
+            wgpu::TexelCopyBufferInfo {
+                buffer: &staging,
+                layout: wgpu::TexelCopyBufferLayout {
+                    offset: 0,
+                    bytes_per_row: Some(padded_bytes_per_row),
+                    rows_per_image: Some(dims.1),
+                },
+            },
+            texture.size(),
+        );
+
+        self.pending = Some(PendingScreenshot {
+            staging,
+            dims,
+            padded_bytes_per_row,
+            bgra,
+            path,
+            rx: None,
+        });
+    }
+
+    // Starts mapping a screenshot captured this frame. Must follow the submit that copies it.
+    pub fn map(&mut self) {
+        let Some(pending) = &mut self.pending else {
+            return;
+        };
+        if pending.rx.is_some() {
+            return;
+        }
+
+        let (tx, rx) = crossbeam::channel::bounded(1);
+        pending
+            .staging
+            .slice(..)
+            .map_async(wgpu::MapMode::Read, move |result| {
+                tx.send(result).ok();
+            });
+        pending.rx = Some(rx);
+    }
+
+    fn next_path(&self) -> anyhow::Result<PathBuf> {
+        std::fs::create_dir_all(&self.dir)
+            .with_context(|| format!("Failed to create {}", self.dir.display()))?;
+        let timestamp = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+        Ok(self.dir.join(format!("screenshot_{timestamp}.png")))
+    }
+}
+
+impl PendingScreenshot {
+    // Tightly packed rgba8, with the row padding dropped. The staging buffer must be mapped.
+    fn unpad(&self) -> Vec<u8> {
+        let row_bytes = 4 * self.dims.0 as usize;
+        let mut pixels = Vec::with_capacity(row_bytes * self.dims.1 as usize);
+        {
+            let mapped = self.staging.slice(..).get_mapped_range();
+            for row in mapped.chunks_exact(self.padded_bytes_per_row as usize) {
+                pixels.extend_from_slice(&row[..row_bytes]);
+            }
+        }
+        self.staging.unmap();
+
+        if self.bgra {
+            for px in pixels.chunks_exact_mut(4) {
+                px.swap(0, 2);
+            }
+        }
+        pixels
+    }
+}
+
+// The bytes are what the surface displayed, already tone mapped and encoded.
+fn write_png(path: &Path, dims: (u32, u32), pixels: Vec<u8>) -> anyhow::Result<()> {
+    let image = image::RgbaImage::from_raw(dims.0, dims.1, pixels)
+        .context("Pixels do not match the dims")?;
+    image
+        .save_with_format(path, image::ImageFormat::Png)
+        .with_context(|| format!("Failed to write {}", path.display()))
+}
@@ -0,0 +1,34 @@
+use bevy_ecs::component::Component;
+use glam::Vec3;
+
+use crate::{bvh::AABB, transform::Transform};
+
+// Geometry index marking an instance as an analytic sphere rather than a mesh.
+// Matches GEOMETRY_SPHERE in common.slang.
+pub const SPHERE_GEOMETRY: u32 = u32::MAX;
+
+// Object space bounds of the unit sphere every sphere instance is traced as.
+pub const SPHERE_AABB: AABB = AABB {
+    lb: Vec3::splat(-1.0),
+    ub: Vec3::splat(1.0),
+};
+
+// An exact sphere, intersected analytically instead of being tessellated. Spawned with a
+// Transform placing its centre and a MaterialId, like a mesh instance.
+#[derive(Component, Clone, Copy, Debug, PartialEq)]
+pub struct Sphere {
+    pub radius: f32,
+}
+
+impl Sphere {
+    pub fn new(radius: f32) -> Self {
+        Self { radius }
+    }
+
+    // The transform of the instance it's traced as, a unit sphere scaled up to the radius.
+    pub fn instance_transform(&self, transform: &Transform) -> Transform {
+        let mut t = *transform;
+        t.scale *= self.radius;
+        t
+    }
+}
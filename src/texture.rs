@@ -1,3 +1,291 @@
+use std::collections::HashMap;
+
+use anyhow::Context;
+use bevy_ecs::prelude::*;
+use tracing::info;
+
+use crate::{
+    app::BevyApp,
+    binder::binder_system,
+    memory::texture_bytes,
+    render_resources::{RenderDevice, RenderQueue},
+    schedule,
+};
+
+// Size of the texture binding array, mirrors MAX_TEXTURES in scene.slang.
+pub const MAX_TEXTURES: usize = 64;
+
+pub fn initialize(app: &mut BevyApp) {
+    app.world.insert_resource(TextureServer::default());
+    app.world.get_resource_or_init::<Schedules>().add_systems(
+        schedule::Update,
+        texture_upload_system.before(binder_system),
+    );
+}
+
+// Colour textures are stored sRGB encoded, data textures (roughness, normals...) are linear.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum TextureKind {
+    Colour,
+    Data,
+}
+
+// How a texture is read between texel centres.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TextureFilter {
+    Nearest,
+    #[default]
+    Bilinear,
+}
+
+// How uvs outside 0.0..=1.0 land back on the texture. Filtering follows the same rule, so
+// bilinear reads across a repeating texture's edge blend with the texels on the far side.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TextureAddress {
+    #[default]
+    Repeat,
+    // Holds the edge texels.
+    Clamp,
+    // Repeats, flipping every other tile.
+    Mirror,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub struct TextureSampling {
+    pub filter: TextureFilter,
+    pub address: TextureAddress,
+}
+
+impl TextureSampling {
+    fn descriptor(self) -> wgpu::SamplerDescriptor<'static> {
+        let filter = match self.filter {
+            TextureFilter::Nearest => wgpu::FilterMode::Nearest,
+            TextureFilter::Bilinear => wgpu::FilterMode::Linear,
+        };
+        let address = match self.address {
+            TextureAddress::Repeat => wgpu::AddressMode::Repeat,
+            TextureAddress::Clamp => wgpu::AddressMode::ClampToEdge,
+            TextureAddress::Mirror => wgpu::AddressMode::MirrorRepeat,
+        };
+        wgpu::SamplerDescriptor {
+            label: Some("Texture Sampler"),
+            address_mode_u: address,
+            address_mode_v: address,
+            mag_filter: filter,
+            min_filter: filter,
+            ..Default::default()
+        }
+    }
+}
+
+#[derive(Clone, Copy, Component, Debug, Eq, PartialEq, Hash)]
+pub struct TextureId(usize);
+
+impl TextureId {
+    // The 1-based index materials refer to textures by, 0 being no texture.
+    pub fn material_index(self) -> u32 {
+        self.0 as u32 + 1
+    }
+}
+
+struct GpuTexture {
+    texture: wgpu::Texture,
+    view: wgpu::TextureView,
+    sampling: TextureSampling,
+}
+
+struct TextureLoading {
+    id: TextureId,
+    kind: TextureKind,
+    sampling: TextureSampling,
+    image: image::RgbaImage,
+}
+
+#[derive(Resource, Default)]
+pub struct TextureServer {
+    loading: Vec<TextureLoading>,
+    textures: Vec<Option<GpuTexture>>,
+    by_path: HashMap<(String, TextureKind, TextureSampling), TextureId>,
+    // Bound in every slot without a texture, the binding array can't have holes:
+    fallback: Option<GpuTexture>,
+    // One per sampling in use, each texture slot is bound with its own:
+    samplers: HashMap<TextureSampling, wgpu::Sampler>,
+}
+
+fn texture_upload_system(
+    mut texture_server: ResMut<TextureServer>,
+    device: Res<RenderDevice>,
+    queue: Res<RenderQueue>,
+) {
+    if texture_server.fallback.is_none() {
+        let white = image::RgbaImage::from_pixel(1, 1, image::Rgba([u8::MAX; 4]));
+        let server = texture_server.as_mut();
+        server.fallback = Some(GpuTexture::new(
+            &device.0,
+            &queue.0,
+            &white,
+            TextureKind::Data,
+            TextureSampling::default(),
+        ));
+        server.samplers.insert(
+            TextureSampling::default(),
+            device
+                .0
+                .create_sampler(&TextureSampling::default().descriptor()),
+        );
+    }
+
+    if texture_server.loading.is_empty() {
+        return;
+    }
+
+    let TextureServer {
+        loading,
+        textures,
+        samplers,
+        ..
+    } = texture_server.as_mut();
+    for l in loading.drain(..) {
+        samplers
+            .entry(l.sampling)
+            .or_insert_with(|| device.0.create_sampler(&l.sampling.descriptor()));
+        textures[l.id.0] = Some(GpuTexture::new(
+            &device.0, &queue.0, &l.image, l.kind, l.sampling,
+        ));
+    }
+}
+
+impl GpuTexture {
+    fn new(
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        image: &image::RgbaImage,
+        kind: TextureKind,
+        sampling: TextureSampling,
+    ) -> Self {
+        let size = wgpu::Extent3d {
+            width: image.width(),
+            height: image.height(),
+            depth_or_array_layers: 1,
+        };
+        // sRGB views are decoded to linear by the sampler:
+        let format = match kind {
+            TextureKind::Colour => wgpu::TextureFormat::Rgba8UnormSrgb,
+            TextureKind::Data => wgpu::TextureFormat::Rgba8Unorm,
+        };
+        let texture = device.create_texture(&wgpu::TextureDescriptor {
+            label: Some("Material Texture"),
+            size,
+            mip_level_count: 1,
+            sample_count: 1,
+            dimension: wgpu::TextureDimension::D2,
+            format,
+            usage: wgpu::TextureUsages::TEXTURE_BINDING | wgpu::TextureUsages::COPY_DST,
+            view_formats: &[],
+        });
+
+        queue.write_texture(
+            wgpu::TexelCopyTextureInfo {
+                texture: &texture,
+                mip_level: 0,
+                origin: wgpu::Origin3d::ZERO,
+                aspect: wgpu::TextureAspect::All,
+            },
+            image.as_raw(),
+            wgpu::TexelCopyBufferLayout {
+                offset: 0,
+                bytes_per_row: Some(4 * image.width()),
+                rows_per_image: Some(image.height()),
+            },
+            size,
+        );
+
+        let view = texture.create_view(&wgpu::TextureViewDescriptor::default());
+        Self {
+            texture,
+            view,
+            sampling,
+        }
+    }
+}
+
+impl TextureServer {
+    // Decodes the image now and uploads it on the next update. The same image with another
+    // sampling is another texture, as each slot has the one sampler.
+    pub fn load_texture(
+        &mut self,
+        path: &str,
+        kind: TextureKind,
+        sampling: TextureSampling,
+    ) -> anyhow::Result<TextureId> {
+        let key = (path.to_owned(), kind, sampling);
+        if let Some(id) = self.by_path.get(&key) {
+            return Ok(*id);
+        }
+        if self.textures.len() >= MAX_TEXTURES {
+            anyhow::bail!("Can't load {path}, already at the limit of {MAX_TEXTURES} textures");
+        }
+
+        let image = image::open(path)
+            .with_context(|| format!("Failed to load texture {path}"))?
+            .into_rgba8();
+        info!(
+            "Loaded texture {path} ({}x{})",
+            image.width(),
+            image.height()
+        );
+
+        let id = TextureId(self.textures.len());
+        self.textures.push(None);
+        self.loading.push(TextureLoading {
+            id,
+            kind,
+            sampling,
+            image,
+        });
+        self.by_path.insert(key, id);
+        Ok(id)
+    }
+
+    // Every slot of the binding array, textures still loading show the fallback.
+    pub fn views(&self) -> Option<Vec<&wgpu::TextureView>> {
+        let fallback = &self.fallback.as_ref()?.view;
+        Some(
+            (0..MAX_TEXTURES)
+                .map(|i| match self.textures.get(i) {
+                    Some(Some(texture)) => &texture.view,
+                    _ => fallback,
+                })
+                .collect(),
+        )
+    }
+
+    // Bytes in the uploaded textures, the fallback included.
+    pub fn texture_bytes(&self) -> u64 {
+        self.textures
+            .iter()
+            .flatten()
+            .chain(&self.fallback)
+            .map(|t| texture_bytes(&t.texture))
+            .sum()
+    }
+
+    // The sampler for every slot of the binding array, matching `views`.
+    pub fn samplers(&self) -> Option<Vec<&wgpu::Sampler>> {
+        let fallback = self.samplers.get(&self.fallback.as_ref()?.sampling)?;
+        Some(
+            (0..MAX_TEXTURES)
+                .map(|i| match self.textures.get(i) {
+                    Some(Some(texture)) => &self.samplers[&texture.sampling],
+                    _ => fallback,
+                })
+                .collect(),
+        )
+    }
+}
+
 // use anyhow::*;
 // use image::GenericImageView;
 // use tracing::error;
@@ -1,5 +1,4 @@
 use bevy_ecs::resource::Resource;
-use glam::Mat4;
 use glam::UVec3;
 use glam::Vec3;
 use glam::Vec4Swizzles;
@@ -13,8 +12,10 @@ use crate::bvh::AABBGPU;
 use crate::bvh::BVH;
 use crate::bvh::BVHNode;
 use crate::bvh::BVHNodeGPU;
+use crate::bvh::SplitStrategy;
 use crate::instance::Instance;
 use crate::mesh::Mesh;
+use crate::sphere::{SPHERE_AABB, SPHERE_GEOMETRY};
 use crate::transform::Transform;
 
 #[derive(Debug, Resource, Default)]
@@ -57,46 +58,62 @@ impl BVH for TLAS {
     }
 }
 
-impl TLAS {
-    pub fn new(aabbs: &Vec<AABB>, transforms: &Vec<Transform>, instances: &Vec<Instance>) -> Self {
-        let aabbs = instances
-            .iter()
-            .map(|i| {
-                let aabb = aabbs[i.geometry_idx as usize];
-                let corners = repeat_n((0..=1).into_iter(), 3)
-                    .multi_cartesian_product()
-                    .map(|p| {
-                        let [x, y, z] = p.try_into().unwrap();
-                        Vec3::new(
-                            if x == 0 { aabb.lb.x } else { aabb.ub.x },
-                            if y == 0 { aabb.lb.y } else { aabb.ub.y },
-                            if z == 0 { aabb.lb.z } else { aabb.ub.z },
-                        )
-                    })
-                    .collect_vec();
-
-                let transform = &transforms[i.transform_idx as usize];
-
-                let translate = Mat4::from_translation(transform.translation.truncate());
+// World space bounds of each instance, in instance order. Moving instances are bounded
+// over everywhere they pass through while the shutter is open.
+fn instance_aabbs(
+    aabbs: &[AABB],
+    transforms: &[Transform],
+    instances: &[Instance],
+    shutter_time: f32,
+) -> Vec<AABB> {
+    instances
+        .iter()
+        .map(|i| {
+            let aabb = if i.geometry_idx == SPHERE_GEOMETRY {
+                SPHERE_AABB
+            } else {
+                aabbs[i.geometry_idx as usize]
+            };
+            let corners = repeat_n((0..=1).into_iter(), 3)
+                .multi_cartesian_product()
+                .map(|p| {
+                    let [x, y, z] = p.try_into().unwrap();
+                    Vec3::new(
+                        if x == 0 { aabb.lb.x } else { aabb.ub.x },
+                        if y == 0 { aabb.lb.y } else { aabb.ub.y },
+                        if z == 0 { aabb.lb.z } else { aabb.ub.z },
+                    )
+                })
+                .collect_vec();
 
-                let rotate = Mat4::from_rotation_x(transform.rotation.x).mul_mat4(
-                    &Mat4::from_rotation_y(transform.rotation.y)
-                        .mul_mat4(&Mat4::from_rotation_z(transform.rotation.z)),
-                );
+            let transform = &transforms[i.transform_idx as usize];
 
-                let scale = Mat4::from_scale(transform.scale.truncate());
+            let m = transform.matrix();
 
-                let m = translate.mul_mat4(&rotate.mul_mat4(&scale));
-
-                let aabb = corners
-                    .iter()
-                    .map(|c| m.mul_vec4(c.extend(1.0)).xyz())
-                    .map(|c| AABB { lb: c, ub: c })
-                    .reduce(|acc, aabb| acc.union(&aabb))
-                    .unwrap();
-                aabb
+            let aabb = corners
+                .iter()
+                .map(|c| m.mul_vec4(c.extend(1.0)).xyz())
+                .map(|c| AABB { lb: c, ub: c })
+                .reduce(|acc, aabb| acc.union(&aabb))
+                .unwrap();
+            let swept = i.velocity.xyz() * shutter_time;
+            aabb.union(&AABB {
+                lb: aabb.lb + swept,
+                ub: aabb.ub + swept,
             })
-            .collect_vec();
+        })
+        .collect_vec()
+}
+
+impl TLAS {
+    pub fn new(
+        aabbs: &[AABB],
+        transforms: &[Transform],
+        instances: &[Instance],
+        leaf_size: usize,
+        shutter_time: f32,
+    ) -> Self {
+        let aabbs = instance_aabbs(aabbs, transforms, instances, shutter_time);
 
         let aabbs2 = aabbs.clone();
         let mut bvh = TLAS {
@@ -127,7 +144,7 @@ impl TLAS {
         //     }
         // }
         // println!("-------");
-        bvh.initialize(1);
+        bvh.initialize(leaf_size.max(1), SplitStrategy::Midpoint);
         // for i in 0..bvh.instance_ids.len() {
         //     println!(
         //         "i: {} id: {}, lb: {}, ub: {}",
@@ -156,6 +173,30 @@ impl TLAS {
 
         bvh
     }
+
+    // Recomputes every node's bounds from the current instance transforms,
+    // keeping the existing partitioning. Only valid while the instances and
+    // their geometry are the same as when the tree was built.
+    pub fn refit(
+        &mut self,
+        aabbs: &[AABB],
+        transforms: &[Transform],
+        instances: &[Instance],
+        shutter_time: f32,
+    ) {
+        let world_aabbs = instance_aabbs(aabbs, transforms, instances, shutter_time);
+        self.aabbs = self
+            .instance_ids
+            .iter()
+            .map(|&id| world_aabbs[id])
+            .collect_vec();
+
+        // Children are always pushed after their parents, so walking the
+        // nodes backwards visits each subtree before its root:
+        for idx in (0..self.nodes.len()).rev() {
+            self.compute_node_bounds(idx);
+        }
+    }
 }
 
 // pub struct TLASData {
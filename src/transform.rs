@@ -1,10 +1,64 @@
 use bevy_ecs::component::Component;
-use glam::Vec4;
+use glam::{EulerRot, Mat3, Mat4, Quat, Vec3, Vec4};
 
 #[repr(C)]
-#[derive(Copy, Clone, Debug, bytemuck::Pod, bytemuck::Zeroable, Default, Component)]
+#[derive(Copy, Clone, Debug, bytemuck::Pod, bytemuck::Zeroable, Component)]
 pub struct Transform {
     pub scale: Vec4,
-    pub rotation: Vec4,
+    pub rotation: Vec4, // Unit quaternion, xyzw
     pub translation: Vec4,
 }
+
+impl Default for Transform {
+    fn default() -> Self {
+        Self::from_trs(Vec3::ZERO, Quat::IDENTITY, Vec3::ONE)
+    }
+}
+
+impl Transform {
+    // Rotation is XYZ euler angles in radians, as transforms were stored before quaternions.
+    pub fn new(scale: Vec3, rotation: Vec3, translation: Vec3) -> Self {
+        Self::from_trs(translation, euler_to_quat(rotation), scale)
+    }
+
+    pub fn from_trs(translation: Vec3, rotation: Quat, scale: Vec3) -> Self {
+        Self {
+            scale: scale.extend(0.0),
+            rotation: Vec4::from(rotation.normalize()),
+            translation: translation.extend(1.0),
+        }
+    }
+
+    pub fn rotation_quat(&self) -> Quat {
+        Quat::from_vec4(self.rotation).normalize()
+    }
+
+    pub fn set_rotation(&mut self, rotation: Quat) {
+        self.rotation = Vec4::from(rotation.normalize());
+    }
+
+    // Turns the local +Z axis towards `target`, keeping local +Y as close to `up` as it can.
+    pub fn look_at(&mut self, target: Vec3, up: Vec3) {
+        let f = (target - self.translation.truncate()).normalize();
+        let r = up.cross(f).normalize();
+        if !f.is_finite() || !r.is_finite() {
+            return;
+        }
+        let u = f.cross(r);
+        self.set_rotation(Quat::from_mat3(&Mat3::from_cols(r, u, f)));
+    }
+
+    // Scale, then rotate, then translate. Mirrors Transform.matrix() in common.slang.
+    pub fn matrix(&self) -> Mat4 {
+        Mat4::from_scale_rotation_translation(
+            self.scale.truncate(),
+            self.rotation_quat(),
+            self.translation.truncate(),
+        )
+    }
+}
+
+// The euler order the shader used, rotating about Z, then Y, then X.
+pub fn euler_to_quat(rotation: Vec3) -> Quat {
+    Quat::from_euler(EulerRot::XYZ, rotation.x, rotation.y, rotation.z)
+}
@@ -0,0 +1,507 @@
+use glam::{Mat4, Vec3, Vec4Swizzles};
+#[cfg(test)]
+use itertools::Itertools;
+
+#[cfg(test)]
+use crate::{blas::BLAS, tlas::TLAS};
+use crate::{
+    bvh::BVHNodeGPU,
+    instance::Instance,
+    mesh::{FACE_FLAT, Mesh},
+    sphere::SPHERE_GEOMETRY,
+    transform::Transform,
+};
+
+// CPU mirrors of the nearest hit search in traverse.slang, as ground truth for it. The
+// nodes are walked as they're uploaded, in BVHNodeGPU form: left on a box hit, otherwise
+// along the skip link. Analytic planes aren't in the TLAS, see planes_first_hit.
+
+#[derive(Clone, Copy, Debug)]
+pub struct Ray {
+    pub pos: Vec3,
+    pub dir: Vec3,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct Hit {
+    pub t: f32,
+    pub position: Vec3,
+    // Interpolated as the shader does, only normalized for TLAS hits:
+    pub normal: Vec3,
+    pub triangle_id: u32,
+    // Only meaningful for TLAS hits:
+    pub instance_id: u32,
+}
+
+// Whole structure walks, only the tests below fire rays through these:
+#[cfg(test)]
+impl BLAS {
+    pub fn intersect(&self, ray: Ray) -> Option<Hit> {
+        let nodes = gpu_nodes(&self.nodes);
+        let mut t = f32::MAX;
+        blas_first_hit(&nodes, &self.mesh, ray, &mut t)
+    }
+}
+
+#[cfg(test)]
+impl TLAS {
+    // `blases` is indexed by geometry, as the mesh server lays them out.
+    pub fn intersect(
+        &self,
+        ray: Ray,
+        instances: &[Instance],
+        transforms: &[Transform],
+        blases: &[BLAS],
+    ) -> Option<Hit> {
+        let nodes = gpu_nodes(&self.nodes);
+        let blas_nodes = blases.iter().map(|b| gpu_nodes(&b.nodes)).collect_vec();
+
+        let mut t = f32::MAX;
+        tlas_first_hit(
+            &nodes,
+            &self.instance_ids,
+            instances,
+            transforms,
+            |g| Some((blas_nodes[g].as_slice(), &blases[g].mesh)),
+            ray,
+            0,
+            &mut t,
+        )
+    }
+}
+
+// The TLAS walk on nodes already in GPU form, with `geometry` giving each geometry index's
+// BLAS nodes and mesh. Instances whose geometry it doesn't have are missed, as are those
+// without every flag in `mask`.
+#[allow(clippy::too_many_arguments)]
+pub fn tlas_first_hit<'a>(
+    nodes: &[BVHNodeGPU],
+    instance_ids: &[usize],
+    instances: &[Instance],
+    transforms: &[Transform],
+    geometry: impl Fn(usize) -> Option<(&'a [BVHNodeGPU], &'a Mesh)>,
+    ray: Ray,
+    mask: u32,
+    t: &mut f32,
+) -> Option<Hit> {
+    let mut hit = None;
+    walk(nodes, ray, true, t, |range, t| {
+        for i in range {
+            let instance_id = instance_ids[i as usize] as u32;
+            let instance = instances[instance_id as usize];
+            if instance.flags & mask != mask {
+                continue;
+            }
+            let m = transforms[instance.transform_idx as usize].matrix();
+            let r = object_ray(ray, m);
+
+            let mut t2 = *t;
+            let h = if instance.geometry_idx == SPHERE_GEOMETRY {
+                ray_sphere_intersect(r, &mut t2)
+            } else {
+                geometry(instance.geometry_idx as usize)
+                    .and_then(|(nodes, mesh)| blas_first_hit(nodes, mesh, r, &mut t2))
+            };
+            if let Some(h) = h {
+                *t = t2;
+                hit = Some(hit_to_world(h, m, instance_id));
+            }
+        }
+    });
+    hit
+}
+
+// The unbounded planes the TLAS leaves out, tested one by one as the shader does after its
+// TLAS walk. `plane_instances` may hold u32::MAX padding.
+pub fn planes_first_hit(
+    plane_instances: &[u32],
+    instances: &[Instance],
+    transforms: &[Transform],
+    ray: Ray,
+    mask: u32,
+    t: &mut f32,
+) -> Option<Hit> {
+    let mut hit = None;
+    for &instance_id in plane_instances {
+        let Some(instance) = instances.get(instance_id as usize) else {
+            continue;
+        };
+        if instance.flags & mask != mask {
+            continue;
+        }
+        let m = transforms[instance.transform_idx as usize].matrix();
+        if let Some(h) = ray_plane_intersect(object_ray(ray, m), t) {
+            hit = Some(hit_to_world(h, m, instance_id));
+        }
+    }
+    hit
+}
+
+fn object_ray(ray: Ray, m: Mat4) -> Ray {
+    let mi = m.inverse();
+    Ray {
+        pos: mi.transform_point3(ray.pos),
+        dir: mi.transform_vector3(ray.dir),
+    }
+}
+
+fn hit_to_world(h: Hit, m: Mat4, instance_id: u32) -> Hit {
+    Hit {
+        position: m.transform_point3(h.position),
+        normal: m.transform_vector3(h.normal).normalize(),
+        instance_id,
+        ..h
+    }
+}
+
+#[cfg(test)]
+fn gpu_nodes(nodes: &[crate::bvh::BVHNode]) -> Vec<BVHNodeGPU> {
+    nodes.iter().map(|n| BVHNodeGPU::from(*n)).collect_vec()
+}
+
+// The stackless loop both first hit searches share. `leaf` is given the element range of
+// every leaf the ray reaches and may shorten `t`. The TLAS also skips boxes behind the ray.
+fn walk(
+    nodes: &[BVHNodeGPU],
+    ray: Ray,
+    cull_behind: bool,
+    t: &mut f32,
+    mut leaf: impl FnMut(std::ops::Range<u32>, &mut f32),
+) {
+    let root = 0;
+    let mut current = 0;
+    loop {
+        let node = &nodes[current as usize];
+        let lb = Vec3::from_array(node.aabb.lower_bound);
+        let ub = Vec3::from_array(node.aabb.upper_bound);
+        let (hit_aabb, tmin, tmax) = ray_box_intersect(ray, lb, ub, *t);
+        let hit_aabb = hit_aabb && (!cull_behind || tmax >= 0.0 || tmin >= 0.0);
+
+        current = if hit_aabb { node.left } else { node.skip };
+        if node.is_leaf == 1 {
+            current = node.skip;
+        }
+
+        if hit_aabb && node.is_leaf == 1 {
+            leaf(node.start..node.end, t);
+        }
+        if current == root {
+            break;
+        }
+    }
+}
+
+fn blas_first_hit(nodes: &[BVHNodeGPU], mesh: &Mesh, ray: Ray, t: &mut f32) -> Option<Hit> {
+    let mut hit = None;
+    walk(nodes, ray, false, t, |range, t| {
+        for p in range {
+            let face = mesh.faces[p as usize];
+            let [i0, i1, i2] = face.xyz().to_array().map(|i| i as usize);
+            let positions = [i0, i1, i2].map(|i| mesh.positions[i].xyz());
+            let normals = [i0, i1, i2].map(|i| mesh.normals[i].xyz());
+            let flat_shaded = (face.w & FACE_FLAT) != 0;
+            if let Some(h) = ray_tri_intersect(ray, positions, normals, flat_shaded, t) {
+                hit = Some(Hit {
+                    triangle_id: p,
+                    ..h
+                });
+            }
+        }
+    });
+    hit
+}
+
+fn ray_box_intersect(ray: Ray, lb: Vec3, ub: Vec3, t: f32) -> (bool, f32, f32) {
+    let mut tmin = f32::MIN;
+    let mut tmax = t;
+    let dir_inv = 1.0 / ray.dir;
+
+    for d in 0..3 {
+        let sign = dir_inv[d] >= 0.0;
+        let bmin = if sign { lb[d] } else { ub[d] };
+        let bmax = if sign { ub[d] } else { lb[d] };
+
+        let dmin = (bmin - ray.pos[d]) * dir_inv[d];
+        let dmax = (bmax - ray.pos[d]) * dir_inv[d];
+
+        tmin = dmin.max(tmin);
+        tmax = dmax.min(tmax);
+    }
+
+    (tmin <= tmax, tmin, tmax)
+}
+
+fn ray_tri_intersect(
+    ray: Ray,
+    [p0, p1, p2]: [Vec3; 3],
+    [n0, n1, n2]: [Vec3; 3],
+    flat_shaded: bool,
+    t: &mut f32,
+) -> Option<Hit> {
+    let e1 = p1 - p0;
+    let e2 = p2 - p0;
+    let q = ray.dir.cross(e2);
+    let alpha = e1.dot(q);
+    if alpha > -10e-8 && alpha < 10e-8 {
+        return None;
+    }
+    let f = 1.0 / alpha;
+    let s = ray.pos - p0;
+    let u = f * s.dot(q);
+    if u < 0.0 {
+        return None;
+    }
+    let r = s.cross(e1);
+    let v = f * ray.dir.dot(r);
+    if v < 0.0 || u + v > 1.0 {
+        return None;
+    }
+
+    let t2 = f * e2.dot(r);
+    if t2 > *t || t2 < 0.0 {
+        return None;
+    }
+
+    *t = t2;
+    let normal = if flat_shaded {
+        e1.cross(e2).normalize()
+    } else {
+        n0 * (1.0 - u - v) + n1 * u + n2 * v
+    };
+    Some(Hit {
+        t: t2,
+        position: p0 + e1 * u + e2 * v,
+        normal,
+        triangle_id: 0,
+        instance_id: 0,
+    })
+}
+
+// The unit sphere at the origin, for a ray that doesn't start on it.
+fn ray_sphere_intersect(ray: Ray, t: &mut f32) -> Option<Hit> {
+    let a = ray.dir.dot(ray.dir);
+    let b = ray.pos.dot(ray.dir);
+    let c = ray.pos.dot(ray.pos) - 1.0;
+    let disc = b * b - a * c;
+    if disc < 0.0 {
+        return None;
+    }
+    let sq = disc.sqrt();
+
+    let mut t2 = (-b - sq) / a;
+    if t2 < 0.0 {
+        // Starting inside:
+        t2 = (-b + sq) / a;
+    }
+    if t2 < 0.0 || t2 > *t {
+        return None;
+    }
+
+    *t = t2;
+    let n = (ray.pos + ray.dir * t2).normalize();
+    Some(Hit {
+        t: t2,
+        position: n,
+        normal: n,
+        triangle_id: 0,
+        instance_id: 0,
+    })
+}
+
+// The y = 0 plane of object space, facing +Y.
+fn ray_plane_intersect(ray: Ray, t: &mut f32) -> Option<Hit> {
+    // Near parallel rays would only graze it, far off and with a huge t:
+    if ray.dir.y.abs() < 1e-6 * ray.dir.length() {
+        return None;
+    }
+    let t2 = -ray.pos.y / ray.dir.y;
+    if t2 < 0.0 || t2 > *t {
+        return None;
+    }
+
+    *t = t2;
+    let p = ray.pos + ray.dir * t2;
+    Some(Hit {
+        t: t2,
+        position: Vec3::new(p.x, 0.0, p.z),
+        normal: Vec3::Y,
+        triangle_id: 0,
+        instance_id: 0,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use glam::{Quat, Vec4};
+
+    use super::*;
+    use crate::{bvh::BVH, mesh::NormalizeMode};
+
+    const TEAPOT: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/assets/teapot.obj");
+
+    fn teapot() -> BLAS {
+        BLAS::new(Mesh::from_obj(TEAPOT, &NormalizeMode::UnitCube).unwrap(), 4)
+    }
+
+    // Every face tested in turn, with no BVH to get wrong:
+    fn brute_force(mesh: &Mesh, ray: Ray) -> Option<Hit> {
+        let mut t = f32::MAX;
+        let mut hit = None;
+        for (p, face) in mesh.faces.iter().enumerate() {
+            let [i0, i1, i2] = face.xyz().to_array().map(|i| i as usize);
+            let positions = [i0, i1, i2].map(|i| mesh.positions[i].xyz());
+            let normals = [i0, i1, i2].map(|i| mesh.normals[i].xyz());
+            let flat_shaded = (face.w & FACE_FLAT) != 0;
+            if let Some(h) = ray_tri_intersect(ray, positions, normals, flat_shaded, &mut t) {
+                hit = Some(Hit {
+                    triangle_id: p as u32,
+                    ..h
+                });
+            }
+        }
+        hit
+    }
+
+    // Rays at the origin from all around, off centre so they don't all graze an edge:
+    fn rays_at_origin() -> impl Iterator<Item = Ray> {
+        (0..64).map(|i| {
+            let a = i as f32 * 0.61;
+            let pos = Vec3::new(a.cos() * 3.0, (i as f32 * 0.37).sin() * 2.0, a.sin() * 3.0);
+            let target = Vec3::new(0.05, 0.03 * (i % 7) as f32, -0.02);
+            Ray {
+                pos,
+                dir: (target - pos).normalize(),
+            }
+        })
+    }
+
+    #[test]
+    fn blas_hits_cube_face() {
+        let cube = BLAS::new(Mesh::cube(), 1);
+        let ray = Ray {
+            pos: Vec3::new(0.1, 0.2, 5.0),
+            dir: Vec3::NEG_Z,
+        };
+        let hit = cube.intersect(ray).expect("Expected to hit the cube");
+        assert!((hit.t - 4.5).abs() < 1e-5, "t {}", hit.t);
+        assert!((hit.position - Vec3::new(0.1, 0.2, 0.5)).length() < 1e-5);
+        assert!(hit.normal.normalize().dot(Vec3::Z) > 0.999);
+
+        // The +Z face, whichever of its two triangles:
+        let face = cube.mesh.faces[hit.triangle_id as usize];
+        for i in face.xyz().to_array() {
+            assert_eq!(cube.mesh.positions[i as usize].z, 0.5);
+        }
+        assert_eq!(
+            hit.triangle_id,
+            brute_force(&cube.mesh, ray).unwrap().triangle_id
+        );
+
+        let miss = Ray {
+            pos: Vec3::new(0.6, 0.0, 5.0),
+            dir: Vec3::NEG_Z,
+        };
+        assert!(cube.intersect(miss).is_none());
+    }
+
+    #[test]
+    fn blas_matches_brute_force_on_teapot() {
+        let teapot = teapot();
+        let mut hits = 0;
+        for ray in rays_at_origin() {
+            let expected = brute_force(&teapot.mesh, ray);
+            let hit = teapot.intersect(ray);
+            match (hit, expected) {
+                (Some(hit), Some(expected)) => {
+                    assert_eq!(hit.triangle_id, expected.triangle_id, "{ray:?}");
+                    assert_eq!(hit.t, expected.t, "{ray:?}");
+                    hits += 1;
+                }
+                (None, None) => {}
+                _ => panic!("{ray:?}: BVH hit {hit:?}, brute force {expected:?}"),
+            }
+        }
+        assert!(hits > 32, "Only {hits} rays hit the teapot");
+    }
+
+    #[test]
+    fn tlas_finds_instances() {
+        let blases = [BLAS::new(Mesh::cube(), 1), teapot()];
+        let aabbs = blases.iter().map(|b| b.node_bounds(0)).collect_vec();
+        let transforms = [
+            Transform::from_trs(Vec3::new(3.0, 0.0, 0.0), Quat::IDENTITY, Vec3::ONE),
+            Transform::from_trs(Vec3::new(-3.0, 0.0, 0.0), Quat::IDENTITY, Vec3::splat(2.0)),
+        ];
+        let instances = [0, 1].map(|i| Instance {
+            transform_idx: i,
+            geometry_idx: i,
+            material_idx: 0,
+            flags: 0,
+            velocity: Vec4::ZERO,
+        });
+        let tlas = TLAS::new(&aabbs, &transforms, &instances, 1, 0.0);
+        let intersect = |ray| tlas.intersect(ray, &instances, &transforms, &blases);
+
+        let hit = intersect(Ray {
+            pos: Vec3::new(3.1, 0.2, 5.0),
+            dir: Vec3::NEG_Z,
+        })
+        .expect("Expected to hit the cube");
+        assert_eq!(hit.instance_id, 0);
+        assert!((hit.t - 4.5).abs() < 1e-5, "t {}", hit.t);
+        assert!((hit.position - Vec3::new(3.1, 0.2, 0.5)).length() < 1e-5);
+        assert_eq!(
+            hit.triangle_id,
+            blases[0]
+                .intersect(Ray {
+                    pos: Vec3::new(0.1, 0.2, 5.0),
+                    dir: Vec3::NEG_Z,
+                })
+                .unwrap()
+                .triangle_id
+        );
+
+        // The teapot is twice the size, so the same t but half the object space direction:
+        let ray = Ray {
+            pos: Vec3::new(-3.0, 0.1, 5.0),
+            dir: Vec3::NEG_Z,
+        };
+        let hit = intersect(ray).expect("Expected to hit the teapot");
+        let expected = brute_force(
+            &blases[1].mesh,
+            Ray {
+                pos: Vec3::new(0.0, 0.05, 2.5),
+                dir: Vec3::NEG_Z * 0.5,
+            },
+        )
+        .unwrap();
+        assert_eq!(hit.instance_id, 1);
+        assert_eq!(hit.triangle_id, expected.triangle_id);
+        assert!(
+            (hit.t - expected.t).abs() < 1e-4,
+            "t {} {}",
+            hit.t,
+            expected.t
+        );
+
+        // Between the two:
+        assert!(
+            intersect(Ray {
+                pos: Vec3::new(0.0, 0.0, 5.0),
+                dir: Vec3::NEG_Z,
+            })
+            .is_none()
+        );
+    }
+
+    // A scene of planes alone still binds a TLAS, see binder_system:
+    #[test]
+    fn empty_tlas_misses() {
+        let tlas = TLAS::new(&[], &[], &[], 1, 0.0);
+        assert_eq!(tlas.nodes.len(), 1);
+        assert!(tlas.nodes[0].is_leaf);
+        for ray in rays_at_origin() {
+            assert!(tlas.intersect(ray, &[], &[], &[]).is_none());
+        }
+    }
+}
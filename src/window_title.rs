@@ -0,0 +1,69 @@
+use bevy_ecs::prelude::*;
+
+use crate::{
+    app::BevyApp, convergence::ConvergenceStats, delta_time::Time, pathtracer::Pathtracer,
+    schedule, winnit::WinitWindow,
+};
+
+pub fn initialize(app: &mut BevyApp) {
+    app.world
+        .get_resource_or_init::<Schedules>()
+        .add_systems(schedule::Update, window_title_system);
+}
+
+// Seconds between title updates, any more often just busies the compositor.
+const TITLE_INTERVAL: f64 = 1.0;
+// Weight of the latest frame in the smoothed frame time:
+const FRAME_TIME_SMOOTHING: f64 = 0.1;
+
+#[derive(Default)]
+struct TitleState {
+    frame_time: Option<f64>,
+    last_update: Option<f64>,
+}
+
+// Keeps a smoothed frame time off the frame clock and shows it in the window title, with
+// the primary pathtracer's resolution and samples per pixel. Headless there's no window,
+// so nothing to do.
+fn window_title_system(
+    mut state: Local<TitleState>,
+    time: Res<Time>,
+    window: Option<Res<WinitWindow>>,
+    pathtracers: Query<&Pathtracer>,
+    convergence: Option<Res<ConvergenceStats>>,
+) {
+    let Some(window) = window else {
+        return;
+    };
+
+    let delta = time.delta();
+    if delta > 0.0 {
+        let smoothed = state.frame_time.get_or_insert(delta);
+        *smoothed += (delta - *smoothed) * FRAME_TIME_SMOOTHING;
+    }
+
+    let now = time.elapsed();
+    if state.last_update.is_some_and(|t| now - t < TITLE_INTERVAL) {
+        return;
+    }
+    let Some(frame_time) = state.frame_time else {
+        return;
+    };
+    state.last_update = Some(now);
+
+    let mut title = format!(
+        "raytracer - {:.0} fps ({:.2} ms)",
+        1.0 / frame_time,
+        frame_time * 1000.0
+    );
+    if let Some(pt) = pathtracers.iter().find(|pt| pt.is_primary) {
+        title += &format!(" - {}x{}", pt.dims.0, pt.dims.1);
+    }
+    if let Some(stats) = convergence {
+        title += &format!(
+            " - {:.1} spp (min {}, max {})",
+            stats.mean_spp, stats.min_spp, stats.max_spp
+        );
+    }
+    window.0.set_title(&title);
+}
@@ -1,20 +1,21 @@
-use std::{sync::Arc, time::Instant};
+use std::sync::Arc;
 
 use bevy_ecs::prelude::*;
 use itertools::Itertools;
+use tracing::warn;
 use wgpu::{Instance, Surface};
 use winit::{
     application::ApplicationHandler,
     dpi::PhysicalSize,
     event::{KeyEvent, WindowEvent},
     event_loop::ActiveEventLoop,
-    keyboard::PhysicalKey,
-    window::Window,
+    keyboard::{KeyCode, PhysicalKey},
+    window::{CursorGrabMode, Window},
 };
 
 use crate::{
     app::BevyApp,
-    delta_time::DeltaTime,
+    delta_time::Time,
     render_resources::{RenderDevice, RenderQueue, RenderSurface},
     schedule,
 };
@@ -67,7 +68,8 @@ pub struct WinitApp {
     device_events: Vec<winit::event::DeviceEvent>,
     resize_event: Option<PhysicalSize<u32>>,
     first_resume: bool,
-    time: Instant,
+    // Only set once the grab has actually succeeded:
+    cursor_grabbed: bool,
 }
 
 impl WinitApp {
@@ -79,7 +81,7 @@ impl WinitApp {
             device_events: Vec::new(),
             resize_event: None,
             first_resume: false,
-            time: Instant::now(),
+            cursor_grabbed: false,
         }
     }
 }
@@ -117,13 +119,36 @@ impl WinitApp {
                 .write(WinitResizeEvent(e))
         });
 
-        self.bevy_app
-            .world
-            .insert_resource(DeltaTime(self.time.elapsed().as_secs_f64()));
-        self.time = Instant::now();
+        self.bevy_app.world.get_resource_or_init::<Time>().tick();
 
         self.bevy_app.run();
     }
+
+    // Locks and hides the cursor for mouse look, or releases it. Leaves the cursor as it
+    // was if the platform refuses the grab, so visibility never disagrees with it.
+    fn set_cursor_grab(&mut self, grab: bool) {
+        let Some(window) = &self.window else {
+            return;
+        };
+        let result = if grab {
+            // X11 can't lock, but confining still keeps motion events coming:
+            window
+                .set_cursor_grab(CursorGrabMode::Locked)
+                .or_else(|_| window.set_cursor_grab(CursorGrabMode::Confined))
+        } else {
+            window.set_cursor_grab(CursorGrabMode::None)
+        };
+        match result {
+            Ok(()) => {
+                window.set_cursor_visible(!grab);
+                self.cursor_grabbed = grab;
+            }
+            Err(e) => warn!(
+                "Failed to {} the cursor: {e}",
+                if grab { "grab" } else { "release" }
+            ),
+        }
+    }
 }
 
 impl ApplicationHandler for WinitApp {
@@ -138,10 +163,7 @@ impl ApplicationHandler for WinitApp {
         self.window = Some(window.clone());
 
         // winit stuff:
-        window
-            .set_cursor_grab(winit::window::CursorGrabMode::Locked)
-            .ok();
-        window.set_cursor_visible(false);
+        self.set_cursor_grab(true);
 
         // register some systems
         self.bevy_app
@@ -174,7 +196,8 @@ impl ApplicationHandler for WinitApp {
         event: winit::event::DeviceEvent,
     ) {
         match event {
-            winit::event::DeviceEvent::MouseMotion { delta: (x, y) } => {
+            // The camera only looks around while the cursor is grabbed:
+            winit::event::DeviceEvent::MouseMotion { delta: (x, y) } if self.cursor_grabbed => {
                 self.device_events.push(event)
                 // const MOUSE_SENSITIVITY: f32 = 0.001;
                 // state.handle_mouse_motion(
@@ -201,14 +224,24 @@ impl ApplicationHandler for WinitApp {
                     KeyEvent {
                         physical_key: PhysicalKey::Code(code),
                         state: key_state,
+                        repeat,
                         ..
                     },
                 ..
-            } => self.window_events.push(event),
+            } => {
+                // Tab toggles between mouse look and a free cursor:
+                if code == KeyCode::Tab && key_state.is_pressed() && !repeat {
+                    self.set_cursor_grab(!self.cursor_grabbed);
+                }
+                self.window_events.push(event)
+            }
             WindowEvent::CursorMoved {
                 device_id,
                 position,
             } => self.window_events.push(event),
+            WindowEvent::MouseInput { .. } | WindowEvent::MouseWheel { .. } => {
+                self.window_events.push(event)
+            }
             _ => {}
         }
     }